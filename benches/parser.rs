@@ -0,0 +1,64 @@
+//! Compare the owned and borrowing decoders.
+use bytes::BytesMut;
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use telly::{TelnetEvent, TelnetOption, TelnetParser, TelnetSubnegotiation};
+
+// Log lines, with the occasional negotiation, as seen by a log relay
+fn text() -> Vec<u8> {
+    let mut bytes = Vec::new();
+    for i in 0..10_000 {
+        bytes.extend(
+            TelnetEvent::Data(format!("{i:05} INFO relay: forwarded message to sink\n").into())
+                .into_bytes(),
+        );
+        if i % 100 == 0 {
+            bytes.extend(TelnetEvent::will(TelnetOption::Echo).into_bytes());
+            bytes.extend(
+                TelnetEvent::from(TelnetSubnegotiation::NegotiateAboutWindowSize {
+                    width: 80,
+                    height: 24,
+                })
+                .into_bytes(),
+            );
+        }
+    }
+    bytes
+}
+
+// Binary data, where every 256th byte is an escaped IAC
+fn binary() -> Vec<u8> {
+    let data: Vec<u8> = (0..=u8::MAX).cycle().take(512 * 1024).collect();
+    TelnetEvent::Data(data).into_binary_bytes()
+}
+
+fn parse(c: &mut Criterion) {
+    let mut group = c.benchmark_group("parse");
+    for (name, input, binary) in [("text", text(), false), ("binary", binary(), true)] {
+        let mut parser = TelnetParser::default();
+        parser.set_binary(binary);
+        group.throughput(Throughput::Bytes(input.len() as u64));
+
+        group.bench_function(format!("{name}/owned"), |b| {
+            b.iter_batched_ref(
+                || BytesMut::from(&input[..]),
+                |rx_buffer| {
+                    while let Some(event) = parser.next_event(rx_buffer) {
+                        black_box(event);
+                    }
+                },
+                BatchSize::SmallInput,
+            )
+        });
+        group.bench_function(format!("{name}/borrowed"), |b| {
+            b.iter(|| {
+                for event in parser.parse_borrowed(&input) {
+                    black_box(event);
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, parse);
+criterion_main!(benches);
@@ -1,55 +1,12 @@
-use std::{
-    io::{Read, Write},
-    net::{TcpListener, TcpStream},
-    thread,
-};
-use telly::{TelnetEvent, TelnetOption, TelnetStream, TelnetSubnegotiation};
+use std::{io::Write, net::TcpStream};
+use telly::{server::TelnetServer, TelnetEvent, TelnetStream, TelnetSubnegotiation};
 
-struct TelnetServer {
-    listener: TcpListener,
-}
-
-impl TelnetServer {
-    pub fn new(host: &str) -> Self {
-        Self {
-            listener: TcpListener::bind(host).unwrap(),
-        }
-    }
-
-    pub fn listen(&self, cb: fn(TcpStream)) {
-        for connection in self.listener.incoming() {
-            match connection {
-                Ok(connection) => {
-                    thread::spawn(move || {
-                        cb(connection);
-                    });
-                }
-                Err(err) => {
-                    panic!("Error: {err}");
-                }
-            }
-        }
-    }
-}
-
-fn handle_client(stream: impl Write + Read) {
-    let mut stream = TelnetStream::from_stream(stream);
-
-    // Enable character mode
-    stream.send_will(TelnetOption::Echo).unwrap();
-    stream.send_will(TelnetOption::SuppressGoAhead).unwrap();
-    stream.send_do(TelnetOption::TerminalType).unwrap();
+fn handle_client(mut stream: TelnetStream<TcpStream>) {
     stream
         .send_event(TelnetSubnegotiation::TerminalTypeRequest.into())
         .unwrap();
 
-    // Get terminal size
-    stream
-        .send_do(TelnetOption::NegotiateAboutWindowSize)
-        .unwrap();
-
-    loop {
-        let event = stream.next().unwrap();
+    while let Some(event) = stream.next() {
         match event {
             TelnetEvent::Data(data) => {
                 for data in data {
@@ -88,7 +45,13 @@ fn handle_client(stream: impl Write + Read) {
 
 fn main() {
     let host = "127.0.0.1:8000";
-    let server = TelnetServer::new(host);
+    let mut server = TelnetServer::bind(host).unwrap();
+    // Enable character mode, and ask for the terminal type and size
+    server
+        .set_negotiation_script("WILL ECHO, WILL SGA, DO TTYPE, DO NAWS")
+        .unwrap();
     println!("Listening on {host}");
-    server.listen(handle_client);
+    server
+        .serve(|stream: TelnetStream<TcpStream>, _| handle_client(stream))
+        .unwrap();
 }
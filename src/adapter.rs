@@ -0,0 +1,184 @@
+//! A plain byte stream over a [TelnetStream], for code that wants [Read], [BufRead] or [Write].
+//!
+//! # Example
+//! ```no_run
+//! use std::{io::BufRead, net::TcpStream, sync::mpsc};
+//! use telly::{adapter::DataStream, TelnetStream};
+//!
+//! let stream = TelnetStream::from_stream(TcpStream::connect("127.0.0.1:23").unwrap());
+//! let (sender, receiver) = mpsc::channel();
+//! let stream = DataStream::new(stream, move |_, event| {
+//!     sender.send(event).ok();
+//!     Ok(())
+//! });
+//! for line in stream.lines() {
+//!     println!("{}", line.unwrap());
+//! }
+//! println!("Control events: {:?}", receiver.try_iter().collect::<Vec<_>>());
+//! ```
+use crate::{errors::TellyResult, TelnetEvent, TelnetStream};
+use std::io::{self, BufRead, Read, Write};
+
+/// Adapts a [TelnetStream] to [Read], [BufRead] and [Write], carrying only data.
+///
+/// Reads yield the payloads of [TelnetEvent::Data], and hand every other event to a callback,
+/// which may reply over the stream. Writes are sent with [TelnetStream::send_data], so they're
+/// IAC-escaped and NVT-encoded as needed.
+pub struct DataStream<S: Read + Write, F: FnMut(&mut TelnetStream<S>, TelnetEvent) -> TellyResult> {
+    stream: TelnetStream<S>,
+    on_event: F,
+    // Data received but not yet read, and how much of it has been read
+    data: Vec<u8>,
+    position: usize,
+}
+
+impl<S, F> DataStream<S, F>
+where
+    S: Read + Write,
+    F: FnMut(&mut TelnetStream<S>, TelnetEvent) -> TellyResult,
+{
+    /// Wrap `stream`, passing control events to `on_event` as they're received. An error from
+    /// `on_event` fails the read.
+    pub fn new(stream: TelnetStream<S>, on_event: F) -> Self {
+        Self {
+            stream,
+            on_event,
+            data: Vec::new(),
+            position: 0,
+        }
+    }
+
+    /// The wrapped stream.
+    pub fn get_mut(&mut self) -> &mut TelnetStream<S> {
+        &mut self.stream
+    }
+
+    /// Unwrap, returning the stream and any data that was received but not read.
+    pub fn into_parts(mut self) -> (TelnetStream<S>, Vec<u8>) {
+        self.data.drain(..self.position);
+        (self.stream, self.data)
+    }
+}
+
+impl<S, F> Read for DataStream<S, F>
+where
+    S: Read + Write,
+    F: FnMut(&mut TelnetStream<S>, TelnetEvent) -> TellyResult,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let data = self.fill_buf()?;
+        let length = data.len().min(buf.len());
+        buf[..length].copy_from_slice(&data[..length]);
+        self.consume(length);
+        Ok(length)
+    }
+}
+
+impl<S, F> BufRead for DataStream<S, F>
+where
+    S: Read + Write,
+    F: FnMut(&mut TelnetStream<S>, TelnetEvent) -> TellyResult,
+{
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        while self.position == self.data.len() {
+            match self.stream.next_event()? {
+                Some(TelnetEvent::Data(data)) => {
+                    self.data = data;
+                    self.position = 0;
+                }
+                Some(event) => (self.on_event)(&mut self.stream, event)?,
+                None => break,
+            }
+        }
+        Ok(&self.data[self.position..])
+    }
+
+    fn consume(&mut self, amount: usize) {
+        self.position = (self.position + amount).min(self.data.len());
+    }
+}
+
+impl<S, F> Write for DataStream<S, F>
+where
+    S: Read + Write,
+    F: FnMut(&mut TelnetStream<S>, TelnetEvent) -> TellyResult,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.send_data(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(self.stream.flush()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{TelnetCommand, TelnetOption};
+    use std::{
+        net::{TcpListener, TcpStream},
+        thread,
+    };
+
+    #[test]
+    fn data_only() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+
+        let server = thread::spawn(move || {
+            let mut server = TelnetStream::from_stream(server);
+            server.send_will(TelnetOption::Echo).unwrap();
+            server.send_str("first line\r\nsec").unwrap();
+            server
+                .send_event(TelnetCommand::AreYouThere.into())
+                .unwrap();
+            server.send_untranslated(b"ond line\r\n\xff").unwrap();
+            // The client's reply to WILL ECHO, then its data
+            assert_eq!(server.next(), Some(TelnetEvent::r#do(TelnetOption::Echo)));
+            let mut data = Vec::new();
+            while !data.ends_with(b"!") {
+                match server.next() {
+                    Some(TelnetEvent::Data(more)) => data.extend(more),
+                    event => panic!("Unexpected {event:?}"),
+                }
+            }
+            data
+        });
+
+        let mut events = Vec::new();
+        let mut stream = DataStream::new(TelnetStream::from_stream(client), |stream, event| {
+            if event == TelnetEvent::will(TelnetOption::Echo) {
+                stream.send_do(TelnetOption::Echo)?;
+            }
+            events.push(event);
+            Ok(())
+        });
+
+        let mut line = String::new();
+        stream.read_line(&mut line).unwrap();
+        assert_eq!(line, "first line\r\n");
+        line.clear();
+        stream.read_line(&mut line).unwrap();
+        assert_eq!(line, "second line\r\n");
+        let mut byte = [0];
+        stream.read_exact(&mut byte).unwrap();
+        assert_eq!(byte, [0xff]);
+
+        stream.write_all(b"hello\n\xff!").unwrap();
+        stream.flush().unwrap();
+        assert_eq!(server.join().unwrap(), b"hello\r\n\xff!");
+
+        let (_, unread) = stream.into_parts();
+        assert!(unread.is_empty());
+        assert_eq!(
+            events,
+            vec![
+                TelnetEvent::will(TelnetOption::Echo),
+                TelnetCommand::AreYouThere.into()
+            ]
+        );
+    }
+}
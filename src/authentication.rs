@@ -0,0 +1,654 @@
+//! The AUTHENTICATION option ([RFC2941](https://www.rfc-editor.org/rfc/rfc2941.html)).
+//!
+//! The server sends DO AUTHENTICATION, and once the client agrees, offers a list of mechanisms
+//! with [TelnetSubnegotiation::AuthenticationSend]. The client picks one, optionally sends the
+//! name it wants to log in as, and then the two exchange mechanism-specific data in
+//! [TelnetSubnegotiation::AuthenticationIs] and [TelnetSubnegotiation::AuthenticationReply]
+//! until the mechanism accepts or rejects.
+//!
+//! Mechanisms implement [Authenticator], and [TelnetStream::authenticate_client] and
+//! [TelnetStream::authenticate_to_server] run the exchange for a server or client. With the
+//! `srp` feature, `telly::srp` provides password authentication.
+//!
+//! # Example
+//! ```no_run
+//! use std::net::TcpListener;
+//! use telly::{authentication::Authenticator, TelnetStream};
+//! # fn mechanisms() -> Vec<Box<dyn Authenticator>> { Vec::new() }
+//!
+//! let listener = TcpListener::bind("0.0.0.0:23").unwrap();
+//! let mut stream = TelnetStream::from_stream(listener.accept().unwrap().0);
+//! // Such as telly::srp::SrpServer
+//! let mut authenticators = mechanisms();
+//!
+//! let authenticated = stream.authenticate_client(&mut authenticators).unwrap();
+//! println!("Logged in as {:?}", authenticated.name);
+//! ```
+use crate::errors::TellyResult;
+#[cfg(feature = "std")]
+use crate::{
+    errors::TellyError, TelnetAction, TelnetEvent, TelnetOption, TelnetStream, TelnetSubnegotiation,
+};
+use alloc::vec::Vec;
+#[cfg(feature = "std")]
+use alloc::{boxed::Box, format, string::String};
+use core::ops::BitOr;
+#[cfg(feature = "std")]
+use std::io::{Read, Write};
+
+/// Authentication mechanisms, as listed in RFC2941 and assigned by IANA.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum AuthenticationType {
+    /// No authentication. Sent by clients that have no mechanism left to try.
+    Null,
+    /// Kerberos version 4.
+    KerberosV4,
+    /// Kerberos version 5.
+    KerberosV5,
+    /// SPX.
+    Spx,
+    /// MINK.
+    Mink,
+    /// The Secure Remote Password protocol. See
+    /// [RFC2944](https://www.rfc-editor.org/rfc/rfc2944.html).
+    Srp,
+    /// RSA.
+    Rsa,
+    /// SSL.
+    Ssl,
+    /// LOKI.
+    Loki,
+    /// SSA.
+    Ssa,
+    /// KEA SKIPJACK.
+    KeaSj,
+    /// KEA SKIPJACK with integrity.
+    KeaSjInteg,
+    /// DSS.
+    Dss,
+    /// NTLM.
+    Ntlm,
+    /// Some other mechanism not listed.
+    Other(u8),
+}
+
+const AUTHENTICATION_TYPES: [(AuthenticationType, u8); 14] = [
+    (AuthenticationType::Null, 0),
+    (AuthenticationType::KerberosV4, 1),
+    (AuthenticationType::KerberosV5, 2),
+    (AuthenticationType::Spx, 3),
+    (AuthenticationType::Mink, 4),
+    (AuthenticationType::Srp, 5),
+    (AuthenticationType::Rsa, 6),
+    (AuthenticationType::Ssl, 7),
+    (AuthenticationType::Loki, 10),
+    (AuthenticationType::Ssa, 11),
+    (AuthenticationType::KeaSj, 12),
+    (AuthenticationType::KeaSjInteg, 13),
+    (AuthenticationType::Dss, 14),
+    (AuthenticationType::Ntlm, 15),
+];
+
+impl From<u8> for AuthenticationType {
+    fn from(byte: u8) -> Self {
+        AUTHENTICATION_TYPES
+            .iter()
+            .find(|(_, value)| *value == byte)
+            .map_or(Self::Other(byte), |(kind, _)| *kind)
+    }
+}
+
+impl From<AuthenticationType> for u8 {
+    fn from(kind: AuthenticationType) -> u8 {
+        match kind {
+            AuthenticationType::Other(byte) => byte,
+            kind => AUTHENTICATION_TYPES
+                .iter()
+                .find(|(listed, _)| *listed == kind)
+                .map_or(0, |(_, value)| *value),
+        }
+    }
+}
+
+/// The modifiers that accompany an [AuthenticationType], as a set of bits.
+///
+/// The default is one-way authentication of the client to the server, without encryption.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct AuthenticationModifiers(pub u8);
+
+impl AuthenticationModifiers {
+    /// The server authenticates to the client, rather than the other way around.
+    pub const SERVER_TO_CLIENT: Self = Self(0x01);
+    /// Both sides authenticate.
+    pub const MUTUAL: Self = Self(0x02);
+    /// Encryption is negotiated with the ENCRYPT option.
+    pub const ENCRYPT_USING_TELOPT: Self = Self(0x04);
+    /// The client's credentials are forwarded to the server.
+    pub const CREDENTIALS_FORWARDING: Self = Self(0x08);
+    /// Encryption starts right after the authentication exchange.
+    pub const ENCRYPT_AFTER_EXCHANGE: Self = Self(0x10);
+
+    /// Whether all the bits of `other` are set.
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for AuthenticationModifiers {
+    type Output = Self;
+
+    fn bitor(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+}
+
+/// A mechanism and its modifiers, as offered by the server and used in each step of the
+/// exchange.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct AuthenticationTypePair {
+    /// The mechanism.
+    pub kind: AuthenticationType,
+    /// How the mechanism is used.
+    pub modifiers: AuthenticationModifiers,
+}
+
+impl AuthenticationTypePair {
+    /// No authentication, which clients send when they have no mechanism left to try.
+    pub const NULL: Self = Self::new(AuthenticationType::Null, AuthenticationModifiers(0));
+
+    /// Pair a mechanism with its modifiers.
+    pub const fn new(kind: AuthenticationType, modifiers: AuthenticationModifiers) -> Self {
+        Self { kind, modifiers }
+    }
+
+    pub(crate) fn decode(bytes: &[u8]) -> Option<Self> {
+        match *bytes {
+            [kind, modifiers, ..] => {
+                Some(Self::new(kind.into(), AuthenticationModifiers(modifiers)))
+            }
+            _ => None,
+        }
+    }
+
+    pub(crate) fn encode(&self) -> [u8; 2] {
+        [self.kind.into(), self.modifiers.0]
+    }
+}
+
+/// What to do after an [Authenticator] handles data from the peer. Bytes are sent to the peer
+/// unless empty.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AuthenticationStep {
+    /// Send the bytes, and wait for the peer's answer.
+    Continue(Vec<u8>),
+    /// Authentication succeeded. The bytes are sent first.
+    Accepted(Vec<u8>),
+    /// Authentication failed. The bytes are sent first. The client may try another mechanism.
+    Rejected(Vec<u8>),
+}
+
+/// One side of an authentication mechanism. Servers handle the data of the client's
+/// [TelnetSubnegotiation::AuthenticationIs], and clients that of the server's
+/// [TelnetSubnegotiation::AuthenticationReply].
+pub trait Authenticator {
+    /// The mechanism and modifiers this handles.
+    fn type_pair(&self) -> AuthenticationTypePair;
+
+    /// Forget any exchange in progress. Called before each exchange starts, so that an
+    /// authenticator used in an earlier exchange starts over.
+    fn reset(&mut self);
+
+    /// The name being authenticated: on servers, as received from the client, and on clients,
+    /// as sent to the server. Called before the first step, if there's a name.
+    fn set_name(&mut self, _name: &str) {}
+
+    /// The data of the client's first IS. Only called on clients.
+    fn start(&mut self) -> TellyResult<Vec<u8>> {
+        Ok(Vec::new())
+    }
+
+    /// Handle data from the peer.
+    fn step(&mut self, data: &[u8]) -> TellyResult<AuthenticationStep>;
+}
+
+/// The outcome of a successful exchange.
+#[cfg(feature = "std")]
+#[derive(Clone, Debug, PartialEq)]
+pub struct Authenticated {
+    /// The mechanism that succeeded.
+    pub pair: AuthenticationTypePair,
+    /// The name the client logged in as, if it sent one.
+    pub name: Option<String>,
+    /// Other events received during the exchange, in order, for the caller to handle.
+    pub events: Vec<TelnetEvent>,
+}
+
+#[cfg(feature = "std")]
+fn failed(reason: &str) -> TellyError {
+    TellyError::AuthenticationFailed(reason.into())
+}
+
+// An AUTHENTICATION subnegotiation, or the event itself if it's anything else
+#[cfg(feature = "std")]
+fn authentication_subnegotiation(
+    event: TelnetEvent,
+) -> TellyResult<Result<TelnetSubnegotiation, TelnetEvent>> {
+    match event {
+        TelnetEvent::Subnegotiation(subnegotiation)
+            if subnegotiation.option == TelnetOption::Authentication =>
+        {
+            Ok(Ok(subnegotiation.try_into()?))
+        }
+        event => Ok(Err(event)),
+    }
+}
+
+#[cfg(feature = "std")]
+impl<S: Read + Write> TelnetStream<S> {
+    /// Authenticate the client, as a server, offering `authenticators` in order of preference.
+    ///
+    /// Each mechanism may be tried once, so that e.g. passwords can't be guessed again and again
+    /// over one connection. Fails with [TellyError::AuthenticationFailed] if the client refuses
+    /// AUTHENTICATION, gives up, or tries a mechanism again after it was rejected, or once every
+    /// mechanism was rejected.
+    pub fn authenticate_client(
+        &mut self,
+        authenticators: &mut [Box<dyn Authenticator>],
+    ) -> TellyResult<Authenticated> {
+        let mut name = None;
+        let mut events = Vec::new();
+        // The mechanism in use, which has been told the name
+        let mut current = None;
+        // Mechanisms that were rejected, which may not be tried again
+        let mut rejected = Vec::new();
+
+        self.send_do(TelnetOption::Authentication)?;
+        loop {
+            let event = self
+                .next_event()?
+                .ok_or_else(|| failed("Connection closed"))?;
+            let subnegotiation = match authentication_subnegotiation(event)? {
+                Ok(subnegotiation) => subnegotiation,
+                Err(TelnetEvent::Negotiation {
+                    action,
+                    option: TelnetOption::Authentication,
+                }) => {
+                    match action {
+                        TelnetAction::Will => {
+                            let pairs = authenticators.iter().map(|a| a.type_pair()).collect();
+                            self.send_event(
+                                TelnetSubnegotiation::AuthenticationSend(pairs).into(),
+                            )?;
+                        }
+                        TelnetAction::Wont => return Err(failed("Client refused AUTHENTICATION")),
+                        _ => {}
+                    }
+                    continue;
+                }
+                Err(event) => {
+                    events.push(event);
+                    continue;
+                }
+            };
+
+            match subnegotiation {
+                TelnetSubnegotiation::AuthenticationName(requested) => name = Some(requested),
+                TelnetSubnegotiation::AuthenticationIs { pair, data } => {
+                    if pair.kind == AuthenticationType::Null {
+                        return Err(failed("Client has no acceptable mechanism"));
+                    }
+                    let index = authenticators
+                        .iter()
+                        .position(|a| a.type_pair() == pair)
+                        .ok_or_else(|| failed(&format!("Client chose unoffered {pair:?}")))?;
+                    if rejected.contains(&index) {
+                        return Err(failed(&format!("Client retried rejected {pair:?}")));
+                    }
+                    let authenticator = &mut authenticators[index];
+                    if current != Some(index) {
+                        current = Some(index);
+                        authenticator.reset();
+                        if let Some(name) = &name {
+                            authenticator.set_name(name);
+                        }
+                    }
+
+                    let (reply, accepted) = match authenticator.step(&data)? {
+                        AuthenticationStep::Continue(reply) => (reply, None),
+                        AuthenticationStep::Accepted(reply) => (reply, Some(true)),
+                        AuthenticationStep::Rejected(reply) => (reply, Some(false)),
+                    };
+                    if !reply.is_empty() {
+                        self.send_event(
+                            TelnetSubnegotiation::AuthenticationReply { pair, data: reply }.into(),
+                        )?;
+                    }
+                    match accepted {
+                        Some(true) => return Ok(Authenticated { pair, name, events }),
+                        Some(false) => {
+                            rejected.push(index);
+                            if rejected.len() == authenticators.len() {
+                                return Err(failed("All mechanisms were rejected"));
+                            }
+                            // The client may try another mechanism
+                            current = None;
+                        }
+                        None => {}
+                    }
+                }
+                subnegotiation => events.push(subnegotiation.into()),
+            }
+        }
+    }
+
+    /// Authenticate to the server, as a client, using whichever of `authenticators` the server
+    /// prefers. If given, `name` is sent as the name to log in as.
+    ///
+    /// Call this before the server sends DO AUTHENTICATION, or with that event not yet received.
+    /// Fails with [TellyError::AuthenticationFailed] if no mechanism succeeds.
+    pub fn authenticate_to_server(
+        &mut self,
+        name: Option<&str>,
+        authenticators: &mut [Box<dyn Authenticator>],
+    ) -> TellyResult<Authenticated> {
+        let mut events = Vec::new();
+        let mut sent_name = false;
+        // The mechanisms the server offered that haven't been tried, once it's offered them,
+        // and the one in use
+        let mut offered: Option<Vec<AuthenticationTypePair>> = None;
+        let mut current: Option<(usize, AuthenticationTypePair)> = None;
+
+        loop {
+            if current.is_none() {
+                if let Some(offered) = &mut offered {
+                    // Try the server's next preference that we support
+                    let mut next = None;
+                    while next.is_none() && !offered.is_empty() {
+                        let pair = offered.remove(0);
+                        next = authenticators
+                            .iter()
+                            .position(|a| a.type_pair() == pair)
+                            .map(|index| (index, pair));
+                    }
+                    let Some((index, pair)) = next else {
+                        self.send_event(
+                            TelnetSubnegotiation::AuthenticationIs {
+                                pair: AuthenticationTypePair::NULL,
+                                data: Vec::new(),
+                            }
+                            .into(),
+                        )?;
+                        return Err(failed("No acceptable mechanism"));
+                    };
+
+                    let authenticator = &mut authenticators[index];
+                    authenticator.reset();
+                    if let Some(name) = name {
+                        if !sent_name {
+                            self.send_event(
+                                TelnetSubnegotiation::AuthenticationName(name.into()).into(),
+                            )?;
+                            sent_name = true;
+                        }
+                        authenticator.set_name(name);
+                    }
+                    let data = authenticator.start()?;
+                    self.send_event(TelnetSubnegotiation::AuthenticationIs { pair, data }.into())?;
+                    current = Some((index, pair));
+                }
+            }
+
+            let event = self
+                .next_event()?
+                .ok_or_else(|| failed("Connection closed"))?;
+            let subnegotiation = match authentication_subnegotiation(event)? {
+                Ok(subnegotiation) => subnegotiation,
+                Err(TelnetEvent::Negotiation {
+                    action,
+                    option: TelnetOption::Authentication,
+                }) => {
+                    match action {
+                        TelnetAction::Do => self.send_will(TelnetOption::Authentication)?,
+                        TelnetAction::Dont => return Err(failed("Server refused AUTHENTICATION")),
+                        _ => {}
+                    }
+                    continue;
+                }
+                Err(event) => {
+                    events.push(event);
+                    continue;
+                }
+            };
+
+            match (subnegotiation, current) {
+                (TelnetSubnegotiation::AuthenticationSend(pairs), None) => offered = Some(pairs),
+                (TelnetSubnegotiation::AuthenticationReply { pair, data }, Some((index, used)))
+                    if pair == used =>
+                {
+                    let step = authenticators[index].step(&data)?;
+                    let (data, accepted) = match step {
+                        AuthenticationStep::Continue(data) => (data, None),
+                        AuthenticationStep::Accepted(data) => (data, Some(true)),
+                        AuthenticationStep::Rejected(data) => (data, Some(false)),
+                    };
+                    if !data.is_empty() {
+                        self.send_event(
+                            TelnetSubnegotiation::AuthenticationIs { pair, data }.into(),
+                        )?;
+                    }
+                    match accepted {
+                        Some(true) => {
+                            return Ok(Authenticated {
+                                pair,
+                                name: name.map(Into::into),
+                                events,
+                            })
+                        }
+                        // Try the next mechanism
+                        Some(false) => current = None,
+                        None => {}
+                    }
+                }
+                (subnegotiation, _) => events.push(subnegotiation.into()),
+            }
+        }
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use std::{
+        mem,
+        net::{TcpListener, TcpStream},
+        thread,
+    };
+
+    // Sends a shared secret in the clear, which the server checks. Only the first guess of an
+    // exchange counts.
+    struct Secret {
+        kind: u8,
+        secret: &'static [u8],
+        name: String,
+        guessed: bool,
+    }
+
+    impl Secret {
+        fn boxed(kind: u8, secret: &'static [u8]) -> Box<dyn Authenticator> {
+            Box::new(Self {
+                kind,
+                secret,
+                name: String::new(),
+                guessed: false,
+            })
+        }
+    }
+
+    fn pair(kind: u8) -> AuthenticationTypePair {
+        AuthenticationTypePair::new(kind.into(), AuthenticationModifiers::default())
+    }
+
+    impl Authenticator for Secret {
+        fn type_pair(&self) -> AuthenticationTypePair {
+            pair(self.kind)
+        }
+
+        fn reset(&mut self) {
+            self.guessed = false;
+        }
+
+        fn set_name(&mut self, name: &str) {
+            self.name = name.into();
+        }
+
+        fn start(&mut self) -> TellyResult<Vec<u8>> {
+            Ok(self.secret.to_vec())
+        }
+
+        fn step(&mut self, data: &[u8]) -> TellyResult<AuthenticationStep> {
+            Ok(match data {
+                b"ok" => AuthenticationStep::Accepted(Vec::new()),
+                b"no" => AuthenticationStep::Rejected(Vec::new()),
+                data if data == self.secret
+                    && self.name == "margaret"
+                    && !mem::replace(&mut self.guessed, true) =>
+                {
+                    AuthenticationStep::Accepted(b"ok".to_vec())
+                }
+                _ => {
+                    self.guessed = true;
+                    AuthenticationStep::Rejected(b"no".to_vec())
+                }
+            })
+        }
+    }
+
+    fn authenticate(
+        secret: &'static [u8],
+    ) -> (TellyResult<Authenticated>, TellyResult<Authenticated>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+
+        let server = thread::spawn(move || {
+            let mut server = TelnetStream::from_stream(server);
+            server.send_do(TelnetOption::NegotiateAboutWindowSize)?;
+            // The client doesn't support the first, and guesses the second wrong
+            let mut authenticators = [
+                Secret::boxed(200, b"unsupported"),
+                Secret::boxed(201, b"other"),
+                Secret::boxed(202, b"sesame"),
+            ];
+            server.authenticate_client(&mut authenticators)
+        });
+
+        let mut client = TelnetStream::from_stream(client);
+        client.send_will(TelnetOption::TerminalType).unwrap();
+        let mut authenticators = [Secret::boxed(201, b"guess"), Secret::boxed(202, secret)];
+        let client = client.authenticate_to_server(Some("margaret"), &mut authenticators);
+        (client, server.join().unwrap())
+    }
+
+    #[test]
+    fn authentication() {
+        let (client, server) = authenticate(b"sesame");
+        let pair = AuthenticationTypePair::new(AuthenticationType::Other(202), Default::default());
+        assert_eq!(
+            client.unwrap(),
+            Authenticated {
+                pair,
+                name: Some("margaret".into()),
+                events: vec![TelnetEvent::r#do(TelnetOption::NegotiateAboutWindowSize)],
+            }
+        );
+        assert_eq!(
+            server.unwrap(),
+            Authenticated {
+                pair,
+                name: Some("margaret".into()),
+                events: vec![TelnetEvent::will(TelnetOption::TerminalType)],
+            }
+        );
+
+        let (client, server) = authenticate(b"wrong");
+        assert!(matches!(client, Err(TellyError::AuthenticationFailed(_))));
+        assert!(matches!(server, Err(TellyError::AuthenticationFailed(_))));
+    }
+
+    fn start_exchange() -> (
+        TelnetStream<TcpStream>,
+        thread::JoinHandle<TellyResult<Authenticated>>,
+    ) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        let server = thread::spawn(move || {
+            let mut authenticators = [Secret::boxed(201, b"other"), Secret::boxed(202, b"sesame")];
+            TelnetStream::from_stream(server).authenticate_client(&mut authenticators)
+        });
+
+        let mut client = TelnetStream::from_stream(client);
+        assert_eq!(
+            client.next(),
+            Some(TelnetEvent::r#do(TelnetOption::Authentication))
+        );
+        client.send_will(TelnetOption::Authentication).unwrap();
+        client
+            .send_event(TelnetSubnegotiation::AuthenticationName("margaret".into()).into())
+            .unwrap();
+        (client, server)
+    }
+
+    // Send IS, and return the data of the server's REPLY, if it sends one before closing
+    fn reply(client: &mut TelnetStream<TcpStream>, kind: u8, data: &[u8]) -> Option<Vec<u8>> {
+        client
+            .send_event(
+                TelnetSubnegotiation::AuthenticationIs {
+                    pair: pair(kind),
+                    data: data.into(),
+                }
+                .into(),
+            )
+            .unwrap();
+        loop {
+            if let Ok(TelnetSubnegotiation::AuthenticationReply { data, .. }) =
+                authentication_subnegotiation(client.next()?).unwrap()
+            {
+                return Some(data);
+            }
+        }
+    }
+
+    #[test]
+    fn fallback() {
+        // Rejected, then on to the next mechanism
+        let (mut client, server) = start_exchange();
+        assert_eq!(reply(&mut client, 201, b"guess"), Some(b"no".to_vec()));
+        assert_eq!(reply(&mut client, 202, b"sesame"), Some(b"ok".to_vec()));
+        assert_eq!(server.join().unwrap().unwrap().pair, pair(202));
+    }
+
+    #[test]
+    fn one_attempt_per_mechanism() {
+        // A rejected mechanism can't be tried again
+        let (mut client, server) = start_exchange();
+        assert_eq!(reply(&mut client, 202, b"guess"), Some(b"no".to_vec()));
+        assert_eq!(reply(&mut client, 202, b"sesame"), None);
+        assert!(matches!(
+            server.join().unwrap(),
+            Err(TellyError::AuthenticationFailed(_))
+        ));
+
+        // Nor can anything else once all were rejected
+        let (mut client, server) = start_exchange();
+        assert_eq!(reply(&mut client, 201, b"guess"), Some(b"no".to_vec()));
+        assert_eq!(reply(&mut client, 202, b"guess"), Some(b"no".to_vec()));
+        assert!(matches!(
+            server.join().unwrap(),
+            Err(TellyError::AuthenticationFailed(_))
+        ));
+    }
+}
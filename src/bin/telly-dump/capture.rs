@@ -0,0 +1,463 @@
+//! Reading TCP segments out of pcap and pcapng captures.
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+const PCAP_MAGIC: u32 = 0xa1b2c3d4;
+const PCAP_NANO_MAGIC: u32 = 0xa1b23c4d;
+const PCAPNG_SECTION_HEADER: u32 = 0x0a0d0d0a;
+const PCAPNG_BYTE_ORDER_MAGIC: u32 = 0x1a2b3c4d;
+
+// Link types, from https://www.tcpdump.org/linktypes.html
+const LINKTYPE_NULL: u16 = 0;
+const LINKTYPE_ETHERNET: u16 = 1;
+const LINKTYPE_RAW: u16 = 101;
+const LINKTYPE_LINUX_SLL: u16 = 113;
+const LINKTYPE_LINUX_SLL2: u16 = 276;
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_IPV6: u16 = 0x86dd;
+const ETHERTYPE_VLAN: u16 = 0x8100;
+const PROTOCOL_TCP: u8 = 6;
+
+/// TCP flags we care about.
+pub const FIN: u8 = 0x01;
+pub const SYN: u8 = 0x02;
+pub const RST: u8 = 0x04;
+
+/// A captured link-layer frame.
+struct Packet<'a> {
+    // Since the epoch
+    time: Duration,
+    link_type: u16,
+    data: &'a [u8],
+}
+
+/// A captured TCP segment.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Segment {
+    /// Capture time, since the epoch.
+    pub time: Duration,
+    pub source: SocketAddr,
+    pub destination: SocketAddr,
+    pub sequence: u32,
+    pub flags: u8,
+    pub payload: Vec<u8>,
+}
+
+/// Whether `bytes` look like a pcap or pcapng capture.
+pub fn is_capture(bytes: &[u8]) -> bool {
+    matches!(
+        bytes
+            .get(..4)
+            .map(|magic| u32::from_le_bytes(magic.try_into().unwrap())),
+        Some(PCAP_MAGIC | PCAP_NANO_MAGIC | PCAPNG_SECTION_HEADER)
+    ) || matches!(
+        bytes
+            .get(..4)
+            .map(|magic| u32::from_be_bytes(magic.try_into().unwrap())),
+        Some(PCAP_MAGIC | PCAP_NANO_MAGIC)
+    )
+}
+
+/// Read the TCP segments out of a pcap or pcapng capture. Other packets are skipped.
+pub fn read_segments(bytes: &[u8]) -> Result<Vec<Segment>, String> {
+    let packets = if bytes.get(..4) == Some(&PCAPNG_SECTION_HEADER.to_le_bytes()) {
+        read_pcapng(bytes)?
+    } else {
+        read_pcap(bytes)?
+    };
+    Ok(packets.iter().filter_map(decode_packet).collect())
+}
+
+// Fixed-endianness reads from a byte slice
+#[derive(Clone, Copy)]
+struct Reader<'a> {
+    bytes: &'a [u8],
+    big_endian: bool,
+}
+
+impl<'a> Reader<'a> {
+    fn u16(&self, offset: usize) -> Result<u16, String> {
+        let bytes = self.slice(offset, 2)?.try_into().unwrap();
+        Ok(if self.big_endian {
+            u16::from_be_bytes(bytes)
+        } else {
+            u16::from_le_bytes(bytes)
+        })
+    }
+
+    fn u32(&self, offset: usize) -> Result<u32, String> {
+        let bytes = self.slice(offset, 4)?.try_into().unwrap();
+        Ok(if self.big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        })
+    }
+
+    fn slice(&self, offset: usize, len: usize) -> Result<&'a [u8], String> {
+        self.bytes
+            .get(offset..offset + len)
+            .ok_or_else(|| "Capture is truncated".to_string())
+    }
+}
+
+fn read_pcap(bytes: &[u8]) -> Result<Vec<Packet<'_>>, String> {
+    let mut reader = Reader {
+        bytes,
+        big_endian: false,
+    };
+    let mut magic = reader.u32(0)?;
+    if !matches!(magic, PCAP_MAGIC | PCAP_NANO_MAGIC) {
+        reader.big_endian = true;
+        magic = reader.u32(0)?;
+    }
+    let nanoseconds = magic == PCAP_NANO_MAGIC;
+    let link_type = reader.u32(20)? as u16;
+
+    let mut packets = Vec::new();
+    let mut offset = 24;
+    while offset < bytes.len() {
+        let seconds = reader.u32(offset)?;
+        let fraction = reader.u32(offset + 4)?;
+        let len = reader.u32(offset + 8)? as usize;
+        let time = Duration::from_secs(seconds.into())
+            + if nanoseconds {
+                Duration::from_nanos(fraction.into())
+            } else {
+                Duration::from_micros(fraction.into())
+            };
+        packets.push(Packet {
+            time,
+            link_type,
+            data: reader.slice(offset + 16, len)?,
+        });
+        offset += 16 + len;
+    }
+    Ok(packets)
+}
+
+fn read_pcapng(bytes: &[u8]) -> Result<Vec<Packet<'_>>, String> {
+    // Link type and timestamp units per second of each interface in the current section
+    let mut interfaces: Vec<(u16, u64)> = Vec::new();
+    let mut packets = Vec::new();
+    let mut reader = Reader {
+        bytes,
+        big_endian: false,
+    };
+
+    let mut offset = 0;
+    while offset < bytes.len() {
+        let block_type = reader.u32(offset)?;
+        if block_type == PCAPNG_SECTION_HEADER {
+            reader.big_endian = reader.u32(offset + 8)? != PCAPNG_BYTE_ORDER_MAGIC;
+            interfaces.clear();
+        }
+        let len = reader.u32(offset + 4)? as usize;
+        if len < 12 {
+            return Err("Bad pcapng block length".into());
+        }
+        let body = offset + 8;
+        let body_len = len - 12;
+
+        match block_type {
+            // Interface description
+            1 => {
+                let link_type = reader.u16(body)?;
+                let mut resolution = 1_000_000;
+                // Options
+                let mut option = body + 8;
+                while option + 4 <= body + body_len {
+                    let code = reader.u16(option)?;
+                    let option_len = reader.u16(option + 2)? as usize;
+                    if code == 0 {
+                        break;
+                    }
+                    // if_tsresol
+                    if code == 9 && option_len == 1 {
+                        let value = reader.slice(option + 4, 1)?[0];
+                        let exponent = u32::from(value & 0x7f);
+                        resolution = if value & 0x80 == 0 {
+                            10u64.checked_pow(exponent)
+                        } else {
+                            2u64.checked_pow(exponent)
+                        }
+                        .ok_or("Bad pcapng timestamp resolution")?;
+                    }
+                    option += 4 + option_len.next_multiple_of(4);
+                }
+                interfaces.push((link_type, resolution));
+            }
+            // Enhanced packet
+            6 => {
+                let interface = reader.u32(body)? as usize;
+                let (link_type, resolution) = *interfaces
+                    .get(interface)
+                    .ok_or("pcapng packet from unknown interface")?;
+                let timestamp =
+                    (u64::from(reader.u32(body + 4)?) << 32) | u64::from(reader.u32(body + 8)?);
+                let captured = reader.u32(body + 12)? as usize;
+                // Widened, since units may be as fine as 2^-63 seconds
+                let nanoseconds =
+                    u128::from(timestamp % resolution) * 1_000_000_000 / u128::from(resolution);
+                packets.push(Packet {
+                    time: Duration::from_secs(timestamp / resolution)
+                        + Duration::from_nanos(nanoseconds as u64),
+                    link_type,
+                    data: reader.slice(body + 20, captured)?,
+                });
+            }
+            // Simple packet, which has no timestamp
+            3 => {
+                let (link_type, _) = *interfaces
+                    .first()
+                    .ok_or("pcapng packet from unknown interface")?;
+                let space = body_len.checked_sub(4).ok_or("Bad pcapng block length")?;
+                let captured = (reader.u32(body)? as usize).min(space);
+                packets.push(Packet {
+                    time: Duration::ZERO,
+                    link_type,
+                    data: reader.slice(body + 4, captured)?,
+                });
+            }
+            _ => {}
+        }
+        offset += len;
+    }
+    Ok(packets)
+}
+
+// Decode a frame down to TCP, if it is TCP
+fn decode_packet(packet: &Packet) -> Option<Segment> {
+    let data = packet.data;
+    let (ethertype, ip) = match packet.link_type {
+        LINKTYPE_ETHERNET => {
+            let mut ethertype = u16::from_be_bytes(data.get(12..14)?.try_into().ok()?);
+            let mut start = 14;
+            while ethertype == ETHERTYPE_VLAN {
+                ethertype = u16::from_be_bytes(data.get(start + 2..start + 4)?.try_into().ok()?);
+                start += 4;
+            }
+            (ethertype, &data[start..])
+        }
+        LINKTYPE_NULL => {
+            // Address family, in the capturing host's byte order
+            let family = data.get(..4)?;
+            let ethertype = match family.iter().find(|byte| **byte != 0)? {
+                2 => ETHERTYPE_IPV4,
+                24 | 28 | 30 => ETHERTYPE_IPV6,
+                _ => return None,
+            };
+            (ethertype, &data[4..])
+        }
+        LINKTYPE_RAW => match data.first()? >> 4 {
+            4 => (ETHERTYPE_IPV4, data),
+            6 => (ETHERTYPE_IPV6, data),
+            _ => return None,
+        },
+        LINKTYPE_LINUX_SLL => (
+            u16::from_be_bytes(data.get(14..16)?.try_into().ok()?),
+            data.get(16..)?,
+        ),
+        LINKTYPE_LINUX_SLL2 => (
+            u16::from_be_bytes(data.get(0..2)?.try_into().ok()?),
+            data.get(20..)?,
+        ),
+        _ => return None,
+    };
+
+    let (source, destination, tcp) = match ethertype {
+        ETHERTYPE_IPV4 => {
+            let header_len = usize::from(ip.first()? & 0x0f) * 4;
+            let total_len = usize::from(u16::from_be_bytes(ip.get(2..4)?.try_into().ok()?));
+            if *ip.get(9)? != PROTOCOL_TCP {
+                return None;
+            }
+            let source: [u8; 4] = ip.get(12..16)?.try_into().ok()?;
+            let destination: [u8; 4] = ip.get(16..20)?.try_into().ok()?;
+            (
+                IpAddr::from(Ipv4Addr::from(source)),
+                IpAddr::from(Ipv4Addr::from(destination)),
+                ip.get(header_len..total_len.min(ip.len()))?,
+            )
+        }
+        ETHERTYPE_IPV6 => {
+            let payload_len = usize::from(u16::from_be_bytes(ip.get(4..6)?.try_into().ok()?));
+            if *ip.get(6)? != PROTOCOL_TCP {
+                return None;
+            }
+            let source: [u8; 16] = ip.get(8..24)?.try_into().ok()?;
+            let destination: [u8; 16] = ip.get(24..40)?.try_into().ok()?;
+            (
+                IpAddr::from(Ipv6Addr::from(source)),
+                IpAddr::from(Ipv6Addr::from(destination)),
+                ip.get(40..(40 + payload_len).min(ip.len()))?,
+            )
+        }
+        _ => return None,
+    };
+
+    let port = |offset: usize| {
+        Some(u16::from_be_bytes(
+            tcp.get(offset..offset + 2)?.try_into().ok()?,
+        ))
+    };
+    let header_len = usize::from(tcp.get(12)? >> 4) * 4;
+    Some(Segment {
+        time: packet.time,
+        source: SocketAddr::new(source, port(0)?),
+        destination: SocketAddr::new(destination, port(2)?),
+        sequence: u32::from_be_bytes(tcp.get(4..8)?.try_into().ok()?),
+        flags: *tcp.get(13)?,
+        payload: tcp.get(header_len..)?.to_vec(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // An Ethernet frame holding an IPv4 TCP segment from 10.0.0.1:1234 to 10.0.0.2:23
+    fn frame(sequence: u32, payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0; 12];
+        frame.extend(ETHERTYPE_IPV4.to_be_bytes());
+        frame.extend([0x45, 0]);
+        frame.extend((40 + payload.len() as u16).to_be_bytes());
+        frame.extend([0, 0, 0, 0, 64, PROTOCOL_TCP, 0, 0, 10, 0, 0, 1, 10, 0, 0, 2]);
+        frame.extend(1234u16.to_be_bytes());
+        frame.extend(23u16.to_be_bytes());
+        frame.extend(sequence.to_be_bytes());
+        frame.extend([0, 0, 0, 0, 0x50, 0x18, 0, 0, 0, 0, 0, 0]);
+        frame.extend(payload);
+        // Ethernet padding
+        frame.extend([0; 4]);
+        frame
+    }
+
+    fn expected(time: Duration) -> Segment {
+        Segment {
+            time,
+            source: "10.0.0.1:1234".parse().unwrap(),
+            destination: "10.0.0.2:23".parse().unwrap(),
+            sequence: 7,
+            flags: 0x18,
+            payload: b"\xff\xfb\x18".to_vec(),
+        }
+    }
+
+    fn pcap(frame: &[u8]) -> Vec<u8> {
+        let mut pcap = Vec::new();
+        for field in [
+            PCAP_MAGIC,
+            0x00040002,
+            0,
+            0,
+            65535,
+            LINKTYPE_ETHERNET.into(),
+        ] {
+            pcap.extend(field.to_le_bytes());
+        }
+        for field in [3, 500, frame.len() as u32, frame.len() as u32] {
+            pcap.extend(field.to_le_bytes());
+        }
+        pcap.extend(frame);
+        pcap
+    }
+
+    // Append a big-endian pcapng block
+    fn block(pcapng: &mut Vec<u8>, block_type: u32, body: &[u8]) {
+        let len = 12 + body.len() as u32;
+        pcapng.extend(block_type.to_be_bytes());
+        pcapng.extend(len.to_be_bytes());
+        pcapng.extend(body);
+        pcapng.extend(len.to_be_bytes());
+    }
+
+    // A big-endian pcapng section with one Ethernet interface, whose timestamps are in units of
+    // 10^-`tsresol` seconds, or 2^-(`tsresol` & 0x7f) if its top bit is set
+    fn pcapng_section(tsresol: u8) -> Vec<u8> {
+        let mut pcapng = Vec::new();
+        let mut section = PCAPNG_BYTE_ORDER_MAGIC.to_be_bytes().to_vec();
+        section.extend([0, 1, 0, 0]);
+        section.extend(u64::MAX.to_be_bytes());
+        block(&mut pcapng, PCAPNG_SECTION_HEADER, &section);
+        let mut interface = LINKTYPE_ETHERNET.to_be_bytes().to_vec();
+        interface.extend([0, 0, 0, 0, 0, 0]);
+        interface.extend([0, 9, 0, 1, tsresol, 0, 0, 0, 0, 0, 0, 0]);
+        block(&mut pcapng, 1, &interface);
+        pcapng
+    }
+
+    fn enhanced_packet(timestamp: u64, frame: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0; 4];
+        packet.extend(((timestamp >> 32) as u32).to_be_bytes());
+        packet.extend((timestamp as u32).to_be_bytes());
+        packet.extend((frame.len() as u32).to_be_bytes());
+        packet.extend((frame.len() as u32).to_be_bytes());
+        packet.extend(frame);
+        packet.resize(packet.len().next_multiple_of(4), 0);
+        packet
+    }
+
+    #[test]
+    fn pcap_capture() {
+        let pcap = pcap(&frame(7, b"\xff\xfb\x18"));
+        assert!(is_capture(&pcap));
+        assert_eq!(
+            read_segments(&pcap).unwrap(),
+            vec![expected(Duration::new(3, 500_000))]
+        );
+    }
+
+    #[test]
+    fn pcapng_capture() {
+        // Nanosecond timestamps
+        let mut pcapng = pcapng_section(9);
+        block(
+            &mut pcapng,
+            6,
+            &enhanced_packet(3_000_000_500, &frame(7, b"\xff\xfb\x18")),
+        );
+
+        assert_eq!(pcapng[..4], PCAPNG_SECTION_HEADER.to_be_bytes());
+        assert!(is_capture(&pcapng));
+        assert_eq!(
+            read_segments(&pcapng).unwrap(),
+            vec![expected(Duration::new(3, 500))]
+        );
+    }
+
+    #[test]
+    fn malformed_captures() {
+        let frame = frame(7, b"\xff\xfb\x18");
+        let mut pcapng = pcapng_section(9);
+        block(&mut pcapng, 6, &enhanced_packet(3_000_000_500, &frame));
+
+        // Captures cut off anywhere fail or lose packets, but don't panic
+        for capture in [pcap(&frame), pcapng] {
+            for len in 0..capture.len() {
+                let _ = read_segments(&capture[..len]);
+            }
+        }
+
+        // Frames cut off before the end of the TCP header are skipped
+        for len in 0..14 + 20 + 20 {
+            assert_eq!(read_segments(&pcap(&frame[..len])).unwrap(), []);
+        }
+
+        // A simple packet block too short to hold its own length
+        let mut pcapng = pcapng_section(9);
+        block(&mut pcapng, 3, &[]);
+        assert!(read_segments(&pcapng).is_err());
+
+        // The finest timestamp resolution there is, 2^-63 seconds
+        let mut pcapng = pcapng_section(0x80 | 63);
+        block(&mut pcapng, 6, &enhanced_packet(u64::MAX, &frame));
+        assert_eq!(
+            read_segments(&pcapng).unwrap()[0].time,
+            Duration::new(1, 999_999_999)
+        );
+    }
+}
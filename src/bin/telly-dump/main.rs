@@ -0,0 +1,267 @@
+//! `telly-dump`: print a readable trace of the Telnet traffic in a capture.
+mod capture;
+mod tcp;
+
+use bytes::BytesMut;
+use capture::{Segment, FIN, RST, SYN};
+use std::{
+    collections::HashMap,
+    env, fs,
+    io::{self, IsTerminal, Read},
+    net::{IpAddr, SocketAddr},
+    process,
+    time::Duration,
+};
+use tcp::Reassembler;
+use telly::{TelnetEvent, TelnetParser, TelnetSubnegotiation};
+
+const USAGE: &str = "\
+Usage: telly-dump [-p PORT] [-H HOST] [--raw] [--color WHEN] FILE
+
+Print the Telnet events in FILE, which is a pcap or pcapng capture, or raw Telnet bytes from
+one direction of a connection. FILE may be - for standard input.
+
+Options:
+    -p PORT       Server port of the connections to follow in a capture (default: 23)
+    -H HOST       Only follow connections to this server address
+    --raw         Treat FILE as raw Telnet bytes, even if it looks like a capture
+    --color WHEN  Colorize output: auto, always or never (default: auto)";
+
+fn usage() -> ! {
+    eprintln!("{USAGE}");
+    process::exit(2);
+}
+
+// ANSI styling, if enabled
+struct Style {
+    enabled: bool,
+}
+
+impl Style {
+    const DIM: &'static str = "2";
+    const RED: &'static str = "31";
+    const GREEN: &'static str = "32";
+    const YELLOW: &'static str = "33";
+    const BLUE: &'static str = "34";
+    const MAGENTA: &'static str = "35";
+    const CYAN: &'static str = "36";
+
+    fn paint(&self, code: &str, text: &str) -> String {
+        if self.enabled {
+            format!("\x1b[{code}m{text}\x1b[0m")
+        } else {
+            text.into()
+        }
+    }
+}
+
+// Describe an event, and choose its color
+fn describe(event: &TelnetEvent) -> (&'static str, String) {
+    match event {
+        TelnetEvent::Data(_) => ("0", format!("DATA {event}")),
+        TelnetEvent::Command(_) => (Style::MAGENTA, event.to_string()),
+        TelnetEvent::Negotiation { .. } => (Style::YELLOW, event.to_string()),
+        TelnetEvent::Subnegotiation(subnegotiation) => {
+            match TelnetSubnegotiation::try_from(subnegotiation.clone()) {
+                Ok(TelnetSubnegotiation::NegotiateAboutWindowSize { width, height }) => {
+                    (Style::BLUE, format!("{event} ({width}x{height})"))
+                }
+                Ok(_) => (Style::BLUE, event.to_string()),
+                Err(err) => (Style::RED, format!("{event} ({err})")),
+            }
+        }
+    }
+}
+
+// One direction of a connection
+#[derive(Default)]
+struct Direction {
+    reassembler: Reassembler,
+    buffer: BytesMut,
+}
+
+struct Connection {
+    id: usize,
+    to_server: Direction,
+    to_client: Direction,
+}
+
+struct Dumper {
+    style: Style,
+    // Parses without stripping NULs, so that nothing is hidden
+    parser: TelnetParser,
+    start: Option<Duration>,
+}
+
+impl Dumper {
+    fn print(&self, time: &str, label: Option<&str>, event: &TelnetEvent) {
+        let (color, description) = describe(event);
+        let label = label.map(|label| format!(" {label}")).unwrap_or_default();
+        println!(
+            "{}{label} {}",
+            self.style.paint(Style::DIM, time),
+            self.style.paint(color, &description)
+        );
+    }
+
+    fn dump_raw(&self, bytes: &[u8]) {
+        let mut buffer = BytesMut::from(bytes);
+        loop {
+            let offset = bytes.len() - buffer.len();
+            let Some(event) = self.parser.next_event(&mut buffer) else {
+                break;
+            };
+            self.print(&format!("{offset:#08x}"), None, &event);
+        }
+        if !buffer.is_empty() {
+            println!(
+                "{}",
+                self.style
+                    .paint(Style::RED, &format!("Incomplete: {:02x?}", buffer.as_ref()))
+            );
+        }
+    }
+
+    fn dump_capture(&mut self, segments: Vec<Segment>, port: u16, host: Option<IpAddr>) {
+        let mut connections: HashMap<(SocketAddr, SocketAddr), Connection> = HashMap::new();
+        for segment in segments {
+            let to_server = segment.destination.port() == port;
+            let (client, server) = if to_server {
+                (segment.source, segment.destination)
+            } else if segment.source.port() == port {
+                (segment.destination, segment.source)
+            } else {
+                continue;
+            };
+            if host.is_some_and(|host| host != server.ip()) {
+                continue;
+            }
+
+            let start = *self.start.get_or_insert(segment.time);
+            let time = format!("{:12.6}", segment.time.saturating_sub(start).as_secs_f64());
+            let next_id = connections.len() + 1;
+            let connection = connections.entry((client, server)).or_insert_with(|| {
+                println!(
+                    "{} {}",
+                    self.style.paint(Style::DIM, &time),
+                    self.style
+                        .paint(Style::CYAN, &format!("#{next_id} {client} -> {server}"))
+                );
+                Connection {
+                    id: next_id,
+                    to_server: Direction::default(),
+                    to_client: Direction::default(),
+                }
+            });
+
+            let (direction, label) = if to_server {
+                (&mut connection.to_server, "C>S")
+            } else {
+                (&mut connection.to_client, "S>C")
+            };
+            let label = format!(
+                "#{} {}",
+                connection.id,
+                self.style
+                    .paint(if to_server { Style::GREEN } else { Style::CYAN }, label)
+            );
+
+            let bytes = direction.reassembler.push(
+                segment.sequence,
+                segment.flags & SYN != 0,
+                &segment.payload,
+            );
+            direction.buffer.extend_from_slice(&bytes);
+            while let Some(event) = self.parser.next_event(&mut direction.buffer) {
+                self.print(&time, Some(&label), &event);
+            }
+
+            for (flag, name) in [(FIN, "FIN"), (RST, "RST")] {
+                if segment.flags & flag != 0 {
+                    println!(
+                        "{} {label} {}",
+                        self.style.paint(Style::DIM, &time),
+                        self.style.paint(Style::RED, name)
+                    );
+                }
+            }
+        }
+    }
+}
+
+fn main() {
+    let mut port = 23;
+    let mut host = None;
+    let mut raw = false;
+    let mut color = None;
+    let mut path = None;
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-p" => {
+                port = args
+                    .next()
+                    .and_then(|arg| arg.parse().ok())
+                    .unwrap_or_else(|| usage())
+            }
+            "-H" => {
+                host = Some(
+                    args.next()
+                        .and_then(|arg| arg.parse().ok())
+                        .unwrap_or_else(|| usage()),
+                )
+            }
+            "--raw" => raw = true,
+            "--color" => {
+                color = match args.next().as_deref() {
+                    Some("auto") => None,
+                    Some("always") => Some(true),
+                    Some("never") => Some(false),
+                    _ => usage(),
+                }
+            }
+            "-h" | "--help" => {
+                println!("{USAGE}");
+                return;
+            }
+            _ if path.is_none() => path = Some(arg),
+            _ => usage(),
+        }
+    }
+    let path = path.unwrap_or_else(|| usage());
+
+    let bytes = if path == "-" {
+        let mut bytes = Vec::new();
+        io::stdin().read_to_end(&mut bytes).map(|_| bytes)
+    } else {
+        fs::read(&path)
+    }
+    .unwrap_or_else(|err| {
+        eprintln!("Failed to read {path}: {err}");
+        process::exit(1);
+    });
+
+    let mut parser = TelnetParser::default();
+    parser.set_binary(true);
+    let mut dumper = Dumper {
+        style: Style {
+            enabled: color
+                .unwrap_or_else(|| io::stdout().is_terminal() && env::var_os("NO_COLOR").is_none()),
+        },
+        parser,
+        start: None,
+    };
+
+    if raw || !capture::is_capture(&bytes) {
+        dumper.dump_raw(&bytes);
+    } else {
+        match capture::read_segments(&bytes) {
+            Ok(segments) => dumper.dump_capture(segments, port, host),
+            Err(err) => {
+                eprintln!("Failed to read capture: {err}");
+                process::exit(1);
+            }
+        }
+    }
+}
@@ -0,0 +1,60 @@
+//! Reassembling one direction of a TCP connection.
+
+/// Puts TCP segments back in order, dropping retransmissions.
+#[derive(Default)]
+pub struct Reassembler {
+    // Sequence number of the next byte expected
+    next: Option<u32>,
+    // Segments that arrived ahead of a gap
+    pending: Vec<(u32, Vec<u8>)>,
+}
+
+impl Reassembler {
+    /// Add a segment, returning whatever bytes are now in order.
+    pub fn push(&mut self, sequence: u32, syn: bool, payload: &[u8]) -> Vec<u8> {
+        if syn {
+            // The SYN takes up a sequence number
+            self.next = Some(sequence.wrapping_add(1));
+            self.pending.clear();
+            return Vec::new();
+        }
+        // Joined mid-connection
+        let next = self.next.get_or_insert(sequence);
+        if !payload.is_empty() {
+            self.pending.push((sequence, payload.to_vec()));
+        }
+
+        let mut output = Vec::new();
+        while let Some(index) = self
+            .pending
+            .iter()
+            .position(|(sequence, _)| (sequence.wrapping_sub(*next) as i32) <= 0)
+        {
+            let (sequence, payload) = self.pending.swap_remove(index);
+            let overlap = next.wrapping_sub(sequence) as usize;
+            if overlap < payload.len() {
+                output.extend_from_slice(&payload[overlap..]);
+                *next = next.wrapping_add((payload.len() - overlap) as u32);
+            }
+        }
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reassemble() {
+        let mut reassembler = Reassembler::default();
+        assert_eq!(reassembler.push(u32::MAX - 2, true, b""), b"");
+        assert_eq!(reassembler.push(u32::MAX - 1, false, b"ab"), b"ab");
+        // Out of order, across the sequence number wrapping around
+        assert_eq!(reassembler.push(2, false, b"ef"), b"");
+        assert_eq!(reassembler.push(0, false, b"cd"), b"cdef");
+        // Retransmissions, whole and partial
+        assert_eq!(reassembler.push(0, false, b"cd"), b"");
+        assert_eq!(reassembler.push(3, false, b"fgh"), b"gh");
+    }
+}
@@ -0,0 +1,58 @@
+//! `telly-ws`: let WebSocket clients, like xterm.js in a browser, reach a Telnet server.
+use std::{env, process};
+use telly::{server::TelnetServer, websocket::WebSocketGateway};
+
+const USAGE: &str = "Usage: telly-ws [-l ADDRESS] [-t TERMINAL_TYPE] HOST [PORT]";
+const DEFAULT_PORT: u16 = 23;
+
+fn usage() -> ! {
+    eprintln!("{USAGE}");
+    process::exit(2);
+}
+
+fn main() {
+    let mut address = String::from("127.0.0.1:8080");
+    let mut terminal_type = None;
+
+    let mut args = env::args().skip(1);
+    let host = loop {
+        let Some(arg) = args.next() else { usage() };
+        match arg.as_str() {
+            "-l" => address = args.next().unwrap_or_else(|| usage()),
+            "-t" => terminal_type = Some(args.next().unwrap_or_else(|| usage())),
+            "-h" | "--help" => {
+                println!("{USAGE}");
+                return;
+            }
+            _ => break arg,
+        }
+    };
+    let port = match args.next() {
+        Some(port) => port.parse().unwrap_or_else(|_| usage()),
+        None => DEFAULT_PORT,
+    };
+    if args.next().is_some() {
+        usage();
+    }
+
+    let mut gateway = WebSocketGateway::new((host.as_str(), port)).unwrap_or_else(|err| {
+        eprintln!("Unable to resolve {host}: {err}");
+        process::exit(1);
+    });
+    if let Some(terminal_type) = terminal_type {
+        gateway.set_terminal_type(&terminal_type);
+    }
+    gateway.set_error_handler(|peer, err| {
+        eprintln!("WebSocket connection from {peer} failed: {err}");
+    });
+
+    let server = TelnetServer::bind(&address).unwrap_or_else(|err| {
+        eprintln!("Failed to listen on {address}: {err}");
+        process::exit(1);
+    });
+    eprintln!("Forwarding WebSocket connections on {address} to {host}:{port}");
+    if let Err(err) = server.serve(gateway) {
+        eprintln!("Server failed: {err}");
+        process::exit(1);
+    }
+}
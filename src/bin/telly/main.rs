@@ -0,0 +1,412 @@
+//! `telly`: an interactive Telnet client.
+//!
+//! Usage: `telly HOST [PORT]`. Press `^]` for a command prompt.
+mod terminal;
+
+use std::{
+    env,
+    io::{self, BufRead, Read, Write},
+    net::{Shutdown, TcpStream},
+    process,
+    sync::{Arc, Mutex, MutexGuard},
+    thread,
+    time::Duration,
+};
+use telly::{
+    errors::TellyResult,
+    negotiation::OptionStates,
+    split::{TelnetReader, TelnetWriter},
+    TelnetAction, TelnetCommand, TelnetEvent, TelnetOption, TelnetStream, TelnetSubnegotiation,
+};
+use terminal::Terminal;
+
+const USAGE: &str = "Usage: telly HOST [PORT]";
+const DEFAULT_PORT: u16 = 23;
+// ^]
+const ESCAPE_CHARACTER: u8 = 0x1d;
+const WINDOW_POLL_INTERVAL: Duration = Duration::from_millis(250);
+const HELP: &str = "Commands:
+    close           close the connection and exit
+    send ayt        send Are You There
+    send brk        send Break
+    send ip         send Interrupt Process
+    send ao         send Abort Output
+    send ec         send Erase Character
+    send el         send Erase Line
+    send nop        send No Operation
+    status          show connection status
+    toggle binary   toggle binary transmission in both directions
+    help            show this help
+An empty line returns to the session.";
+
+// Options we're willing to perform.
+const LOCAL_OPTIONS: [TelnetOption; 4] = [
+    TelnetOption::BinaryTransmission,
+    TelnetOption::SuppressGoAhead,
+    TelnetOption::TerminalType,
+    TelnetOption::NegotiateAboutWindowSize,
+];
+// Options we're willing to let the server perform.
+const REMOTE_OPTIONS: [TelnetOption; 3] = [
+    TelnetOption::BinaryTransmission,
+    TelnetOption::Echo,
+    TelnetOption::SuppressGoAhead,
+];
+
+// The sending side of the connection, shared between threads. Options are shared with the
+// reader, so it switches modes as soon as the server agrees to them.
+struct Connection {
+    host: String,
+    writer: TelnetWriter<TcpStream>,
+    window_size: Option<(u16, u16)>,
+    // Whether the user asked for binary mode
+    binary_requested: bool,
+}
+
+impl Connection {
+    fn send(&mut self, event: TelnetEvent) -> TellyResult {
+        self.writer.send_event(event)
+    }
+
+    fn options(&self) -> OptionStates {
+        self.writer.options()
+    }
+
+    // Send keyboard input, NVT-encoded unless in binary mode.
+    fn send_input(&mut self, input: &[u8]) -> TellyResult {
+        self.writer.send_data(input)
+    }
+
+    fn send_window_size(&mut self) -> TellyResult {
+        match self.window_size {
+            Some((width, height))
+                if self
+                    .options()
+                    .is_local_enabled(TelnetOption::NegotiateAboutWindowSize) =>
+            {
+                self.send(TelnetSubnegotiation::NegotiateAboutWindowSize { width, height }.into())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // Answer a negotiation so that both sides agree, without acknowledging acknowledgements.
+    // `before` is the options as they were before it was received.
+    fn on_negotiation(
+        &mut self,
+        before: &OptionStates,
+        action: TelnetAction,
+        option: TelnetOption,
+    ) -> TellyResult {
+        let was_local = before.is_local_enabled(option);
+        let was_remote = before.is_remote_enabled(option);
+        let options = self.options();
+
+        match action {
+            TelnetAction::Do if !LOCAL_OPTIONS.contains(&option) => {
+                self.send(TelnetEvent::wont(option))?
+            }
+            TelnetAction::Do if !options.is_local_enabled(option) => {
+                self.send(TelnetEvent::will(option))?
+            }
+            TelnetAction::Will if !REMOTE_OPTIONS.contains(&option) => {
+                self.send(TelnetEvent::dont(option))?
+            }
+            TelnetAction::Will if !options.is_remote_enabled(option) => {
+                self.send(TelnetEvent::r#do(option))?
+            }
+            TelnetAction::Dont if was_local => self.send(TelnetEvent::wont(option))?,
+            TelnetAction::Wont if was_remote => self.send(TelnetEvent::dont(option))?,
+            _ => {}
+        }
+
+        // The size is sent as soon as NAWS is agreed on, whoever asked first
+        if option == TelnetOption::NegotiateAboutWindowSize
+            && !was_local
+            && self.options().is_local_enabled(option)
+        {
+            self.send_window_size()?;
+        }
+        Ok(())
+    }
+
+    fn on_subnegotiation(&mut self, subnegotiation: TelnetSubnegotiation) -> TellyResult {
+        if subnegotiation == TelnetSubnegotiation::TerminalTypeRequest {
+            let terminal_type = env::var("TERM").unwrap_or_else(|_| "unknown".into());
+            self.send(TelnetSubnegotiation::TerminalTypeResponse(terminal_type).into())?;
+        }
+        Ok(())
+    }
+
+    fn toggle_binary(&mut self) -> TellyResult {
+        self.binary_requested = !self.binary_requested;
+        if self.binary_requested {
+            self.send(TelnetEvent::will(TelnetOption::BinaryTransmission))?;
+            self.send(TelnetEvent::r#do(TelnetOption::BinaryTransmission))
+        } else {
+            self.send(TelnetEvent::wont(TelnetOption::BinaryTransmission))?;
+            self.send(TelnetEvent::dont(TelnetOption::BinaryTransmission))
+        }
+    }
+
+    fn status(&self) -> String {
+        let names = |options: Vec<TelnetOption>| {
+            let names: Vec<&str> = options.iter().map(TelnetOption::name).collect();
+            if names.is_empty() {
+                "none".into()
+            } else {
+                names.join(" ")
+            }
+        };
+        let window_size = match self.window_size {
+            Some((width, height)) => format!("{width}x{height}"),
+            None => "unknown".into(),
+        };
+        let binary = |enabled| if enabled { "on" } else { "off" };
+        let options = self.options();
+
+        format!(
+            "Connected to {}.\n\
+             Local options: {}\n\
+             Remote options: {}\n\
+             Binary: sending {}, receiving {}\n\
+             Window size: {window_size}\n\
+             Escape character is '^]'.",
+            self.host,
+            names(options.local_options()),
+            names(options.remote_options()),
+            binary(options.is_local_enabled(TelnetOption::BinaryTransmission)),
+            binary(options.is_remote_enabled(TelnetOption::BinaryTransmission)),
+        )
+    }
+}
+
+type SharedConnection = Arc<Mutex<Connection>>;
+
+fn lock(connection: &SharedConnection) -> MutexGuard<'_, Connection> {
+    connection.lock().expect("Connection poisoned")
+}
+
+fn exit(terminal: &Terminal, message: &str, code: i32) -> ! {
+    terminal.restore();
+    eprintln!("{message}");
+    process::exit(code);
+}
+
+// Print what the server sends, and answer its negotiations.
+fn receive(mut reader: TelnetReader<TcpStream>, connection: SharedConnection, terminal: Terminal) {
+    let mut stdout = io::stdout();
+    loop {
+        let before = reader.options();
+        let event = match reader.next_event() {
+            Ok(Some(event)) => event,
+            Ok(None) => break,
+            Err(err) => exit(&terminal, &format!("\r\nConnection error: {err}"), 1),
+        };
+        let result = match event {
+            TelnetEvent::Data(data) => stdout
+                .write_all(&data)
+                .and_then(|_| stdout.flush())
+                .map_err(Into::into),
+            TelnetEvent::Negotiation { action, option } => {
+                lock(&connection).on_negotiation(&before, action, option)
+            }
+            TelnetEvent::Subnegotiation(subnegotiation) => match subnegotiation.try_into() {
+                Ok(subnegotiation) => lock(&connection).on_subnegotiation(subnegotiation),
+                Err(_) => Ok(()),
+            },
+            TelnetEvent::Command(_) => Ok(()),
+        };
+        if let Err(err) = result {
+            exit(&terminal, &format!("\r\nConnection error: {err}"), 1);
+        }
+    }
+    exit(&terminal, "\r\nConnection closed by foreign host.", 0);
+}
+
+// Keep the server informed of window size changes.
+fn watch_window_size(connection: SharedConnection) {
+    loop {
+        thread::sleep(WINDOW_POLL_INTERVAL);
+        let size = Terminal::window_size();
+        let mut connection = lock(&connection);
+        if size != connection.window_size {
+            connection.window_size = size;
+            if connection.send_window_size().is_err() {
+                return;
+            }
+        }
+    }
+}
+
+enum Prompt {
+    Continue,
+    Close,
+}
+
+// Run the escape-character command prompt in cooked mode.
+fn prompt(connection: &SharedConnection, terminal: &Terminal) -> TellyResult<Prompt> {
+    terminal.restore();
+    print!("\r\ntelly> ");
+    io::stdout().flush()?;
+
+    let mut line = String::new();
+    io::stdin().lock().read_line(&mut line)?;
+    let words: Vec<&str> = line.split_whitespace().collect();
+
+    let mut connection = lock(connection);
+    match words.as_slice() {
+        [] => {}
+        ["close" | "quit"] => return Ok(Prompt::Close),
+        ["send", what] => {
+            let command = match what.to_ascii_lowercase().as_str() {
+                "ayt" => TelnetCommand::AreYouThere,
+                "brk" => TelnetCommand::Break,
+                "ip" => TelnetCommand::InterruptProcess,
+                "ao" => TelnetCommand::AbortOutput,
+                "ec" => TelnetCommand::EraseCharacter,
+                "el" => TelnetCommand::EraseLine,
+                "nop" => TelnetCommand::Nop,
+                _ => {
+                    println!("?Unknown command to send: '{what}'");
+                    return Ok(Prompt::Continue);
+                }
+            };
+            connection.send(command.into())?;
+        }
+        ["status"] => println!("{}", connection.status()),
+        ["toggle", "binary"] => {
+            connection.toggle_binary()?;
+            println!(
+                "{} binary mode with remote host.",
+                if connection.binary_requested {
+                    "Negotiating"
+                } else {
+                    "Leaving"
+                }
+            );
+        }
+        ["help" | "?"] => println!("{HELP}"),
+        _ => println!("?Invalid command. Type 'help' for a list of commands."),
+    }
+    Ok(Prompt::Continue)
+}
+
+// Forward keyboard input until the user closes the connection or stdin ends.
+fn send_input(connection: &SharedConnection, terminal: &Terminal) -> TellyResult {
+    let mut stdin = io::stdin();
+    let mut buffer = [0; 1024];
+    loop {
+        let count = stdin.read(&mut buffer)?;
+        if count == 0 {
+            return Ok(());
+        }
+
+        let mut input = &buffer[..count];
+        while !input.is_empty() {
+            let end = input
+                .iter()
+                .position(|&byte| byte == ESCAPE_CHARACTER)
+                .unwrap_or(input.len());
+            let (data, rest) = input.split_at(end);
+
+            if !data.is_empty() {
+                let mut connection = lock(connection);
+                connection.send_input(data)?;
+                // Nobody else will echo
+                if !connection.options().is_remote_enabled(TelnetOption::Echo) {
+                    let echo: Vec<u8> = data
+                        .iter()
+                        .flat_map(|&byte| match byte {
+                            b'\r' => vec![b'\r', b'\n'],
+                            byte => vec![byte],
+                        })
+                        .collect();
+                    let mut stdout = io::stdout();
+                    stdout.write_all(&echo)?;
+                    stdout.flush()?;
+                }
+            }
+
+            if let Some(rest) = rest.strip_prefix(&[ESCAPE_CHARACTER]) {
+                if let Prompt::Close = prompt(connection, terminal)? {
+                    return Ok(());
+                }
+                terminal.enable_raw_mode()?;
+                input = rest;
+            } else {
+                input = rest;
+            }
+        }
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let (host, port) = match args.as_slice() {
+        [host] => (host.clone(), DEFAULT_PORT),
+        [host, port] => match port.parse() {
+            Ok(port) => (host.clone(), port),
+            Err(_) => {
+                eprintln!("{USAGE}");
+                process::exit(2);
+            }
+        },
+        _ => {
+            eprintln!("{USAGE}");
+            process::exit(2);
+        }
+    };
+
+    let terminal = Terminal::new();
+    let socket = TcpStream::connect((host.as_str(), port))
+        .unwrap_or_else(|err| exit(&terminal, &format!("Unable to connect: {err}"), 1));
+    let (reader, writer) = socket
+        .try_clone()
+        .map_err(Into::into)
+        .and_then(|stream| TelnetStream::from_stream(stream).split())
+        .unwrap_or_else(|err| exit(&terminal, &format!("Unable to connect: {err}"), 1));
+    println!("Connected to {host}.\nEscape character is '^]'.");
+
+    let connection = Arc::new(Mutex::new(Connection {
+        host,
+        writer,
+        window_size: Terminal::window_size(),
+        binary_requested: false,
+    }));
+
+    // Offer what we can do up front, and ask for character mode
+    let offered = {
+        let mut connection = lock(&connection);
+        connection
+            .send(TelnetEvent::will(TelnetOption::TerminalType))
+            .and_then(|_| {
+                connection.send(TelnetEvent::will(TelnetOption::NegotiateAboutWindowSize))
+            })
+            .and_then(|_| connection.send(TelnetEvent::r#do(TelnetOption::SuppressGoAhead)))
+    };
+    if let Err(err) = offered {
+        exit(&terminal, &format!("Connection error: {err}"), 1);
+    }
+
+    if let Err(err) = terminal.enable_raw_mode() {
+        exit(&terminal, &format!("Unable to set up terminal: {err}"), 1);
+    }
+
+    {
+        let connection = connection.clone();
+        let terminal = terminal.clone();
+        thread::spawn(move || receive(reader, connection, terminal));
+    }
+    {
+        let connection = connection.clone();
+        thread::spawn(move || watch_window_size(connection));
+    }
+
+    let result = send_input(&connection, &terminal);
+    let _ = socket.shutdown(Shutdown::Both);
+    match result {
+        Ok(()) => exit(&terminal, "\r\nConnection closed.", 0),
+        Err(err) => exit(&terminal, &format!("\r\nConnection error: {err}"), 1),
+    }
+}
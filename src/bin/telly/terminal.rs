@@ -0,0 +1,83 @@
+//! Local terminal control: raw mode and window size.
+use std::{
+    io, mem,
+    sync::{Arc, Mutex},
+};
+
+/// The local terminal on stdin. Cloning yields another handle to the same terminal.
+#[derive(Clone)]
+pub struct Terminal {
+    // Settings to restore, while in raw mode
+    original: Arc<Mutex<Option<libc::termios>>>,
+}
+
+impl Terminal {
+    pub fn new() -> Self {
+        Self {
+            original: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Whether stdin is a terminal at all.
+    pub fn is_tty() -> bool {
+        // SAFETY: isatty() has no preconditions.
+        unsafe { libc::isatty(libc::STDIN_FILENO) == 1 }
+    }
+
+    /// Switch to raw mode: no line buffering, no local echo, no signals from ^C, and no output
+    /// processing. Does nothing if stdin is not a terminal.
+    pub fn enable_raw_mode(&self) -> io::Result<()> {
+        let mut original = self.original.lock().expect("Terminal poisoned");
+        if original.is_some() || !Self::is_tty() {
+            return Ok(());
+        }
+
+        // SAFETY: termios is plain data, fully initialized by tcgetattr() on success.
+        unsafe {
+            let mut termios: libc::termios = mem::zeroed();
+            if libc::tcgetattr(libc::STDIN_FILENO, &mut termios) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let saved = termios;
+            libc::cfmakeraw(&mut termios);
+            if libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &termios) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            *original = Some(saved);
+        }
+        Ok(())
+    }
+
+    /// Restore the settings from before [Terminal::enable_raw_mode]. Safe to call repeatedly.
+    pub fn restore(&self) {
+        if let Some(original) = self.original.lock().expect("Terminal poisoned").take() {
+            // SAFETY: Restoring settings previously returned by tcgetattr().
+            unsafe {
+                libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &original);
+            }
+        }
+    }
+
+    /// The window size as `(width, height)`, if stdout is a terminal.
+    pub fn window_size() -> Option<(u16, u16)> {
+        // SAFETY: TIOCGWINSZ fills in a winsize, which outlives the call.
+        unsafe {
+            let mut size: libc::winsize = mem::zeroed();
+            if libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) != 0
+                || size.ws_col == 0
+            {
+                return None;
+            }
+            Some((size.ws_col, size.ws_row))
+        }
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        // Only the last handle restores, so a clone going away doesn't leave raw mode
+        if Arc::strong_count(&self.original) == 1 {
+            self.restore();
+        }
+    }
+}
@@ -0,0 +1,54 @@
+//! Minimal `telnetd`: serve a program on a PTY to every Telnet client.
+use std::{env, process};
+use telly::{pty::PtyConfig, server::TelnetServer};
+
+const USAGE: &str = "Usage: telnetd [-l ADDRESS] [-m MAX_CONNECTIONS] PROGRAM [ARGS...]";
+
+fn usage() -> ! {
+    eprintln!("{USAGE}");
+    process::exit(2);
+}
+
+fn main() {
+    let mut address = String::from("127.0.0.1:2323");
+    let mut max_connections = None;
+
+    let mut args = env::args_os().skip(1);
+    let program = loop {
+        let Some(arg) = args.next() else { usage() };
+        match arg.to_str() {
+            Some("-l") => {
+                address = args
+                    .next()
+                    .and_then(|arg| arg.into_string().ok())
+                    .unwrap_or_else(|| usage());
+            }
+            Some("-m") => {
+                max_connections = args
+                    .next()
+                    .and_then(|arg| arg.to_str()?.parse().ok())
+                    .or_else(|| usage());
+            }
+            Some("-h" | "--help") => {
+                println!("{USAGE}");
+                return;
+            }
+            _ => break arg,
+        }
+    };
+
+    let mut config = PtyConfig::new(program);
+    config.args = args.collect();
+    config.set_error_handler(|peer, err| eprintln!("Connection from {peer} failed: {err}"));
+
+    let mut server = TelnetServer::bind(&address).unwrap_or_else(|err| {
+        eprintln!("Failed to listen on {address}: {err}");
+        process::exit(1);
+    });
+    server.set_max_connections(max_connections);
+    eprintln!("Serving {:?} on {address}", config.program);
+    if let Err(err) = server.serve(config) {
+        eprintln!("Server failed: {err}");
+        process::exit(1);
+    }
+}
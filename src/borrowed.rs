@@ -0,0 +1,301 @@
+//! Decoding events that borrow from the receive buffer, instead of copying out of it.
+use crate::{
+    constants::{IAC, SB, SE},
+    TelnetAction, TelnetCommand, TelnetEvent, TelnetOption, TelnetParser,
+    UnparsedTelnetSubnegotiation,
+};
+use alloc::{borrow::Cow, vec::Vec};
+use core::iter::FusedIterator;
+
+/// A [TelnetEvent] that borrows its bytes from the buffer it was decoded from. Produced by
+/// [TelnetParser::parse_borrowed].
+#[derive(Clone, Debug, PartialEq)]
+pub enum TelnetEventRef<'a> {
+    /// A Telnet command.
+    Command(TelnetCommand),
+    /// A negotiation like `WILL <option>`.
+    Negotiation {
+        /// The Telnet command.
+        action: TelnetAction,
+        /// The option to request/demand/ack/nack.
+        option: TelnetOption,
+    },
+    /// A subnegotiation. The bytes are only copied if they contain escaped IACs.
+    Subnegotiation {
+        /// The [TelnetOption] this subnegotiation is associated with.
+        option: TelnetOption,
+        /// The unescaped inner bytes of the subnegotiation.
+        bytes: Cow<'a, [u8]>,
+    },
+    /// Data, which is never copied. Unlike with [TelnetEvent::Data], a run of data is split
+    /// into several events where an IAC was escaped or NUL padding was stripped.
+    Data(&'a [u8]),
+}
+
+impl TelnetEventRef<'_> {
+    /// Copy into an owned [TelnetEvent].
+    pub fn into_owned(self) -> TelnetEvent {
+        match self {
+            Self::Command(command) => TelnetEvent::Command(command),
+            Self::Negotiation { action, option } => TelnetEvent::Negotiation { action, option },
+            Self::Subnegotiation { option, bytes } => {
+                TelnetEvent::Subnegotiation(UnparsedTelnetSubnegotiation {
+                    option,
+                    bytes: bytes.into_owned(),
+                })
+            }
+            Self::Data(data) => TelnetEvent::Data(data.into()),
+        }
+    }
+}
+
+impl From<TelnetEventRef<'_>> for TelnetEvent {
+    fn from(event: TelnetEventRef<'_>) -> Self {
+        event.into_owned()
+    }
+}
+
+/// Iterator over the complete events in a buffer, created by [TelnetParser::parse_borrowed].
+#[derive(Clone, Debug)]
+pub struct BorrowedEvents<'a> {
+    input: &'a [u8],
+    translate: bool,
+}
+
+impl<'a> BorrowedEvents<'a> {
+    /// The bytes that have not been decoded yet. Once iteration has finished, this is an
+    /// incomplete event that should be kept until more bytes arrive.
+    pub const fn remainder(&self) -> &'a [u8] {
+        self.input
+    }
+
+    // Decode the run of data starting at `start`, returning it and its length. The first byte
+    // is always data
+    fn data(&self, start: usize) -> (TelnetEventRef<'a>, usize) {
+        let data = &self.input[start..];
+        let end = data[1..]
+            .iter()
+            .position(|&byte| byte == IAC || (byte == 0 && self.translate))
+            .map_or(data.len(), |position| position + 1);
+
+        match data[end..] {
+            // Keep the escaped IAC, and skip its escape
+            [IAC, IAC, ..] => (TelnetEventRef::Data(&data[..=end]), start + end + 2),
+            // Skip NVT padding
+            [0, ..] if self.translate => (TelnetEventRef::Data(&data[..end]), start + end + 1),
+            _ => (TelnetEventRef::Data(&data[..end]), start + end),
+        }
+    }
+
+    // Decode the subnegotiation at the start of the input, returning it and its length
+    fn subnegotiation(&self) -> Option<(TelnetEventRef<'a>, usize)> {
+        let option = TelnetOption::from(*self.input.get(2)?);
+        let body = &self.input[3..];
+
+        let mut escaped = false;
+        let mut index = 0;
+        let end = loop {
+            let offset = body[index..].iter().position(|&byte| byte == IAC)?;
+            index += offset;
+            match *body.get(index + 1)? {
+                SE => break index,
+                _ => escaped = true,
+            }
+            index += 2;
+        };
+
+        let bytes = if escaped {
+            // IAC IAC is a literal IAC. Other commands within the subnegotiation are dropped, and
+            // their bytes kept, as with TelnetParser::next_event
+            let mut bytes = Vec::with_capacity(end);
+            let mut iac = false;
+            for &byte in &body[..end] {
+                iac = byte == IAC && !iac;
+                if !iac {
+                    bytes.push(byte);
+                }
+            }
+            Cow::Owned(bytes)
+        } else {
+            Cow::Borrowed(&body[..end])
+        };
+
+        Some((TelnetEventRef::Subnegotiation { option, bytes }, end + 5))
+    }
+}
+
+impl<'a> Iterator for BorrowedEvents<'a> {
+    type Item = TelnetEventRef<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.translate {
+            // Skip NVT padding
+            let padding = self.input.iter().take_while(|&&byte| byte == 0).count();
+            self.input = &self.input[padding..];
+        }
+
+        let input = self.input;
+        let (event, length) = match *input {
+            [] | [IAC] => return None,
+            [IAC, SB, ..] => self.subnegotiation()?,
+            // Skip the escape of an escaped IAC
+            [IAC, IAC, ..] => self.data(1),
+            [IAC, command, ..] => match TelnetAction::try_from(command) {
+                Ok(action) => {
+                    let option = TelnetOption::from(*input.get(2)?);
+                    (TelnetEventRef::Negotiation { action, option }, 3)
+                }
+                Err(_) => (TelnetEventRef::Command(TelnetCommand::from(command)), 2),
+            },
+            _ => self.data(0),
+        };
+
+        self.input = &input[length..];
+        Some(event)
+    }
+}
+
+impl FusedIterator for BorrowedEvents<'_> {}
+
+impl TelnetParser {
+    /// Decode the complete events in `input` without copying data out of it.
+    ///
+    /// Once the iterator is exhausted, [BorrowedEvents::remainder] holds any incomplete event at
+    /// the end of `input`.
+    ///
+    /// # Example
+    /// ```
+    /// use bytes::{Buf, BytesMut};
+    /// use telly::{TelnetEventRef, TelnetOption, TelnetParser};
+    ///
+    /// let mut rx_buffer = BytesMut::from(&b"hello\xff\xfb\x01world\xff"[..]);
+    /// let parser = TelnetParser::default();
+    ///
+    /// let mut events = parser.parse_borrowed(&rx_buffer);
+    /// assert_eq!(events.next(), Some(TelnetEventRef::Data(b"hello")));
+    /// assert_eq!(
+    ///     events.next(),
+    ///     Some(TelnetEventRef::Negotiation {
+    ///         action: telly::TelnetAction::Will,
+    ///         option: TelnetOption::Echo,
+    ///     })
+    /// );
+    /// assert_eq!(events.next(), Some(TelnetEventRef::Data(b"world")));
+    /// assert_eq!(events.next(), None);
+    ///
+    /// // Keep the incomplete IAC for later
+    /// let consumed = rx_buffer.len() - events.remainder().len();
+    /// rx_buffer.advance(consumed);
+    /// assert_eq!(&rx_buffer[..], b"\xff");
+    /// ```
+    pub fn parse_borrowed<'a>(&self, input: &'a [u8]) -> BorrowedEvents<'a> {
+        BorrowedEvents {
+            input,
+            translate: !self.is_binary(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TelnetSubnegotiation;
+    use alloc::{vec, vec::Vec};
+    use bytes::BytesMut;
+    use rand::prelude::*;
+
+    #[test]
+    fn borrowed() {
+        let parser = TelnetParser::default();
+        let input = b"\0ab\xff\xffc\0\0d\xff\xfa\x18\x00xterm\xff\xf0\xff\xfa\x05\xff\xff\xff\xf0\xff\xf6\xff\xfd";
+        let mut events = parser.parse_borrowed(input);
+        let expected = [
+            TelnetEventRef::Data(b"ab\xff"),
+            TelnetEventRef::Data(b"c"),
+            TelnetEventRef::Data(b"d"),
+            TelnetEventRef::Subnegotiation {
+                option: TelnetOption::TerminalType,
+                bytes: Cow::Borrowed(b"\x00xterm"),
+            },
+            TelnetEventRef::Subnegotiation {
+                option: TelnetOption::Status,
+                bytes: Cow::Owned(vec![0xff]),
+            },
+            TelnetEventRef::Command(TelnetCommand::AreYouThere),
+        ];
+        for expected in expected {
+            let event = events.next().unwrap();
+            if let TelnetEventRef::Data(data) = event {
+                // Points into the input rather than being copied
+                assert!(input.as_ptr_range().contains(&data.as_ptr()));
+            }
+            assert_eq!(event, expected);
+        }
+        assert_eq!(events.next(), None);
+        assert_eq!(events.remainder(), b"\xff\xfd");
+
+        // In binary mode, NUL is data
+        let mut parser = TelnetParser::default();
+        parser.set_binary(true);
+        assert_eq!(
+            parser
+                .parse_borrowed(b"\0\xff\xff\xff\xfa\x1f\0\x50\0\x18\xff\xf0")
+                .map(TelnetEvent::from)
+                .collect::<Vec<_>>(),
+            vec![
+                TelnetEvent::Data(vec![0, 0xff]),
+                TelnetSubnegotiation::NegotiateAboutWindowSize {
+                    width: 80,
+                    height: 24
+                }
+                .into()
+            ]
+        );
+    }
+
+    // Merge runs of data, which the two parsers split differently
+    fn merge(events: impl IntoIterator<Item = TelnetEvent>) -> Vec<TelnetEvent> {
+        let mut merged: Vec<TelnetEvent> = Vec::new();
+        for event in events {
+            match (merged.last_mut(), event) {
+                (Some(TelnetEvent::Data(data)), TelnetEvent::Data(more)) => data.extend(more),
+                (_, event) => merged.push(event),
+            }
+        }
+        merged
+    }
+
+    #[test]
+    fn matches_owned_parser() {
+        let mut rng = rand::thread_rng();
+        for binary in [false, true] {
+            let mut parser = TelnetParser::default();
+            parser.set_binary(binary);
+
+            for _ in 0..100 {
+                let mut input = Vec::new();
+                for _ in 0..rng.gen_range(0..20) {
+                    let mut bytes = vec![0; rng.gen_range(0..8)];
+                    rng.fill(&mut bytes[..]);
+                    let event = match rng.gen_range(0..4) {
+                        0 => TelnetCommand::AreYouThere.into(),
+                        1 => TelnetEvent::will(rng.gen::<u8>().into()),
+                        2 => TelnetEvent::Subnegotiation(UnparsedTelnetSubnegotiation {
+                            option: rng.gen::<u8>().into(),
+                            bytes,
+                        }),
+                        _ => TelnetEvent::Data(bytes),
+                    };
+                    input.extend(event.into_binary_bytes());
+                }
+
+                let mut rx_buffer = BytesMut::from(&input[..]);
+                let owned = core::iter::from_fn(|| parser.next_event(&mut rx_buffer));
+                let mut events = parser.parse_borrowed(&input);
+                let borrowed = merge(events.by_ref().map(TelnetEvent::from));
+                assert_eq!(borrowed, merge(owned), "{input:02x?}");
+                assert_eq!(events.remainder(), b"");
+            }
+        }
+    }
+}
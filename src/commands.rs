@@ -1,10 +1,14 @@
+use crate::errors::{TellyError, TellyResult};
+use alloc::format;
+use core::{fmt, str::FromStr};
+
 macro_rules! impl_telnet_command_enum {
     (
         $(
             $(
                 #[doc = $doc:expr]
             )*
-            $name: ident = $value: expr,
+            $name: ident = $value: expr, $short: literal,
         )*
     ) => {
         #[derive(Copy, Clone, Debug, PartialEq)]
@@ -43,34 +47,64 @@ macro_rules! impl_telnet_command_enum {
                 }
             }
         }
+
+        impl fmt::Display for TelnetCommand {
+            /// Format as the command's short name from the RFCs, like "AYT", or as a decimal
+            /// number for other commands.
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                match self {
+                    $(
+                        TelnetCommand::$name => f.write_str($short),
+                    )*
+                    TelnetCommand::Other(byte) => write!(f, "{byte}"),
+                }
+            }
+        }
+
+        impl FromStr for TelnetCommand {
+            type Err = TellyError;
+
+            /// Parse a command from its short name (case-insensitive) or its decimal value.
+            fn from_str(s: &str) -> TellyResult<Self> {
+                if let Ok(byte) = s.parse::<u8>() {
+                    return Ok(Self::from(byte));
+                }
+                $(
+                    if s.eq_ignore_ascii_case($short) {
+                        return Ok(TelnetCommand::$name);
+                    }
+                )*
+                Err(TellyError::ConversionError(format!("Unknown Telnet command '{s}'")))
+            }
+        }
     }
 }
 
 impl_telnet_command_enum! {
     /// No operation.
-    Nop = 0xf1,
+    Nop = 0xf1, "NOP",
     /// The data stream portion of a Synch. This should always be accompanied by a TCP Urgent notification.
-    DataMark = 0xf2,
+    DataMark = 0xf2, "DM",
     /// NVT character BRK.
-    Break = 0xf3,
+    Break = 0xf3, "BRK",
     /// Suspend, interrupt, abort or terminate the process to which the NVT is connected. Also,
     /// part of the out-of-band signal for other protocols which use Telnet
-    InterruptProcess = 0xf4,
+    InterruptProcess = 0xf4, "IP",
     /// Allow the current process to (appear to) run to completion, but do not send its output to
     /// the user. Also, send a Synch to the user.
-    AbortOutput = 0xf5,
+    AbortOutput = 0xf5, "AO",
     /// It's me, Margaret. Tell the receive to send back to the NVT some visible (i.e., printable)
     /// evidence that the AYT was received. This function may be invoked by the user when the
     /// system is unexpectedly "silent" for a long time, because of the unanticipated (by the user)
     /// length of a computation, an unusually heavy system load, etc. AYT is the standard
     /// representation for invoking this function.
-    AreYouThere = 0xf6,
+    AreYouThere = 0xf6, "AYT",
     /// Inform the recipient that they should delete the last preceding undeleted character or
     /// "print position" from the data stream.
-    EraseCharacter = 0xf7,
+    EraseCharacter = 0xf7, "EC",
     /// Inform the recipient that they should delete characters from the data stream back to, but
     /// not including, the last "CR LF" sequence sent over the Telnet connection.
-    EraseLine = 0xf8,
+    EraseLine = 0xf8, "EL",
     /// The GA signal.
-    GoAhead = 0xf9,
+    GoAhead = 0xf9, "GA",
 }
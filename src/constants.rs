@@ -2,6 +2,36 @@
 pub const IS: u8 = 0x00;
 /// Used in some request subnegotiations
 pub const SEND: u8 = 0x01;
+/// Used in some unsolicited informational subnegotiations
+pub const INFO: u8 = 0x02;
+/// NEW-ENVIRON: a well-known variable name follows
+pub const VAR: u8 = 0x00;
+/// NEW-ENVIRON: a variable value follows
+pub const VALUE: u8 = 0x01;
+/// NEW-ENVIRON: the next byte is to be taken literally
+pub const ESC: u8 = 0x02;
+/// NEW-ENVIRON: a user-defined variable name follows
+pub const USERVAR: u8 = 0x03;
+/// AUTHENTICATION and ENCRYPT: a reply to the other side's data
+pub const REPLY: u8 = 0x02;
+/// AUTHENTICATION: the name to authenticate as
+pub const NAME: u8 = 0x03;
+/// ENCRYPT: the encryption types supported
+pub const SUPPORT: u8 = 0x01;
+/// ENCRYPT: what follows is encrypted
+pub const START: u8 = 0x03;
+/// ENCRYPT: what follows is no longer encrypted
+pub const END: u8 = 0x04;
+/// ENCRYPT: a request that the other side START
+pub const REQUEST_START: u8 = 0x05;
+/// ENCRYPT: a request that the other side END
+pub const REQUEST_END: u8 = 0x06;
+/// ENCRYPT: the key ID to encrypt with
+pub const ENC_KEYID: u8 = 0x07;
+/// ENCRYPT: the key ID to decrypt with
+pub const DEC_KEYID: u8 = 0x08;
+/// START_TLS: the TLS handshake follows
+pub const FOLLOWS: u8 = 0x01;
 /// End of subnegotiation parameters
 pub const SE: u8 = 0xf0;
 /// Indicates that what follows is subnegotiation of the indicated option.
@@ -0,0 +1,142 @@
+//! The ENCRYPT option ([RFC2946](https://www.rfc-editor.org/rfc/rfc2946.html)).
+//!
+//! Each direction of a connection is encrypted separately. The side that will receive
+//! encrypted data lists the types it supports with [TelnetSubnegotiation::EncryptionSupport],
+//! the other picks one, and the two exchange keys with [TelnetSubnegotiation::EncryptionIs] and
+//! [TelnetSubnegotiation::EncryptionReply]. Everything after a
+//! [TelnetSubnegotiation::EncryptionStart] is encrypted, up to and including the
+//! [TelnetSubnegotiation::EncryptionEnd].
+//!
+//! Key exchange is specific to each encryption type, and left to the application. Once a
+//! [Cipher] has been agreed on, give it to [TelnetStream::set_encryption] or
+//! [TelnetStream::set_decryption], and the stream will switch it on and off at the exact bytes
+//! where START and END are sent or received.
+//!
+//! [TelnetStream::set_encryption]: crate::TelnetStream::set_encryption
+//! [TelnetStream::set_decryption]: crate::TelnetStream::set_decryption
+#[cfg(doc)]
+use crate::TelnetSubnegotiation;
+#[cfg(feature = "std")]
+use crate::{constants, TelnetEvent, TelnetOption};
+#[cfg(feature = "std")]
+use alloc::boxed::Box;
+
+/// Encryption types, as listed in RFC2946 and assigned by IANA.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum EncryptionType {
+    /// No encryption.
+    Null,
+    /// DES in 64-bit cipher feedback mode.
+    DesCfb64,
+    /// DES in 64-bit output feedback mode.
+    DesOfb64,
+    /// Triple DES in 64-bit cipher feedback mode.
+    Des3Cfb64,
+    /// Triple DES in 64-bit output feedback mode.
+    Des3Ofb64,
+    /// 40-bit CAST-128 in 64-bit cipher feedback mode.
+    Cast5_40Cfb64,
+    /// 40-bit CAST-128 in 64-bit output feedback mode.
+    Cast5_40Ofb64,
+    /// CAST-128 in 64-bit cipher feedback mode.
+    Cast128Cfb64,
+    /// CAST-128 in 64-bit output feedback mode.
+    Cast128Ofb64,
+    /// Some other encryption type not listed.
+    Other(u8),
+}
+
+const ENCRYPTION_TYPES: [(EncryptionType, u8); 9] = [
+    (EncryptionType::Null, 0),
+    (EncryptionType::DesCfb64, 1),
+    (EncryptionType::DesOfb64, 2),
+    (EncryptionType::Des3Cfb64, 3),
+    (EncryptionType::Des3Ofb64, 4),
+    (EncryptionType::Cast5_40Cfb64, 8),
+    (EncryptionType::Cast5_40Ofb64, 9),
+    (EncryptionType::Cast128Cfb64, 10),
+    (EncryptionType::Cast128Ofb64, 11),
+];
+
+impl From<u8> for EncryptionType {
+    fn from(byte: u8) -> Self {
+        ENCRYPTION_TYPES
+            .iter()
+            .find(|(_, value)| *value == byte)
+            .map_or(Self::Other(byte), |(kind, _)| *kind)
+    }
+}
+
+impl From<EncryptionType> for u8 {
+    fn from(kind: EncryptionType) -> u8 {
+        match kind {
+            EncryptionType::Other(byte) => byte,
+            kind => ENCRYPTION_TYPES
+                .iter()
+                .find(|(listed, _)| *listed == kind)
+                .map_or(0, |(_, value)| *value),
+        }
+    }
+}
+
+/// A stream cipher for one direction of a connection. Bytes are transformed in place, one for
+/// one, each call continuing where the last left off.
+pub trait Cipher: Send {
+    /// Encrypt bytes we're about to send.
+    fn encrypt(&mut self, bytes: &mut [u8]);
+
+    /// Decrypt bytes we've received.
+    fn decrypt(&mut self, bytes: &mut [u8]);
+}
+
+// Whether an event starts (true) or ends (false) encryption of what follows it, in the
+// direction it's sent
+#[cfg(feature = "std")]
+pub(crate) fn switch(event: &TelnetEvent) -> Option<bool> {
+    match event {
+        TelnetEvent::Subnegotiation(subnegotiation)
+            if subnegotiation.option == TelnetOption::Encrypt =>
+        {
+            match subnegotiation.bytes.first() {
+                Some(&constants::START) => Some(true),
+                Some(&constants::END) => Some(false),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+// One direction's cipher, and whether it's switched on
+#[cfg(feature = "std")]
+#[derive(Default)]
+pub(crate) struct CipherState {
+    cipher: Option<Box<dyn Cipher>>,
+    active: bool,
+}
+
+#[cfg(feature = "std")]
+impl CipherState {
+    pub(crate) fn set(&mut self, cipher: Option<Box<dyn Cipher>>) {
+        self.active &= cipher.is_some();
+        self.cipher = cipher;
+    }
+
+    pub(crate) const fn is_active(&self) -> bool {
+        self.active
+    }
+
+    pub(crate) const fn has_cipher(&self) -> bool {
+        self.cipher.is_some()
+    }
+
+    pub(crate) fn set_active(&mut self, active: bool) {
+        self.active = active && self.cipher.is_some();
+    }
+
+    // The cipher, if switched on
+    pub(crate) fn active(&mut self) -> Option<&mut (dyn Cipher + 'static)> {
+        self.cipher.as_deref_mut().filter(|_| self.active)
+    }
+}
@@ -1,4 +1,6 @@
 //! Error and result types for Telly.
+use alloc::{string::String, vec::Vec};
+#[cfg(feature = "std")]
 use std::io;
 use thiserror::Error;
 
@@ -6,6 +8,7 @@ use thiserror::Error;
 #[derive(Error, Debug)]
 pub enum TellyError {
     /// IO error wrapper.
+    #[cfg(feature = "std")]
     #[error("IoError")]
     IoError(#[from] io::Error),
     /// Not all bytes were written in a call to [write()](std::io::Write::write).
@@ -17,7 +20,69 @@ pub enum TellyError {
     /// Invalid conversion.
     #[error("Invalid variant: {0}")]
     ConversionError(String),
+    /// No session with the given ID is registered.
+    #[error("No such session: {0}")]
+    NoSuchSession(u64),
+    /// The remote did not respond to a keepalive probe in time.
+    #[error("Remote did not respond to keepalive probe")]
+    IdleTimeout,
+    /// A keepalive probe could not be sent, so the remote is likely gone.
+    #[cfg(feature = "std")]
+    #[error("Failed to send keepalive probe: {0}")]
+    KeepAliveFailed(io::Error),
+    /// None of the expected patterns were received in time.
+    #[error("Timed out waiting for {patterns:?}")]
+    ExpectTimeout {
+        /// The patterns that were expected.
+        patterns: Vec<String>,
+        /// Data received while waiting that didn't match.
+        unmatched: String,
+    },
+    /// Authentication was refused or failed.
+    #[error("Authentication failed: {0}")]
+    AuthenticationFailed(String),
+    /// A [proxy](crate::proxy) rule, or the proxy itself, panicked with this message.
+    #[cfg(feature = "std")]
+    #[error("Proxy panicked: {0}")]
+    ProxyPanicked(String),
+    /// Encryption was switched on without a cipher to do it with.
+    #[error("No cipher set for ENCRYPT")]
+    NoCipher,
+    /// TLS failed, e.g. because the handshake or certificate verification failed.
+    #[cfg(feature = "tls")]
+    #[error("TLS error: {0}")]
+    TlsError(#[from] rustls::Error),
+    /// A Telnet connection could not be upgraded to TLS in its current state.
+    #[cfg(feature = "tls")]
+    #[error("Can't upgrade to TLS: {0}")]
+    TlsUpgradeError(String),
+    /// A certificate or key could not be loaded from PEM.
+    #[cfg(feature = "tls")]
+    #[error("Bad PEM: {0}")]
+    PemError(#[from] rustls::pki_types::pem::Error),
+    /// The WebSocket handshake or connection failed.
+    #[cfg(feature = "websocket")]
+    #[error("WebSocket error: {0}")]
+    WebSocketError(Box<tungstenite::Error>),
 }
 
 /// Result type used in this crate.
 pub type TellyResult<T = ()> = Result<T, TellyError>;
+
+#[cfg(feature = "std")]
+impl From<TellyError> for io::Error {
+    fn from(err: TellyError) -> Self {
+        match err {
+            TellyError::IoError(err) => err,
+            err => io::Error::other(err),
+        }
+    }
+}
+
+#[cfg(feature = "websocket")]
+impl From<tungstenite::Error> for TellyError {
+    // Boxed, since it would more than double the size of every result
+    fn from(err: tungstenite::Error) -> Self {
+        Self::WebSocketError(Box::new(err))
+    }
+}
@@ -0,0 +1,371 @@
+//! Expect-style automation of Telnet sessions, e.g. for scripting network equipment.
+//!
+//! Waiting relies on reads from the underlying stream timing out, so the stream needs a read
+//! timeout. [Expect::connect] sets one up.
+//!
+//! # Example
+//! ```no_run
+//! use regex::Regex;
+//! use std::time::Duration;
+//! use telly::expect::Expect;
+//!
+//! let mut session = Expect::connect("192.168.1.1:23").unwrap();
+//! session.login("admin", "hunter2").unwrap();
+//!
+//! let prompt = Regex::new(r"[>#]\s*$").unwrap();
+//! session.expect(&prompt, Duration::from_secs(5)).unwrap();
+//! session.send_line("show version").unwrap();
+//! let reply = session.expect(&prompt, Duration::from_secs(5)).unwrap();
+//! println!("{}", reply.before);
+//! ```
+use crate::{
+    errors::{TellyError, TellyResult},
+    TelnetAction, TelnetEvent, TelnetOption, TelnetStream,
+};
+use regex::Regex;
+use std::{
+    io::{ErrorKind, Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    sync::OnceLock,
+    time::{Duration, Instant},
+};
+
+// Read timeout used by Expect::connect, which bounds how late a timeout is noticed.
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// A successful match of [Expect::expect] or [Expect::expect_any].
+#[derive(Clone, Debug, PartialEq)]
+pub struct Match {
+    /// Index of the pattern that matched. Always 0 for [Expect::expect].
+    pub index: usize,
+    /// Data received before the match.
+    pub before: String,
+    /// The matched text.
+    pub matched: String,
+    /// Capture groups of the pattern, not including the whole match.
+    pub captures: Vec<Option<String>>,
+}
+
+/// Wraps a [TelnetStream] to wait for expected output and respond to it.
+///
+/// Negotiations from the remote are answered automatically: it may ECHO and SUPPRESS-GO-AHEAD,
+/// and everything else is refused.
+pub struct Expect<S: Read + Write> {
+    stream: TelnetStream<S>,
+    // Received data that hasn't been matched yet
+    unmatched: String,
+    transcript: String,
+    // The start of a UTF-8 character that was cut off at the end of the last read
+    partial: Vec<u8>,
+    // Timeout used by the login helper
+    timeout: Duration,
+}
+
+impl Expect<TcpStream> {
+    /// Connect to a Telnet server.
+    pub fn connect(address: impl ToSocketAddrs) -> TellyResult<Self> {
+        let stream = TcpStream::connect(address)?;
+        stream.set_read_timeout(Some(POLL_INTERVAL))?;
+        Ok(Self::new(TelnetStream::from_stream(stream)))
+    }
+}
+
+impl<S: Read + Write> Expect<S> {
+    /// Wrap a stream. Its underlying stream should have a read timeout, or `expect` may block
+    /// past its timeout.
+    pub fn new(stream: TelnetStream<S>) -> Self {
+        Self {
+            stream,
+            unmatched: String::new(),
+            transcript: String::new(),
+            partial: Vec::new(),
+            timeout: Duration::from_secs(10),
+        }
+    }
+
+    /// Set the timeout used by [Expect::login]. Defaults to 10 seconds.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// Everything received so far.
+    pub fn transcript(&self) -> &str {
+        &self.transcript
+    }
+
+    /// The wrapped stream.
+    pub fn stream_mut(&mut self) -> &mut TelnetStream<S> {
+        &mut self.stream
+    }
+
+    /// Unwrap the stream. Received data that wasn't matched yet is lost.
+    pub fn into_inner(self) -> TelnetStream<S> {
+        self.stream
+    }
+
+    /// Send a line of text, terminated with CR LF.
+    pub fn send_line(&mut self, line: &str) -> TellyResult {
+        self.stream.send_data(format!("{line}\n").as_bytes())
+    }
+
+    /// Send text as-is.
+    pub fn send(&mut self, text: &str) -> TellyResult {
+        self.stream.send_data(text.as_bytes())
+    }
+
+    /// Wait until received data matches `pattern`. Data up to the end of the match is consumed.
+    ///
+    /// Fails with [TellyError::ExpectTimeout] if nothing matches within `timeout`.
+    pub fn expect(&mut self, pattern: &Regex, timeout: Duration) -> TellyResult<Match> {
+        self.expect_any(std::slice::from_ref(pattern), timeout)
+    }
+
+    /// Wait until received data matches any of `patterns`. If several match, the one matching
+    /// earliest in the data wins. Data up to the end of the match is consumed.
+    ///
+    /// Fails with [TellyError::ExpectTimeout] if nothing matches within `timeout`.
+    pub fn expect_any(&mut self, patterns: &[Regex], timeout: Duration) -> TellyResult<Match> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(found) = self.find(patterns) {
+                return Ok(found);
+            }
+            if Instant::now() >= deadline {
+                return Err(TellyError::ExpectTimeout {
+                    patterns: patterns.iter().map(|p| p.as_str().into()).collect(),
+                    unmatched: self.unmatched.clone(),
+                });
+            }
+            self.receive()?;
+        }
+    }
+
+    /// Log in by answering `login:`/`Username:` and `Password:` prompts. Devices that only ask
+    /// for a password are supported too.
+    pub fn login(&mut self, username: &str, password: &str) -> TellyResult {
+        static PROMPTS: OnceLock<[Regex; 2]> = OnceLock::new();
+        let [login_prompt, password_prompt] = PROMPTS.get_or_init(|| {
+            [
+                Regex::new(r"(?i)(login|user ?name)\s*:\s*$").expect("Bad login regex"),
+                Regex::new(r"(?i)password\s*:\s*$").expect("Bad password regex"),
+            ]
+        });
+
+        let found = self.expect_any(
+            &[login_prompt.clone(), password_prompt.clone()],
+            self.timeout,
+        )?;
+        if found.index == 0 {
+            self.send_line(username)?;
+            self.expect(password_prompt, self.timeout)?;
+        }
+        self.send_line(password)
+    }
+
+    // Consume and return the earliest match, if any.
+    fn find(&mut self, patterns: &[Regex]) -> Option<Match> {
+        let (index, captures) = patterns
+            .iter()
+            .enumerate()
+            .filter_map(|(index, pattern)| Some((index, pattern.captures(&self.unmatched)?)))
+            .min_by_key(|(_, captures)| captures.get(0).map(|m| m.start()))?;
+
+        let whole = captures.get(0).expect("Bug: capture without match");
+        let found = Match {
+            index,
+            before: self.unmatched[..whole.start()].into(),
+            matched: whole.as_str().into(),
+            captures: captures
+                .iter()
+                .skip(1)
+                .map(|group| group.map(|group| group.as_str().into()))
+                .collect(),
+        };
+        let end = whole.end();
+        self.unmatched.drain(..end);
+        Some(found)
+    }
+
+    // Wait for one event, handling it. Returns normally on read timeouts.
+    fn receive(&mut self) -> TellyResult {
+        let event = match self.stream.next_event() {
+            Ok(Some(event)) => event,
+            Ok(None) => return Err(std::io::Error::from(ErrorKind::UnexpectedEof).into()),
+            Err(TellyError::IoError(err))
+                if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) =>
+            {
+                return Ok(())
+            }
+            Err(err) => return Err(err),
+        };
+
+        match event {
+            TelnetEvent::Data(data) => {
+                // Characters split across reads are decoded once they're complete
+                self.partial.extend(data);
+                let rest = self
+                    .partial
+                    .split_off(self.partial.len() - incomplete_suffix(&self.partial));
+                let text = String::from_utf8_lossy(&self.partial);
+                self.unmatched.push_str(&text);
+                self.transcript.push_str(&text);
+                self.partial = rest;
+            }
+            TelnetEvent::Negotiation { action, option } => {
+                let options = self.stream.options();
+                match action {
+                    TelnetAction::Will
+                        if matches!(option, TelnetOption::Echo | TelnetOption::SuppressGoAhead)
+                            && !options.is_remote_enabled(option) =>
+                    {
+                        self.stream.send_do(option)?
+                    }
+                    TelnetAction::Will if !options.is_remote_enabled(option) => {
+                        self.stream.send_dont(option)?
+                    }
+                    TelnetAction::Do if !options.is_local_enabled(option) => {
+                        self.stream.send_wont(option)?
+                    }
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+// The length of the incomplete UTF-8 character at the end of `bytes`, if any.
+fn incomplete_suffix(bytes: &[u8]) -> usize {
+    // Characters are at most 4 bytes long, so an incomplete one starts within the last 3
+    (bytes.len().saturating_sub(3)..bytes.len())
+        .find(|&start| {
+            std::str::from_utf8(&bytes[start..])
+                .is_err_and(|err| err.valid_up_to() == 0 && err.error_len().is_none())
+        })
+        .map_or(0, |start| bytes.len() - start)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{net::TcpListener, thread};
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    // What the fake device received: lines of text, and other Telnet events
+    type Received = (Vec<String>, Vec<TelnetEvent>);
+
+    // A fake device that asks for credentials, then runs one command.
+    fn fake_device() -> (std::net::SocketAddr, thread::JoinHandle<Received>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let device = thread::spawn(move || {
+            let (socket, _) = listener.accept().unwrap();
+            let mut stream = TelnetStream::from_stream(socket);
+            let mut lines = Vec::new();
+            let mut events = Vec::new();
+            let mut pending = Vec::new();
+            let mut read_line = |stream: &mut TelnetStream<TcpStream>| {
+                while !pending.contains(&b'\n') {
+                    match stream.next_event().unwrap() {
+                        Some(TelnetEvent::Data(data)) => pending.extend(data),
+                        Some(event) => events.push(event),
+                        None => break,
+                    }
+                }
+                let end = pending.iter().position(|&byte| byte == b'\n');
+                let line: Vec<u8> = pending
+                    .drain(..end.map_or(pending.len(), |end| end + 1))
+                    .collect();
+                String::from_utf8(line).unwrap().trim_end().to_string()
+            };
+
+            stream.send_do(TelnetOption::TerminalType).unwrap();
+            stream.send_str("Router login: ").unwrap();
+            lines.push(read_line(&mut stream));
+            stream.send_str("Password: ").unwrap();
+            lines.push(read_line(&mut stream));
+            stream.send_str("\r\nWelcome!\r\nrouter# ").unwrap();
+            lines.push(read_line(&mut stream));
+            stream.send_str("Version 1.2.3\r\nrouter# ").unwrap();
+            (lines, events)
+        });
+        (address, device)
+    }
+
+    #[test]
+    fn login_and_run_command() {
+        let (address, device) = fake_device();
+        let mut session = Expect::connect(address).unwrap();
+        session.set_timeout(TIMEOUT);
+        session.login("admin", "secret").unwrap();
+
+        let prompt = Regex::new(r"(\w+)# $").unwrap();
+        let found = session.expect(&prompt, TIMEOUT).unwrap();
+        assert_eq!(found.captures, vec![Some("router".to_string())]);
+        assert_eq!(found.before, "\r\nWelcome!\r\n");
+
+        session.send_line("show version").unwrap();
+        let version = Regex::new(r"Version ([\d.]+)").unwrap();
+        let found = session.expect_any(&[prompt, version], TIMEOUT).unwrap();
+        assert_eq!(found.index, 1);
+        assert_eq!(found.matched, "Version 1.2.3");
+
+        let transcript = session.transcript().to_string();
+        assert!(transcript.starts_with("Router login: Password: \r\nWelcome!"));
+
+        let (lines, events) = device.join().unwrap();
+        assert_eq!(lines, ["admin", "secret", "show version"]);
+        // The TTYPE request was refused
+        assert_eq!(events, [TelnetEvent::wont(TelnetOption::TerminalType)]);
+    }
+
+    #[test]
+    fn split_characters() {
+        assert_eq!(incomplete_suffix(b"abc"), 0);
+        assert_eq!(incomplete_suffix(b"\xc3\xa9\xe2\x82"), 2);
+        assert_eq!(incomplete_suffix(b"\xff"), 0);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let device = thread::spawn(move || {
+            let (mut socket, _) = listener.accept().unwrap();
+            // "ñ" and "€" each cut in two
+            for chunk in [&b"Contrase\xc3"[..], b"\xb1a \xe2\x82", b"\xac: "] {
+                socket.write_all(chunk).unwrap();
+                thread::sleep(Duration::from_millis(50));
+            }
+        });
+
+        let mut session = Expect::connect(address).unwrap();
+        let prompt = Regex::new("Contraseña €: $").unwrap();
+        session.expect(&prompt, TIMEOUT).unwrap();
+        assert_eq!(session.transcript(), "Contraseña €: ");
+        device.join().unwrap();
+    }
+
+    #[test]
+    fn timeout() {
+        let (address, device) = fake_device();
+        let mut session = Expect::connect(address).unwrap();
+
+        let pattern = Regex::new("never").unwrap();
+        let start = Instant::now();
+        match session.expect(&pattern, Duration::from_millis(100)) {
+            Err(TellyError::ExpectTimeout {
+                patterns,
+                unmatched,
+            }) => {
+                assert_eq!(patterns, vec!["never".to_string()]);
+                assert_eq!(unmatched, "Router login: ");
+            }
+            other => panic!("Expected a timeout, but got {other:?}"),
+        }
+        assert!(start.elapsed() >= Duration::from_millis(100));
+
+        drop(session);
+        // The device's result doesn't matter once we've hung up
+        let _ = device.join();
+    }
+}
@@ -0,0 +1,313 @@
+//! Idle detection and keepalive probes, for noticing half-open connections.
+//!
+//! A [TelnetStream](crate::TelnetStream) can only notice silence if reads on the underlying
+//! stream time out, so a read timeout must be set on it, e.g. with
+//! [TcpStream::set_read_timeout](std::net::TcpStream::set_read_timeout) and
+//! [KeepAlive::poll_interval].
+//!
+//! # Example
+//! ```no_run
+//! use std::{net::TcpStream, time::Duration};
+//! use telly::{
+//!     keepalive::{KeepAlive, KeepAliveProbe},
+//!     TelnetStream,
+//! };
+//!
+//! let keepalive = KeepAlive::new(
+//!     Duration::from_secs(60),
+//!     KeepAliveProbe::TimingMark,
+//!     Some(Duration::from_secs(10)),
+//! );
+//! let stream = TcpStream::connect("127.0.0.1:23").unwrap();
+//! stream
+//!     .set_read_timeout(Some(keepalive.poll_interval()))
+//!     .unwrap();
+//!
+//! let mut stream = TelnetStream::from_stream(stream);
+//! stream.set_keepalive(Some(keepalive));
+//! // Fails with `TellyError::IdleTimeout` if the peer goes away
+//! while let Some(event) = stream.next_event().unwrap() {
+//!     println!("{event:?}");
+//! }
+//! ```
+use crate::{TelnetAction, TelnetCommand, TelnetEvent, TelnetOption};
+use std::time::{Duration, Instant};
+
+/// The event sent to an idle peer to check that it's still there.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum KeepAliveProbe {
+    /// `IAC NOP`. Peers don't reply to this, so without a deadline, dead peers are only detected
+    /// when a later probe's write fails.
+    Nop,
+    /// `IAC AYT`. Peers typically reply with some visible text, like `[Yes]`.
+    AreYouThere,
+    /// `IAC DO TIMING-MARK`. Peers reply with WILL or WONT TIMING-MARK. The reply is consumed by
+    /// the stream and not yielded.
+    TimingMark,
+}
+
+impl KeepAliveProbe {
+    /// The event sent for this probe.
+    pub const fn event(&self) -> TelnetEvent {
+        match self {
+            Self::Nop => TelnetEvent::Command(TelnetCommand::Nop),
+            Self::AreYouThere => TelnetEvent::Command(TelnetCommand::AreYouThere),
+            Self::TimingMark => TelnetEvent::r#do(TelnetOption::TimingMark),
+        }
+    }
+}
+
+/// Keepalive configuration for a [TelnetStream](crate::TelnetStream).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct KeepAlive {
+    /// How long the peer may be silent before a probe is sent.
+    pub idle: Duration,
+    /// What to send once the peer has been idle.
+    pub probe: KeepAliveProbe,
+    /// How long to wait for any data after sending a probe before giving up on the peer. `None`
+    /// means probes are repeated every `idle` interval until one fails to write.
+    pub deadline: Option<Duration>,
+}
+
+impl KeepAlive {
+    /// Construct a keepalive configuration.
+    pub const fn new(idle: Duration, probe: KeepAliveProbe, deadline: Option<Duration>) -> Self {
+        Self {
+            idle,
+            probe,
+            deadline,
+        }
+    }
+
+    /// A suitable read timeout for the underlying stream, so that idleness and deadlines are
+    /// noticed reasonably promptly.
+    pub fn poll_interval(&self) -> Duration {
+        const MIN_INTERVAL: Duration = Duration::from_millis(10);
+        let shortest = match self.deadline {
+            Some(deadline) => self.idle.min(deadline),
+            None => self.idle,
+        };
+        (shortest / 4).max(MIN_INTERVAL)
+    }
+}
+
+/// What a stream should do after its read timed out.
+#[derive(Debug, PartialEq)]
+pub(crate) enum KeepAliveAction {
+    /// Nothing to do; keep reading.
+    Wait,
+    /// The peer has been idle; send it this probe.
+    Probe(TelnetEvent),
+    /// The peer hasn't replied to the probe in time.
+    Expired,
+}
+
+/// Keepalive bookkeeping for a single stream.
+#[derive(Debug)]
+pub(crate) struct KeepAliveState {
+    config: KeepAlive,
+    last_received: Instant,
+    probe_sent: Option<Instant>,
+    // A TIMING-MARK probe has been sent whose reply hasn't been consumed yet
+    awaiting_timing_mark: bool,
+}
+
+impl KeepAliveState {
+    pub(crate) fn new(config: KeepAlive) -> Self {
+        Self {
+            config,
+            last_received: Instant::now(),
+            probe_sent: None,
+            awaiting_timing_mark: false,
+        }
+    }
+
+    pub(crate) const fn config(&self) -> KeepAlive {
+        self.config
+    }
+
+    /// Record that bytes were received from the peer.
+    pub(crate) fn on_receive(&mut self, now: Instant) {
+        self.last_received = now;
+        self.probe_sent = None;
+    }
+
+    /// Decide what to do after a read timed out.
+    pub(crate) fn poll(&mut self, now: Instant) -> KeepAliveAction {
+        match (self.probe_sent, self.config.deadline) {
+            (None, _) if now.duration_since(self.last_received) >= self.config.idle => {
+                self.send_probe(now)
+            }
+            (Some(sent), Some(deadline)) if now.duration_since(sent) >= deadline => {
+                KeepAliveAction::Expired
+            }
+            // The first probe usually lands in the send buffer of a half-open connection, so keep
+            // probing until a write fails
+            (Some(sent), None) if now.duration_since(sent) >= self.config.idle => {
+                self.send_probe(now)
+            }
+            _ => KeepAliveAction::Wait,
+        }
+    }
+
+    fn send_probe(&mut self, now: Instant) -> KeepAliveAction {
+        self.probe_sent = Some(now);
+        if self.config.probe == KeepAliveProbe::TimingMark {
+            self.awaiting_timing_mark = true;
+        }
+        KeepAliveAction::Probe(self.config.probe.event())
+    }
+
+    /// Whether `event` is the reply to our own probe, and should be swallowed.
+    pub(crate) fn consume_reply(&mut self, event: &TelnetEvent) -> bool {
+        match event {
+            TelnetEvent::Negotiation {
+                action: TelnetAction::Will | TelnetAction::Wont,
+                option: TelnetOption::TimingMark,
+            } if self.awaiting_timing_mark => {
+                self.awaiting_timing_mark = false;
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{errors::TellyError, TelnetStream};
+    use std::{
+        io::{self, ErrorKind, Read, Write},
+        net::{TcpListener, TcpStream},
+        thread,
+    };
+
+    const MS: Duration = Duration::from_millis(1);
+
+    #[test]
+    fn state_machine() {
+        let start = Instant::now();
+        let mut state = KeepAliveState::new(KeepAlive::new(
+            100 * MS,
+            KeepAliveProbe::TimingMark,
+            Some(50 * MS),
+        ));
+        state.on_receive(start);
+
+        assert_eq!(state.poll(start + 99 * MS), KeepAliveAction::Wait);
+        assert_eq!(
+            state.poll(start + 100 * MS),
+            KeepAliveAction::Probe(TelnetEvent::r#do(TelnetOption::TimingMark))
+        );
+        assert_eq!(state.poll(start + 120 * MS), KeepAliveAction::Wait);
+
+        // Reply resets the timers
+        state.on_receive(start + 130 * MS);
+        assert!(state.consume_reply(&TelnetEvent::wont(TelnetOption::TimingMark)));
+        assert!(!state.consume_reply(&TelnetEvent::wont(TelnetOption::TimingMark)));
+        assert_eq!(state.poll(start + 200 * MS), KeepAliveAction::Wait);
+
+        assert!(matches!(
+            state.poll(start + 230 * MS),
+            KeepAliveAction::Probe(_)
+        ));
+        assert_eq!(state.poll(start + 280 * MS), KeepAliveAction::Expired);
+    }
+
+    #[test]
+    fn without_deadline_never_expires() {
+        let start = Instant::now();
+        let mut state = KeepAliveState::new(KeepAlive::new(10 * MS, KeepAliveProbe::Nop, None));
+        state.on_receive(start);
+        assert_eq!(
+            state.poll(start + 10 * MS),
+            KeepAliveAction::Probe(TelnetEvent::Command(TelnetCommand::Nop))
+        );
+        assert_eq!(state.poll(start + 19 * MS), KeepAliveAction::Wait);
+        // Probes repeat, so a half-open connection eventually fails a write
+        assert_eq!(
+            state.poll(start + 20 * MS),
+            KeepAliveAction::Probe(TelnetEvent::Command(TelnetCommand::Nop))
+        );
+    }
+
+    fn connected_pair(keepalive: KeepAlive) -> (TelnetStream<TcpStream>, TelnetStream<TcpStream>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        server
+            .set_read_timeout(Some(keepalive.poll_interval()))
+            .unwrap();
+
+        let mut server = TelnetStream::from_stream(server);
+        server.set_keepalive(Some(keepalive));
+        (server, TelnetStream::from_stream(client))
+    }
+
+    #[test]
+    fn silent_peer_times_out() {
+        let keepalive = KeepAlive::new(50 * MS, KeepAliveProbe::TimingMark, Some(50 * MS));
+        let (mut server, mut client) = connected_pair(keepalive);
+
+        // Hand the client back so the connection stays open, but silent
+        let peer = thread::spawn(move || (client.next(), client));
+        assert!(matches!(server.next_event(), Err(TellyError::IdleTimeout)));
+        assert_eq!(
+            peer.join().unwrap().0,
+            Some(TelnetEvent::r#do(TelnetOption::TimingMark))
+        );
+    }
+
+    #[test]
+    fn responsive_peer_stays_connected() {
+        let keepalive = KeepAlive::new(50 * MS, KeepAliveProbe::TimingMark, Some(200 * MS));
+        let (mut server, mut client) = connected_pair(keepalive);
+
+        let peer = thread::spawn(move || {
+            assert_eq!(
+                client.next(),
+                Some(TelnetEvent::r#do(TelnetOption::TimingMark))
+            );
+            client.send_wont(TelnetOption::TimingMark).unwrap();
+            client.send_str("still here").unwrap();
+        });
+
+        // The probe reply is swallowed
+        assert_eq!(
+            server.next_event().unwrap(),
+            Some(TelnetEvent::Data(b"still here".to_vec()))
+        );
+        peer.join().unwrap();
+    }
+
+    // A connection whose peer has silently gone away
+    struct DeadPeer;
+
+    impl Read for DeadPeer {
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+            Err(ErrorKind::WouldBlock.into())
+        }
+    }
+
+    impl Write for DeadPeer {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+            Err(ErrorKind::BrokenPipe.into())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn buffered_probe_fails() {
+        let mut stream = TelnetStream::from_stream(DeadPeer);
+        stream.set_write_buffer(Some(64));
+        stream.set_keepalive(Some(KeepAlive::new(MS, KeepAliveProbe::Nop, None)));
+        match stream.next_event() {
+            Err(TellyError::KeepAliveFailed(err)) => assert_eq!(err.kind(), ErrorKind::BrokenPipe),
+            result => panic!("Unexpected result: {result:?}"),
+        }
+    }
+}
@@ -0,0 +1,202 @@
+//! Middleware for [TelnetStream], so that behaviors like logging or auto-negotiation can be
+//! composed instead of written into the stream.
+//!
+//! A stream has a stack of [Layer]s, added with [TelnetStream::push_layer]. Events the
+//! application sends pass down through each layer before they're written, and events received
+//! pass up through each layer before they're returned by [TelnetStream::next_event]. Along the
+//! way, a layer may let an event through, change it, swallow it, or emit more events in either
+//! direction.
+//!
+//! # Example
+//! ```
+//! use telly::{
+//!     layer::{Events, Layer},
+//!     TelnetAction, TelnetEvent, TelnetOption,
+//! };
+//!
+//! // Agree to suppress go-ahead whenever asked, without bothering the application
+//! struct AcceptSga;
+//!
+//! impl Layer for AcceptSga {
+//!     fn on_receive(&mut self, event: TelnetEvent, events: &mut Events) {
+//!         match event {
+//!             TelnetEvent::Negotiation {
+//!                 action: TelnetAction::Do,
+//!                 option: TelnetOption::SuppressGoAhead,
+//!             } => events.reverse(TelnetEvent::will(TelnetOption::SuppressGoAhead)),
+//!             event => events.forward(event),
+//!         }
+//!     }
+//! }
+//! ```
+//!
+//! Everything sent or received through the stream passes through its layers, except keepalive
+//! probes. When the stream is [split](TelnetStream::split), the layers move to the writer, and
+//! events received by the reader pass up through them there. Events a layer turns back while
+//! sending are returned by the reader.
+//!
+//! Negotiated options are tracked from the events that come out of the layers: what the
+//! application receives, and what's written to the remote. A negotiation swallowed by a layer
+//! doesn't count.
+use crate::TelnetEvent;
+#[cfg(doc)]
+use crate::TelnetStream;
+use alloc::{boxed::Box, vec::Vec};
+
+/// Where a [Layer] puts the events it lets through or emits.
+#[derive(Debug, Default)]
+pub struct Events {
+    forward: Vec<TelnetEvent>,
+    reverse: Vec<TelnetEvent>,
+}
+
+impl Events {
+    /// Pass an event on in the direction it was going: down towards the remote when sending,
+    /// or up towards the application when receiving.
+    pub fn forward(&mut self, event: TelnetEvent) {
+        self.forward.push(event);
+    }
+
+    /// Turn an event back the way it came: up to the application as if received when sending,
+    /// or down to the remote as if sent when receiving. It passes through the layers on that
+    /// side of this one.
+    pub fn reverse(&mut self, event: TelnetEvent) {
+        self.reverse.push(event);
+    }
+}
+
+/// One layer of middleware on a [TelnetStream]. See the [module documentation](self).
+///
+/// Both methods pass events through unchanged by default. Not passing an event on to `events`
+/// swallows it.
+pub trait Layer: Send {
+    /// Handle an event being sent, on its way down to the remote.
+    fn on_send(&mut self, event: TelnetEvent, events: &mut Events) {
+        events.forward(event);
+    }
+
+    /// Handle an event received, on its way up to the application.
+    fn on_receive(&mut self, event: TelnetEvent, events: &mut Events) {
+        events.forward(event);
+    }
+}
+
+// Events that came out of the layers: to be sent to the remote, and to be returned to the
+// application
+#[derive(Default)]
+pub(crate) struct Outcome {
+    pub(crate) remote: Vec<TelnetEvent>,
+    pub(crate) application: Vec<TelnetEvent>,
+}
+
+impl Outcome {
+    // Pass an event sent by the application down through all layers
+    pub(crate) fn send(layers: &mut [Box<dyn Layer>], event: TelnetEvent) -> Self {
+        let mut outcome = Self::default();
+        outcome.down(layers, 0, event);
+        outcome
+    }
+
+    // Pass an event received from the remote up through all layers
+    pub(crate) fn receive(layers: &mut [Box<dyn Layer>], event: TelnetEvent) -> Self {
+        let mut outcome = Self::default();
+        outcome.up(layers, layers.len(), event);
+        outcome
+    }
+
+    // Pass an event down through the layers from `index`, the top being 0
+    fn down(&mut self, layers: &mut [Box<dyn Layer>], index: usize, event: TelnetEvent) {
+        let Some(layer) = layers.get_mut(index) else {
+            self.remote.push(event);
+            return;
+        };
+        let mut events = Events::default();
+        layer.on_send(event, &mut events);
+        for event in events.forward {
+            self.down(layers, index + 1, event);
+        }
+        for event in events.reverse {
+            self.up(layers, index, event);
+        }
+    }
+
+    // Pass an event up through the layers above `index`
+    fn up(&mut self, layers: &mut [Box<dyn Layer>], index: usize, event: TelnetEvent) {
+        let Some(index) = index.checked_sub(1) else {
+            self.application.push(event);
+            return;
+        };
+        let mut events = Events::default();
+        layers[index].on_receive(event, &mut events);
+        for event in events.forward {
+            self.up(layers, index, event);
+        }
+        for event in events.reverse {
+            self.down(layers, index + 1, event);
+        }
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use crate::{TelnetCommand, TelnetOption, TelnetStream};
+    use std::{
+        io::{Read, Write},
+        net::{TcpListener, TcpStream},
+    };
+
+    // Marks data sent, answers AYT itself, and hides the remote's WILL ECHO
+    struct Gatekeeper;
+
+    impl Layer for Gatekeeper {
+        fn on_send(&mut self, event: TelnetEvent, events: &mut Events) {
+            match event {
+                TelnetEvent::Data(data) => {
+                    events.forward(TelnetEvent::Data([b">", &*data].concat()))
+                }
+                TelnetEvent::Command(TelnetCommand::AreYouThere) => {
+                    events.reverse(TelnetEvent::Data(b"[yes]".to_vec()))
+                }
+                event => events.forward(event),
+            }
+        }
+
+        fn on_receive(&mut self, event: TelnetEvent, events: &mut Events) {
+            if event != TelnetEvent::will(TelnetOption::Echo) {
+                events.forward(event);
+            }
+        }
+    }
+
+    #[test]
+    fn split_stream() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut remote = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let mut stream = TelnetStream::from_stream(listener.accept().unwrap().0);
+        stream.push_layer(Gatekeeper);
+        let (mut reader, writer) = stream.split().unwrap();
+
+        // Sent through the layers from the writer, and turned back up to the reader
+        writer.send_do(TelnetOption::Echo).unwrap();
+        writer.send_str("hi").unwrap();
+        writer
+            .send_event(TelnetCommand::AreYouThere.into())
+            .unwrap();
+        let mut sent = [0; 6];
+        remote.read_exact(&mut sent).unwrap();
+        assert_eq!(&sent, b"\xff\xfd\x01>hi");
+        assert_eq!(
+            reader.next_event().unwrap(),
+            Some(TelnetEvent::Data(b"[yes]".to_vec()))
+        );
+
+        // The swallowed WILL ECHO doesn't enable it
+        remote.write_all(b"\xff\xfb\x01\xff\xfb\x03").unwrap();
+        assert_eq!(
+            reader.next_event().unwrap(),
+            Some(TelnetEvent::will(TelnetOption::SuppressGoAhead))
+        );
+        assert!(!reader.options().is_remote_enabled(TelnetOption::Echo));
+    }
+}
@@ -1,16 +1,58 @@
 //! A Telnet parsing library.
+//!
+//! The protocol core ([TelnetParser], [TelnetEvent] and friends) only needs `alloc`. Everything
+//! that does I/O, like [TelnetStream], needs the `std` feature, which is enabled by default.
+#![cfg_attr(not(feature = "std"), no_std)]
 #![warn(missing_docs)]
+extern crate alloc;
+
+#[cfg(feature = "std")]
+pub mod adapter;
+pub mod authentication;
+pub mod encryption;
 pub mod errors;
+#[cfg(feature = "expect")]
+pub mod expect;
+#[cfg(feature = "std")]
+pub mod keepalive;
+#[cfg(feature = "std")]
+pub mod layer;
+pub mod negotiation;
+#[cfg(feature = "std")]
+pub mod proxy;
+#[cfg(all(feature = "pty", unix))]
+pub mod pty;
+#[cfg(feature = "std")]
+pub mod recording;
+#[cfg(feature = "std")]
+pub mod server;
+#[cfg(feature = "std")]
+pub mod session;
+#[cfg(feature = "std")]
+pub mod split;
+#[cfg(feature = "srp")]
+pub mod srp;
+#[cfg(feature = "tls")]
+pub mod tls;
 pub mod utils;
+#[cfg(feature = "websocket")]
+pub mod websocket;
 
+mod borrowed;
 mod commands;
 mod constants;
+mod notation;
+#[cfg(feature = "serde")]
+mod serialization;
+#[cfg(feature = "std")]
 mod stream;
 mod telnet;
 
+pub use borrowed::{BorrowedEvents, TelnetEventRef};
 pub use commands::TelnetCommand;
+#[cfg(feature = "std")]
 pub use stream::TelnetStream;
 pub use telnet::{
-    TelnetAction, TelnetEvent, TelnetOption, TelnetParser, TelnetSubnegotiation,
-    UnparsedTelnetSubnegotiation,
+    EnvironmentVariable, TelnetAction, TelnetEvent, TelnetOption, TelnetParser,
+    TelnetSubnegotiation, UnparsedTelnetSubnegotiation,
 };
@@ -0,0 +1,146 @@
+//! Tracking of negotiated Telnet options.
+use crate::{TelnetAction, TelnetEvent, TelnetOption};
+use alloc::{collections::BTreeMap, vec::Vec};
+
+// One side of an option: an option is enabled once it's been both requested and agreed to.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct Side {
+    // We sent WILL (for local options) or DO (for remote options)
+    requested: bool,
+    // They sent DO (for local options) or WILL (for remote options)
+    acknowledged: bool,
+}
+
+impl Side {
+    const fn enabled(&self) -> bool {
+        self.requested && self.acknowledged
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct OptionState {
+    local: Side,
+    remote: Side,
+}
+
+/// Records which options are enabled on each side of a connection, by observing the negotiations
+/// sent and received.
+///
+/// A local option (one *we* perform) is enabled once we've sent WILL and the remote has sent DO,
+/// in either order. A remote option is enabled once we've sent DO and the remote has sent WILL.
+/// WONT or DONT from either side disables the option.
+///
+/// # Example
+/// ```
+/// use telly::{negotiation::OptionStates, TelnetEvent, TelnetOption};
+///
+/// let mut states = OptionStates::default();
+/// states.on_send(&TelnetEvent::will(TelnetOption::Echo));
+/// assert!(!states.is_local_enabled(TelnetOption::Echo));
+/// states.on_receive(&TelnetEvent::r#do(TelnetOption::Echo));
+/// assert!(states.is_local_enabled(TelnetOption::Echo));
+/// ```
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct OptionStates {
+    // Keyed by option number, so that iteration is in numerical order
+    options: BTreeMap<u8, OptionState>,
+}
+
+impl OptionStates {
+    /// Record an event sent to the remote.
+    pub fn on_send(&mut self, event: &TelnetEvent) {
+        if let TelnetEvent::Negotiation { action, option } = *event {
+            let state = self.options.entry(option.into()).or_default();
+            match action {
+                TelnetAction::Will => state.local.requested = true,
+                TelnetAction::Wont => state.local = Side::default(),
+                TelnetAction::Do => state.remote.requested = true,
+                TelnetAction::Dont => state.remote = Side::default(),
+            }
+        }
+    }
+
+    /// Record an event received from the remote.
+    pub fn on_receive(&mut self, event: &TelnetEvent) {
+        if let TelnetEvent::Negotiation { action, option } = *event {
+            let state = self.options.entry(option.into()).or_default();
+            match action {
+                TelnetAction::Do => state.local.acknowledged = true,
+                TelnetAction::Dont => state.local = Side::default(),
+                TelnetAction::Will => state.remote.acknowledged = true,
+                TelnetAction::Wont => state.remote = Side::default(),
+            }
+        }
+    }
+
+    /// Whether we are performing `option`.
+    pub fn is_local_enabled(&self, option: TelnetOption) -> bool {
+        self.options
+            .get(&u8::from(option))
+            .is_some_and(|state| state.local.enabled())
+    }
+
+    /// Whether the remote is performing `option`.
+    pub fn is_remote_enabled(&self, option: TelnetOption) -> bool {
+        self.options
+            .get(&u8::from(option))
+            .is_some_and(|state| state.remote.enabled())
+    }
+
+    /// All options we are performing, in numerical order.
+    pub fn local_options(&self) -> Vec<TelnetOption> {
+        self.collect(|state| state.local.enabled())
+    }
+
+    /// All options the remote is performing, in numerical order.
+    pub fn remote_options(&self) -> Vec<TelnetOption> {
+        self.collect(|state| state.remote.enabled())
+    }
+
+    fn collect(&self, filter: impl Fn(&OptionState) -> bool) -> Vec<TelnetOption> {
+        self.options
+            .iter()
+            .filter(|(_, state)| filter(state))
+            .map(|(&option, _)| option.into())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    #[test]
+    fn negotiate() {
+        let mut states = OptionStates::default();
+
+        // Remote asks first, we agree
+        states.on_receive(&TelnetEvent::r#do(TelnetOption::SuppressGoAhead));
+        assert!(!states.is_local_enabled(TelnetOption::SuppressGoAhead));
+        states.on_send(&TelnetEvent::will(TelnetOption::SuppressGoAhead));
+        assert!(states.is_local_enabled(TelnetOption::SuppressGoAhead));
+        assert!(!states.is_remote_enabled(TelnetOption::SuppressGoAhead));
+
+        // We ask, remote refuses
+        states.on_send(&TelnetEvent::r#do(TelnetOption::TerminalType));
+        states.on_receive(&TelnetEvent::wont(TelnetOption::TerminalType));
+        assert!(!states.is_remote_enabled(TelnetOption::TerminalType));
+
+        // We ask, remote agrees, then later disables
+        states.on_send(&TelnetEvent::r#do(TelnetOption::NegotiateAboutWindowSize));
+        states.on_receive(&TelnetEvent::will(TelnetOption::NegotiateAboutWindowSize));
+        states.on_send(&TelnetEvent::r#do(TelnetOption::Echo));
+        states.on_receive(&TelnetEvent::will(TelnetOption::Echo));
+        assert_eq!(
+            states.remote_options(),
+            vec![TelnetOption::Echo, TelnetOption::NegotiateAboutWindowSize]
+        );
+        states.on_receive(&TelnetEvent::wont(TelnetOption::Echo));
+        assert_eq!(
+            states.remote_options(),
+            vec![TelnetOption::NegotiateAboutWindowSize]
+        );
+        assert_eq!(states.local_options(), vec![TelnetOption::SuppressGoAhead]);
+    }
+}
@@ -0,0 +1,485 @@
+//! Formatting and parsing Telnet events in the notation the RFCs use, like
+//! `IAC SB TTYPE IS "xterm" IAC SE`.
+use crate::{
+    constants,
+    errors::{TellyError, TellyResult},
+    TelnetAction, TelnetCommand, TelnetEvent, TelnetOption, TelnetSubnegotiation,
+    UnparsedTelnetSubnegotiation,
+};
+use alloc::{format, string::String, vec, vec::Vec};
+use core::{fmt, iter::Peekable, str::FromStr};
+
+impl fmt::Display for TelnetEvent {
+    /// Format in RFC notation. Data is formatted as a quoted string, with non-printable bytes
+    /// escaped.
+    ///
+    /// # Example
+    /// ```
+    /// use telly::{TelnetEvent, TelnetOption, TelnetSubnegotiation};
+    ///
+    /// let event = TelnetEvent::r#do(TelnetOption::NegotiateAboutWindowSize);
+    /// assert_eq!(event.to_string(), "IAC DO NAWS");
+    ///
+    /// let event: TelnetEvent = TelnetSubnegotiation::TerminalTypeResponse("xterm".into()).into();
+    /// assert_eq!(event.to_string(), r#"IAC SB TTYPE IS "xterm" IAC SE"#);
+    ///
+    /// let event = TelnetEvent::Data(b"login: \r\n".to_vec());
+    /// assert_eq!(event.to_string(), r#""login: \r\n""#);
+    /// ```
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Command(command) => write!(f, "IAC {command}"),
+            Self::Negotiation { action, option } => write!(f, "IAC {action} {option}"),
+            Self::Subnegotiation(subnegotiation) => subnegotiation.fmt(f),
+            Self::Data(data) => write!(f, "\"{}\"", data.escape_ascii()),
+        }
+    }
+}
+
+impl fmt::Display for UnparsedTelnetSubnegotiation {
+    /// Format in RFC notation. Keywords like IS and VAR are used where the option defines them,
+    /// and other bytes are formatted as decimal numbers or quoted strings.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let keyword = |byte, keywords: &[&'static str]| keywords.get(usize::from(byte)).copied();
+        write!(f, "IAC SB {}", self.option)?;
+
+        match (self.option, self.bytes.split_first()) {
+            (TelnetOption::TerminalType, Some((&constants::SEND, []))) => f.write_str(" SEND")?,
+            (TelnetOption::TerminalType, Some((&constants::IS, name))) => {
+                write!(f, " IS \"{}\"", name.escape_ascii())?
+            }
+            (TelnetOption::Authentication, Some((&constants::NAME, name))) => {
+                write!(f, " NAME \"{}\"", name.escape_ascii())?
+            }
+            (TelnetOption::Authentication, Some((&command, rest)))
+                if command <= constants::REPLY =>
+            {
+                write!(f, " {}", AUTHENTICATION_COMMANDS[usize::from(command)])?;
+                for byte in rest {
+                    write!(f, " {byte}")?;
+                }
+            }
+            (TelnetOption::Encrypt, Some((&command, rest))) if command <= constants::DEC_KEYID => {
+                write!(f, " {}", ENCRYPTION_COMMANDS[usize::from(command)])?;
+                for byte in rest {
+                    write!(f, " {byte}")?;
+                }
+            }
+            (TelnetOption::StartTls, Some((&constants::FOLLOWS, []))) => f.write_str(" FOLLOWS")?,
+            (TelnetOption::NewEnvironment, Some((&command, variables)))
+                if command <= constants::INFO =>
+            {
+                write!(f, " {}", COMMANDS[usize::from(command)])?;
+
+                // Runs of bytes between keywords are strings
+                let mut string = Vec::new();
+                let mut escaped = false;
+                for &byte in variables {
+                    match keyword(byte, &VARIABLE_TYPES) {
+                        Some(keyword) if !escaped => {
+                            if !string.is_empty() {
+                                write!(f, " \"{}\"", string.escape_ascii())?;
+                                string.clear();
+                            }
+                            write!(f, " {keyword}")?;
+                            escaped = byte == constants::ESC;
+                        }
+                        _ => {
+                            string.push(byte);
+                            escaped = false;
+                        }
+                    }
+                }
+                if !string.is_empty() {
+                    write!(f, " \"{}\"", string.escape_ascii())?;
+                }
+            }
+            _ => {
+                for byte in &self.bytes {
+                    write!(f, " {byte}")?;
+                }
+            }
+        }
+
+        f.write_str(" IAC SE")
+    }
+}
+
+impl fmt::Display for TelnetSubnegotiation {
+    /// Format in RFC notation. See [UnparsedTelnetSubnegotiation]'s implementation.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        UnparsedTelnetSubnegotiation::from(self.clone()).fmt(f)
+    }
+}
+
+// Keywords of subnegotiations, by value
+const COMMANDS: [&str; 3] = ["IS", "SEND", "INFO"];
+const VARIABLE_TYPES: [&str; 4] = ["VAR", "VALUE", "ESC", "USERVAR"];
+const AUTHENTICATION_COMMANDS: [&str; 4] = ["IS", "SEND", "REPLY", "NAME"];
+const ENCRYPTION_COMMANDS: [&str; 9] = [
+    "IS",
+    "SUPPORT",
+    "REPLY",
+    "START",
+    "END",
+    "REQUEST-START",
+    "REQUEST-END",
+    "ENC_KEYID",
+    "DEC_KEYID",
+];
+
+#[derive(Debug, PartialEq)]
+enum Token {
+    Word(String),
+    Quoted(Vec<u8>),
+}
+
+impl Token {
+    fn is_word(&self, word: &str) -> bool {
+        matches!(self, Self::Word(w) if w.eq_ignore_ascii_case(word))
+    }
+}
+
+fn tokenize(text: &str) -> TellyResult<Vec<Token>> {
+    let error = |message: &str| TellyError::DecodeError(format!("{message} in '{text}'"));
+    let mut tokens = Vec::new();
+    let mut chars = text.chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == '"' {
+            chars.next();
+            let mut bytes = Vec::new();
+            loop {
+                match chars.next().ok_or_else(|| error("Unterminated string"))? {
+                    '"' => break,
+                    '\\' => {
+                        let escaped = match chars.next() {
+                            Some('n') => b'\n',
+                            Some('r') => b'\r',
+                            Some('t') => b'\t',
+                            Some('0') => 0,
+                            Some(c @ ('\\' | '"' | '\'')) => c as u8,
+                            Some('x') => {
+                                let hex: String = chars.by_ref().take(2).collect();
+                                u8::from_str_radix(&hex, 16).map_err(|_| error("Bad \\x escape"))?
+                            }
+                            _ => return Err(error("Bad escape")),
+                        };
+                        bytes.push(escaped);
+                    }
+                    c => bytes.extend(c.encode_utf8(&mut [0; 4]).as_bytes()),
+                }
+            }
+            tokens.push(Token::Quoted(bytes));
+        } else {
+            let mut word = String::new();
+            while let Some(&c) = chars.peek() {
+                if c.is_whitespace() || c == '"' {
+                    break;
+                }
+                word.push(c);
+                chars.next();
+            }
+            tokens.push(Token::Word(word));
+        }
+    }
+    Ok(tokens)
+}
+
+fn parse_event(tokens: &mut Peekable<impl Iterator<Item = Token>>) -> TellyResult<TelnetEvent> {
+    let error = |message: String| TellyError::DecodeError(message);
+    let mut word = || match tokens.next() {
+        Some(Token::Word(word)) => Ok(word),
+        Some(Token::Quoted(bytes)) => Err(error(format!(
+            "Expected a word, but found \"{}\"",
+            bytes.escape_ascii()
+        ))),
+        None => Err(error("Unexpected end of input".into())),
+    };
+
+    let first = word()?;
+    if !first.eq_ignore_ascii_case("IAC") {
+        return Err(error(format!(
+            "Expected IAC or a string, but found {first}"
+        )));
+    }
+
+    let command = word()?;
+    let byte = if command.eq_ignore_ascii_case("SB") {
+        constants::SB
+    } else if command.eq_ignore_ascii_case("IAC") {
+        constants::IAC
+    } else if let Ok(action) = TelnetAction::from_str(&command) {
+        action.into()
+    } else {
+        TelnetCommand::from_str(&command)?.into()
+    };
+
+    if let Ok(action) = TelnetAction::try_from(byte) {
+        let option = TelnetOption::from_str(&word()?)?;
+        return Ok(TelnetEvent::Negotiation { action, option });
+    }
+    match byte {
+        constants::IAC => Ok(TelnetEvent::Data(vec![constants::IAC])),
+        constants::SE => Err(error("SE outside of a subnegotiation".into())),
+        constants::SB => {
+            let option = TelnetOption::from_str(&word()?)?;
+            let mut bytes = Vec::new();
+            loop {
+                let token = tokens
+                    .next()
+                    .ok_or_else(|| error("Unterminated subnegotiation".into()))?;
+                if token.is_word("IAC") && tokens.peek().is_some_and(|next| next.is_word("SE")) {
+                    tokens.next();
+                    break;
+                }
+                match token {
+                    Token::Quoted(quoted) => bytes.extend(quoted),
+                    Token::Word(word) => {
+                        let keyword = |keywords: &[&str]| {
+                            keywords.iter().position(|k| k.eq_ignore_ascii_case(&word))
+                        };
+                        let byte = keyword(&COMMANDS)
+                            .or_else(|| keyword(&VARIABLE_TYPES))
+                            .or_else(|| keyword(&AUTHENTICATION_COMMANDS))
+                            .or_else(|| keyword(&ENCRYPTION_COMMANDS))
+                            .map(|value| value as u8)
+                            .or_else(|| {
+                                word.eq_ignore_ascii_case("FOLLOWS")
+                                    .then_some(constants::FOLLOWS)
+                            })
+                            .or_else(|| word.parse().ok())
+                            .ok_or_else(|| {
+                                error(format!("Unexpected '{word}' in subnegotiation"))
+                            })?;
+                        bytes.push(byte);
+                    }
+                }
+            }
+            Ok(TelnetEvent::Subnegotiation(UnparsedTelnetSubnegotiation {
+                option,
+                bytes,
+            }))
+        }
+        byte => Ok(TelnetEvent::Command(TelnetCommand::from(byte))),
+    }
+}
+
+impl TelnetEvent {
+    /// Parse a sequence of events from RFC notation, as produced by [TelnetEvent]'s `Display`
+    /// implementation. Names are case-insensitive, and options, commands and subnegotiation bytes
+    /// may also be given as decimal numbers. `IAC IAC` is parsed as the data byte 255.
+    ///
+    /// # Example
+    /// ```
+    /// use telly::{TelnetEvent, TelnetOption, TelnetSubnegotiation};
+    ///
+    /// let events = TelnetEvent::parse_sequence(
+    ///     r#"IAC WILL NAWS IAC SB NAWS 0 80 0 24 IAC SE "hello\r\n""#,
+    /// )
+    /// .unwrap();
+    /// assert_eq!(
+    ///     events,
+    ///     vec![
+    ///         TelnetEvent::will(TelnetOption::NegotiateAboutWindowSize),
+    ///         TelnetSubnegotiation::NegotiateAboutWindowSize {
+    ///             width: 80,
+    ///             height: 24
+    ///         }
+    ///         .into(),
+    ///         TelnetEvent::Data(b"hello\r\n".to_vec()),
+    ///     ]
+    /// );
+    /// ```
+    pub fn parse_sequence(text: &str) -> TellyResult<Vec<Self>> {
+        let mut tokens = tokenize(text)?.into_iter().peekable();
+        let mut events = Vec::new();
+        while let Some(token) = tokens.peek() {
+            if let Token::Quoted(_) = token {
+                let Some(Token::Quoted(data)) = tokens.next() else {
+                    unreachable!()
+                };
+                events.push(Self::Data(data));
+            } else {
+                events.push(parse_event(&mut tokens)?);
+            }
+        }
+        Ok(events)
+    }
+}
+
+impl FromStr for TelnetEvent {
+    type Err = TellyError;
+
+    /// Parse a single event from RFC notation. See [TelnetEvent::parse_sequence].
+    fn from_str(s: &str) -> TellyResult<Self> {
+        let mut events = Self::parse_sequence(s)?;
+        if events.len() != 1 {
+            return Err(TellyError::DecodeError(format!(
+                "Expected one event, but found {} in '{s}'",
+                events.len()
+            )));
+        }
+        Ok(events.remove(0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        authentication::{AuthenticationModifiers, AuthenticationType, AuthenticationTypePair},
+        encryption::EncryptionType,
+        EnvironmentVariable,
+    };
+    use alloc::string::ToString;
+
+    #[test]
+    fn round_trip() {
+        let vectors: Vec<(&str, TelnetEvent)> = vec![
+            (
+                "IAC DO NAWS",
+                TelnetEvent::r#do(TelnetOption::NegotiateAboutWindowSize),
+            ),
+            (
+                "IAC WONT BINARY",
+                TelnetEvent::wont(TelnetOption::BinaryTransmission),
+            ),
+            ("IAC AYT", TelnetCommand::AreYouThere.into()),
+            ("IAC 200", TelnetCommand::Other(200).into()),
+            (
+                r#"IAC SB TTYPE IS "xterm" IAC SE"#,
+                TelnetSubnegotiation::TerminalTypeResponse("xterm".into()).into(),
+            ),
+            (
+                "IAC SB TTYPE SEND IAC SE",
+                TelnetSubnegotiation::TerminalTypeRequest.into(),
+            ),
+            (
+                "IAC SB NAWS 1 0 0 24 IAC SE",
+                TelnetSubnegotiation::NegotiateAboutWindowSize {
+                    width: 256,
+                    height: 24,
+                }
+                .into(),
+            ),
+            (
+                r#"IAC SB NEW-ENVIRON IS VAR "USER" VALUE "m" ESC "\x01x" USERVAR "X" IAC SE"#,
+                TelnetSubnegotiation::NewEnvironmentResponse(vec![
+                    EnvironmentVariable::var("USER", Some("m\x01x")),
+                    EnvironmentVariable::uservar("X", None),
+                ])
+                .into(),
+            ),
+            (
+                "IAC SB NEW-ENVIRON SEND IAC SE",
+                TelnetSubnegotiation::NewEnvironmentRequest(vec![]).into(),
+            ),
+            (
+                "IAC SB START_TLS FOLLOWS IAC SE",
+                TelnetSubnegotiation::StartTlsFollows.into(),
+            ),
+            (
+                "IAC SB AUTHENTICATION SEND 5 0 2 2 IAC SE",
+                TelnetSubnegotiation::AuthenticationSend(vec![
+                    AuthenticationTypePair::new(AuthenticationType::Srp, Default::default()),
+                    AuthenticationTypePair::new(
+                        AuthenticationType::KerberosV5,
+                        AuthenticationModifiers::MUTUAL,
+                    ),
+                ])
+                .into(),
+            ),
+            (
+                "IAC SB AUTHENTICATION REPLY 5 0 9 255 IAC SE",
+                TelnetSubnegotiation::AuthenticationReply {
+                    pair: AuthenticationTypePair::new(AuthenticationType::Srp, Default::default()),
+                    data: vec![9, 255],
+                }
+                .into(),
+            ),
+            (
+                r#"IAC SB AUTHENTICATION NAME "margaret" IAC SE"#,
+                TelnetSubnegotiation::AuthenticationName("margaret".into()).into(),
+            ),
+            (
+                "IAC SB ENCRYPT SUPPORT 1 2 IAC SE",
+                TelnetSubnegotiation::EncryptionSupport(vec![
+                    EncryptionType::DesCfb64,
+                    EncryptionType::DesOfb64,
+                ])
+                .into(),
+            ),
+            (
+                "IAC SB ENCRYPT IS 1 7 IAC SE",
+                TelnetSubnegotiation::EncryptionIs {
+                    kind: EncryptionType::DesCfb64,
+                    data: vec![7],
+                }
+                .into(),
+            ),
+            (
+                "IAC SB ENCRYPT START 0 IAC SE",
+                TelnetSubnegotiation::EncryptionStart(vec![0]).into(),
+            ),
+            (
+                "IAC SB ENCRYPT REQUEST-END IAC SE",
+                TelnetSubnegotiation::EncryptionRequestEnd.into(),
+            ),
+            (
+                "IAC SB LINEMODE 1 255 IAC SE",
+                TelnetEvent::Subnegotiation(UnparsedTelnetSubnegotiation {
+                    option: TelnetOption::LineMode,
+                    bytes: vec![1, 255],
+                }),
+            ),
+            (
+                r#""\"quoted\" \\ \x00\xff\r\n""#,
+                TelnetEvent::Data(b"\"quoted\" \\ \0\xff\r\n".to_vec()),
+            ),
+        ];
+
+        for (text, event) in vectors {
+            assert_eq!(event.to_string(), text);
+            assert_eq!(text.parse::<TelnetEvent>().unwrap(), event, "{text}");
+        }
+    }
+
+    #[test]
+    fn parse_variants() {
+        let events = TelnetEvent::parse_sequence(
+            "iac will 24 IAC IAC IAC 251 1 IAC SB 24 0 \"vt\" \"100\" IAC SE \"ünï\"",
+        )
+        .unwrap();
+        assert_eq!(
+            events,
+            vec![
+                TelnetEvent::will(TelnetOption::TerminalType),
+                TelnetEvent::Data(vec![0xff]),
+                TelnetEvent::will(TelnetOption::Echo),
+                TelnetSubnegotiation::TerminalTypeResponse("vt100".into()).into(),
+                TelnetEvent::Data("ünï".as_bytes().to_vec()),
+            ]
+        );
+        assert_eq!(TelnetEvent::parse_sequence("").unwrap(), vec![]);
+
+        for bad in [
+            "DO NAWS",
+            "IAC",
+            "IAC DO",
+            "IAC DO NOPE",
+            "IAC FOO",
+            "IAC SE",
+            "IAC SB NAWS 0 80",
+            "IAC SB NAWS 256 IAC SE",
+            "IAC SB NAWS \"x\" FOO IAC SE",
+            "\"unterminated",
+            "\"\\q\"",
+            "IAC NOP IAC NOP",
+        ] {
+            assert!(bad.parse::<TelnetEvent>().is_err(), "{bad}");
+        }
+    }
+}
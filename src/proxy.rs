@@ -0,0 +1,507 @@
+//! A Telnet proxy that enforces a policy on the options negotiated through it.
+//!
+//! Every event passing through is shown to a list of [ProxyRule]s, which may let it pass, drop
+//! it, replace it with other events, or reply to its sender. Events that every rule lets pass are
+//! forwarded as the exact bytes they arrived as, so the proxy is transparent to whatever it
+//! doesn't touch.
+//!
+//! # Example
+//! ```no_run
+//! use std::net::TcpStream;
+//! use telly::{
+//!     proxy::{ForceTerminalType, StripOption, TelnetProxy, Verdict},
+//!     server::TelnetServer,
+//!     TelnetEvent, TelnetOption, TelnetStream,
+//! };
+//!
+//! let mut proxy = TelnetProxy::new();
+//! proxy.add_rule(StripOption(TelnetOption::Encrypt));
+//! proxy.add_rule(ForceTerminalType("VT100".into()));
+//! proxy.add_rule(|direction, event: &TelnetEvent, _: &mut Vec<TelnetEvent>| {
+//!     if let TelnetEvent::Data(data) = event {
+//!         println!("{direction:?}: {}", String::from_utf8_lossy(data));
+//!     }
+//!     Verdict::Pass
+//! });
+//!
+//! let server = TelnetServer::bind("127.0.0.1:2323").unwrap();
+//! server
+//!     .serve(move |client, _| {
+//!         let host = TcpStream::connect("legacy-host:23").unwrap();
+//!         let _ = proxy.run(client, TelnetStream::from_stream(host));
+//!     })
+//!     .unwrap();
+//! ```
+use crate::{
+    errors::{TellyError, TellyResult},
+    negotiation::OptionStates,
+    TelnetAction, TelnetEvent, TelnetOption, TelnetParser, TelnetStream, TelnetSubnegotiation,
+};
+use bytes::{BufMut, BytesMut};
+use std::{
+    any::Any,
+    io::{ErrorKind, Read, Write},
+    net::{Shutdown, TcpStream},
+    panic::{self, AssertUnwindSafe},
+    sync::{Arc, Mutex, MutexGuard},
+    thread,
+};
+
+/// The way an event is travelling through a [TelnetProxy].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    /// From the client to the server.
+    ClientToServer,
+    /// From the server to the client.
+    ServerToClient,
+}
+
+impl Direction {
+    /// The opposite direction.
+    pub const fn reverse(self) -> Self {
+        match self {
+            Self::ClientToServer => Self::ServerToClient,
+            Self::ServerToClient => Self::ClientToServer,
+        }
+    }
+
+    // Index of the side events travelling this way come from
+    const fn source(self) -> usize {
+        self as usize
+    }
+}
+
+/// What a [ProxyRule] decides to do with an event.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Verdict {
+    /// Forward the event, as the bytes it arrived as if no other rule changes it.
+    Pass,
+    /// Forward nothing.
+    Drop,
+    /// Forward these events instead. Later rules see each of them in turn.
+    Replace(Vec<TelnetEvent>),
+}
+
+/// A policy applied by a [TelnetProxy] to every event passing through it.
+///
+/// This is implemented for all `FnMut(Direction, &TelnetEvent, &mut Vec<TelnetEvent>) ->
+/// Verdict` closures, so a plain closure can be used as a rule.
+pub trait ProxyRule: Send + 'static {
+    /// Decide what to do with `event`. Events pushed to `replies` are sent back to where
+    /// `event` came from, e.g. to refuse a negotiation that isn't forwarded.
+    fn on_event(
+        &mut self,
+        direction: Direction,
+        event: &TelnetEvent,
+        replies: &mut Vec<TelnetEvent>,
+    ) -> Verdict;
+}
+
+impl<F> ProxyRule for F
+where
+    F: FnMut(Direction, &TelnetEvent, &mut Vec<TelnetEvent>) -> Verdict + Send + 'static,
+{
+    fn on_event(
+        &mut self,
+        direction: Direction,
+        event: &TelnetEvent,
+        replies: &mut Vec<TelnetEvent>,
+    ) -> Verdict {
+        self(direction, event, replies)
+    }
+}
+
+/// Keep an option from being negotiated through the proxy, in either direction. Offers and
+/// requests are refused, and its subnegotiations dropped.
+#[derive(Clone, Copy, Debug)]
+pub struct StripOption(pub TelnetOption);
+
+impl ProxyRule for StripOption {
+    fn on_event(
+        &mut self,
+        _direction: Direction,
+        event: &TelnetEvent,
+        replies: &mut Vec<TelnetEvent>,
+    ) -> Verdict {
+        match event {
+            TelnetEvent::Negotiation { action, option } if *option == self.0 => {
+                match action {
+                    TelnetAction::Will => replies.push(TelnetEvent::dont(*option)),
+                    TelnetAction::Do => replies.push(TelnetEvent::wont(*option)),
+                    TelnetAction::Wont | TelnetAction::Dont => {}
+                }
+                Verdict::Drop
+            }
+            TelnetEvent::Subnegotiation(subnegotiation) if subnegotiation.option == self.0 => {
+                Verdict::Drop
+            }
+            _ => Verdict::Pass,
+        }
+    }
+}
+
+/// Report this terminal type to the server, whatever the client says.
+#[derive(Clone, Debug)]
+pub struct ForceTerminalType(pub String);
+
+impl ProxyRule for ForceTerminalType {
+    fn on_event(
+        &mut self,
+        direction: Direction,
+        event: &TelnetEvent,
+        _replies: &mut Vec<TelnetEvent>,
+    ) -> Verdict {
+        match event {
+            TelnetEvent::Subnegotiation(subnegotiation)
+                if direction == Direction::ClientToServer
+                    && matches!(
+                        subnegotiation.clone().try_into(),
+                        Ok(TelnetSubnegotiation::TerminalTypeResponse(_))
+                    ) =>
+            {
+                Verdict::Replace(vec![TelnetSubnegotiation::TerminalTypeResponse(
+                    self.0.clone(),
+                )
+                .into()])
+            }
+            _ => Verdict::Pass,
+        }
+    }
+}
+
+// An event to forward: the original, or one a rule put in its place
+enum Forward {
+    Original,
+    Event(TelnetEvent),
+}
+
+// Builds a rule for each connection
+type RuleFactory = Arc<dyn Fn() -> Box<dyn ProxyRule> + Send + Sync>;
+
+/// Forwards events between a client and a server, applying [ProxyRule]s. See the
+/// [module documentation](self).
+///
+/// Each connection the proxy runs gets its own rules, so state kept by a rule is per
+/// connection.
+#[derive(Clone, Default)]
+pub struct TelnetProxy {
+    rules: Vec<RuleFactory>,
+}
+
+impl TelnetProxy {
+    /// Construct a proxy with no rules, which forwards everything unchanged.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a rule, applied after those already added. Each connection gets a clone of it.
+    pub fn add_rule(&mut self, rule: impl ProxyRule + Clone + Sync) {
+        self.add_rule_with(move || rule.clone());
+    }
+
+    /// Add a rule built by `factory` for each connection, applied after those already added.
+    pub fn add_rule_with<R: ProxyRule>(&mut self, factory: impl Fn() -> R + Send + Sync + 'static) {
+        self.rules.push(Arc::new(move || Box::new(factory())));
+    }
+
+    /// Forward events between `client` and `server` until either closes, then close both.
+    ///
+    /// Events and bytes already received by either stream are forwarded first. Bytes buffered
+    /// to be sent are discarded, so flush the streams before handing them over.
+    ///
+    /// Fails with [TellyError::ProxyPanicked] if a rule panics, closing both streams.
+    pub fn run(
+        &self,
+        client: TelnetStream<TcpStream>,
+        server: TelnetStream<TcpStream>,
+    ) -> TellyResult {
+        let (client, client_leftover) = unread(client);
+        let (server, server_leftover) = unread(server);
+        let connection = Arc::new(Connection {
+            rules: Mutex::new(self.rules.iter().map(|factory| factory()).collect()),
+            sides: [client.try_clone()?, server.try_clone()?],
+            writers: [
+                Mutex::new(client.try_clone()?),
+                Mutex::new(server.try_clone()?),
+            ],
+            options: Mutex::default(),
+        });
+
+        let upstream = {
+            let connection = connection.clone();
+            thread::spawn(move || {
+                connection.forward(Direction::ServerToClient, server, &server_leftover)
+            })
+        };
+        let result = connection.forward(Direction::ClientToServer, client, &client_leftover);
+        let upstream_result = upstream
+            .join()
+            .unwrap_or_else(|panic| Err(TellyError::ProxyPanicked(panic_message(&*panic))));
+        result.and(upstream_result)
+    }
+}
+
+// The state of a connection through the proxy, shared by both directions
+struct Connection {
+    rules: Mutex<Vec<Box<dyn ProxyRule>>>,
+    // Client and server, for shutting down
+    sides: [TcpStream; 2],
+    writers: [Mutex<TcpStream>; 2],
+    // What's been negotiated with each side, from the proxy's point of view
+    options: Mutex<[OptionStates; 2]>,
+}
+
+impl Connection {
+    // Forward events from `source` until it closes or fails, then close both sides, so the
+    // other direction stops too
+    fn forward(&self, direction: Direction, source: TcpStream, leftover: &[u8]) -> TellyResult {
+        let result = self.forward_until_closed(direction, source, leftover);
+        for side in &self.sides {
+            let _ = side.shutdown(Shutdown::Both);
+        }
+        result
+    }
+
+    fn forward_until_closed(
+        &self,
+        direction: Direction,
+        mut source: TcpStream,
+        leftover: &[u8],
+    ) -> TellyResult {
+        const BUFFER_SIZE: usize = 1024;
+        let mut buffer = [0; BUFFER_SIZE];
+        let mut rx_buffer = BytesMut::from(leftover);
+        let mut parser = TelnetParser::default();
+
+        loop {
+            // The raw bytes of each event are found by what the parser consumed of these
+            let raw = rx_buffer.to_vec();
+            let mut offset = 0;
+            while let Some(event) = {
+                parser.set_binary(
+                    lock(&self.options)[direction.source()]
+                        .is_remote_enabled(TelnetOption::BinaryTransmission),
+                );
+                parser.next_event(&mut rx_buffer)
+            } {
+                let length = raw.len() - offset - rx_buffer.len();
+                self.process(direction, event, &raw[offset..offset + length])?;
+                offset += length;
+            }
+
+            let bytes_read = match source.read(&mut buffer) {
+                Ok(0) => return Ok(()),
+                Ok(bytes_read) => bytes_read,
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                // Shut down because the other direction ended
+                Err(err) if err.kind() == ErrorKind::NotConnected => return Ok(()),
+                Err(err) => return Err(err.into()),
+            };
+            rx_buffer.put(&buffer[..bytes_read]);
+        }
+    }
+
+    // Apply the rules to an event, and forward the result
+    fn process(&self, direction: Direction, event: TelnetEvent, raw: &[u8]) -> TellyResult {
+        let mut forwards = vec![Forward::Original];
+        let mut replies = Vec::new();
+        for rule in lock(&self.rules).iter_mut() {
+            let mut next = Vec::new();
+            for forward in forwards {
+                let verdict = {
+                    let event = match &forward {
+                        Forward::Original => &event,
+                        Forward::Event(event) => event,
+                    };
+                    // Caught here, so the lock isn't poisoned and the connection is closed
+                    // cleanly
+                    panic::catch_unwind(AssertUnwindSafe(|| {
+                        rule.on_event(direction, event, &mut replies)
+                    }))
+                    .map_err(|panic| TellyError::ProxyPanicked(panic_message(&*panic)))?
+                };
+                match verdict {
+                    Verdict::Pass => next.push(forward),
+                    Verdict::Drop => {}
+                    Verdict::Replace(events) => next.extend(events.into_iter().map(Forward::Event)),
+                }
+            }
+            forwards = next;
+        }
+
+        let source = direction.source();
+        let destination = direction.reverse().source();
+        let mut options = lock(&self.options);
+        options[source].on_receive(&event);
+        let mut forwarded = Vec::new();
+        for forward in forwards {
+            match forward {
+                Forward::Original => {
+                    options[destination].on_send(&event);
+                    forwarded.extend_from_slice(raw);
+                }
+                Forward::Event(event) => {
+                    forwarded.extend(encode(&mut options[destination], event));
+                }
+            }
+        }
+        let replies: Vec<u8> = replies
+            .into_iter()
+            .flat_map(|reply| encode(&mut options[source], reply))
+            .collect();
+        drop(options);
+
+        for (side, bytes) in [(destination, forwarded), (source, replies)] {
+            if !bytes.is_empty() {
+                let mut writer = lock(&self.writers[side]);
+                writer.write_all(&bytes)?;
+                writer.flush()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// The message a panic was raised with
+fn panic_message(panic: &(dyn Any + Send)) -> String {
+    match (panic.downcast_ref::<&str>(), panic.downcast_ref::<String>()) {
+        (Some(message), _) => (*message).into(),
+        (_, Some(message)) => message.clone(),
+        _ => "unknown panic".into(),
+    }
+}
+
+// Unwrap a stream, with the events and bytes it received but didn't return encoded again, to be
+// forwarded first
+fn unread(stream: TelnetStream<TcpStream>) -> (TcpStream, Vec<u8>) {
+    let (stream, events, leftover) = stream.into_parts();
+    let mut unread: Vec<u8> = events
+        .into_iter()
+        .flat_map(TelnetEvent::into_bytes)
+        .collect();
+    unread.extend(leftover);
+    (stream, unread)
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().expect("Proxy state poisoned")
+}
+
+// Encode an event sent to a side with the given options
+fn encode(options: &mut OptionStates, event: TelnetEvent) -> Vec<u8> {
+    options.on_send(&event);
+    if options.is_local_enabled(TelnetOption::BinaryTransmission) {
+        event.into_binary_bytes()
+    } else {
+        event.into_bytes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        net::TcpListener,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    // Two ends of a TCP connection
+    fn pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let connected = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        (connected, listener.accept().unwrap().0)
+    }
+
+    fn receive(stream: &mut TcpStream, len: usize) -> Vec<u8> {
+        let mut received = vec![0; len];
+        stream.read_exact(&mut received).unwrap();
+        received
+    }
+
+    #[test]
+    fn proxy() {
+        let logged = Arc::new(Mutex::new(Vec::new()));
+        let mut proxy = TelnetProxy::new();
+        proxy.add_rule(StripOption(TelnetOption::Encrypt));
+        proxy.add_rule(ForceTerminalType("XTERM".into()));
+        {
+            let logged = logged.clone();
+            proxy.add_rule(move |direction, event: &TelnetEvent, _: &mut _| {
+                if let TelnetEvent::Data(data) = event {
+                    logged.lock().unwrap().push((direction, data.clone()));
+                }
+                Verdict::Pass
+            });
+        }
+
+        let (mut client, proxy_client) = pair();
+        let (proxy_server, mut server) = pair();
+        let thread = thread::spawn(move || {
+            proxy.run(
+                TelnetStream::from_stream(proxy_client),
+                TelnetStream::from_stream(proxy_server),
+            )
+        });
+
+        // Passed through as is, though re-encoding would add a CR and drop the NUL
+        let passed = b"hi\n\xff\xf1\xff\xfb\x18\xff\xfa\x1f\x00\xff\xff\x00\x18\xff\xf0\r\x00";
+        client.write_all(passed).unwrap();
+        assert_eq!(receive(&mut server, passed.len()), passed);
+
+        // Refused on behalf of the server
+        client.write_all(b"\xff\xfb\x26").unwrap();
+        assert_eq!(receive(&mut client, 3), b"\xff\xfe\x26");
+
+        // Rewritten, and the server sees nothing of what was stripped
+        client.write_all(b"\xff\xfa\x18\x00dumb\xff\xf0").unwrap();
+        let expected = b"\xff\xfa\x18\x00XTERM\xff\xf0";
+        assert_eq!(receive(&mut server, expected.len()), expected);
+
+        server.write_all(b"login: ").unwrap();
+        assert_eq!(receive(&mut client, 7), b"login: ");
+
+        drop(client);
+        thread.join().unwrap().unwrap();
+        assert_eq!(server.read(&mut [0]).unwrap(), 0);
+        assert_eq!(
+            *logged.lock().unwrap(),
+            [
+                (Direction::ClientToServer, b"hi\n".to_vec()),
+                // Rules see the data parsed, without the NUL that was forwarded
+                (Direction::ClientToServer, b"\r".to_vec()),
+                (Direction::ServerToClient, b"login: ".to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn rule_panics() {
+        let built = Arc::new(AtomicUsize::new(0));
+        let mut proxy = TelnetProxy::new();
+        {
+            let built = built.clone();
+            proxy.add_rule_with(move || {
+                built.fetch_add(1, Ordering::Relaxed);
+                |_, _: &TelnetEvent, _: &mut _| -> Verdict { panic!("Bad rule") }
+            });
+        }
+
+        let (mut client, proxy_client) = pair();
+        let (proxy_server, mut server) = pair();
+        let thread = thread::spawn(move || {
+            proxy.run(
+                TelnetStream::from_stream(proxy_client),
+                TelnetStream::from_stream(proxy_server),
+            )
+        });
+
+        // Both sides are closed, without waiting on the client
+        server.write_all(b"login: ").unwrap();
+        assert!(matches!(
+            thread.join().unwrap(),
+            Err(TellyError::ProxyPanicked(message)) if message == "Bad rule"
+        ));
+        assert_eq!(client.read(&mut [0]).unwrap(), 0);
+        assert_eq!(built.load(Ordering::Relaxed), 1);
+    }
+}
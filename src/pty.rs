@@ -0,0 +1,728 @@
+//! Serve a program running on a pseudo-terminal over Telnet, like `telnetd`.
+//!
+//! For each connection, a PTY is allocated and the configured program is spawned on it. Bytes are
+//! bridged both ways, and the Telnet session is reflected onto the PTY:
+//!
+//! * NAWS window sizes are applied with `TIOCSWINSZ`.
+//! * The TTYPE terminal type becomes the program's `TERM`.
+//! * NEW-ENVIRON variables are passed into the program's environment, if allowed by
+//!   [PtyConfig::environment_allowlist]. Nothing else of the server's environment is passed on,
+//!   except for `PATH` and `HOME`.
+//! * IP (interrupt process) sends `SIGINT`, and BRK sends `SIGQUIT`, to the PTY's foreground
+//!   process group.
+//!
+//! # Example
+//! ```no_run
+//! use telly::{pty::PtyConfig, server::TelnetServer};
+//!
+//! let server = TelnetServer::bind("127.0.0.1:2323").unwrap();
+//! server.serve(PtyConfig::new("/bin/sh")).unwrap();
+//! ```
+use crate::{
+    errors::{TellyError, TellyResult},
+    server::{ConnectionHandler, ErrorReporter},
+    EnvironmentVariable, TelnetAction, TelnetCommand, TelnetEvent, TelnetOption, TelnetStream,
+    TelnetSubnegotiation,
+};
+use std::{
+    env,
+    ffi::OsString,
+    fs::File,
+    io::{self, ErrorKind, Read, Write},
+    net::{Shutdown, SocketAddr, TcpStream},
+    os::unix::{io::AsRawFd, io::FromRawFd, process::CommandExt},
+    path::PathBuf,
+    process::{Child, Command, Stdio},
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+// Window size used when the client doesn't do NAWS.
+const DEFAULT_WINDOW_SIZE: (u16, u16) = (80, 24);
+// How often to check the negotiation deadline.
+const NEGOTIATION_POLL_INTERVAL: Duration = Duration::from_millis(20);
+// How long the program gets to exit after SIGHUP before it's killed.
+const HANGUP_GRACE_PERIOD: Duration = Duration::from_secs(1);
+// How often to check whether the program has exited after SIGHUP.
+const HANGUP_POLL_INTERVAL: Duration = Duration::from_millis(10);
+// `PATH` and `HOME` for the program, if the server has none.
+const DEFAULT_PATH: &str = "/usr/local/bin:/usr/bin:/bin";
+const DEFAULT_HOME: &str = "/";
+
+/// A pseudo-terminal pair.
+pub struct Pty {
+    master: File,
+    // Handed to the child on spawn; the parent must not keep it open, or reading the master
+    // never fails after the child exits.
+    slave: Option<File>,
+}
+
+impl Pty {
+    /// Allocate a PTY with the given window size.
+    pub fn open(width: u16, height: u16) -> io::Result<Self> {
+        let mut master = 0;
+        let mut slave = 0;
+        let size = window_size(width, height);
+        // SAFETY: All pointers are valid for the duration of the call, and the name is not
+        // requested.
+        let result = unsafe {
+            libc::openpty(
+                &mut master,
+                &mut slave,
+                std::ptr::null_mut(),
+                std::ptr::null_mut(),
+                &size,
+            )
+        };
+        if result != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // SAFETY: openpty() succeeded, so these are open file descriptors that we now own.
+        let (master, slave) = unsafe { (File::from_raw_fd(master), File::from_raw_fd(slave)) };
+        Ok(Self {
+            master,
+            slave: Some(slave),
+        })
+    }
+
+    /// The master side of the PTY. Reading from it yields the program's output, and writing to
+    /// it provides the program's input.
+    pub const fn master(&self) -> &File {
+        &self.master
+    }
+
+    /// Change the window size. The foreground process group receives `SIGWINCH`.
+    pub fn set_window_size(&self, width: u16, height: u16) -> io::Result<()> {
+        let size = window_size(width, height);
+        // SAFETY: TIOCSWINSZ reads a winsize, which outlives the call.
+        if unsafe { libc::ioctl(self.master.as_raw_fd(), libc::TIOCSWINSZ, &size) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Spawn a program with the PTY as its controlling terminal and standard streams. This can
+    /// only be done once per PTY.
+    // Takes the command by value, so that its copies of the slave are closed once spawned.
+    pub fn spawn(&mut self, mut command: Command) -> io::Result<Child> {
+        let slave = self
+            .slave
+            .take()
+            .ok_or_else(|| io::Error::other("PTY has already been used to spawn a program"))?;
+
+        command
+            .stdin(Stdio::from(slave.try_clone()?))
+            .stdout(Stdio::from(slave.try_clone()?))
+            .stderr(Stdio::from(slave));
+        // SAFETY: Only async-signal-safe functions are called between fork and exec.
+        unsafe {
+            command.pre_exec(|| {
+                // Become a session leader, then adopt stdin (the PTY) as controlling terminal
+                if libc::setsid() == -1 || libc::ioctl(0, libc::TIOCSCTTY as _, 0) == -1 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        command.spawn()
+    }
+
+    /// Send a signal to the PTY's foreground process group.
+    pub fn signal(&self, signal: libc::c_int) -> io::Result<()> {
+        // SAFETY: Plain syscalls on a file descriptor we own.
+        unsafe {
+            let group = libc::tcgetpgrp(self.master.as_raw_fd());
+            if group <= 0 || libc::killpg(group, signal) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+
+    // The character the terminal currently uses for a control function, e.g. VERASE.
+    fn control_character(&self, index: usize) -> Option<u8> {
+        // SAFETY: termios is plain data, and tcgetattr() fully initializes it on success.
+        unsafe {
+            let mut termios: libc::termios = std::mem::zeroed();
+            if libc::tcgetattr(self.master.as_raw_fd(), &mut termios) != 0 {
+                return None;
+            }
+            Some(termios.c_cc[index])
+        }
+    }
+}
+
+fn window_size(width: u16, height: u16) -> libc::winsize {
+    libc::winsize {
+        ws_row: height,
+        ws_col: width,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    }
+}
+
+/// Configuration for serving a program over Telnet. Usable directly as a [ConnectionHandler].
+#[derive(Clone, Debug)]
+pub struct PtyConfig {
+    /// The program to run for each connection.
+    pub program: PathBuf,
+    /// Arguments passed to the program.
+    pub args: Vec<OsString>,
+    /// NEW-ENVIRON variables the client may set in the program's environment. Entries ending in
+    /// `*` match any variable with that prefix. Everything else the client sends is ignored, so
+    /// that e.g. `LD_PRELOAD` can't be injected.
+    pub environment_allowlist: Vec<String>,
+    /// `TERM` for clients that don't report a terminal type.
+    pub default_terminal_type: String,
+    /// How long to wait for the client to report its terminal type, window size and environment
+    /// before starting the program without them.
+    pub negotiation_timeout: Duration,
+    on_error: ErrorReporter,
+}
+
+impl PtyConfig {
+    /// Configure a program to run with no arguments.
+    pub fn new(program: impl Into<PathBuf>) -> Self {
+        Self {
+            program: program.into(),
+            args: Vec::new(),
+            environment_allowlist: ["USER", "DISPLAY", "LANG", "LC_*", "TZ", "PRINTER"]
+                .into_iter()
+                .map(Into::into)
+                .collect(),
+            default_terminal_type: "dumb".into(),
+            negotiation_timeout: Duration::from_secs(2),
+            on_error: ErrorReporter::default(),
+        }
+    }
+
+    /// Call `handler` with the client's address and the error whenever a connection served as
+    /// a [ConnectionHandler] fails, e.g. to log it. Errors are dropped by default.
+    pub fn set_error_handler(
+        &mut self,
+        handler: impl Fn(SocketAddr, &TellyError) + Send + Sync + 'static,
+    ) {
+        self.on_error = ErrorReporter::new(handler);
+    }
+
+    /// Run the program for a single connection, returning once either side hangs up.
+    pub fn run(&self, mut stream: TelnetStream<TcpStream>) -> TellyResult {
+        let socket = stream.get_ref().try_clone()?;
+        let mut writer = TelnetStream::from_stream(socket.try_clone()?);
+        let options = Arc::new(Mutex::new(stream.options().clone()));
+        writer.share_options(options.clone());
+        stream.share_options(options);
+        // Everything goes out through the writer, including keepalive probes, so it's in order
+        // with the program's output
+        let writer = Arc::new(Mutex::new(writer));
+        stream.send_through(writer.clone());
+
+        let mut client = ClientState::new(self, writer.clone());
+        client.negotiate(&mut stream, &socket)?;
+
+        let (width, height) = client.window_size.unwrap_or(DEFAULT_WINDOW_SIZE);
+        let mut pty = Pty::open(width, height)?;
+        let mut command = Command::new(&self.program);
+        command
+            .args(&self.args)
+            .env_clear()
+            .env("PATH", env::var_os("PATH").unwrap_or(DEFAULT_PATH.into()))
+            .env("HOME", env::var_os("HOME").unwrap_or(DEFAULT_HOME.into()))
+            .env(
+                "TERM",
+                client
+                    .terminal_type
+                    .as_deref()
+                    .unwrap_or(&self.default_terminal_type),
+            );
+        for (name, value) in &client.environment {
+            command.env(name, value);
+        }
+        let mut child = pty.spawn(command)?;
+
+        let output = {
+            let mut master = pty.master().try_clone()?;
+            let writer = writer.clone();
+            let socket = socket.try_clone()?;
+            thread::spawn(move || {
+                let mut buffer = [0; 4096];
+                loop {
+                    let count = match master.read(&mut buffer) {
+                        Ok(0) => break,
+                        Ok(count) => count,
+                        Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                        // EIO once the program and all its children have exited
+                        Err(_) => break,
+                    };
+                    let mut writer = writer.lock().expect("Writer poisoned");
+                    if writer.send_untranslated(&buffer[..count]).is_err() {
+                        break;
+                    }
+                }
+                // Wake up the input loop
+                let _ = socket.shutdown(Shutdown::Both);
+            })
+        };
+
+        let result = client.bridge(&mut stream, &pty);
+
+        hang_up(&mut child);
+        drop(pty);
+        let _ = output.join();
+        result
+    }
+
+    fn allows_variable(&self, name: &str) -> bool {
+        self.environment_allowlist
+            .iter()
+            .any(|allowed| match allowed.strip_suffix('*') {
+                Some(prefix) => name.starts_with(prefix),
+                None => name == allowed,
+            })
+    }
+}
+
+impl ConnectionHandler for PtyConfig {
+    fn handle_connection(&self, stream: TelnetStream<TcpStream>, peer: SocketAddr) {
+        if let Err(err) = self.run(stream) {
+            self.on_error.report(peer, &err);
+        }
+    }
+}
+
+// Ask the program to exit like a terminal hang-up would, then make sure it does.
+fn hang_up(child: &mut Child) {
+    // SAFETY: The child is a session leader, so its PID is also its process group ID.
+    unsafe {
+        libc::killpg(child.id() as libc::pid_t, libc::SIGHUP);
+    }
+    let deadline = Instant::now() + HANGUP_GRACE_PERIOD;
+    while Instant::now() < deadline {
+        if !matches!(child.try_wait(), Ok(None)) {
+            return;
+        }
+        thread::sleep(HANGUP_POLL_INTERVAL);
+    }
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+// What we know about the client, and the state of the input translation.
+struct ClientState<'a> {
+    config: &'a PtyConfig,
+    writer: Arc<Mutex<TelnetStream<TcpStream>>>,
+    terminal_type: Option<String>,
+    window_size: Option<(u16, u16)>,
+    environment: Vec<(String, String)>,
+    // Options asked for during negotiation that haven't been answered yet
+    pending: Vec<TelnetOption>,
+    // Options we've offered with WILL that the client hasn't answered yet
+    offered: Vec<TelnetOption>,
+    // Options we're performing, as agreed with the client
+    local_options: Vec<TelnetOption>,
+    // Input that arrived before the PTY existed
+    early_input: Vec<u8>,
+    // The last input byte was a CR, so a following LF or NUL should be dropped
+    after_cr: bool,
+}
+
+impl<'a> ClientState<'a> {
+    fn new(config: &'a PtyConfig, writer: Arc<Mutex<TelnetStream<TcpStream>>>) -> Self {
+        Self {
+            config,
+            writer,
+            terminal_type: None,
+            window_size: None,
+            environment: Vec::new(),
+            pending: Vec::new(),
+            offered: Vec::new(),
+            local_options: Vec::new(),
+            early_input: Vec::new(),
+            after_cr: false,
+        }
+    }
+
+    fn send(&self, event: TelnetEvent) -> TellyResult {
+        self.writer
+            .lock()
+            .expect("Writer poisoned")
+            .send_event(event)
+    }
+
+    // Negotiate character mode and ask about the client's terminal, waiting for answers until
+    // the negotiation timeout.
+    fn negotiate(
+        &mut self,
+        stream: &mut TelnetStream<TcpStream>,
+        socket: &TcpStream,
+    ) -> TellyResult {
+        self.pending = vec![
+            TelnetOption::TerminalType,
+            TelnetOption::NegotiateAboutWindowSize,
+            TelnetOption::NewEnvironment,
+        ];
+        self.offered = vec![TelnetOption::Echo, TelnetOption::SuppressGoAhead];
+        for option in self.offered.clone() {
+            self.send(TelnetEvent::will(option))?;
+        }
+        for option in self.pending.clone() {
+            self.send(TelnetEvent::r#do(option))?;
+        }
+
+        // Keepalive would handle read timeouts itself, so the deadline would never be checked.
+        // It's paused until the end of negotiation, which is short anyway.
+        let keepalive = stream.keepalive();
+        let read_timeout = socket.read_timeout()?;
+        stream.set_keepalive(None);
+        socket.set_read_timeout(Some(NEGOTIATION_POLL_INTERVAL))?;
+        let result = self.await_answers(stream);
+        socket.set_read_timeout(read_timeout)?;
+        stream.set_keepalive(keepalive);
+        result
+    }
+
+    // Handle events until the client has answered everything we asked, or the negotiation
+    // timeout passes
+    fn await_answers(&mut self, stream: &mut TelnetStream<TcpStream>) -> TellyResult {
+        let deadline = Instant::now() + self.config.negotiation_timeout;
+        while !self.pending.is_empty() && Instant::now() < deadline {
+            match stream.next_event() {
+                Ok(Some(event)) => self.on_event(event, None)?,
+                Ok(None) => return Err(io::Error::from(ErrorKind::UnexpectedEof).into()),
+                Err(TellyError::IoError(err))
+                    if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+
+    // Forward client input to the PTY until the client hangs up.
+    fn bridge(&mut self, stream: &mut TelnetStream<TcpStream>, pty: &Pty) -> TellyResult {
+        let early_input = std::mem::take(&mut self.early_input);
+        pty.master().write_all(&early_input)?;
+
+        while let Some(event) = stream.next_event()? {
+            self.on_event(event, Some(pty))?;
+        }
+        Ok(())
+    }
+
+    fn on_event(&mut self, event: TelnetEvent, pty: Option<&Pty>) -> TellyResult {
+        match event {
+            TelnetEvent::Data(data) => {
+                let input = self.translate_input(&data);
+                match pty {
+                    Some(pty) => pty.master().write_all(&input)?,
+                    None => self.early_input.extend(input),
+                }
+            }
+            TelnetEvent::Negotiation { action, option } => self.on_negotiation(action, option)?,
+            TelnetEvent::Subnegotiation(subnegotiation) => {
+                if let Ok(subnegotiation) = subnegotiation.try_into() {
+                    self.on_subnegotiation(subnegotiation, pty)?;
+                }
+            }
+            TelnetEvent::Command(command) => {
+                if let Some(pty) = pty {
+                    self.on_command(command, pty)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn on_negotiation(&mut self, action: TelnetAction, option: TelnetOption) -> TellyResult {
+        use TelnetOption::*;
+        match (action, option) {
+            (TelnetAction::Will, TerminalType) => {
+                self.send(TelnetSubnegotiation::TerminalTypeRequest.into())?
+            }
+            (TelnetAction::Will, NewEnvironment) => {
+                self.send(TelnetSubnegotiation::NewEnvironmentRequest(Vec::new()).into())?
+            }
+            // The window size follows in a subnegotiation
+            (TelnetAction::Will, NegotiateAboutWindowSize) => {}
+            (TelnetAction::Wont, option) => self.pending.retain(|pending| *pending != option),
+            // Refuse anything we didn't offer. Refusals are never answered, so this can't loop.
+            (TelnetAction::Will, option) => self.send(TelnetEvent::dont(option))?,
+            (TelnetAction::Do, option @ (Echo | SuppressGoAhead)) => {
+                if !self.local_options.contains(&option) {
+                    // Agree, unless this answers our offer
+                    if !self.offered.contains(&option) {
+                        self.send(TelnetEvent::will(option))?;
+                    }
+                    self.offered.retain(|offered| *offered != option);
+                    self.local_options.push(option);
+                }
+            }
+            (TelnetAction::Do, option) => self.send(TelnetEvent::wont(option))?,
+            (TelnetAction::Dont, option) => {
+                self.offered.retain(|offered| *offered != option);
+                // Only acknowledge DONT for options that are on, so this can't loop either
+                if self.local_options.contains(&option) {
+                    self.local_options.retain(|enabled| *enabled != option);
+                    self.send(TelnetEvent::wont(option))?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn on_subnegotiation(
+        &mut self,
+        subnegotiation: TelnetSubnegotiation,
+        pty: Option<&Pty>,
+    ) -> TellyResult {
+        let option = match subnegotiation {
+            TelnetSubnegotiation::TerminalTypeResponse(terminal_type) => {
+                self.terminal_type = Some(terminal_type.to_ascii_lowercase());
+                TelnetOption::TerminalType
+            }
+            TelnetSubnegotiation::NegotiateAboutWindowSize { width, height } => {
+                self.window_size = Some((width, height));
+                if let Some(pty) = pty {
+                    pty.set_window_size(width, height)?;
+                }
+                TelnetOption::NegotiateAboutWindowSize
+            }
+            TelnetSubnegotiation::NewEnvironmentResponse(variables)
+            | TelnetSubnegotiation::NewEnvironmentInfo(variables) => {
+                // The program's environment can't change once it's running
+                if pty.is_none() {
+                    self.add_environment(variables);
+                }
+                TelnetOption::NewEnvironment
+            }
+            _ => return Ok(()),
+        };
+        self.pending.retain(|pending| *pending != option);
+        Ok(())
+    }
+
+    fn add_environment(&mut self, variables: Vec<EnvironmentVariable>) {
+        for variable in variables {
+            if let Some(value) = variable.value {
+                if self.config.allows_variable(&variable.name) {
+                    self.environment.push((variable.name, value));
+                }
+            }
+        }
+    }
+
+    fn on_command(&self, command: TelnetCommand, pty: &Pty) -> TellyResult {
+        match command {
+            TelnetCommand::InterruptProcess => pty.signal(libc::SIGINT)?,
+            TelnetCommand::Break => pty.signal(libc::SIGQUIT)?,
+            TelnetCommand::AreYouThere => self
+                .writer
+                .lock()
+                .expect("Writer poisoned")
+                .send_untranslated(b"\r\n[Yes]\r\n")?,
+            TelnetCommand::EraseCharacter | TelnetCommand::EraseLine => {
+                let index = if command == TelnetCommand::EraseCharacter {
+                    libc::VERASE
+                } else {
+                    libc::VKILL
+                };
+                if let Some(character) = pty.control_character(index) {
+                    pty.master().write_all(&[character])?;
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    // Clients send Enter as CR LF or CR NUL, while the PTY expects a lone CR.
+    fn translate_input(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut input = Vec::with_capacity(data.len());
+        for &byte in data {
+            let after_cr = std::mem::replace(&mut self.after_cr, byte == b'\r');
+            if after_cr && (byte == b'\n' || byte == 0) {
+                continue;
+            }
+            input.push(byte);
+        }
+        input
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        keepalive::{KeepAlive, KeepAliveProbe},
+        server::TelnetServer,
+    };
+
+    #[test]
+    fn translate_input() {
+        let config = PtyConfig::new("true");
+        let socket = {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            TcpStream::connect(listener.local_addr().unwrap()).unwrap()
+        };
+        let mut client = ClientState::new(
+            &config,
+            Arc::new(Mutex::new(TelnetStream::from_stream(socket))),
+        );
+        assert_eq!(client.translate_input(b"ls\r\nls\r"), b"ls\rls\r");
+        // CR LF split across events
+        assert_eq!(client.translate_input(b"\n\r\0x\n"), b"\rx\n");
+    }
+
+    #[test]
+    fn dont_is_acknowledged() {
+        let config = PtyConfig::new("true");
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let socket = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let mut peer = TelnetStream::from_stream(listener.accept().unwrap().0);
+        let mut client = ClientState::new(
+            &config,
+            Arc::new(Mutex::new(TelnetStream::from_stream(socket))),
+        );
+        client.offered = vec![TelnetOption::Echo];
+
+        // Answers to our offer aren't answered again
+        client
+            .on_negotiation(TelnetAction::Do, TelnetOption::Echo)
+            .unwrap();
+        // Options that are off are refused without acknowledgement
+        client
+            .on_negotiation(TelnetAction::Dont, TelnetOption::SuppressGoAhead)
+            .unwrap();
+        client
+            .on_negotiation(TelnetAction::Dont, TelnetOption::Echo)
+            .unwrap();
+        assert!(client.local_options.is_empty());
+        client
+            .on_negotiation(TelnetAction::Dont, TelnetOption::Echo)
+            .unwrap();
+        // Turning it back on is agreed to
+        client
+            .on_negotiation(TelnetAction::Do, TelnetOption::Echo)
+            .unwrap();
+
+        assert_eq!(peer.next(), Some(TelnetEvent::wont(TelnetOption::Echo)));
+        assert_eq!(peer.next(), Some(TelnetEvent::will(TelnetOption::Echo)));
+        assert_eq!(client.local_options, vec![TelnetOption::Echo]);
+    }
+
+    #[test]
+    fn environment_allowlist() {
+        let config = PtyConfig::new("true");
+        assert!(config.allows_variable("USER"));
+        assert!(config.allows_variable("LC_ALL"));
+        assert!(!config.allows_variable("LD_PRELOAD"));
+        assert!(!config.allows_variable("USERNAME"));
+    }
+
+    #[test]
+    fn run_program() {
+        let mut config = PtyConfig::new("/bin/sh");
+        config.args = vec![
+            "-c".into(),
+            "echo \"$TERM $USER $PATH_HIJACK ${PATH:+path} ${HOME:+home} ${CARGO:-clear}\"; stty size"
+                .into(),
+        ];
+        let server = TelnetServer::bind("127.0.0.1:0").unwrap();
+        let address = server.local_addr().unwrap();
+        let shutdown = server.shutdown_handle();
+        let server_thread = thread::spawn(move || server.serve(config).unwrap());
+
+        let mut client = TelnetStream::from_stream(TcpStream::connect(address).unwrap());
+        client.send_will(TelnetOption::TerminalType).unwrap();
+        client
+            .send_will(TelnetOption::NegotiateAboutWindowSize)
+            .unwrap();
+        client
+            .send_event(
+                TelnetSubnegotiation::NegotiateAboutWindowSize {
+                    width: 100,
+                    height: 40,
+                }
+                .into(),
+            )
+            .unwrap();
+        client.send_will(TelnetOption::NewEnvironment).unwrap();
+
+        let mut output = Vec::new();
+        while let Some(event) = client.next() {
+            match event {
+                TelnetEvent::Data(data) => output.extend(data),
+                TelnetEvent::Subnegotiation(subnegotiation) => {
+                    match subnegotiation.try_into().unwrap() {
+                        TelnetSubnegotiation::TerminalTypeRequest => client
+                            .send_event(
+                                TelnetSubnegotiation::TerminalTypeResponse("XTERM-TEST".into())
+                                    .into(),
+                            )
+                            .unwrap(),
+                        TelnetSubnegotiation::NewEnvironmentRequest(_) => client
+                            .send_event(
+                                TelnetSubnegotiation::NewEnvironmentResponse(vec![
+                                    EnvironmentVariable::var("USER", Some("margaret")),
+                                    EnvironmentVariable::uservar("PATH_HIJACK", Some("evil")),
+                                ])
+                                .into(),
+                            )
+                            .unwrap(),
+                        other => panic!("Unexpected subnegotiation {other:?}"),
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        let output = String::from_utf8_lossy(&output);
+        // The server's environment isn't inherited
+        assert!(
+            output.contains("xterm-test margaret  path home clear\r\n"),
+            "{output:?}"
+        );
+        assert!(output.contains("40 100\r\n"), "{output:?}");
+
+        shutdown.shutdown();
+        server_thread.join().unwrap();
+    }
+
+    #[test]
+    fn keepalive_after_negotiation() {
+        let mut config = PtyConfig::new("/bin/sh");
+        config.args = vec!["-c".into(), "echo started; sleep 2".into()];
+        config.negotiation_timeout = Duration::from_millis(100);
+        let mut server = TelnetServer::bind("127.0.0.1:0").unwrap();
+        server.set_keepalive(Some(KeepAlive::new(
+            Duration::from_millis(200),
+            KeepAliveProbe::Nop,
+            None,
+        )));
+        let address = server.local_addr().unwrap();
+        let shutdown = server.shutdown_handle();
+        let server_thread = thread::spawn(move || server.serve(config).unwrap());
+
+        // The client never answers, so negotiation runs into its timeout
+        let mut client = TelnetStream::from_stream(TcpStream::connect(address).unwrap());
+        let mut output = Vec::new();
+        while !String::from_utf8_lossy(&output).contains("started") {
+            match client
+                .next()
+                .expect("Connection closed before the program started")
+            {
+                TelnetEvent::Data(data) => output.extend(data),
+                TelnetEvent::Command(TelnetCommand::Nop) => {
+                    panic!("Probe sent before the program started")
+                }
+                _ => {}
+            }
+        }
+        let probe = client.find(|event| matches!(event, TelnetEvent::Command(_)));
+        assert_eq!(probe, Some(TelnetEvent::Command(TelnetCommand::Nop)));
+
+        shutdown.shutdown();
+        server_thread.join().unwrap();
+    }
+}
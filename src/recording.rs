@@ -0,0 +1,547 @@
+//! Recording sessions to [asciicast v2](https://docs.asciinema.org/manual/asciicast/v2/) or
+//! [ttyrec](https://nethackwiki.com/wiki/Ttyrec) files, and replaying them.
+//!
+//! An asciicast recording holds what the remote sent as "o" (output) events, what we sent as "i"
+//! (input) events, window size changes as "r" events, and other Telnet events in RFC notation as
+//! "m" (marker) events. Markers for events we sent start with `> `. Asciicast needs the
+//! `asciicast` feature.
+//!
+//! A ttyrec recording holds only the data the remote sent, as a terminal would show it, so it
+//! can be played back by any ttyrec player.
+//!
+//! # Example
+//! ```no_run
+//! # #[cfg(feature = "asciicast")] {
+//! use std::{fs::File, net::TcpStream};
+//! use telly::{
+//!     recording::{RecordingFormat, RecordingStream, Replay},
+//!     TelnetStream,
+//! };
+//!
+//! let stream = TelnetStream::from_stream(TcpStream::connect("127.0.0.1:23").unwrap());
+//! let file = File::create("session.cast").unwrap();
+//! let format = RecordingFormat::Asciicast {
+//!     width: 80,
+//!     height: 24,
+//! };
+//! let mut stream = RecordingStream::new(stream, file, format).unwrap();
+//! while let Some(event) = stream.next_event().unwrap() {
+//!     println!("{event:?}");
+//! }
+//!
+//! // Later
+//! let replay = Replay::from_asciicast(File::open("session.cast").unwrap()).unwrap();
+//! for event in replay.events(4.0).unwrap() {
+//!     println!("{event:?}");
+//! }
+//! # }
+//! ```
+#[cfg(feature = "asciicast")]
+use crate::TelnetSubnegotiation;
+use crate::{
+    errors::{TellyError, TellyResult},
+    TelnetEvent, TelnetParser, TelnetStream,
+};
+use bytes::BytesMut;
+#[cfg(feature = "asciicast")]
+use std::io::{BufRead, BufReader};
+use std::{
+    io::{self, ErrorKind, Read, Write},
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// File format of a recording.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RecordingFormat {
+    /// asciicast v2, as used by asciinema.
+    #[cfg(feature = "asciicast")]
+    Asciicast {
+        /// Initial terminal width.
+        width: u16,
+        /// Initial terminal height.
+        height: u16,
+    },
+    /// ttyrec, as used by ttyplay and termrec.
+    Ttyrec,
+}
+
+/// Wraps a [TelnetStream] to record events sent and received over it.
+///
+/// Events sent through [RecordingStream::get_mut] are not recorded.
+pub struct RecordingStream<S: Read + Write, W: Write> {
+    stream: TelnetStream<S>,
+    output: W,
+    format: RecordingFormat,
+    start: Instant,
+    // Wall clock time at `start`, for ttyrec timestamps
+    start_time: SystemTime,
+    // Incomplete UTF-8 at the end of the data received and sent so far, for asciicast
+    #[cfg(feature = "asciicast")]
+    partial: [Vec<u8>; 2],
+}
+
+impl<S: Read + Write, W: Write> RecordingStream<S, W> {
+    /// Start recording to `output`, writing the header if the format has one.
+    pub fn new(
+        stream: TelnetStream<S>,
+        #[cfg_attr(not(feature = "asciicast"), allow(unused_mut))] mut output: W,
+        format: RecordingFormat,
+    ) -> TellyResult<Self> {
+        let start_time = SystemTime::now();
+        #[cfg(feature = "asciicast")]
+        if let RecordingFormat::Asciicast { width, height } = format {
+            let timestamp = start_time
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs();
+            let header = serde_json::json!({
+                "version": 2,
+                "width": width,
+                "height": height,
+                "timestamp": timestamp,
+            });
+            serde_json::to_writer(&mut output, &header).map_err(io::Error::from)?;
+            writeln!(output)?;
+        }
+        Ok(Self {
+            stream,
+            output,
+            format,
+            start: Instant::now(),
+            start_time,
+            #[cfg(feature = "asciicast")]
+            partial: Default::default(),
+        })
+    }
+
+    /// The wrapped stream.
+    pub fn get_mut(&mut self) -> &mut TelnetStream<S> {
+        &mut self.stream
+    }
+
+    /// Stop recording, returning the stream and the recording's output.
+    pub fn into_parts(mut self) -> TellyResult<(TelnetStream<S>, W)> {
+        self.output.flush()?;
+        Ok((self.stream, self.output))
+    }
+
+    /// Receive the next event from remote, recording it. See [TelnetStream::next_event].
+    pub fn next_event(&mut self) -> TellyResult<Option<TelnetEvent>> {
+        let event = self.stream.next_event()?;
+        if let Some(event) = &event {
+            self.record(event, true)?;
+        }
+        Ok(event)
+    }
+
+    /// Send an event to remote, recording it once it's sent. See [TelnetStream::send_event].
+    pub fn send_event(&mut self, event: TelnetEvent) -> TellyResult {
+        self.stream.send_event(event.clone())?;
+        self.record(&event, false)
+    }
+
+    /// Convenience function to send data to remote.
+    pub fn send_data(&mut self, data: &[u8]) -> TellyResult {
+        self.send_event(TelnetEvent::Data(data.into()))
+    }
+
+    fn record(&mut self, event: &TelnetEvent, received: bool) -> TellyResult {
+        let elapsed = self.start.elapsed();
+        match self.format {
+            #[cfg(feature = "asciicast")]
+            RecordingFormat::Asciicast { .. } => {
+                let (code, data) = match event {
+                    TelnetEvent::Data(data) => {
+                        let code = if received { "o" } else { "i" };
+                        let text = decode_utf8(&mut self.partial[usize::from(received)], data);
+                        if text.is_empty() {
+                            return Ok(());
+                        }
+                        (code, text)
+                    }
+                    TelnetEvent::Subnegotiation(subnegotiation) if received => {
+                        match subnegotiation.clone().try_into() {
+                            Ok(TelnetSubnegotiation::NegotiateAboutWindowSize {
+                                width,
+                                height,
+                            }) => ("r", format!("{width}x{height}")),
+                            _ => ("m", event.to_string()),
+                        }
+                    }
+                    event if received => ("m", event.to_string()),
+                    event => ("m", format!("{SENT_MARKER}{event}")),
+                };
+                serde_json::to_writer(&mut self.output, &(elapsed.as_secs_f64(), code, data))
+                    .map_err(io::Error::from)?;
+                writeln!(self.output)?;
+            }
+            // Negotiations and the like would be garbage to a terminal
+            RecordingFormat::Ttyrec => {
+                let TelnetEvent::Data(bytes) = event else {
+                    return Ok(());
+                };
+                if !received {
+                    return Ok(());
+                }
+                let time = (self.start_time + elapsed)
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default();
+                let length = u32::try_from(bytes.len())
+                    .map_err(|_| TellyError::ConversionError("ttyrec frame too long".into()))?;
+                self.output
+                    .write_all(&(time.as_secs() as u32).to_le_bytes())?;
+                self.output.write_all(&time.subsec_micros().to_le_bytes())?;
+                self.output.write_all(&length.to_le_bytes())?;
+                self.output.write_all(bytes)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<S: Read + Write, W: Write> Iterator for RecordingStream<S, W> {
+    type Item = TelnetEvent;
+
+    /// Receive and record the next event. Returns `None` at the end of the stream, or if an
+    /// error occurred. Use [RecordingStream::next_event] to see errors.
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_event().ok().flatten()
+    }
+}
+
+// Prefix of markers for events we sent, which aren't replayed
+#[cfg(feature = "asciicast")]
+const SENT_MARKER: &str = "> ";
+
+// Decode UTF-8 that may be split between chunks, keeping an incomplete character at the end
+// for next time
+#[cfg(feature = "asciicast")]
+fn decode_utf8(partial: &mut Vec<u8>, data: &[u8]) -> String {
+    partial.extend_from_slice(data);
+    let complete = match std::str::from_utf8(partial) {
+        Ok(_) => partial.len(),
+        Err(err) if err.error_len().is_none() => err.valid_up_to(),
+        Err(_) => partial.len(),
+    };
+    let text = String::from_utf8_lossy(&partial[..complete]).into_owned();
+    partial.drain(..complete);
+    text
+}
+
+/// Something the remote sent, at some point in a recording.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Frame {
+    /// Time since the start of the recording.
+    pub time: Duration,
+    /// Telnet-encoded bytes.
+    pub bytes: Vec<u8>,
+}
+
+/// A recording loaded for replay.
+///
+/// Only what the remote sent is replayed.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Replay {
+    frames: Vec<Frame>,
+}
+
+impl Replay {
+    /// Load an asciicast v2 recording. Output, window size changes and Telnet events are
+    /// replayed.
+    #[cfg(feature = "asciicast")]
+    pub fn from_asciicast(reader: impl Read) -> TellyResult<Self> {
+        let bad = |err: serde_json::Error| TellyError::DecodeError(format!("Bad asciicast: {err}"));
+        let mut lines = BufReader::new(reader).lines();
+        let header = lines
+            .next()
+            .ok_or_else(|| TellyError::DecodeError("Empty asciicast".into()))??;
+        let header: serde_json::Value = serde_json::from_str(&header).map_err(bad)?;
+        if header.get("version") != Some(&2.into()) {
+            return Err(TellyError::DecodeError("Not an asciicast v2 header".into()));
+        }
+
+        let mut frames = Vec::new();
+        for line in lines {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let (time, code, data) =
+                serde_json::from_str::<(f64, String, String)>(&line).map_err(bad)?;
+            let time = Duration::try_from_secs_f64(time)
+                .map_err(|_| TellyError::DecodeError(format!("Bad asciicast time: {time}")))?;
+            let events = match code.as_str() {
+                "o" => vec![TelnetEvent::Data(data.into_bytes())],
+                "r" => {
+                    let size = data.split_once('x').and_then(|(width, height)| {
+                        Some((width.parse().ok()?, height.parse().ok()?))
+                    });
+                    let Some((width, height)) = size else {
+                        return Err(TellyError::DecodeError(format!(
+                            "Bad asciicast window size: {data}"
+                        )));
+                    };
+                    vec![TelnetSubnegotiation::NegotiateAboutWindowSize { width, height }.into()]
+                }
+                "m" if !data.starts_with(SENT_MARKER) => TelnetEvent::parse_sequence(&data)?,
+                _ => continue,
+            };
+            frames.push(Frame {
+                time,
+                bytes: events
+                    .into_iter()
+                    .flat_map(TelnetEvent::into_binary_bytes)
+                    .collect(),
+            });
+        }
+        Ok(Self { frames })
+    }
+
+    /// Load a ttyrec recording.
+    pub fn from_ttyrec(mut reader: impl Read) -> TellyResult<Self> {
+        let mut frames = Vec::new();
+        let mut first = None;
+        let mut header = [0; 12];
+        loop {
+            match reader.read_exact(&mut header) {
+                Ok(()) => {}
+                Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => break,
+                Err(err) => return Err(err.into()),
+            }
+            let field = |index: usize| {
+                u32::from_le_bytes(header[index * 4..index * 4 + 4].try_into().unwrap())
+            };
+            let time =
+                Duration::from_secs(field(0).into()) + Duration::from_micros(field(1).into());
+            // The length is untrusted, so only allocate for what's actually there
+            let length = field(2);
+            let mut data = Vec::new();
+            reader.by_ref().take(length.into()).read_to_end(&mut data)?;
+            if data.len() != length as usize {
+                return Err(TellyError::DecodeError(format!(
+                    "Truncated ttyrec frame: expected {length} bytes, got {}",
+                    data.len()
+                )));
+            }
+
+            let first = *first.get_or_insert(time);
+            frames.push(Frame {
+                time: time.saturating_sub(first),
+                bytes: TelnetEvent::Data(data).into_binary_bytes(),
+            });
+        }
+        Ok(Self { frames })
+    }
+
+    /// The frames of this recording.
+    pub fn frames(&self) -> &[Frame] {
+        &self.frames
+    }
+
+    /// Replay the recording through a [TelnetParser], yielding events as they were received.
+    ///
+    /// `speed` is how much faster than the original to go: 1.0 replays in real time, and
+    /// [f64::INFINITY] replays without waiting. Fails with [ErrorKind::InvalidInput] unless
+    /// it's positive.
+    ///
+    /// Data is yielded exactly as it was recorded, without NVT translation.
+    pub fn events(&self, speed: f64) -> TellyResult<impl Iterator<Item = TelnetEvent> + '_> {
+        if speed.is_nan() || speed <= 0.0 {
+            return Err(
+                io::Error::new(ErrorKind::InvalidInput, "Replay speed must be positive").into(),
+            );
+        }
+        let start = Instant::now();
+        let mut parser = TelnetParser::default();
+        // Frames hold data as recorded, with only IAC escaped
+        parser.set_binary(true);
+        let mut buffer = BytesMut::new();
+        let mut frames = self.frames.iter();
+
+        Ok(std::iter::from_fn(move || loop {
+            if let Some(event) = parser.next_event(&mut buffer) {
+                return Some(event);
+            }
+            let frame = frames.next()?;
+            let due = start + frame.time.div_f64(speed);
+            if let Some(wait) = due.checked_duration_since(Instant::now()) {
+                thread::sleep(wait);
+            }
+            buffer.extend_from_slice(&frame.bytes);
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{TelnetCommand, TelnetOption, TelnetSubnegotiation};
+    use std::net::{TcpListener, TcpStream};
+
+    // Events from a fake server, as seen by a recording client
+    fn record(format: RecordingFormat) -> (Vec<TelnetEvent>, Vec<u8>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+
+        let server = thread::spawn(move || {
+            let mut server = TelnetStream::from_stream(server);
+            server.send_will(TelnetOption::Echo).unwrap();
+            server.send_str("login: ").unwrap();
+            thread::sleep(Duration::from_millis(50));
+            server
+                .send_str("\u{1b}[1mbold\u{1b}[0m \"quoted\" \u{1f980}\r\n")
+                .unwrap();
+            server
+                .send_event(TelnetCommand::AreYouThere.into())
+                .unwrap();
+            server
+                .send_event(
+                    TelnetSubnegotiation::NegotiateAboutWindowSize {
+                        width: 132,
+                        height: 43,
+                    }
+                    .into(),
+                )
+                .unwrap();
+            server.send_untranslated(b"\xff").unwrap();
+            // Hang up only once the client's input is read, so the connection isn't reset
+            assert_eq!(server.next(), Some(TelnetEvent::r#do(TelnetOption::Echo)));
+            assert_eq!(server.next(), Some(TelnetEvent::Data(b"root\r\n".to_vec())));
+        });
+
+        let client = TelnetStream::from_stream(client);
+        let mut client = RecordingStream::new(client, Vec::new(), format).unwrap();
+        let mut events = Vec::new();
+        while let Some(event) = client.next_event().unwrap() {
+            if events.is_empty() {
+                client
+                    .send_event(TelnetEvent::r#do(TelnetOption::Echo))
+                    .unwrap();
+                client.send_data(b"root\n").unwrap();
+            }
+            events.push(event);
+        }
+        server.join().unwrap();
+        (events, client.into_parts().unwrap().1)
+    }
+
+    #[cfg(feature = "asciicast")]
+    fn data(events: impl Iterator<Item = TelnetEvent>) -> Vec<u8> {
+        events
+            .into_iter()
+            .filter_map(|event| match event {
+                TelnetEvent::Data(data) => Some(data),
+                _ => None,
+            })
+            .flatten()
+            .collect()
+    }
+
+    #[test]
+    #[cfg(feature = "asciicast")]
+    fn asciicast() {
+        let (received, recording) = record(RecordingFormat::Asciicast {
+            width: 80,
+            height: 24,
+        });
+        let recording = String::from_utf8(recording).unwrap();
+        let mut lines = recording.lines();
+        let header: serde_json::Value = serde_json::from_str(lines.next().unwrap()).unwrap();
+        assert_eq!(header["version"], 2);
+        assert_eq!(header["width"], 80);
+        assert_eq!(header["height"], 24);
+
+        let events: Vec<(String, String)> = lines
+            .map(|line| serde_json::from_str::<(f64, String, String)>(line).unwrap())
+            .map(|(_, code, data)| (code, data))
+            .collect();
+        let text = |wanted: &str| -> String {
+            events
+                .iter()
+                .filter(|(code, _)| code == wanted)
+                .map(|(_, data)| data.as_str())
+                .collect()
+        };
+        assert_eq!(
+            text("o"),
+            "login: \u{1b}[1mbold\u{1b}[0m \"quoted\" \u{1f980}\r\n\u{fffd}"
+        );
+        assert_eq!(text("i"), "root\n");
+        assert_eq!(text("r"), "132x43");
+        assert_eq!(events[0], ("m".to_string(), "IAC WILL ECHO".to_string()));
+        assert_eq!(events[1], ("m".to_string(), "> IAC DO ECHO".to_string()));
+        assert!(events.contains(&("m".to_string(), "IAC AYT".to_string())));
+
+        // Replays what was received, Telnet events and all
+        let replay = Replay::from_asciicast(recording.as_bytes()).unwrap();
+        assert!(replay.frames().last().unwrap().time >= Duration::from_millis(50));
+        let replayed: Vec<_> = replay.events(f64::INFINITY).unwrap().collect();
+        let not_data = |events: &[TelnetEvent]| -> Vec<TelnetEvent> {
+            events
+                .iter()
+                .filter(|event| !matches!(event, TelnetEvent::Data(_)))
+                .cloned()
+                .collect()
+        };
+        assert_eq!(not_data(&replayed), not_data(&received));
+        assert_eq!(
+            String::from_utf8(data(replayed.into_iter())).unwrap(),
+            text("o")
+        );
+
+        assert!(Replay::from_asciicast(&b"{\"version\": 20}\n"[..]).is_err());
+    }
+
+    #[test]
+    fn ttyrec() {
+        let (events, recording) = record(RecordingFormat::Ttyrec);
+        // Only the data is recorded, unescaped, as a terminal would see it
+        assert!(!recording.windows(2).any(|bytes| bytes == b"\xff\xfb"));
+        assert!(recording.ends_with(b"\x01\0\0\0\xff"));
+        let replay = Replay::from_ttyrec(recording.as_slice()).unwrap();
+        assert_eq!(replay.frames()[0].bytes, b"login: ");
+
+        // Replays the data, at the original pace
+        let events: Vec<_> = events
+            .into_iter()
+            .filter(|event| matches!(event, TelnetEvent::Data(_)))
+            .collect();
+        let start = Instant::now();
+        assert_eq!(replay.events(1.0).unwrap().collect::<Vec<_>>(), events);
+        assert!(start.elapsed() >= Duration::from_millis(50));
+
+        // Or faster
+        let start = Instant::now();
+        assert_eq!(replay.events(10.0).unwrap().collect::<Vec<_>>(), events);
+        assert!(start.elapsed() < Duration::from_millis(50));
+
+        // NULs and CRs are replayed as they were recorded
+        let mut nul = b"\0\0\0\0\0\0\0\0\x05\0\0\0".to_vec();
+        nul.extend(b"a\r\0b\0");
+        let replay = Replay::from_ttyrec(nul.as_slice()).unwrap();
+        assert_eq!(
+            replay.events(f64::INFINITY).unwrap().collect::<Vec<_>>(),
+            vec![TelnetEvent::Data(b"a\r\0b\0".to_vec())]
+        );
+        for speed in [0.0, -1.0, f64::NAN] {
+            assert!(matches!(
+                replay.events(speed),
+                Err(TellyError::IoError(err)) if err.kind() == ErrorKind::InvalidInput
+            ));
+        }
+
+        // A frame claiming more data than there is
+        let mut truncated = b"\0\0\0\0\0\0\0\0\xff\xff\xff\xffabc".to_vec();
+        assert!(matches!(
+            Replay::from_ttyrec(truncated.as_slice()),
+            Err(TellyError::DecodeError(_))
+        ));
+        truncated.truncate(12);
+        assert!(matches!(
+            Replay::from_ttyrec(truncated.as_slice()),
+            Err(TellyError::DecodeError(_))
+        ));
+    }
+}
@@ -0,0 +1,199 @@
+//! Serde support, behind the `serde` feature. The representation is documented on
+//! [TelnetEvent](crate::TelnetEvent).
+use crate::{errors::TellyError, TelnetAction, TelnetCommand, TelnetOption};
+use core::{fmt, marker::PhantomData, str::FromStr};
+use serde::{
+    de::{self, Visitor},
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+
+// Deserializes a type from its name, or from its byte value in formats that can tell the two
+// apart
+struct NameVisitor<T>(PhantomData<T>);
+
+impl<'de, T> Visitor<'de> for NameVisitor<T>
+where
+    T: FromStr + TryFrom<u8>,
+    <T as FromStr>::Err: fmt::Display,
+{
+    type Value = T;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a Telnet name or byte value")
+    }
+
+    fn visit_str<E: de::Error>(self, s: &str) -> Result<T, E> {
+        T::from_str(s).map_err(E::custom)
+    }
+
+    fn visit_u64<E: de::Error>(self, value: u64) -> Result<T, E> {
+        u8::try_from(value)
+            .ok()
+            .and_then(|byte| T::try_from(byte).ok())
+            .ok_or_else(|| E::invalid_value(de::Unexpected::Unsigned(value), &self))
+    }
+}
+
+fn deserialize_name<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr + TryFrom<u8>,
+    <T as FromStr>::Err: fmt::Display,
+{
+    // Formats like bincode can't say what's next, so they're told to expect the name that's
+    // always serialized
+    if deserializer.is_human_readable() {
+        deserializer.deserialize_any(NameVisitor(PhantomData))
+    } else {
+        deserializer.deserialize_str(NameVisitor(PhantomData))
+    }
+}
+
+impl Serialize for TelnetOption {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.name())
+    }
+}
+
+impl<'de> Deserialize<'de> for TelnetOption {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserialize_name(deserializer).map(|SerializedOption(option)| option)
+    }
+}
+
+// An option as serialized, which unlike TelnetOption::from_str, takes "UNKNOWN" back, so that
+// whatever is serialized can be deserialized
+struct SerializedOption(TelnetOption);
+
+impl FromStr for SerializedOption {
+    type Err = TellyError;
+
+    fn from_str(s: &str) -> Result<Self, TellyError> {
+        if s.eq_ignore_ascii_case(TelnetOption::Unknown.name()) {
+            return Ok(Self(TelnetOption::Unknown));
+        }
+        TelnetOption::from_str(s).map(Self)
+    }
+}
+
+impl From<u8> for SerializedOption {
+    fn from(byte: u8) -> Self {
+        Self(byte.into())
+    }
+}
+
+impl Serialize for TelnetAction {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.name())
+    }
+}
+
+impl<'de> Deserialize<'de> for TelnetAction {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserialize_name(deserializer)
+    }
+}
+
+impl Serialize for TelnetCommand {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for TelnetCommand {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserialize_name(deserializer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        EnvironmentVariable, TelnetCommand, TelnetEvent, TelnetOption, TelnetSubnegotiation,
+    };
+    use alloc::vec;
+    use serde_json::json;
+
+    #[test]
+    fn representation() {
+        let vectors = [
+            (
+                TelnetEvent::will(TelnetOption::TerminalType),
+                json!({"Negotiation": {"action": "WILL", "option": "TTYPE"}}),
+            ),
+            (TelnetCommand::AreYouThere.into(), json!({"Command": "AYT"})),
+            (TelnetCommand::Other(200).into(), json!({"Command": "200"})),
+            (
+                TelnetSubnegotiation::NegotiateAboutWindowSize {
+                    width: 80,
+                    height: 24,
+                }
+                .into(),
+                json!({"Subnegotiation": {"option": "NAWS", "bytes": [0, 80, 0, 24]}}),
+            ),
+            (
+                TelnetEvent::Data(b"hi".to_vec()),
+                json!({"Data": [104, 105]}),
+            ),
+        ];
+        for (event, value) in vectors {
+            assert_eq!(serde_json::to_value(&event).unwrap(), value);
+            assert_eq!(serde_json::from_value::<TelnetEvent>(value).unwrap(), event);
+        }
+
+        let subnegotiation =
+            TelnetSubnegotiation::NewEnvironmentInfo(vec![EnvironmentVariable::var(
+                "USER",
+                Some("margaret"),
+            )]);
+        let value = json!({"NewEnvironmentInfo": [
+            {"user_defined": false, "name": "USER", "value": "margaret"}
+        ]});
+        assert_eq!(serde_json::to_value(&subnegotiation).unwrap(), value);
+        assert_eq!(
+            serde_json::from_value::<TelnetSubnegotiation>(value).unwrap(),
+            subnegotiation
+        );
+        assert_eq!(
+            serde_json::to_value(TelnetSubnegotiation::TerminalTypeRequest).unwrap(),
+            json!("TerminalTypeRequest")
+        );
+    }
+
+    #[test]
+    fn non_self_describing() {
+        let events = [
+            TelnetEvent::will(TelnetOption::TerminalType),
+            TelnetEvent::dont(TelnetOption::Unknown),
+            TelnetCommand::AreYouThere.into(),
+            TelnetCommand::Other(200).into(),
+            TelnetSubnegotiation::TerminalTypeResponse("VT100".into()).into(),
+            TelnetEvent::Data(b"hi".to_vec()),
+        ];
+        for event in events {
+            let bytes = bincode::serialize(&event).unwrap();
+            assert_eq!(bincode::deserialize::<TelnetEvent>(&bytes).unwrap(), event);
+        }
+    }
+
+    #[test]
+    fn lenient_names() {
+        let event: TelnetEvent =
+            serde_json::from_value(json!({"Negotiation": {"action": "do", "option": 31}})).unwrap();
+        assert_eq!(
+            event,
+            TelnetEvent::r#do(TelnetOption::NegotiateAboutWindowSize)
+        );
+        let event: TelnetEvent = serde_json::from_value(json!({"Command": 246})).unwrap();
+        assert_eq!(event, TelnetCommand::AreYouThere.into());
+
+        for bad in [
+            json!({"Negotiation": {"action": "MAYBE", "option": "ECHO"}}),
+            json!({"Negotiation": {"action": 1, "option": "ECHO"}}),
+            json!({"Negotiation": {"action": "DO", "option": 256}}),
+            json!({"Command": "NOPE"}),
+        ] {
+            assert!(serde_json::from_value::<TelnetEvent>(bad).is_err());
+        }
+    }
+}
@@ -0,0 +1,429 @@
+//! A reusable, multi-client Telnet server.
+//!
+//! # Example
+//! ```no_run
+//! use telly::server::TelnetServer;
+//!
+//! let mut server = TelnetServer::bind("127.0.0.1:8000").unwrap();
+//! server
+//!     .set_negotiation_script("WILL ECHO, WILL SGA, DO NAWS, DO TTYPE")
+//!     .unwrap();
+//! server.set_max_connections(Some(32));
+//!
+//! server
+//!     .serve(|mut stream: telly::TelnetStream<_>, peer| {
+//!         println!("{peer} connected");
+//!         stream.send_str("Hello!\n").unwrap();
+//!     })
+//!     .unwrap();
+//! ```
+use crate::{
+    errors::{TellyError, TellyResult},
+    keepalive::KeepAlive,
+    TelnetAction, TelnetEvent, TelnetOption, TelnetStream,
+};
+use std::{
+    collections::HashMap,
+    fmt,
+    io::{self, ErrorKind},
+    net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+// How often the listener checks whether it has been asked to shut down.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Handles a single client connection of a [TelnetServer].
+///
+/// This is implemented for all `Fn(TelnetStream<TcpStream>, SocketAddr)` closures, so a plain
+/// closure can be used as a handler.
+pub trait ConnectionHandler: Send + Sync + 'static {
+    /// Serve a newly accepted client. This is called on the connection's own thread, after the
+    /// server's negotiation script has been sent. The connection is closed when this returns.
+    fn handle_connection(&self, stream: TelnetStream<TcpStream>, peer: SocketAddr);
+
+    /// Called when accepting a connection fails. The listener keeps running regardless.
+    fn on_accept_error(&self, _error: &io::Error) {}
+
+    /// Called when a connection is turned away because the server is at its connection limit.
+    fn on_connection_refused(&self, _peer: SocketAddr) {}
+}
+
+impl<F> ConnectionHandler for F
+where
+    F: Fn(TelnetStream<TcpStream>, SocketAddr) + Send + Sync + 'static,
+{
+    fn handle_connection(&self, stream: TelnetStream<TcpStream>, peer: SocketAddr) {
+        self(stream, peer)
+    }
+}
+
+// Reports errors from the connections a handler in this crate serves, to a callback set by the
+// application. Errors are dropped if there's none.
+#[derive(Clone, Default)]
+pub(crate) struct ErrorReporter(Option<ErrorCallback>);
+
+type ErrorCallback = Arc<dyn Fn(SocketAddr, &TellyError) + Send + Sync>;
+
+impl ErrorReporter {
+    pub(crate) fn new(callback: impl Fn(SocketAddr, &TellyError) + Send + Sync + 'static) -> Self {
+        Self(Some(Arc::new(callback)))
+    }
+
+    pub(crate) fn report(&self, peer: SocketAddr, error: &TellyError) {
+        if let Some(callback) = &self.0 {
+            callback(peer, error);
+        }
+    }
+}
+
+impl fmt::Debug for ErrorReporter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(if self.0.is_some() {
+            "ErrorReporter(Some(..))"
+        } else {
+            "ErrorReporter(None)"
+        })
+    }
+}
+
+/// Parse a negotiation script such as "WILL ECHO, WILL SGA, DO NAWS, DO TTYPE" into a list of
+/// negotiation events.
+///
+/// Negotiations are separated by commas. Options may be given by short name (see
+/// [TelnetOption::name]) or by number.
+///
+/// # Example
+/// ```
+/// use telly::{server::parse_negotiation_script, TelnetEvent, TelnetOption};
+///
+/// let events = parse_negotiation_script("WILL ECHO, DO NAWS").unwrap();
+/// assert_eq!(
+///     events,
+///     vec![
+///         TelnetEvent::will(TelnetOption::Echo),
+///         TelnetEvent::r#do(TelnetOption::NegotiateAboutWindowSize),
+///     ]
+/// );
+/// ```
+pub fn parse_negotiation_script(script: &str) -> TellyResult<Vec<TelnetEvent>> {
+    script
+        .split(',')
+        .map(str::trim)
+        .filter(|negotiation| !negotiation.is_empty())
+        .map(|negotiation| {
+            let mut words = negotiation.split_whitespace();
+            match (words.next(), words.next(), words.next()) {
+                (Some(action), Some(option), None) => Ok(TelnetEvent::Negotiation {
+                    action: action.parse::<TelnetAction>()?,
+                    option: option.parse::<TelnetOption>()?,
+                }),
+                _ => Err(TellyError::DecodeError(format!(
+                    "Expected '<action> <option>', but found '{negotiation}'"
+                ))),
+            }
+        })
+        .collect()
+}
+
+/// Handle used to stop a running [TelnetServer] from another thread.
+#[derive(Clone, Debug)]
+pub struct ShutdownHandle {
+    flag: Arc<AtomicBool>,
+}
+
+impl ShutdownHandle {
+    /// Ask the server to stop. The server stops accepting connections, closes all open
+    /// connections and waits for their handlers to return.
+    pub fn shutdown(&self) {
+        self.flag.store(true, Ordering::SeqCst);
+    }
+
+    /// Whether a shutdown has been requested.
+    pub fn is_shutdown(&self) -> bool {
+        self.flag.load(Ordering::SeqCst)
+    }
+}
+
+// Decrements the active connection count when a connection thread ends, even if the handler
+// panics.
+struct ConnectionGuard {
+    id: usize,
+    active: Arc<AtomicUsize>,
+    connections: Arc<Mutex<HashMap<usize, TcpStream>>>,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        if let Ok(mut connections) = self.connections.lock() {
+            connections.remove(&self.id);
+        }
+        self.active.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// A multi-client Telnet server. Each connection is served on its own thread by a
+/// [ConnectionHandler].
+pub struct TelnetServer {
+    listener: TcpListener,
+    negotiation: Vec<TelnetEvent>,
+    max_connections: Option<usize>,
+    keepalive: Option<KeepAlive>,
+    active: Arc<AtomicUsize>,
+    shutdown: ShutdownHandle,
+}
+
+impl TelnetServer {
+    /// Bind a server to an address.
+    pub fn bind(address: impl ToSocketAddrs) -> TellyResult<Self> {
+        Self::from_listener(TcpListener::bind(address)?)
+    }
+
+    /// Construct a server from an already bound listener.
+    pub fn from_listener(listener: TcpListener) -> TellyResult<Self> {
+        listener.set_nonblocking(true)?;
+        Ok(Self {
+            listener,
+            negotiation: Vec::new(),
+            max_connections: None,
+            keepalive: None,
+            active: Arc::new(AtomicUsize::new(0)),
+            shutdown: ShutdownHandle {
+                flag: Arc::new(AtomicBool::new(false)),
+            },
+        })
+    }
+
+    /// The address the server is listening on.
+    pub fn local_addr(&self) -> TellyResult<SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// Set the events sent to every client as soon as it connects.
+    pub fn set_negotiation(&mut self, events: Vec<TelnetEvent>) {
+        self.negotiation = events;
+    }
+
+    /// Set the events sent to every client as soon as it connects, from a script such as
+    /// "WILL ECHO, WILL SGA, DO NAWS, DO TTYPE". See [parse_negotiation_script].
+    pub fn set_negotiation_script(&mut self, script: &str) -> TellyResult {
+        self.negotiation = parse_negotiation_script(script)?;
+        Ok(())
+    }
+
+    /// Limit the number of simultaneous connections. Clients connecting while the limit is reached
+    /// are disconnected immediately. `None` means no limit.
+    pub fn set_max_connections(&mut self, max_connections: Option<usize>) {
+        self.max_connections = max_connections;
+    }
+
+    /// Enable keepalive probes on every connection, so that dead clients are noticed. See
+    /// [crate::keepalive].
+    pub fn set_keepalive(&mut self, keepalive: Option<KeepAlive>) {
+        self.keepalive = keepalive;
+    }
+
+    /// The number of currently open connections.
+    pub fn active_connections(&self) -> usize {
+        self.active.load(Ordering::SeqCst)
+    }
+
+    /// Get a handle that can be used to shut the server down from another thread.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// Accept and serve connections until shut down via a [ShutdownHandle].
+    ///
+    /// Accept errors are reported to [ConnectionHandler::on_accept_error] and do not stop the
+    /// server.
+    pub fn serve(&self, handler: impl ConnectionHandler) -> TellyResult {
+        let handler = Arc::new(handler);
+        let connections: Arc<Mutex<HashMap<usize, TcpStream>>> = Default::default();
+        let mut threads: Vec<JoinHandle<()>> = Vec::new();
+        let mut next_id = 0;
+
+        while !self.shutdown.is_shutdown() {
+            let (stream, peer) = match self.listener.accept() {
+                Ok(connection) => connection,
+                Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                    threads.retain(|thread| !thread.is_finished());
+                    thread::sleep(POLL_INTERVAL);
+                    continue;
+                }
+                Err(err) => {
+                    handler.on_accept_error(&err);
+                    // Errors like EMFILE tend to repeat immediately, so back off a little
+                    thread::sleep(POLL_INTERVAL);
+                    continue;
+                }
+            };
+
+            if self
+                .max_connections
+                .is_some_and(|max| self.active.load(Ordering::SeqCst) >= max)
+            {
+                handler.on_connection_refused(peer);
+                let _ = stream.shutdown(Shutdown::Both);
+                continue;
+            }
+
+            let id = next_id;
+            next_id += 1;
+            let guard = match self.register(id, &stream, &connections) {
+                Ok(guard) => guard,
+                Err(err) => {
+                    handler.on_accept_error(&err);
+                    continue;
+                }
+            };
+
+            let handler = handler.clone();
+            let negotiation = self.negotiation.clone();
+            let keepalive = self.keepalive;
+            threads.push(thread::spawn(move || {
+                let _guard = guard;
+                let mut stream = TelnetStream::from_stream(stream);
+                stream.set_keepalive(keepalive);
+                for event in negotiation {
+                    if stream.send_event(event).is_err() {
+                        return;
+                    }
+                }
+                handler.handle_connection(stream, peer);
+            }));
+        }
+
+        // Unblock all handlers stuck in a read, then wait for them to finish.
+        if let Ok(connections) = connections.lock() {
+            for stream in connections.values() {
+                let _ = stream.shutdown(Shutdown::Both);
+            }
+        }
+        for thread in threads {
+            let _ = thread.join();
+        }
+
+        Ok(())
+    }
+
+    fn register(
+        &self,
+        id: usize,
+        stream: &TcpStream,
+        connections: &Arc<Mutex<HashMap<usize, TcpStream>>>,
+    ) -> io::Result<ConnectionGuard> {
+        // Accepted sockets inherit the listener's non-blocking mode on some platforms
+        stream.set_nonblocking(false)?;
+        // Keepalive needs reads to time out to notice idleness
+        stream.set_read_timeout(self.keepalive.map(|keepalive| keepalive.poll_interval()))?;
+        let clone = stream.try_clone()?;
+
+        self.active.fetch_add(1, Ordering::SeqCst);
+        connections
+            .lock()
+            .expect("Connection registry poisoned")
+            .insert(id, clone);
+
+        Ok(ConnectionGuard {
+            id,
+            active: self.active.clone(),
+            connections: connections.clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{io::Read, sync::mpsc};
+
+    fn start_server(
+        server: TelnetServer,
+        handler: impl ConnectionHandler,
+    ) -> (SocketAddr, ShutdownHandle, JoinHandle<()>) {
+        let address = server.local_addr().unwrap();
+        let handle = server.shutdown_handle();
+        let thread = thread::spawn(move || server.serve(handler).unwrap());
+        (address, handle, thread)
+    }
+
+    #[test]
+    fn negotiation_script() {
+        assert_eq!(
+            parse_negotiation_script("WILL ECHO, will sga,DO NAWS, DO 24").unwrap(),
+            vec![
+                TelnetEvent::will(TelnetOption::Echo),
+                TelnetEvent::will(TelnetOption::SuppressGoAhead),
+                TelnetEvent::r#do(TelnetOption::NegotiateAboutWindowSize),
+                TelnetEvent::r#do(TelnetOption::TerminalType),
+            ]
+        );
+        assert!(parse_negotiation_script("WILL").is_err());
+        assert!(parse_negotiation_script("MAYBE ECHO").is_err());
+        assert!(parse_negotiation_script("DO ECHO SGA").is_err());
+        // Options that would be sent as something else
+        assert!(matches!(
+            parse_negotiation_script("DO 200"),
+            Err(TellyError::ConversionError(_))
+        ));
+        assert!(parse_negotiation_script("DO 254").is_err());
+        assert!(parse_negotiation_script("WILL UNKNOWN").is_err());
+    }
+
+    #[test]
+    fn sends_negotiation_then_serves() {
+        let mut server = TelnetServer::bind("127.0.0.1:0").unwrap();
+        server.set_negotiation_script("WILL ECHO, DO NAWS").unwrap();
+        let (address, handle, thread) =
+            start_server(server, |mut stream: TelnetStream<TcpStream>, _| {
+                stream.send_str("hi").unwrap();
+            });
+
+        let client = TcpStream::connect(address).unwrap();
+        let events: Vec<TelnetEvent> = TelnetStream::from_stream(client).collect();
+        assert_eq!(
+            events,
+            vec![
+                TelnetEvent::will(TelnetOption::Echo),
+                TelnetEvent::r#do(TelnetOption::NegotiateAboutWindowSize),
+                TelnetEvent::Data(b"hi".to_vec()),
+            ]
+        );
+
+        handle.shutdown();
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn max_connections_and_shutdown() {
+        let mut server = TelnetServer::bind("127.0.0.1:0").unwrap();
+        server.set_max_connections(Some(1));
+        let (connected_tx, connected_rx) = mpsc::channel();
+        let connected_tx = Mutex::new(connected_tx);
+        let (address, handle, thread) =
+            start_server(server, move |stream: TelnetStream<TcpStream>, _| {
+                connected_tx.lock().unwrap().send(()).unwrap();
+                // Block until the server closes us
+                for _ in stream {}
+            });
+
+        let _first = TcpStream::connect(address).unwrap();
+        connected_rx.recv().unwrap();
+
+        // Second connection is turned away
+        let mut second = TcpStream::connect(address).unwrap();
+        let mut buffer = [0; 1];
+        assert_eq!(second.read(&mut buffer).unwrap(), 0);
+
+        // Shutdown closes the first connection and returns
+        handle.shutdown();
+        thread.join().unwrap();
+        assert!(connected_rx.try_recv().is_err());
+    }
+}
@@ -0,0 +1,557 @@
+//! A registry of connected sessions, for servers that need to address all of their clients, like
+//! chat servers and MUDs.
+//!
+//! # Example
+//! ```no_run
+//! use telly::{
+//!     server::TelnetServer,
+//!     session::{Session, SessionManager},
+//!     TelnetEvent,
+//! };
+//!
+//! let mut server = TelnetServer::bind("127.0.0.1:8000").unwrap();
+//! server.set_negotiation_script("DO NAWS, DO TTYPE").unwrap();
+//!
+//! let manager = SessionManager::new();
+//! let sessions = manager.clone();
+//! server
+//!     .serve(manager.service(move |mut session: Session| {
+//!         while let Some(event) = session.next() {
+//!             if let TelnetEvent::Data(data) = event {
+//!                 sessions.broadcast_except(session.id(), TelnetEvent::Data(data));
+//!             }
+//!         }
+//!     }))
+//!     .unwrap();
+//! ```
+use crate::{
+    errors::{TellyError, TellyResult},
+    negotiation::OptionStates,
+    server::{ConnectionHandler, ErrorReporter},
+    TelnetEvent, TelnetOption, TelnetStream, TelnetSubnegotiation,
+};
+use std::{
+    collections::HashMap,
+    net::{Shutdown, SocketAddr, TcpStream},
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+/// Unique identifier of a [Session] within a [SessionManager].
+pub type SessionId = u64;
+
+type SharedWriter = Arc<Mutex<TelnetStream<TcpStream>>>;
+
+/// A snapshot of what is known about a session.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SessionInfo {
+    /// The session's ID.
+    pub id: SessionId,
+    /// The remote address of the session.
+    pub peer: SocketAddr,
+    /// The terminal type reported by the client, if any. See
+    /// [TelnetSubnegotiation::TerminalTypeResponse].
+    pub terminal_type: Option<String>,
+    /// The window size reported by the client as `(width, height)`, if any. See
+    /// [TelnetSubnegotiation::NegotiateAboutWindowSize].
+    pub window_size: Option<(u16, u16)>,
+    /// Options negotiated with the client.
+    pub options: OptionStates,
+}
+
+impl SessionInfo {
+    /// Options the server is performing.
+    pub fn local_options(&self) -> Vec<TelnetOption> {
+        self.options.local_options()
+    }
+
+    /// Options the client is performing.
+    pub fn remote_options(&self) -> Vec<TelnetOption> {
+        self.options.remote_options()
+    }
+
+    fn on_receive(&mut self, event: &TelnetEvent) {
+        if let TelnetEvent::Subnegotiation(subnegotiation) = event {
+            match subnegotiation.clone().try_into() {
+                Ok(TelnetSubnegotiation::NegotiateAboutWindowSize { width, height }) => {
+                    self.window_size = Some((width, height));
+                }
+                Ok(TelnetSubnegotiation::TerminalTypeResponse(terminal_type)) => {
+                    self.terminal_type = Some(terminal_type);
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+struct SessionEntry {
+    // Everything but the options, which are kept in `options`
+    info: SessionInfo,
+    // Shared by the session's reader and writer, which record negotiations as they're sent and
+    // received
+    options: Arc<Mutex<OptionStates>>,
+    writer: SharedWriter,
+    // Kept separately so a session can be kicked while its writer is busy
+    socket: TcpStream,
+}
+
+impl SessionEntry {
+    fn info(&self) -> SessionInfo {
+        SessionInfo {
+            options: self
+                .options
+                .lock()
+                .expect("Session options poisoned")
+                .clone(),
+            ..self.info.clone()
+        }
+    }
+}
+
+#[derive(Default)]
+struct Registry {
+    sessions: HashMap<SessionId, SessionEntry>,
+    next_id: SessionId,
+}
+
+/// Thread-safe registry of all connected sessions.
+///
+/// Cloning a `SessionManager` yields another handle to the same registry.
+#[derive(Clone, Default)]
+pub struct SessionManager {
+    registry: Arc<Mutex<Registry>>,
+}
+
+impl SessionManager {
+    /// Construct an empty session manager.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Wrap a [SessionHandler] into a [ConnectionHandler] that registers each connection with
+    /// this manager, for use with [TelnetServer::serve](crate::server::TelnetServer::serve).
+    pub fn service<H: SessionHandler>(&self, handler: H) -> SessionService<H> {
+        SessionService {
+            manager: self.clone(),
+            handler,
+            on_error: ErrorReporter::default(),
+        }
+    }
+
+    /// Register a connection, returning the [Session] wrapping it. The session is unregistered
+    /// when dropped.
+    ///
+    /// As with [TelnetStream::split], what's sent to the session uses the stream's newline
+    /// translation, and whatever the session sends itself, like keepalive probes, is sent in
+    /// order with it. Sends aren't buffered, whatever the stream's write buffer.
+    pub fn register(
+        &self,
+        mut stream: TelnetStream<TcpStream>,
+        peer: SocketAddr,
+    ) -> TellyResult<Session> {
+        stream.flush()?;
+        let socket = stream.get_ref().try_clone()?;
+        let mut writer = TelnetStream::from_stream(socket.try_clone()?);
+        writer.set_newline_policy(stream.newline_policy());
+        // Carry over anything negotiated before registration, e.g. by the server's negotiation
+        // script
+        let options = Arc::new(Mutex::new(stream.options().clone()));
+        writer.share_options(options.clone());
+        stream.share_options(options.clone());
+        let writer = Arc::new(Mutex::new(writer));
+        stream.send_through(writer.clone());
+
+        let mut registry = self.lock();
+        let id = registry.next_id;
+        registry.next_id += 1;
+        let info = SessionInfo {
+            id,
+            peer,
+            terminal_type: None,
+            window_size: None,
+            options: OptionStates::default(),
+        };
+        registry.sessions.insert(
+            id,
+            SessionEntry {
+                info,
+                options,
+                writer,
+                socket,
+            },
+        );
+
+        Ok(Session {
+            id,
+            peer,
+            reader: stream,
+            manager: self.clone(),
+        })
+    }
+
+    /// Information about every connected session, ordered by ID.
+    pub fn sessions(&self) -> Vec<SessionInfo> {
+        let mut sessions: Vec<SessionInfo> = self
+            .lock()
+            .sessions
+            .values()
+            .map(SessionEntry::info)
+            .collect();
+        sessions.sort_by_key(|info| info.id);
+        sessions
+    }
+
+    /// Information about a single session.
+    pub fn session(&self, id: SessionId) -> Option<SessionInfo> {
+        self.lock().sessions.get(&id).map(SessionEntry::info)
+    }
+
+    /// The number of connected sessions.
+    pub fn len(&self) -> usize {
+        self.lock().sessions.len()
+    }
+
+    /// Whether no sessions are connected.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Send an event to a single session.
+    pub fn send_to(&self, id: SessionId, event: TelnetEvent) -> TellyResult {
+        let writer = self
+            .lock()
+            .sessions
+            .get(&id)
+            .map(|entry| entry.writer.clone())
+            .ok_or(TellyError::NoSuchSession(id))?;
+
+        // Don't hold the registry lock while writing; a slow client shouldn't stall everyone.
+        // Negotiations are recorded by the writer, in the order they're sent.
+        let mut writer = writer.lock().expect("Session writer poisoned");
+        writer.send_event(event)
+    }
+
+    /// Send an event to every session. Returns the number of sessions it was delivered to.
+    pub fn broadcast(&self, event: TelnetEvent) -> usize {
+        self.broadcast_filtered(event, |_| true)
+    }
+
+    /// Send an event to every session except one, e.g. the session the event originated from.
+    /// Returns the number of sessions it was delivered to.
+    pub fn broadcast_except(&self, except: SessionId, event: TelnetEvent) -> usize {
+        self.broadcast_filtered(event, |id| id != except)
+    }
+
+    /// Disconnect a session. Its [Session] stops yielding events.
+    pub fn kick(&self, id: SessionId) -> TellyResult {
+        let registry = self.lock();
+        let entry = registry
+            .sessions
+            .get(&id)
+            .ok_or(TellyError::NoSuchSession(id))?;
+        entry.socket.shutdown(Shutdown::Both)?;
+        Ok(())
+    }
+
+    fn broadcast_filtered(&self, event: TelnetEvent, filter: impl Fn(SessionId) -> bool) -> usize {
+        let ids: Vec<SessionId> = self
+            .lock()
+            .sessions
+            .keys()
+            .copied()
+            .filter(|id| filter(*id))
+            .collect();
+
+        ids.into_iter()
+            .filter(|id| self.send_to(*id, event.clone()).is_ok())
+            .count()
+    }
+
+    fn update(&self, id: SessionId, event: &TelnetEvent) {
+        if let Some(entry) = self.lock().sessions.get_mut(&id) {
+            entry.info.on_receive(event);
+        }
+    }
+
+    fn unregister(&self, id: SessionId) {
+        self.lock().sessions.remove(&id);
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Registry> {
+        self.registry.lock().expect("Session registry poisoned")
+    }
+}
+
+/// A connection registered with a [SessionManager].
+///
+/// Iterating over a session yields events from the client, while keeping the manager's
+/// [SessionInfo] up to date.
+pub struct Session {
+    id: SessionId,
+    peer: SocketAddr,
+    reader: TelnetStream<TcpStream>,
+    manager: SessionManager,
+}
+
+impl Session {
+    /// This session's ID.
+    pub const fn id(&self) -> SessionId {
+        self.id
+    }
+
+    /// The remote address of this session.
+    pub const fn peer_addr(&self) -> SocketAddr {
+        self.peer
+    }
+
+    /// The manager this session is registered with.
+    pub const fn manager(&self) -> &SessionManager {
+        &self.manager
+    }
+
+    /// Current information about this session.
+    pub fn info(&self) -> SessionInfo {
+        self.manager
+            .session(self.id)
+            .expect("Bug: live session is not registered")
+    }
+
+    /// Send a TelnetEvent to the client.
+    pub fn send_event(&self, event: TelnetEvent) -> TellyResult {
+        self.manager.send_to(self.id, event)
+    }
+
+    /// Convenience function to send ASCII data to the client.
+    pub fn send_str(&self, data: &str) -> TellyResult {
+        self.send_data(data.as_bytes())
+    }
+
+    /// Convenience function to send data to the client.
+    pub fn send_data(&self, data: &[u8]) -> TellyResult {
+        self.send_event(TelnetEvent::Data(Vec::from(data)))
+    }
+}
+
+impl Iterator for Session {
+    type Item = TelnetEvent;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let event = self.reader.next()?;
+        self.manager.update(self.id, &event);
+        Some(event)
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.manager.unregister(self.id);
+    }
+}
+
+/// Handles a single [Session]. The session is unregistered when this returns.
+///
+/// This is implemented for all `Fn(Session)` closures.
+pub trait SessionHandler: Send + Sync + 'static {
+    /// Serve a newly connected session.
+    fn handle_session(&self, session: Session);
+}
+
+impl<F> SessionHandler for F
+where
+    F: Fn(Session) + Send + Sync + 'static,
+{
+    fn handle_session(&self, session: Session) {
+        self(session)
+    }
+}
+
+/// [ConnectionHandler] created by [SessionManager::service].
+pub struct SessionService<H: SessionHandler> {
+    manager: SessionManager,
+    handler: H,
+    on_error: ErrorReporter,
+}
+
+impl<H: SessionHandler> SessionService<H> {
+    /// Call `handler` with the client's address and the error whenever a connection can't be
+    /// registered, e.g. to log it. Errors are dropped by default.
+    pub fn set_error_handler(
+        &mut self,
+        handler: impl Fn(SocketAddr, &TellyError) + Send + Sync + 'static,
+    ) {
+        self.on_error = ErrorReporter::new(handler);
+    }
+}
+
+impl<H: SessionHandler> ConnectionHandler for SessionService<H> {
+    fn handle_connection(&self, stream: TelnetStream<TcpStream>, peer: SocketAddr) {
+        match self.manager.register(stream, peer) {
+            Ok(session) => self.handler.handle_session(session),
+            Err(err) => self.on_error.report(peer, &err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{server::TelnetServer, utils::NewlinePolicy};
+    use std::{
+        io::Read,
+        net::TcpListener,
+        sync::mpsc,
+        thread,
+        time::{Duration, Instant},
+    };
+
+    fn wait_until(condition: impl Fn() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !condition() {
+            assert!(Instant::now() < deadline, "Timed out");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    // Read `len` bytes of data, skipping other events.
+    fn read_data(stream: &mut TelnetStream<TcpStream>, len: usize) -> Vec<u8> {
+        let mut data = Vec::new();
+        while data.len() < len {
+            if let Some(TelnetEvent::Data(bytes)) = stream.next() {
+                data.extend(bytes);
+            }
+        }
+        data
+    }
+
+    #[test]
+    fn track_broadcast_and_kick() {
+        let mut server = TelnetServer::bind("127.0.0.1:0").unwrap();
+        server.set_negotiation_script("WILL ECHO, DO NAWS").unwrap();
+        let address = server.local_addr().unwrap();
+        let shutdown = server.shutdown_handle();
+
+        let manager = SessionManager::new();
+        let (ended_tx, ended_rx) = mpsc::channel();
+        let ended_tx = Mutex::new(ended_tx);
+        let service = manager.service(move |session: Session| {
+            let id = session.id();
+            for _ in session {}
+            ended_tx.lock().unwrap().send(id).unwrap();
+        });
+        let server_thread = thread::spawn(move || server.serve(service).unwrap());
+
+        let mut alice = TelnetStream::from_stream(TcpStream::connect(address).unwrap());
+        assert_eq!(alice.next(), Some(TelnetEvent::will(TelnetOption::Echo)));
+        assert_eq!(
+            alice.next(),
+            Some(TelnetEvent::r#do(TelnetOption::NegotiateAboutWindowSize))
+        );
+        alice.send_do(TelnetOption::Echo).unwrap();
+        alice
+            .send_will(TelnetOption::NegotiateAboutWindowSize)
+            .unwrap();
+        alice
+            .send_event(
+                TelnetSubnegotiation::NegotiateAboutWindowSize {
+                    width: 80,
+                    height: 24,
+                }
+                .into(),
+            )
+            .unwrap();
+        alice
+            .send_event(TelnetSubnegotiation::TerminalTypeResponse("xterm".into()).into())
+            .unwrap();
+
+        // Alice is registered first, as her session has received her terminal type before Bob
+        // connects
+        wait_until(|| {
+            manager
+                .sessions()
+                .first()
+                .is_some_and(|info| info.terminal_type.is_some())
+        });
+        let mut bob = TelnetStream::from_stream(TcpStream::connect(address).unwrap());
+        wait_until(|| manager.len() == 2);
+
+        let sessions = manager.sessions();
+        let info = &sessions[0];
+        assert_eq!(info.window_size, Some((80, 24)));
+        assert_eq!(info.terminal_type.as_deref(), Some("xterm"));
+        assert_eq!(info.local_options(), vec![TelnetOption::Echo]);
+        assert_eq!(
+            info.remote_options(),
+            vec![TelnetOption::NegotiateAboutWindowSize]
+        );
+        assert_eq!(sessions[1].window_size, None);
+        let (alice_id, bob_id) = (sessions[0].id, sessions[1].id);
+
+        // Everyone gets broadcasts, except who we leave out
+        assert_eq!(manager.broadcast(TelnetEvent::Data(b"all".to_vec())), 2);
+        assert_eq!(
+            manager.broadcast_except(alice_id, TelnetEvent::Data(b"bob".to_vec())),
+            1
+        );
+        assert_eq!(read_data(&mut alice, 3), b"all");
+        assert_eq!(read_data(&mut bob, 6), b"allbob");
+
+        manager.kick(bob_id).unwrap();
+        assert_eq!(ended_rx.recv().unwrap(), bob_id);
+        wait_until(|| manager.len() == 1);
+        assert!(matches!(
+            manager.send_to(bob_id, TelnetEvent::Data(vec![])),
+            Err(TellyError::NoSuchSession(id)) if id == bob_id
+        ));
+
+        shutdown.shutdown();
+        server_thread.join().unwrap();
+        assert!(manager.is_empty());
+    }
+
+    #[test]
+    fn writer_settings() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, peer) = listener.accept().unwrap();
+        let mut stream = TelnetStream::from_stream(server);
+        stream.set_newline_policy(Some(NewlinePolicy::Passthrough));
+
+        let manager = SessionManager::new();
+        let session = manager.register(stream, peer).unwrap();
+        session.send_data(b"a\nb").unwrap();
+        let mut received = [0; 3];
+        client.read_exact(&mut received).unwrap();
+        assert_eq!(&received, b"a\nb");
+    }
+
+    #[test]
+    fn shared_options() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, peer) = listener.accept().unwrap();
+        let mut client = TelnetStream::from_stream(client);
+
+        let manager = SessionManager::new();
+        let mut session = manager
+            .register(TelnetStream::from_stream(server), peer)
+            .unwrap();
+        session
+            .send_event(TelnetEvent::will(TelnetOption::BinaryTransmission))
+            .unwrap();
+        client.send_do(TelnetOption::BinaryTransmission).unwrap();
+        assert_eq!(
+            session.next(),
+            Some(TelnetEvent::r#do(TelnetOption::BinaryTransmission))
+        );
+        assert_eq!(
+            session.info().local_options(),
+            vec![TelnetOption::BinaryTransmission]
+        );
+
+        // The registry sees negotiations as soon as they're sent
+        session
+            .send_event(TelnetEvent::wont(TelnetOption::BinaryTransmission))
+            .unwrap();
+        assert!(session.info().local_options().is_empty());
+    }
+}
@@ -0,0 +1,610 @@
+//! Splitting a [TelnetStream] into halves that can be used from different threads, so that events
+//! can be sent while another thread is blocked receiving.
+//!
+//! Both halves share one record of negotiated options, so e.g. a DO BINARY sent through the
+//! writer switches the reader to binary as soon as the remote agrees.
+//!
+//! Splitting works on any blocking stream that implements [TryClone], which this crate provides
+//! for [TcpStream] and Unix sockets. With the `tokio` feature, `split_async` splits an async
+//! stream into an `AsyncTelnetReader` and an `AsyncTelnetWriter` instead.
+//!
+//! # Example
+//! ```no_run
+//! use std::{net::TcpStream, thread};
+//! use telly::{TelnetEvent, TelnetOption, TelnetStream};
+//!
+//! let stream = TelnetStream::from_stream(TcpStream::connect("127.0.0.1:23").unwrap());
+//! let (mut reader, writer) = stream.split().unwrap();
+//! // Refuse whatever the server asks for that we didn't
+//! reader.set_auto_reply(true);
+//!
+//! let sender = writer.clone();
+//! thread::spawn(move || {
+//!     for line in std::io::stdin().lines() {
+//!         sender.send_str(&(line.unwrap() + "\r\n")).unwrap();
+//!     }
+//! });
+//!
+//! writer.send_do(TelnetOption::SuppressGoAhead).unwrap();
+//! while let Some(event) = reader.next_event().unwrap() {
+//!     if let TelnetEvent::Data(data) = event {
+//!         print!("{}", String::from_utf8_lossy(&data));
+//!     }
+//! }
+//! ```
+use crate::{
+    errors::TellyResult, negotiation::OptionStates, TelnetAction, TelnetEvent, TelnetOption,
+    TelnetStream,
+};
+#[cfg(feature = "tokio")]
+use crate::{utils::NewlinePolicy, TelnetParser};
+#[cfg(feature = "tokio")]
+use bytes::BytesMut;
+use std::{
+    io::{self, Read, Write},
+    net::TcpStream,
+    sync::{Arc, Mutex, MutexGuard},
+};
+#[cfg(feature = "tokio")]
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf};
+
+/// Streams that can be duplicated into another handle to the same connection, like
+/// [TcpStream::try_clone].
+pub trait TryClone: Read + Write + Sized {
+    /// Create another handle to the same connection.
+    fn try_clone(&self) -> io::Result<Self>;
+}
+
+impl TryClone for TcpStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        TcpStream::try_clone(self)
+    }
+}
+
+#[cfg(unix)]
+impl TryClone for std::os::unix::net::UnixStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        std::os::unix::net::UnixStream::try_clone(self)
+    }
+}
+
+impl<S: TryClone> TelnetStream<S> {
+    /// Split into a reader and a writer over the same connection, sharing negotiated options.
+    /// Buffered writes are flushed first.
+    ///
+    /// The reader keeps everything already received, along with any keepalive, newline and
+    /// decryption settings. The writer gets the newline and write buffer settings, and the
+    /// cipher for what's sent (see [TelnetStream::set_encryption]).
+    ///
+    /// Whatever the reader sends itself, like keepalive probes, goes through the writer, so it's
+    /// ordered and encrypted along with the writer's events. The [layers](crate::layer) move to
+    /// the writer too, and what the reader receives passes up through them there.
+    pub fn split(mut self) -> TellyResult<(TelnetReader<S>, TelnetWriter<S>)> {
+        self.flush()?;
+
+        let mut writer = TelnetStream::from_stream(self.get_ref().try_clone()?);
+        writer.set_newline_policy(self.newline_policy());
+        writer.set_write_buffer(self.write_buffer());
+
+        let shared = Arc::new(Mutex::new(self.options().clone()));
+        writer.share_options(shared.clone());
+        self.share_options(shared.clone());
+
+        let writer = Arc::new(Mutex::new(writer));
+        self.send_through(writer.clone());
+        let writer = TelnetWriter {
+            stream: writer,
+            options: shared,
+        };
+        let reader = TelnetReader {
+            stream: self,
+            writer: writer.clone(),
+            auto_reply: false,
+        };
+        Ok((reader, writer))
+    }
+}
+
+/// The receiving half of a [TelnetStream], created by [TelnetStream::split].
+pub struct TelnetReader<S: TryClone> {
+    stream: TelnetStream<S>,
+    writer: TelnetWriter<S>,
+    auto_reply: bool,
+}
+
+impl<S: TryClone> TelnetReader<S> {
+    /// Reply to negotiations automatically, through the writer:
+    ///
+    /// * WILL or DO for an option we haven't asked for is refused with DONT or WONT.
+    /// * WONT or DONT for an option that was enabled is acknowledged with DONT or WONT.
+    ///
+    /// To accept an option, send DO or WILL first. Events are still returned after being
+    /// replied to.
+    pub fn set_auto_reply(&mut self, auto_reply: bool) {
+        self.auto_reply = auto_reply;
+    }
+
+    /// The writer this reader replies through.
+    pub const fn writer(&self) -> &TelnetWriter<S> {
+        &self.writer
+    }
+
+    /// The options negotiated over either half so far.
+    pub fn options(&self) -> OptionStates {
+        self.writer.options()
+    }
+
+    /// Receive the next event from remote. See [TelnetStream::next_event].
+    pub fn next_event(&mut self) -> TellyResult<Option<TelnetEvent>> {
+        let before = self.writer.options();
+        let event = self.stream.next_event()?;
+
+        if let Some(TelnetEvent::Negotiation { action, option }) = event {
+            if self.auto_reply {
+                let after = self.writer.options();
+                if let Some(reply) = reply(&before, &after, action, option) {
+                    self.writer.send_event(reply)?;
+                }
+            }
+        }
+        Ok(event)
+    }
+}
+
+// The automatic reply to a negotiation, given the options before and after receiving it
+fn reply(
+    before: &OptionStates,
+    after: &OptionStates,
+    action: TelnetAction,
+    option: TelnetOption,
+) -> Option<TelnetEvent> {
+    match action {
+        TelnetAction::Will if !after.is_remote_enabled(option) => Some(TelnetEvent::dont(option)),
+        TelnetAction::Do if !after.is_local_enabled(option) => Some(TelnetEvent::wont(option)),
+        TelnetAction::Wont if before.is_remote_enabled(option) => Some(TelnetEvent::dont(option)),
+        TelnetAction::Dont if before.is_local_enabled(option) => Some(TelnetEvent::wont(option)),
+        _ => None,
+    }
+}
+
+impl<S: TryClone> Iterator for TelnetReader<S> {
+    type Item = TelnetEvent;
+
+    /// Receive the next event from remote. Returns `None` at the end of the stream, or if an
+    /// error occurred. Use [TelnetReader::next_event] to see errors.
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_event().ok().flatten()
+    }
+}
+
+/// The sending half of a [TelnetStream], created by [TelnetStream::split].
+///
+/// Cloning a `TelnetWriter` yields another handle to the same writer, so events can be sent from
+/// several threads.
+pub struct TelnetWriter<S: TryClone> {
+    stream: Arc<Mutex<TelnetStream<S>>>,
+    options: Arc<Mutex<OptionStates>>,
+}
+
+impl<S: TryClone> Clone for TelnetWriter<S> {
+    fn clone(&self) -> Self {
+        Self {
+            stream: self.stream.clone(),
+            options: self.options.clone(),
+        }
+    }
+}
+
+impl<S: TryClone> TelnetWriter<S> {
+    fn lock(&self) -> MutexGuard<'_, TelnetStream<S>> {
+        self.stream.lock().expect("Telnet writer poisoned")
+    }
+
+    /// The options negotiated over either half so far.
+    pub fn options(&self) -> OptionStates {
+        self.options
+            .lock()
+            .expect("Shared options poisoned")
+            .clone()
+    }
+
+    /// Send an event to remote. See [TelnetStream::send_event].
+    pub fn send_event(&self, event: TelnetEvent) -> TellyResult {
+        self.lock().send_event(event)
+    }
+
+    /// Convenience function to send a WILL negotiation event
+    pub fn send_will(&self, option: TelnetOption) -> TellyResult {
+        self.send_event(TelnetEvent::will(option))
+    }
+
+    /// Convenience function to send a DO negotiation event
+    pub fn send_do(&self, option: TelnetOption) -> TellyResult {
+        self.send_event(TelnetEvent::r#do(option))
+    }
+
+    /// Convenience function to send a WONT negotiation event
+    pub fn send_wont(&self, option: TelnetOption) -> TellyResult {
+        self.send_event(TelnetEvent::wont(option))
+    }
+
+    /// Convenience function to send a DONT negotiation event
+    pub fn send_dont(&self, option: TelnetOption) -> TellyResult {
+        self.send_event(TelnetEvent::dont(option))
+    }
+
+    /// Send data to remote. See [TelnetStream::send_data].
+    pub fn send_data(&self, data: &[u8]) -> TellyResult {
+        self.lock().send_data(data)
+    }
+
+    /// Send a string to remote. See [TelnetStream::send_str].
+    pub fn send_str(&self, data: &str) -> TellyResult {
+        self.lock().send_str(data)
+    }
+
+    /// Send data to remote without NVT translation. See [TelnetStream::send_untranslated].
+    pub fn send_untranslated(&self, data: &[u8]) -> TellyResult {
+        self.lock().send_untranslated(data)
+    }
+
+    /// Whether what's sent is currently encrypted. See [TelnetStream::is_encrypting].
+    pub fn is_encrypting(&self) -> bool {
+        self.lock().is_encrypting()
+    }
+
+    /// Write any buffered events. See [TelnetStream::flush].
+    pub fn flush(&self) -> TellyResult {
+        self.lock().flush()
+    }
+}
+
+/// Split an async stream, like a tokio `TcpStream`, into a reader and a writer that share
+/// negotiated options, so events can be sent from other tasks while one awaits the next event.
+///
+/// The async halves track negotiations and switch to binary as [TelnetReader] and [TelnetWriter]
+/// do, but leave out the rest of [TelnetStream]: there's no newline translation, write
+/// buffering, keepalive, encryption or layers.
+#[cfg(feature = "tokio")]
+pub fn split_async<S: AsyncRead + AsyncWrite>(
+    stream: S,
+) -> (AsyncTelnetReader<S>, AsyncTelnetWriter<S>) {
+    let (reader, writer) = tokio::io::split(stream);
+    let writer = AsyncTelnetWriter {
+        stream: Arc::new(tokio::sync::Mutex::new(writer)),
+        options: Arc::new(Mutex::new(OptionStates::default())),
+    };
+    let reader = AsyncTelnetReader {
+        stream: reader,
+        rx_buffer: BytesMut::new(),
+        parser: TelnetParser::default(),
+        writer: writer.clone(),
+        auto_reply: false,
+    };
+    (reader, writer)
+}
+
+/// The receiving half of an async stream, created by [split_async].
+#[cfg(feature = "tokio")]
+pub struct AsyncTelnetReader<S> {
+    stream: ReadHalf<S>,
+    // Bytes read from stream, waiting to be parsed
+    rx_buffer: BytesMut,
+    parser: TelnetParser,
+    writer: AsyncTelnetWriter<S>,
+    auto_reply: bool,
+}
+
+#[cfg(feature = "tokio")]
+impl<S: AsyncRead + AsyncWrite> AsyncTelnetReader<S> {
+    /// Reply to negotiations automatically, through the writer. See
+    /// [TelnetReader::set_auto_reply].
+    pub fn set_auto_reply(&mut self, auto_reply: bool) {
+        self.auto_reply = auto_reply;
+    }
+
+    /// The writer this reader replies through.
+    pub const fn writer(&self) -> &AsyncTelnetWriter<S> {
+        &self.writer
+    }
+
+    /// The options negotiated over either half so far.
+    pub fn options(&self) -> OptionStates {
+        self.writer.options()
+    }
+
+    /// Receive the next event from remote. Returns `None` at the end of the stream.
+    ///
+    /// NUL padding is stripped from data, unless the remote has negotiated BINARY-TRANSMISSION.
+    pub async fn next_event(&mut self) -> TellyResult<Option<TelnetEvent>> {
+        loop {
+            // Re-checked per event, so that mode switches take effect at the exact byte
+            let before = self.writer.options();
+            self.parser
+                .set_binary(before.is_remote_enabled(TelnetOption::BinaryTransmission));
+            if let Some(event) = self.parser.next_event(&mut self.rx_buffer) {
+                self.writer.lock_options().on_receive(&event);
+                if let TelnetEvent::Negotiation { action, option } = event {
+                    if self.auto_reply {
+                        let after = self.writer.options();
+                        if let Some(reply) = reply(&before, &after, action, option) {
+                            self.writer.send_event(reply).await?;
+                        }
+                    }
+                }
+                return Ok(Some(event));
+            }
+
+            match self.stream.read_buf(&mut self.rx_buffer).await {
+                Ok(0) => return Ok(None),
+                Ok(_) => {}
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err.into()),
+            }
+        }
+    }
+}
+
+/// The sending half of an async stream, created by [split_async].
+///
+/// Cloning an `AsyncTelnetWriter` yields another handle to the same writer, so events can be
+/// sent from several tasks.
+#[cfg(feature = "tokio")]
+pub struct AsyncTelnetWriter<S> {
+    stream: Arc<tokio::sync::Mutex<WriteHalf<S>>>,
+    options: Arc<Mutex<OptionStates>>,
+}
+
+#[cfg(feature = "tokio")]
+impl<S> Clone for AsyncTelnetWriter<S> {
+    fn clone(&self) -> Self {
+        Self {
+            stream: self.stream.clone(),
+            options: self.options.clone(),
+        }
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl<S: AsyncRead + AsyncWrite> AsyncTelnetWriter<S> {
+    fn lock_options(&self) -> MutexGuard<'_, OptionStates> {
+        self.options.lock().expect("Shared options poisoned")
+    }
+
+    /// The options negotiated over either half so far.
+    pub fn options(&self) -> OptionStates {
+        self.lock_options().clone()
+    }
+
+    /// Send an event to remote. Data is NVT-encoded, unless we've negotiated
+    /// BINARY-TRANSMISSION.
+    pub async fn send_event(&self, event: TelnetEvent) -> TellyResult {
+        self.write_event(event, false).await
+    }
+
+    /// Convenience function to send a WILL negotiation event
+    pub async fn send_will(&self, option: TelnetOption) -> TellyResult {
+        self.send_event(TelnetEvent::will(option)).await
+    }
+
+    /// Convenience function to send a DO negotiation event
+    pub async fn send_do(&self, option: TelnetOption) -> TellyResult {
+        self.send_event(TelnetEvent::r#do(option)).await
+    }
+
+    /// Convenience function to send a WONT negotiation event
+    pub async fn send_wont(&self, option: TelnetOption) -> TellyResult {
+        self.send_event(TelnetEvent::wont(option)).await
+    }
+
+    /// Convenience function to send a DONT negotiation event
+    pub async fn send_dont(&self, option: TelnetOption) -> TellyResult {
+        self.send_event(TelnetEvent::dont(option)).await
+    }
+
+    /// Send data to remote. See [TelnetStream::send_data].
+    pub async fn send_data(&self, data: &[u8]) -> TellyResult {
+        self.send_event(TelnetEvent::Data(data.to_vec())).await
+    }
+
+    /// Send a string to remote. See [TelnetStream::send_str].
+    pub async fn send_str(&self, data: &str) -> TellyResult {
+        self.send_untranslated(data.as_bytes()).await
+    }
+
+    /// Send data to remote without NVT translation. See [TelnetStream::send_untranslated].
+    pub async fn send_untranslated(&self, data: &[u8]) -> TellyResult {
+        self.write_event(TelnetEvent::Data(data.to_vec()), true)
+            .await
+    }
+
+    // Encode and write an event, escaping only IACs if `untranslated`. Negotiations are recorded
+    // once written, as TelnetStream does.
+    async fn write_event(&self, event: TelnetEvent, untranslated: bool) -> TellyResult {
+        let mut stream = self.stream.lock().await;
+        let negotiation = matches!(event, TelnetEvent::Negotiation { .. }).then(|| event.clone());
+        let binary = self
+            .lock_options()
+            .is_local_enabled(TelnetOption::BinaryTransmission);
+        let bytes = if binary || untranslated {
+            event.into_binary_bytes()
+        } else {
+            event.into_bytes_with(NewlinePolicy::default())
+        };
+        stream.write_all(&bytes).await?;
+        stream.flush().await?;
+        if let Some(negotiation) = negotiation {
+            self.lock_options().on_send(&negotiation);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{encryption::Cipher, TelnetCommand, TelnetSubnegotiation};
+    use std::{net::TcpListener, thread};
+
+    fn connect() -> (TelnetStream<TcpStream>, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        (TelnetStream::from_stream(client), server)
+    }
+
+    #[test]
+    fn concurrent() {
+        let (client, server) = connect();
+        let mut server = TelnetStream::from_stream(server);
+        let (mut reader, writer) = client.split().unwrap();
+
+        // Send from several threads while the reader is blocked
+        let senders: Vec<_> = (0..4)
+            .map(|_| {
+                let writer = writer.clone();
+                thread::spawn(move || writer.send_event(TelnetCommand::Nop.into()))
+            })
+            .collect();
+        for sender in senders {
+            sender.join().unwrap().unwrap();
+        }
+        for _ in 0..4 {
+            assert_eq!(server.next(), Some(TelnetCommand::Nop.into()));
+        }
+
+        server.send_str("hello").unwrap();
+        assert_eq!(reader.next(), Some(TelnetEvent::Data(b"hello".to_vec())));
+    }
+
+    #[test]
+    fn shared_options() {
+        let (client, mut raw_server) = connect();
+        let mut server = TelnetStream::from_stream(raw_server.try_clone().unwrap());
+        let (mut reader, writer) = client.split().unwrap();
+
+        writer.send_do(TelnetOption::BinaryTransmission).unwrap();
+        assert_eq!(
+            server.next(),
+            Some(TelnetEvent::r#do(TelnetOption::BinaryTransmission))
+        );
+        // Binary data right behind the agreement, so the reader must switch at that byte
+        raw_server.write_all(b"\xff\xfb\x00a\r\0b").unwrap();
+
+        assert_eq!(
+            reader.next(),
+            Some(TelnetEvent::will(TelnetOption::BinaryTransmission))
+        );
+        assert_eq!(reader.next(), Some(TelnetEvent::Data(b"a\r\0b".to_vec())));
+        assert!(writer
+            .options()
+            .is_remote_enabled(TelnetOption::BinaryTransmission));
+        assert_eq!(reader.options(), writer.options());
+    }
+
+    // XORs every byte with a constant
+    struct Xor(u8);
+
+    impl Cipher for Xor {
+        fn encrypt(&mut self, bytes: &mut [u8]) {
+            bytes.iter_mut().for_each(|byte| *byte ^= self.0);
+        }
+
+        fn decrypt(&mut self, bytes: &mut [u8]) {
+            self.encrypt(bytes);
+        }
+    }
+
+    #[test]
+    fn encryption() {
+        let (mut client, mut server) = connect();
+        client.set_encryption(Some(Box::new(Xor(0x55))));
+        let (_reader, writer) = client.split().unwrap();
+        assert!(!writer.is_encrypting());
+
+        writer
+            .send_event(TelnetSubnegotiation::EncryptionStart(Vec::new()).into())
+            .unwrap();
+        assert!(writer.is_encrypting());
+        writer.send_str("secret").unwrap();
+
+        // START goes out in the clear, and everything after it is encrypted
+        let mut expected = b"\xff\xfa\x26\x03\xff\xf0".to_vec();
+        expected.extend(b"secret".map(|byte| byte ^ 0x55));
+        let mut sent = vec![0; expected.len()];
+        server.read_exact(&mut sent).unwrap();
+        assert_eq!(sent, expected);
+    }
+
+    #[test]
+    fn auto_reply() {
+        let (client, server) = connect();
+        let mut server = TelnetStream::from_stream(server);
+        let (mut reader, writer) = client.split().unwrap();
+        reader.set_auto_reply(true);
+
+        writer.send_will(TelnetOption::SuppressGoAhead).unwrap();
+        server.send_will(TelnetOption::Echo).unwrap();
+        server.send_do(TelnetOption::TerminalType).unwrap();
+        server.send_do(TelnetOption::SuppressGoAhead).unwrap();
+        server.send_dont(TelnetOption::SuppressGoAhead).unwrap();
+        server.send_str("done").unwrap();
+
+        while reader.next() != Some(TelnetEvent::Data(b"done".to_vec())) {}
+        let expected = [
+            TelnetEvent::will(TelnetOption::SuppressGoAhead),
+            TelnetEvent::dont(TelnetOption::Echo),
+            TelnetEvent::wont(TelnetOption::TerminalType),
+            // Agreed to without a reply, then acknowledged when disabled
+            TelnetEvent::wont(TelnetOption::SuppressGoAhead),
+        ];
+        for expected in expected {
+            assert_eq!(server.next(), Some(expected));
+        }
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn split_async() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let (client, mut server) = tokio::io::duplex(64);
+        let (mut reader, writer) = super::split_async(client);
+        reader.set_auto_reply(true);
+
+        let sender = writer.clone();
+        tokio::spawn(async move { sender.send_do(TelnetOption::BinaryTransmission).await })
+            .await
+            .unwrap()
+            .unwrap();
+        let mut sent = [0; 3];
+        server.read_exact(&mut sent).await.unwrap();
+        assert_eq!(sent, *b"\xff\xfd\x00");
+
+        // Binary data right behind the agreement, then an option we didn't ask for
+        server
+            .write_all(b"\xff\xfb\x00a\r\0b\xff\xfb\x01")
+            .await
+            .unwrap();
+        assert_eq!(
+            reader.next_event().await.unwrap(),
+            Some(TelnetEvent::will(TelnetOption::BinaryTransmission))
+        );
+        assert_eq!(
+            reader.next_event().await.unwrap(),
+            Some(TelnetEvent::Data(b"a\r\0b".to_vec()))
+        );
+        assert_eq!(
+            reader.next_event().await.unwrap(),
+            Some(TelnetEvent::will(TelnetOption::Echo))
+        );
+        server.read_exact(&mut sent).await.unwrap();
+        assert_eq!(sent, *b"\xff\xfe\x01");
+        assert!(writer
+            .options()
+            .is_remote_enabled(TelnetOption::BinaryTransmission));
+
+        drop(server);
+        assert_eq!(reader.next_event().await.unwrap(), None);
+    }
+}
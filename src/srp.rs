@@ -0,0 +1,496 @@
+//! Password authentication with the Secure Remote Password protocol, for the AUTHENTICATION
+//! option ([RFC2944](https://www.rfc-editor.org/rfc/rfc2944.html)), behind the `srp` feature.
+//!
+//! Servers store an [SrpVerifier] for each user instead of their password, and neither the
+//! password nor anything that could be used to guess it offline crosses the connection. The
+//! server also proves that it knows the verifier, so clients can't be fooled by impostors.
+//!
+//! The 2048-bit group from [RFC5054](https://www.rfc-editor.org/rfc/rfc5054.html#appendix-A)
+//! is used, and clients refuse any other.
+//!
+//! # Example
+//! ```no_run
+//! use std::net::TcpStream;
+//! use telly::{authentication::Authenticator, srp::SrpClient, TelnetStream};
+//!
+//! let mut stream = TelnetStream::from_stream(TcpStream::connect("127.0.0.1:23").unwrap());
+//! let mut authenticators: Vec<Box<dyn Authenticator>> =
+//!     vec![Box::new(SrpClient::new("hunter2"))];
+//! stream
+//!     .authenticate_to_server(Some("margaret"), &mut authenticators)
+//!     .unwrap();
+//! ```
+use crate::{
+    authentication::{
+        AuthenticationModifiers, AuthenticationStep, AuthenticationType, AuthenticationTypePair,
+        Authenticator,
+    },
+    errors::{TellyError, TellyResult},
+};
+use num_bigint::BigUint;
+use num_traits::Zero;
+use rand::RngCore;
+use sha1::{Digest, Sha1};
+use std::mem;
+
+// The first byte of SRP data
+const AUTH: u8 = 0;
+const REJECT: u8 = 1;
+const ACCEPT: u8 = 2;
+const CHALLENGE: u8 = 3;
+const RESPONSE: u8 = 4;
+const EXP: u8 = 8;
+const PARAMS: u8 = 9;
+
+// The 2048-bit group from RFC5054, appendix A
+const MODULUS: &str = "\
+    AC6BDB41324A9A9BF166DE5E1389582FAF72B6651987EE07FC3192943DB56050A37329CBB4A099ED8193E075\
+    7767A13DD52312AB4B03310DCD7F48A9DA04FD50E8083969EDB767B0CF6095179A163AB3661A05FBD5FAAAE8\
+    2918A9962F0B93B855F97993EC975EEAA80D740ADBF4FF747359D041D5C33EA71D281E446B14773BCA97B43A\
+    23FB801676BD207A436C6481F1D2B9078717461A5B9D32E688F87748544523B524B0D57D5EA77A2775D2ECFA\
+    032CFBDBF52FB3786160279004E57AE6AF874E7303CE53299CCC041C7BC308D82A5698F3A8D0C38271AE35F8\
+    E9DBFBB694B5C803D89F7AE435DE236D525F54759B65E372FCD68EF20FA7111F9E4AFF73";
+const GENERATOR: u32 = 2;
+
+const SRP: AuthenticationTypePair =
+    AuthenticationTypePair::new(AuthenticationType::Srp, AuthenticationModifiers(0));
+
+struct Group {
+    modulus: BigUint,
+    generator: BigUint,
+}
+
+impl Group {
+    fn get() -> Self {
+        Self {
+            modulus: BigUint::parse_bytes(MODULUS.as_bytes(), 16).expect("Bad SRP modulus"),
+            generator: GENERATOR.into(),
+        }
+    }
+
+    fn power(&self, exponent: &BigUint) -> BigUint {
+        self.generator.modpow(exponent, &self.modulus)
+    }
+
+    // A secret exponent
+    fn random_exponent() -> BigUint {
+        let mut bytes = [0; 32];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        BigUint::from_bytes_be(&bytes)
+    }
+
+    // K, u, M1 and M2 per RFC2945, given the shared secret S
+    fn proofs(
+        &self,
+        name: &str,
+        salt: &[u8],
+        a: &BigUint,
+        b: &BigUint,
+        secret: &BigUint,
+    ) -> ([u8; 20], [u8; 20]) {
+        let key = interleave(secret);
+        let (modulus_hash, generator_hash) = (
+            sha1(&[&self.modulus.to_bytes_be()]),
+            sha1(&[&self.generator.to_bytes_be()]),
+        );
+        let group_hash: Vec<u8> = modulus_hash
+            .iter()
+            .zip(generator_hash)
+            .map(|(n, g)| n ^ g)
+            .collect();
+        let a = a.to_bytes_be();
+        let client = sha1(&[
+            &group_hash,
+            &sha1(&[name.as_bytes()]),
+            salt,
+            &a,
+            &b.to_bytes_be(),
+            &key,
+        ]);
+        let server = sha1(&[&a, &client, &key]);
+        (client, server)
+    }
+}
+
+fn sha1(parts: &[&[u8]]) -> [u8; 20] {
+    let mut hasher = Sha1::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize().into()
+}
+
+// The session key, per SHA_Interleave in RFC2945
+fn interleave(secret: &BigUint) -> Vec<u8> {
+    let bytes = secret.to_bytes_be();
+    let bytes = &bytes[bytes.len() % 2..];
+    let even: Vec<u8> = bytes.iter().step_by(2).copied().collect();
+    let odd: Vec<u8> = bytes.iter().skip(1).step_by(2).copied().collect();
+    sha1(&[&even])
+        .into_iter()
+        .zip(sha1(&[&odd]))
+        .flat_map(|(g, h)| [g, h])
+        .collect()
+}
+
+// u: the first 32 bits of H(B)
+fn scrambler(b: &BigUint) -> BigUint {
+    BigUint::from_bytes_be(&sha1(&[&b.to_bytes_be()])[..4])
+}
+
+// x = H(s | H(U | ":" | p))
+fn private_key(name: &str, password: &str, salt: &[u8]) -> BigUint {
+    let inner = sha1(&[name.as_bytes(), b":", password.as_bytes()]);
+    BigUint::from_bytes_be(&sha1(&[salt, &inner]))
+}
+
+// Compare proofs without revealing how much matched
+fn proofs_match(received: &[u8], expected: &[u8]) -> bool {
+    received.len() == expected.len()
+        && received
+            .iter()
+            .zip(expected)
+            .fold(0, |difference, (a, b)| difference | (a ^ b))
+            == 0
+}
+
+// n, g and s, each preceded by its length as two bytes
+fn encode_params(fields: [&[u8]; 3]) -> Vec<u8> {
+    let mut data = vec![PARAMS];
+    for field in fields {
+        data.extend((field.len() as u16).to_be_bytes());
+        data.extend(field);
+    }
+    data
+}
+
+fn decode_params(mut data: &[u8]) -> TellyResult<[Vec<u8>; 3]> {
+    let mut field = || -> TellyResult<Vec<u8>> {
+        let error = || TellyError::DecodeError("Malformed SRP parameters".into());
+        let (length, rest) = data.split_first_chunk::<2>().ok_or_else(error)?;
+        let length = usize::from(u16::from_be_bytes(*length));
+        let field = rest.get(..length).ok_or_else(error)?.to_vec();
+        data = &rest[length..];
+        Ok(field)
+    };
+    Ok([field()?, field()?, field()?])
+}
+
+fn unexpected() -> TellyError {
+    TellyError::DecodeError("Unexpected SRP message".into())
+}
+
+/// What a server stores to authenticate a user, in place of their password.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SrpVerifier {
+    salt: Vec<u8>,
+    verifier: Vec<u8>,
+}
+
+impl SrpVerifier {
+    /// Compute the verifier for `name` logging in with `password`, with a random salt.
+    pub fn new(name: &str, password: &str) -> Self {
+        let mut salt = vec![0; 16];
+        rand::thread_rng().fill_bytes(&mut salt);
+        Self::with_salt(name, password, salt)
+    }
+
+    /// Compute the verifier for `name` logging in with `password`, with the given salt.
+    pub fn with_salt(name: &str, password: &str, salt: Vec<u8>) -> Self {
+        let verifier = Group::get().power(&private_key(name, password, &salt));
+        Self {
+            salt,
+            verifier: verifier.to_bytes_be(),
+        }
+    }
+
+    /// Restore a verifier from its stored salt and value.
+    pub const fn from_parts(salt: Vec<u8>, verifier: Vec<u8>) -> Self {
+        Self { salt, verifier }
+    }
+
+    /// The salt, to be stored.
+    pub fn salt(&self) -> &[u8] {
+        &self.salt
+    }
+
+    /// The verifier, to be stored.
+    pub fn verifier(&self) -> &[u8] {
+        &self.verifier
+    }
+}
+
+enum ServerState {
+    // Waiting for AUTH
+    Start,
+    // Sent PARAMS, waiting for EXP
+    Parameters(SrpVerifier),
+    // Sent CHALLENGE, waiting for RESPONSE
+    Challenged {
+        client_proof: [u8; 20],
+        server_proof: [u8; 20],
+    },
+}
+
+/// The server side of SRP. Looks up the verifier of the user the client names with a
+/// callback, rejecting the client if there's none.
+pub struct SrpServer<F: FnMut(&str) -> Option<SrpVerifier>> {
+    lookup: F,
+    name: String,
+    state: ServerState,
+}
+
+impl<F: FnMut(&str) -> Option<SrpVerifier>> SrpServer<F> {
+    /// Authenticate clients using the verifiers returned by `lookup` for each name.
+    pub const fn new(lookup: F) -> Self {
+        Self {
+            lookup,
+            name: String::new(),
+            state: ServerState::Start,
+        }
+    }
+}
+
+impl<F: FnMut(&str) -> Option<SrpVerifier>> Authenticator for SrpServer<F> {
+    fn type_pair(&self) -> AuthenticationTypePair {
+        SRP
+    }
+
+    fn reset(&mut self) {
+        self.state = ServerState::Start;
+    }
+
+    fn set_name(&mut self, name: &str) {
+        self.name = name.into();
+    }
+
+    fn step(&mut self, data: &[u8]) -> TellyResult<AuthenticationStep> {
+        let group = Group::get();
+        let (&code, data) = data.split_first().ok_or_else(unexpected)?;
+
+        match (mem::replace(&mut self.state, ServerState::Start), code) {
+            (_, AUTH) => {
+                let Some(verifier) = (self.lookup)(&self.name) else {
+                    return Ok(AuthenticationStep::Rejected(vec![REJECT]));
+                };
+                let reply = encode_params([
+                    &group.modulus.to_bytes_be(),
+                    &group.generator.to_bytes_be(),
+                    &verifier.salt,
+                ]);
+                self.state = ServerState::Parameters(verifier);
+                Ok(AuthenticationStep::Continue(reply))
+            }
+            (ServerState::Parameters(verifier), EXP) => {
+                let a = BigUint::from_bytes_be(data);
+                if (&a % &group.modulus).is_zero() {
+                    return Ok(AuthenticationStep::Rejected(vec![REJECT]));
+                }
+
+                // B = v + g^b, where u must not be zero
+                let v = BigUint::from_bytes_be(&verifier.verifier);
+                let (secret, b) = loop {
+                    let secret = Group::random_exponent();
+                    let b = (&v + group.power(&secret)) % &group.modulus;
+                    if !scrambler(&b).is_zero() {
+                        break (secret, b);
+                    }
+                };
+
+                // S = (A * v^u)^b
+                let shared = (a.clone() * v.modpow(&scrambler(&b), &group.modulus))
+                    .modpow(&secret, &group.modulus);
+                let (client_proof, server_proof) =
+                    group.proofs(&self.name, &verifier.salt, &a, &b, &shared);
+                self.state = ServerState::Challenged {
+                    client_proof,
+                    server_proof,
+                };
+
+                let mut reply = vec![CHALLENGE];
+                reply.extend(b.to_bytes_be());
+                Ok(AuthenticationStep::Continue(reply))
+            }
+            (
+                ServerState::Challenged {
+                    client_proof,
+                    server_proof,
+                },
+                RESPONSE,
+            ) => {
+                if proofs_match(data, &client_proof) {
+                    let mut reply = vec![ACCEPT];
+                    reply.extend(server_proof);
+                    Ok(AuthenticationStep::Accepted(reply))
+                } else {
+                    Ok(AuthenticationStep::Rejected(vec![REJECT]))
+                }
+            }
+            _ => Err(unexpected()),
+        }
+    }
+}
+
+enum ClientState {
+    // Sent AUTH, waiting for PARAMS
+    Start,
+    // Sent EXP, waiting for CHALLENGE
+    Exponent {
+        salt: Vec<u8>,
+        secret: BigUint,
+        a: BigUint,
+    },
+    // Sent RESPONSE, waiting for ACCEPT
+    Responded {
+        server_proof: [u8; 20],
+    },
+}
+
+/// The client side of SRP. The name to log in as is the one given to
+/// [TelnetStream::authenticate_to_server](crate::TelnetStream::authenticate_to_server).
+pub struct SrpClient {
+    password: String,
+    name: String,
+    state: ClientState,
+}
+
+impl SrpClient {
+    /// Authenticate with `password`.
+    pub fn new(password: &str) -> Self {
+        Self {
+            password: password.into(),
+            name: String::new(),
+            state: ClientState::Start,
+        }
+    }
+}
+
+impl Authenticator for SrpClient {
+    fn type_pair(&self) -> AuthenticationTypePair {
+        SRP
+    }
+
+    fn reset(&mut self) {
+        self.state = ClientState::Start;
+    }
+
+    fn set_name(&mut self, name: &str) {
+        self.name = name.into();
+    }
+
+    fn start(&mut self) -> TellyResult<Vec<u8>> {
+        Ok(vec![AUTH])
+    }
+
+    fn step(&mut self, data: &[u8]) -> TellyResult<AuthenticationStep> {
+        let group = Group::get();
+        let failed = |reason: &str| Err(TellyError::AuthenticationFailed(reason.into()));
+        let (&code, data) = data.split_first().ok_or_else(unexpected)?;
+
+        match (mem::replace(&mut self.state, ClientState::Start), code) {
+            (_, REJECT) => Ok(AuthenticationStep::Rejected(Vec::new())),
+            (ClientState::Start, PARAMS) => {
+                let [modulus, generator, salt] = decode_params(data)?;
+                if BigUint::from_bytes_be(&modulus) != group.modulus
+                    || BigUint::from_bytes_be(&generator) != group.generator
+                {
+                    return failed("Server proposed an unknown SRP group");
+                }
+
+                let secret = Group::random_exponent();
+                let a = group.power(&secret);
+                let mut reply = vec![EXP];
+                reply.extend(a.to_bytes_be());
+                self.state = ClientState::Exponent { salt, secret, a };
+                Ok(AuthenticationStep::Continue(reply))
+            }
+            (ClientState::Exponent { salt, secret, a }, CHALLENGE) => {
+                let b = BigUint::from_bytes_be(data) % &group.modulus;
+                let u = scrambler(&b);
+                if b.is_zero() || u.is_zero() {
+                    return failed("Server sent an unsafe SRP challenge");
+                }
+
+                // S = (B - g^x)^(a + u * x)
+                let x = private_key(&self.name, &self.password, &salt);
+                let base = (&b + &group.modulus - group.power(&x)) % &group.modulus;
+                let shared = base.modpow(&(secret + u * x), &group.modulus);
+                let (client_proof, server_proof) = group.proofs(&self.name, &salt, &a, &b, &shared);
+                self.state = ClientState::Responded { server_proof };
+
+                let mut reply = vec![RESPONSE];
+                reply.extend(client_proof);
+                Ok(AuthenticationStep::Continue(reply))
+            }
+            (ClientState::Responded { server_proof }, ACCEPT) => {
+                if proofs_match(data, &server_proof) {
+                    Ok(AuthenticationStep::Accepted(Vec::new()))
+                } else {
+                    failed("Server could not prove it knows the SRP verifier")
+                }
+            }
+            _ => Err(unexpected()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{authentication::Authenticated, TelnetStream};
+    use std::{
+        net::{TcpListener, TcpStream},
+        thread,
+    };
+
+    fn authenticate(
+        name: &str,
+        password: &str,
+    ) -> (TellyResult<Authenticated>, TellyResult<Authenticated>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+
+        let server = thread::spawn(move || {
+            let verifier = SrpVerifier::new("margaret", "hunter2");
+            let lookup = move |name: &str| (name == "margaret").then(|| verifier.clone());
+            let mut authenticators: Vec<Box<dyn Authenticator>> =
+                vec![Box::new(SrpServer::new(lookup))];
+            TelnetStream::from_stream(server).authenticate_client(&mut authenticators)
+        });
+
+        let mut authenticators: Vec<Box<dyn Authenticator>> =
+            vec![Box::new(SrpClient::new(password))];
+        let client = TelnetStream::from_stream(client)
+            .authenticate_to_server(Some(name), &mut authenticators);
+        (client, server.join().unwrap())
+    }
+
+    #[test]
+    fn verifier() {
+        let verifier = SrpVerifier::with_salt("margaret", "hunter2", b"salt".to_vec());
+        assert_eq!(verifier.salt(), b"salt");
+        assert_eq!(
+            verifier.verifier()[..16],
+            [
+                0xa8, 0x4b, 0x14, 0x94, 0x82, 0xdc, 0xa7, 0xba, 0x8a, 0x03, 0xdd, 0x8d, 0xed, 0xeb,
+                0x61, 0x81
+            ]
+        );
+        assert_ne!(SrpVerifier::new("margaret", "hunter2").salt(), b"salt");
+    }
+
+    #[test]
+    fn srp() {
+        let (client, server) = authenticate("margaret", "hunter2");
+        let (client, server) = (client.unwrap(), server.unwrap());
+        assert_eq!((client.pair, server.pair), (SRP, SRP));
+        assert_eq!(server.name.as_deref(), Some("margaret"));
+
+        for (name, password) in [("margaret", "hunter3"), ("mallory", "hunter2")] {
+            let (client, server) = authenticate(name, password);
+            assert!(matches!(client, Err(TellyError::AuthenticationFailed(_))));
+            assert!(matches!(server, Err(TellyError::AuthenticationFailed(_))));
+        }
+    }
+}
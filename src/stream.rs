@@ -1,12 +1,23 @@
 use crate::{
+    constants::{IAC, SE},
+    encryption::{self, Cipher, CipherState},
     errors::{TellyError, TellyResult},
-    utils::TellyIterTraits,
+    keepalive::{KeepAlive, KeepAliveAction, KeepAliveState},
+    layer::{Layer, Outcome},
+    negotiation::OptionStates,
+    utils::{NewlineDecoder, NewlinePolicy},
     TelnetEvent, TelnetOption, TelnetParser,
 };
 use bytes::{BufMut, BytesMut};
 use std::{
-    io::{Read, Write},
-    iter::Iterator,
+    collections::VecDeque,
+    io::{ErrorKind, IoSlice, Read, Write},
+    iter::{self, Iterator},
+    mem,
+    net::{Shutdown, SocketAddr, TcpStream},
+    slice,
+    sync::{Arc, Mutex},
+    time::Instant,
 };
 
 /// Abstraction representing a Telnet server or client. This is a stateful wrapper around
@@ -19,8 +30,47 @@ where
     stream: StreamType,
     // Bytes read from stream, waiting to be processed
     rx_buffer: BytesMut,
+    // Bytes sent, waiting to be written to the stream
+    tx_buffer: Vec<u8>,
+    // Size the write buffer may grow to before it's written, if buffering is enabled
+    tx_capacity: Option<usize>,
 
     parser: TelnetParser,
+    // Options negotiated so far
+    options: OptionStates,
+    // Options shared with the other half of a split stream, which `options` is kept in sync with
+    shared_options: Option<Arc<Mutex<OptionStates>>>,
+    // Idle detection, if enabled
+    keepalive: Option<KeepAliveState>,
+    // Line ending translation of received data, if enabled
+    newlines: Option<NewlineDecoder>,
+    // Event to yield after the data that was held back before it
+    pending_event: Option<TelnetEvent>,
+    // ENCRYPT ciphers for what we send and what we receive
+    encryption: CipherState,
+    decryption: CipherState,
+    // Bytes read from stream while decrypting, not yet decrypted
+    rx_ciphertext: Vec<u8>,
+    // Middleware, top (nearest the application) first
+    layers: Vec<Box<dyn Layer>>,
+    // Events that came up out of the layers, waiting to be returned
+    layered_events: VecDeque<TelnetEvent>,
+    // The writing half, if this is the reading half of a split stream. What this half sends,
+    // like keepalive probes, goes through it, so it's encrypted in order with everything else,
+    // and what it receives passes through the layers there
+    sender: Option<Arc<Mutex<TelnetStream<StreamType>>>>,
+}
+
+// Settings of a stream, as opposed to the state of its connection, for carrying over to a new
+// stream over the same connection
+#[cfg(feature = "tls")]
+pub(crate) struct Settings {
+    newline_policy: Option<NewlinePolicy>,
+    write_buffer: Option<usize>,
+    keepalive: Option<KeepAlive>,
+    layers: Vec<Box<dyn Layer>>,
+    encryption: CipherState,
+    decryption: CipherState,
 }
 
 impl<StreamType: Write + Read> TelnetStream<StreamType> {
@@ -30,14 +80,272 @@ impl<StreamType: Write + Read> TelnetStream<StreamType> {
         Self {
             stream,
             rx_buffer: BytesMut::with_capacity(CAPACITY),
+            tx_buffer: Vec::new(),
+            tx_capacity: None,
             parser: TelnetParser::default(),
+            options: OptionStates::default(),
+            shared_options: None,
+            keepalive: None,
+            newlines: None,
+            pending_event: None,
+            encryption: CipherState::default(),
+            decryption: CipherState::default(),
+            rx_ciphertext: Vec::new(),
+            layers: Vec::new(),
+            layered_events: VecDeque::new(),
+            sender: None,
+        }
+    }
+
+    /// Translate line endings according to `policy`, in both directions. See [NewlinePolicy] for
+    /// how.
+    ///
+    /// With no policy (the default), data is sent per [NewlinePolicy::Strict], and received
+    /// data is yielded with its line endings untouched, minus any NUL padding.
+    ///
+    /// Translation is suspended in each direction while BINARY-TRANSMISSION is in effect.
+    pub fn set_newline_policy(&mut self, policy: Option<NewlinePolicy>) {
+        self.newlines = policy.map(NewlineDecoder::new);
+    }
+
+    /// The line ending translation set with [TelnetStream::set_newline_policy].
+    pub fn newline_policy(&self) -> Option<NewlinePolicy> {
+        self.newlines.as_ref().map(NewlineDecoder::policy)
+    }
+
+    /// Buffer sent events, writing them once `capacity` bytes are pending, on
+    /// [TelnetStream::flush], or before blocking to receive. With no capacity (the default),
+    /// every event is written and flushed as it's sent.
+    ///
+    /// Buffering batches many small sends, like echoed characters, into fewer writes. Pending
+    /// bytes are not written when the stream is dropped, so call [TelnetStream::flush] first.
+    pub fn set_write_buffer(&mut self, capacity: Option<usize>) {
+        self.tx_capacity = capacity;
+        if let Some(capacity) = capacity {
+            self.tx_buffer.reserve(capacity);
         }
     }
 
-    /// Send a TelnetEvent to remote
+    /// Enable or disable keepalive probes. See [crate::keepalive] for details.
+    ///
+    /// The underlying stream must have a read timeout for idleness to be noticed.
+    pub fn set_keepalive(&mut self, keepalive: Option<KeepAlive>) {
+        self.keepalive = keepalive.map(KeepAliveState::new);
+    }
+
+    /// The keepalive configuration set with [TelnetStream::set_keepalive].
+    pub fn keepalive(&self) -> Option<KeepAlive> {
+        self.keepalive.as_ref().map(KeepAliveState::config)
+    }
+
+    /// Set the cipher for what we send, agreed on through the ENCRYPT option. It's switched on
+    /// after we send [TelnetSubnegotiation::EncryptionStart], and off after we send
+    /// [TelnetSubnegotiation::EncryptionEnd]. See [crate::encryption].
+    ///
+    /// [TelnetSubnegotiation::EncryptionStart]: crate::TelnetSubnegotiation::EncryptionStart
+    /// [TelnetSubnegotiation::EncryptionEnd]: crate::TelnetSubnegotiation::EncryptionEnd
+    pub fn set_encryption(&mut self, cipher: Option<Box<dyn Cipher>>) {
+        self.encryption.set(cipher);
+    }
+
+    /// Set the cipher for what we receive, switched on and off as the remote sends START and
+    /// END. See [TelnetStream::set_encryption].
+    pub fn set_decryption(&mut self, cipher: Option<Box<dyn Cipher>>) {
+        self.decryption.set(cipher);
+    }
+
+    /// Whether what we send is currently encrypted.
+    pub const fn is_encrypting(&self) -> bool {
+        self.encryption.is_active()
+    }
+
+    /// Whether what we receive is currently decrypted.
+    pub const fn is_decrypting(&self) -> bool {
+        self.decryption.is_active()
+    }
+
+    /// Add a layer of middleware on top of those already added, so that it's the first to see
+    /// events sent, and the last to see events received. See [crate::layer].
+    pub fn push_layer(&mut self, layer: impl Layer + 'static) {
+        self.layers.insert(0, Box::new(layer));
+    }
+
+    /// Reference to the underlying stream.
+    pub const fn get_ref(&self) -> &StreamType {
+        &self.stream
+    }
+
+    /// Mutable reference to the underlying stream. Reading or writing through it bypasses the
+    /// Telnet state kept here, such as negotiated options.
+    pub fn get_mut(&mut self) -> &mut StreamType {
+        &mut self.stream
+    }
+
+    /// Unwrap the underlying stream. Events and bytes received but not yet returned are lost, so
+    /// use [TelnetStream::into_parts] to keep them.
+    pub fn into_inner(self) -> StreamType {
+        self.stream
+    }
+
+    /// Unwrap the underlying stream, along with the events that were received but not yet
+    /// returned, and the bytes after them that were received but not yet parsed, so the
+    /// connection can be handed off to another protocol without losing data.
+    ///
+    /// The events are those already passed up through the layers, followed by any data held
+    /// back by newline translation and the event held back with it, which haven't been through
+    /// the layers. Received bytes that were not yet decrypted are returned as they are.
+    ///
+    /// Bytes pending in the write buffer are discarded, so call [TelnetStream::flush] first.
+    pub fn into_parts(mut self) -> (StreamType, Vec<TelnetEvent>, Vec<u8>) {
+        let mut events = Vec::from(mem::take(&mut self.layered_events));
+        if let Some(byte) = self.newlines.as_mut().and_then(NewlineDecoder::finish) {
+            events.push(TelnetEvent::Data(vec![byte]));
+        }
+        events.extend(self.pending_event.take());
+
+        let mut leftover = self.rx_buffer.to_vec();
+        leftover.extend(self.rx_ciphertext);
+        (self.stream, events, leftover)
+    }
+
+    /// The options negotiated over this stream so far.
+    pub fn options(&self) -> &OptionStates {
+        &self.options
+    }
+
+    // Mutable access to the option states, for tests that fake the remote's answers.
+    #[cfg(test)]
+    pub(crate) fn options_mut(&mut self) -> &mut OptionStates {
+        &mut self.options
+    }
+
+    // Share option states with another stream over the same connection, such as the other half
+    // of a split stream.
+    pub(crate) fn share_options(&mut self, shared: Arc<Mutex<OptionStates>>) {
+        self.shared_options = Some(shared);
+    }
+
+    // Pick up negotiations made over the other half of a split stream.
+    fn sync_options(&mut self) {
+        if let Some(shared) = &self.shared_options {
+            self.options
+                .clone_from(&shared.lock().expect("Shared options poisoned"));
+        }
+    }
+
+    fn record_send(&mut self, event: &TelnetEvent) {
+        self.options.on_send(event);
+        if let Some(shared) = &self.shared_options {
+            shared
+                .lock()
+                .expect("Shared options poisoned")
+                .on_send(event);
+        }
+    }
+
+    fn record_receive(&mut self, event: &TelnetEvent) {
+        self.options.on_receive(event);
+        if let Some(shared) = &self.shared_options {
+            shared
+                .lock()
+                .expect("Shared options poisoned")
+                .on_receive(event);
+        }
+    }
+
+    // Size of the write buffer, if enabled.
+    pub(crate) const fn write_buffer(&self) -> Option<usize> {
+        self.tx_capacity
+    }
+
+    // Take the settings to carry over to another stream, leaving this one with the defaults
+    #[cfg(feature = "tls")]
+    pub(crate) fn take_settings(&mut self) -> Settings {
+        Settings {
+            newline_policy: self.newline_policy(),
+            write_buffer: self.tx_capacity,
+            keepalive: self.keepalive(),
+            layers: mem::take(&mut self.layers),
+            encryption: mem::take(&mut self.encryption),
+            decryption: mem::take(&mut self.decryption),
+        }
+    }
+
+    // Apply settings taken from another stream
+    #[cfg(feature = "tls")]
+    pub(crate) fn apply_settings(&mut self, settings: Settings) {
+        self.set_newline_policy(settings.newline_policy);
+        self.set_write_buffer(settings.write_buffer);
+        self.set_keepalive(settings.keepalive);
+        self.layers = settings.layers;
+        self.encryption = settings.encryption;
+        self.decryption = settings.decryption;
+    }
+
+    // Make this the reading half of a split stream or session, sending through `sender` from now
+    // on. The cipher for what's sent and the layers move there with it, and received events pass
+    // up through the layers there.
+    pub(crate) fn send_through(&mut self, sender: Arc<Mutex<TelnetStream<StreamType>>>) {
+        {
+            let mut sender = sender.lock().expect("Telnet writer poisoned");
+            sender.encryption = mem::take(&mut self.encryption);
+            sender.layers = mem::take(&mut self.layers);
+        }
+        self.sender = Some(sender);
+    }
+
+    /// Send a TelnetEvent to remote. Data is NVT-encoded, unless we've negotiated
+    /// BINARY-TRANSMISSION.
+    ///
+    /// ENCRYPT START and END switch encryption of what follows them. See
+    /// [TelnetStream::set_encryption].
     pub fn send_event(&mut self, event: TelnetEvent) -> TellyResult {
-        let bytes = event.into_bytes();
-        self.send_raw_bytes(&bytes)
+        self.send_through_layers(event, false)
+    }
+
+    // Pass an event down through the layers, and send whatever comes out of the bottom. Data is
+    // sent untranslated if `untranslated`.
+    fn send_through_layers(&mut self, event: TelnetEvent, untranslated: bool) -> TellyResult {
+        if self.layers.is_empty() {
+            return self.write_event(event);
+        }
+        let outcome = Outcome::send(&mut self.layers, event);
+        self.layered_events.extend(outcome.application);
+        for event in outcome.remote {
+            match event {
+                TelnetEvent::Data(data) if untranslated => self.write_untranslated(&data)?,
+                event => self.write_event(event)?,
+            }
+        }
+        Ok(())
+    }
+
+    // Send an event, below the layers
+    fn write_event(&mut self, event: TelnetEvent) -> TellyResult {
+        let switch = encryption::switch(&event);
+        if switch == Some(true) && !self.encryption.has_cipher() {
+            return Err(TellyError::NoCipher);
+        }
+        self.sync_options();
+        // Only negotiations change option states. They're recorded once written, so a failed
+        // write doesn't leave us believing the peer was told
+        let negotiation = matches!(event, TelnetEvent::Negotiation { .. }).then(|| event.clone());
+        let bytes = if self
+            .options
+            .is_local_enabled(TelnetOption::BinaryTransmission)
+        {
+            event.into_binary_bytes()
+        } else {
+            event.into_bytes_with(self.newline_policy().unwrap_or_default())
+        };
+        self.send_raw_bytes(&bytes)?;
+        if let Some(negotiation) = negotiation {
+            self.record_send(&negotiation);
+        }
+        if let Some(on) = switch {
+            self.encryption.set_active(on);
+        }
+        Ok(())
     }
 
     /// Convenience function to send a WILL negotiation event
@@ -62,60 +370,396 @@ impl<StreamType: Write + Read> TelnetStream<StreamType> {
 
     /// Convenience function to send ASCII data to remote.
     pub fn send_str(&mut self, data: &str) -> TellyResult {
-        let bytes: Vec<u8> = data.as_bytes().iter().copied().escape_iacs().collect();
-        self.send_raw_bytes(&bytes)
+        self.send_untranslated(data.as_bytes())
+    }
+
+    /// Send data to remote without NVT translation, only escaping IACs. Useful for data that's
+    /// already formatted for a terminal, like the output of a PTY.
+    pub fn send_untranslated(&mut self, data: &[u8]) -> TellyResult {
+        if self.layers.is_empty() {
+            self.write_untranslated(data)
+        } else {
+            self.send_through_layers(TelnetEvent::Data(data.to_vec()), true)
+        }
+    }
+
+    // Send data untranslated, below the layers
+    fn write_untranslated(&mut self, data: &[u8]) -> TellyResult {
+        // Slices of the data, each IAC followed by another to escape it, so that nothing needs
+        // to be copied
+        let mut slices = Vec::new();
+        for chunk in data.split_inclusive(|&byte| byte == IAC) {
+            slices.push(chunk);
+            if chunk.ends_with(&[IAC]) {
+                slices.push(&[IAC]);
+            }
+        }
+        self.send_raw_slices(&slices)
     }
 
     /// Convenience function to send ASCII data to remote.
     pub fn send_data(&mut self, data: &[u8]) -> TellyResult {
+        self.sync_options();
+        if self
+            .options
+            .is_local_enabled(TelnetOption::BinaryTransmission)
+        {
+            // Only IACs need escaping
+            return self.send_untranslated(data);
+        }
         self.send_event(TelnetEvent::Data(Vec::from(data)))
     }
 
+    /// Write any buffered events, and flush the underlying stream. See
+    /// [TelnetStream::set_write_buffer].
+    pub fn flush(&mut self) -> TellyResult {
+        self.write_pending(&[])?;
+        self.stream.flush()?;
+        Ok(())
+    }
+
     /// Send raw telnet data to remote. This does NOT escape ASCII data.
     fn send_raw_bytes(&mut self, bytes: &[u8]) -> TellyResult {
-        if self.stream.write(bytes)? != bytes.len() {
-            return Err(TellyError::DidNotWriteAllBytes);
+        self.send_raw_slices(&[bytes])
+    }
+
+    // Buffer or write the concatenation of `slices`, encrypted if need be
+    fn send_raw_slices(&mut self, slices: &[&[u8]]) -> TellyResult {
+        if let Some(sender) = &self.sender {
+            return sender
+                .lock()
+                .expect("Telnet writer poisoned")
+                .send_raw_slices(slices);
         }
-        self.stream.flush()?;
-        Ok(())
+        if let Some(cipher) = self.encryption.active() {
+            let mut bytes = slices.concat();
+            cipher.encrypt(&mut bytes);
+            return self.write_or_buffer(&[&bytes]);
+        }
+        self.write_or_buffer(slices)
+    }
+
+    // Buffer or write the concatenation of `slices` as they are
+    fn write_or_buffer(&mut self, slices: &[&[u8]]) -> TellyResult {
+        if let Some(capacity) = self.tx_capacity {
+            let length: usize = slices.iter().map(|slice| slice.len()).sum();
+            if self.tx_buffer.len() + length <= capacity {
+                for slice in slices {
+                    self.tx_buffer.extend_from_slice(slice);
+                }
+                return Ok(());
+            }
+            self.write_pending(slices)
+        } else {
+            self.write_pending(slices)?;
+            self.stream.flush()?;
+            Ok(())
+        }
+    }
+
+    // Write the write buffer followed by `slices`, in as few writes as possible
+    fn write_pending(&mut self, slices: &[&[u8]]) -> TellyResult {
+        let pending = self.tx_buffer.len();
+        let mut written = 0;
+        let result = {
+            let mut slices: Vec<IoSlice> = iter::once(&self.tx_buffer[..])
+                .chain(slices.iter().copied())
+                .filter(|slice| !slice.is_empty())
+                .map(IoSlice::new)
+                .collect();
+            let mut slices = &mut slices[..];
+            loop {
+                if slices.is_empty() {
+                    break Ok(());
+                }
+                match self.stream.write_vectored(slices) {
+                    Ok(0) => break Err(TellyError::DidNotWriteAllBytes),
+                    Ok(count) => {
+                        written += count;
+                        IoSlice::advance_slices(&mut slices, count);
+                    }
+                    Err(err) if err.kind() == ErrorKind::Interrupted => {}
+                    Err(err) => break Err(err.into()),
+                }
+            }
+        };
+
+        // Whatever wasn't written stays buffered, so nothing is sent twice
+        self.tx_buffer.drain(..written.min(pending));
+        result
     }
 }
 
-impl<T: Write + Read> Iterator for TelnetStream<T> {
-    type Item = TelnetEvent;
+impl<StreamType: Write + Read> TelnetStream<StreamType> {
+    /// Receive the next TelnetEvent from remote. Returns `None` at the end of the stream.
+    ///
+    /// NUL padding is stripped from data, unless the remote has negotiated BINARY-TRANSMISSION.
+    ///
+    /// If keepalive is enabled (see [TelnetStream::set_keepalive]), read timeouts on the
+    /// underlying stream are used to probe the remote, and this fails with
+    /// [TellyError::IdleTimeout] or [TellyError::KeepAliveFailed] if the remote is gone.
+    ///
+    /// Received events pass up through the layers added with [TelnetStream::push_layer] first.
+    pub fn next_event(&mut self) -> TellyResult<Option<TelnetEvent>> {
+        loop {
+            if let Some(sender) = &self.sender {
+                // Turned back up by the layers while sending
+                let mut sender = sender.lock().expect("Telnet writer poisoned");
+                self.layered_events.extend(sender.layered_events.drain(..));
+            }
+            // Options are tracked as the application sees them, so a negotiation swallowed by
+            // a layer doesn't count. This happens before anything more is parsed, so mode
+            // switches still take effect at the exact byte.
+            if let Some(event) = self.layered_events.pop_front() {
+                self.record_receive(&event);
+                return Ok(Some(event));
+            }
+            let event = self.receive_event()?;
+            let Some(event) = event else {
+                return Ok(None);
+            };
+            if self.layers.is_empty() && self.sender.is_none() {
+                self.record_receive(&event);
+                return Ok(Some(event));
+            }
 
-    fn next(&mut self) -> Option<Self::Item> {
+            let application = match &self.sender {
+                Some(sender) => sender
+                    .lock()
+                    .expect("Telnet writer poisoned")
+                    .receive_through_layers(event)?,
+                None => self.receive_through_layers(event)?,
+            };
+            self.layered_events.extend(application);
+        }
+    }
+
+    // Pass a received event up through the layers, sending what they turn back down, and
+    // returning what comes out of the top
+    fn receive_through_layers(&mut self, event: TelnetEvent) -> TellyResult<Vec<TelnetEvent>> {
+        if self.layers.is_empty() {
+            return Ok(vec![event]);
+        }
+        let outcome = Outcome::receive(&mut self.layers, event);
+        for event in outcome.remote {
+            self.write_event(event)?;
+        }
+        Ok(outcome.application)
+    }
+
+    // Receive the next event, below the layers
+    fn receive_event(&mut self) -> TellyResult<Option<TelnetEvent>> {
         const BUFFER_SIZE: usize = 16;
         let mut vec: Vec<u8> = vec![0; BUFFER_SIZE];
 
-        if let Some(event) = self.parser.next_event(&mut self.rx_buffer) {
-            return Some(event);
+        if let Some(event) = self.pending_event.take() {
+            return Ok(Some(event));
         }
 
         loop {
-            let bytes_read = self.stream.read(&mut vec).expect("fuck");
-            self.rx_buffer.put(&vec[0..bytes_read]);
+            // Re-checked per event, so that mode switches take effect at the exact byte
+            while let Some(event) = {
+                self.sync_options();
+                // NULs are left for the newline decoder to deal with
+                self.parser.set_binary(
+                    self.options
+                        .is_remote_enabled(TelnetOption::BinaryTransmission)
+                        || self.newlines.is_some(),
+                );
+                self.parser.next_event(&mut self.rx_buffer)
+            } {
+                if let Some(on) = encryption::switch(&event) {
+                    self.switch_decryption(on)?;
+                }
+                if let Some(keepalive) = &mut self.keepalive {
+                    if keepalive.consume_reply(&event) {
+                        continue;
+                    }
+                }
+                let binary = self
+                    .options
+                    .is_remote_enabled(TelnetOption::BinaryTransmission);
+
+                let Some(decoder) = &mut self.newlines else {
+                    return Ok(Some(event));
+                };
+                match event {
+                    TelnetEvent::Data(data) if !binary => {
+                        let mut decoded = Vec::with_capacity(data.len());
+                        decoder.decode(&data, &mut decoded);
+                        // Possibly just a CR, held back
+                        if !decoded.is_empty() {
+                            return Ok(Some(TelnetEvent::Data(decoded)));
+                        }
+                    }
+                    event => {
+                        return Ok(Some(match decoder.finish() {
+                            Some(byte) => {
+                                self.pending_event = Some(event);
+                                TelnetEvent::Data(vec![byte])
+                            }
+                            None => event,
+                        }));
+                    }
+                }
+            }
+
+            if self.decryption.is_active() && !self.rx_ciphertext.is_empty() {
+                self.decrypt_pending();
+                continue;
+            }
+
+            // The remote may be waiting on what we've sent before it says anything more
+            if !self.tx_buffer.is_empty() {
+                self.flush()?;
+            }
+
+            let bytes_read = match self.stream.read(&mut vec) {
+                Ok(bytes_read) => bytes_read,
+                Err(err)
+                    if self.keepalive.is_some()
+                        && matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) =>
+                {
+                    self.poll_keepalive()?;
+                    continue;
+                }
+                Err(err) => return Err(err.into()),
+            };
+            if bytes_read == 0 {
+                let held_back = self.newlines.as_mut().and_then(NewlineDecoder::finish);
+                return Ok(held_back.map(|byte| TelnetEvent::Data(vec![byte])));
+            }
+
+            if let Some(keepalive) = &mut self.keepalive {
+                keepalive.on_receive(Instant::now());
+            }
+            if self.decryption.is_active() {
+                self.rx_ciphertext.extend_from_slice(&vec[0..bytes_read]);
+            } else {
+                self.rx_buffer.put(&vec[0..bytes_read]);
+            }
+        }
+    }
+
+    // Called when the remote sends ENCRYPT START or END
+    fn switch_decryption(&mut self, on: bool) -> TellyResult {
+        if on == self.decryption.is_active() {
+            return Ok(());
+        }
+        if on {
+            if !self.decryption.has_cipher() {
+                return Err(TellyError::NoCipher);
+            }
+            // Everything after START is encrypted
+            self.rx_ciphertext = self.rx_buffer.split().to_vec();
+        } else {
+            // Nothing after END was decrypted, so what's left is plaintext
+            self.rx_buffer.put(&self.rx_ciphertext[..]);
+            self.rx_ciphertext.clear();
+        }
+        self.decryption.set_active(on);
+        Ok(())
+    }
 
-            if let Some(event) = self.parser.next_event(&mut self.rx_buffer) {
-                return Some(event);
-            } else if bytes_read == 0 {
-                println!("next> End of stream!");
-                return None;
+    // Decrypt received bytes up to the end of the next subnegotiation, and no further, in case
+    // it's END
+    fn decrypt_pending(&mut self) {
+        let cipher = self
+            .decryption
+            .active()
+            .expect("Bug: decrypted while not decrypting");
+        let mut previous = self.rx_buffer.last().copied();
+        let mut count = 0;
+        for byte in &mut self.rx_ciphertext {
+            cipher.decrypt(slice::from_mut(byte));
+            count += 1;
+            if previous == Some(IAC) && *byte == SE {
+                break;
+            }
+            previous = Some(*byte);
+        }
+        self.rx_buffer.put(&self.rx_ciphertext[..count]);
+        self.rx_ciphertext.drain(..count);
+    }
+
+    // Called when a read timed out with keepalive enabled.
+    fn poll_keepalive(&mut self) -> TellyResult {
+        let keepalive = self
+            .keepalive
+            .as_mut()
+            .expect("Bug: polled keepalive while disabled");
+        match keepalive.poll(Instant::now()) {
+            KeepAliveAction::Wait => Ok(()),
+            KeepAliveAction::Expired => Err(TellyError::IdleTimeout),
+            // Bypass option tracking; the probe is ours, not the application's. It's flushed
+            // right away, so a failed write is noticed even if writes are buffered
+            KeepAliveAction::Probe(event) => {
+                let bytes = event.into_bytes();
+                let sent = match &self.sender {
+                    Some(sender) => {
+                        let mut sender = sender.lock().expect("Telnet writer poisoned");
+                        sender.send_raw_bytes(&bytes).and_then(|()| sender.flush())
+                    }
+                    None => self.send_raw_bytes(&bytes).and_then(|()| self.flush()),
+                };
+                sent.map_err(|err| match err {
+                    TellyError::IoError(err) => TellyError::KeepAliveFailed(err),
+                    err => err,
+                })
             }
         }
     }
 }
 
+impl TelnetStream<TcpStream> {
+    /// The address of the remote.
+    pub fn peer_addr(&self) -> TellyResult<SocketAddr> {
+        Ok(self.stream.peer_addr()?)
+    }
+
+    /// The local address of the connection.
+    pub fn local_addr(&self) -> TellyResult<SocketAddr> {
+        Ok(self.stream.local_addr()?)
+    }
+
+    /// Shut down the read half, write half, or both halves of the connection. Buffered events
+    /// are written before the write half is shut down.
+    pub fn shutdown(&mut self, how: Shutdown) -> TellyResult {
+        if how != Shutdown::Read {
+            self.flush()?;
+        }
+        Ok(self.stream.shutdown(how)?)
+    }
+}
+
+impl<T: Write + Read> Iterator for TelnetStream<T> {
+    type Item = TelnetEvent;
+
+    /// Receive the next TelnetEvent from remote. Returns `None` at the end of the stream, or if an
+    /// error occurred. Use [TelnetStream::next_event] to see errors.
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_event().ok().flatten()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::{TelnetCommand, TelnetSubnegotiation, UnparsedTelnetSubnegotiation};
-    use std::{collections::VecDeque, io::Result};
+    use crate::{
+        layer::Events, TelnetAction, TelnetCommand, TelnetSubnegotiation,
+        UnparsedTelnetSubnegotiation,
+    };
+    use std::{collections::VecDeque, io::Result, net::TcpListener};
 
     // A loopback stream: `write()`'s feed its own read buffer.
     #[derive(Default)]
     struct MockStream {
         buffer: VecDeque<u8>,
+        // Number of calls to write() or write_vectored(), and flush()
+        writes: usize,
+        flushes: usize,
+        // Most bytes accepted per write, to simulate short writes
+        max_write: Option<usize>,
     }
 
     impl Read for MockStream {
@@ -135,17 +779,38 @@ mod tests {
 
     impl Write for MockStream {
         fn write(&mut self, buf: &[u8]) -> Result<usize> {
-            for byte in buf {
-                self.buffer.push_back(*byte);
-            }
-            Ok(buf.len())
+            self.write_vectored(&[IoSlice::new(buf)])
+        }
+
+        fn write_vectored(&mut self, bufs: &[IoSlice]) -> Result<usize> {
+            self.writes += 1;
+            let limit = self.max_write.unwrap_or(usize::MAX);
+            let bytes: Vec<u8> = bufs.iter().flat_map(|buf| buf.iter()).copied().collect();
+            let written = bytes.len().min(limit);
+            self.buffer.extend(&bytes[..written]);
+            Ok(written)
         }
 
         fn flush(&mut self) -> Result<()> {
+            self.flushes += 1;
             Ok(())
         }
     }
 
+    #[test]
+    fn failed_negotiation_not_recorded() {
+        let mut stream = TelnetStream::from_stream(MockStream {
+            max_write: Some(0),
+            ..Default::default()
+        });
+        assert!(stream.send_will(TelnetOption::Echo).is_err());
+        assert_eq!(stream.options(), &OptionStates::default());
+
+        stream.get_mut().max_write = None;
+        stream.send_will(TelnetOption::Echo).unwrap();
+        assert_ne!(stream.options(), &OptionStates::default());
+    }
+
     #[test]
     fn send_string() {
         let stream = MockStream::default();
@@ -191,4 +856,362 @@ mod tests {
         }
         assert_eq!(stream.next(), None);
     }
+
+    // Receive data until `len` bytes have been collected
+    fn receive_data(stream: &mut TelnetStream<MockStream>, len: usize) -> Vec<u8> {
+        let mut received = Vec::new();
+        while received.len() < len {
+            match stream.next() {
+                Some(TelnetEvent::Data(data)) => received.extend(data),
+                other => panic!("Expected data, but got {other:?}"),
+            }
+        }
+        received
+    }
+
+    #[test]
+    fn binary_transmission() {
+        let mut blob: Vec<u8> = (0..=u8::MAX).collect();
+        blob.extend(b"\r\n\r\0\n\r\xff\xff\0");
+        blob.extend((0..4096).map(|_| rand::random::<u8>()));
+
+        let mut stream = TelnetStream::from_stream(MockStream::default());
+
+        // Not negotiated yet, so CR gets padded, and the padding stripped
+        stream.send_data(b"\0\r").unwrap();
+        assert_eq!(receive_data(&mut stream, 1), b"\r");
+
+        // Binary in one direction only. The loopback acknowledges our own requests.
+        stream.send_will(TelnetOption::BinaryTransmission).unwrap();
+        stream
+            .options_mut()
+            .on_receive(&TelnetEvent::r#do(TelnetOption::BinaryTransmission));
+        assert_eq!(
+            stream.next(),
+            Some(TelnetEvent::will(TelnetOption::BinaryTransmission))
+        );
+        assert!(stream
+            .options()
+            .is_local_enabled(TelnetOption::BinaryTransmission));
+        stream.send_data(&blob).unwrap();
+        let nvt: Vec<u8> = blob.iter().copied().filter(|byte| *byte != 0).collect();
+        assert_eq!(receive_data(&mut stream, nvt.len()), nvt);
+
+        // Binary in both directions, since the loopback already said WILL. The mode switches
+        // exactly after the negotiation.
+        stream.send_do(TelnetOption::BinaryTransmission).unwrap();
+        stream.send_data(&blob).unwrap();
+        assert_eq!(
+            stream.next(),
+            Some(TelnetEvent::r#do(TelnetOption::BinaryTransmission))
+        );
+        assert!(stream
+            .options()
+            .is_remote_enabled(TelnetOption::BinaryTransmission));
+        assert_eq!(receive_data(&mut stream, blob.len()), blob);
+
+        // And back again
+        stream.send_dont(TelnetOption::BinaryTransmission).unwrap();
+        stream.send_data(b"\r").unwrap();
+        assert_eq!(
+            stream.next(),
+            Some(TelnetEvent::dont(TelnetOption::BinaryTransmission))
+        );
+        assert_eq!(receive_data(&mut stream, 1), b"\r");
+    }
+
+    #[test]
+    fn newline_policy() {
+        let mut stream = TelnetStream::from_stream(MockStream::default());
+        assert_eq!(stream.newline_policy(), None);
+
+        // A peer using bare CR and CR NUL, with a CR LF split by a negotiation
+        let quirky = b"a\rb\r\0c\r\xff\xfb\x01\nd\r";
+
+        stream.set_newline_policy(Some(NewlinePolicy::Lenient));
+        stream.send_raw_bytes(quirky).unwrap();
+        assert_eq!(receive_data(&mut stream, 6), b"a\nb\nc\n");
+        assert_eq!(stream.next(), Some(TelnetEvent::will(TelnetOption::Echo)));
+        // The LF completes the CR before the negotiation, so it's swallowed
+        assert_eq!(receive_data(&mut stream, 2), b"d\n");
+
+        stream.set_newline_policy(Some(NewlinePolicy::Strict));
+        stream.send_raw_bytes(quirky).unwrap();
+        // The CR before the negotiation is held back until the negotiation shows it's bare
+        assert_eq!(receive_data(&mut stream, 6), b"a\rb\rc\r");
+        assert_eq!(stream.next(), Some(TelnetEvent::will(TelnetOption::Echo)));
+        assert_eq!(receive_data(&mut stream, 2), b"\nd");
+        // The final CR is held back until the end of the stream
+        assert_eq!(stream.next(), Some(TelnetEvent::Data(b"\r".to_vec())));
+        assert_eq!(stream.next(), None);
+
+        stream.set_newline_policy(Some(NewlinePolicy::Passthrough));
+        stream.send_raw_bytes(quirky).unwrap();
+        assert_eq!(receive_data(&mut stream, 7), b"a\rb\r\0c\r");
+        stream.next();
+        assert_eq!(receive_data(&mut stream, 3), b"\nd\r");
+
+        // Sending follows the policy too
+        stream.send_data(b"x\n").unwrap();
+        assert_eq!(receive_data(&mut stream, 2), b"x\n");
+        stream.set_newline_policy(Some(NewlinePolicy::Strict));
+        stream.send_data(b"x\r\n").unwrap();
+        assert_eq!(receive_data(&mut stream, 3), b"x\r\n");
+    }
+
+    #[test]
+    fn write_buffer() {
+        let raw = |stream: &mut TelnetStream<MockStream>| -> Vec<u8> {
+            stream.stream.buffer.drain(..).collect()
+        };
+
+        // Unbuffered, every send is a write and a flush
+        let mut stream = TelnetStream::from_stream(MockStream::default());
+        for byte in b"echo" {
+            stream.send_data(&[*byte]).unwrap();
+        }
+        assert_eq!((stream.stream.writes, stream.stream.flushes), (4, 4));
+        assert_eq!(raw(&mut stream), b"echo");
+
+        // Buffered, sends are batched until the buffer fills or is flushed
+        let mut stream = TelnetStream::from_stream(MockStream::default());
+        stream.set_write_buffer(Some(8));
+        for byte in b"echo" {
+            stream.send_data(&[*byte]).unwrap();
+        }
+        stream.send_do(TelnetOption::Echo).unwrap();
+        assert_eq!((stream.stream.writes, stream.stream.flushes), (0, 0));
+        // Overflowing writes the buffer and the new bytes together
+        stream.send_str("\u{1b}[0m").unwrap();
+        assert_eq!((stream.stream.writes, stream.stream.flushes), (1, 0));
+        stream.send_data(b"!").unwrap();
+        stream.flush().unwrap();
+        assert_eq!((stream.stream.writes, stream.stream.flushes), (2, 1));
+        assert_eq!(raw(&mut stream), b"echo\xff\xfd\x01\x1b[0m!");
+
+        // Pending bytes are written before blocking to read
+        stream.send_str("ping").unwrap();
+        assert_eq!(receive_data(&mut stream, 4), b"ping");
+
+        // Short writes are retried, with escaped IACs sent without copying
+        let mut stream = TelnetStream::from_stream(MockStream {
+            max_write: Some(3),
+            ..Default::default()
+        });
+        stream.send_untranslated(b"\xffab\xff\xffcd").unwrap();
+        assert_eq!(stream.stream.writes, 4);
+        assert_eq!(raw(&mut stream), b"\xff\xffab\xff\xff\xff\xffcd");
+
+        // A stream that accepts nothing
+        let mut stream = TelnetStream::from_stream(MockStream {
+            max_write: Some(0),
+            ..Default::default()
+        });
+        assert!(matches!(
+            stream.send_str("x"),
+            Err(TellyError::DidNotWriteAllBytes)
+        ));
+    }
+
+    // A toy keystream, so that bytes decrypted out of step come out garbled
+    struct Keystream(u8);
+
+    impl Cipher for Keystream {
+        fn encrypt(&mut self, bytes: &mut [u8]) {
+            for byte in bytes {
+                *byte ^= self.0;
+                self.0 = self.0.wrapping_mul(5).wrapping_add(3);
+            }
+        }
+
+        fn decrypt(&mut self, bytes: &mut [u8]) {
+            self.encrypt(bytes)
+        }
+    }
+
+    #[test]
+    fn encryption() {
+        let mut stream = TelnetStream::from_stream(MockStream::default());
+        let start: TelnetEvent = TelnetSubnegotiation::EncryptionStart(vec![0]).into();
+        let end: TelnetEvent = TelnetSubnegotiation::EncryptionEnd.into();
+        assert!(matches!(
+            stream.send_event(start.clone()),
+            Err(TellyError::NoCipher)
+        ));
+        assert!(stream.get_ref().buffer.is_empty());
+
+        stream.set_encryption(Some(Box::new(Keystream(0x42))));
+        stream.set_decryption(Some(Box::new(Keystream(0x42))));
+        let events = [
+            TelnetEvent::Data(b"plain".to_vec()),
+            start,
+            TelnetEvent::Data(b"secret\xff".to_vec()),
+            TelnetEvent::will(TelnetOption::Echo),
+            end,
+            TelnetEvent::Data(b"plain again".to_vec()),
+        ];
+        for event in events.clone() {
+            stream.send_event(event).unwrap();
+        }
+        assert!(!stream.is_encrypting());
+
+        // Only what's between START and END is encrypted, END included
+        let sent: Vec<u8> = stream.get_ref().buffer.iter().copied().collect();
+        assert!(sent.starts_with(b"plain\xff\xfa\x26\x03\x00\xff\xf0"));
+        assert!(sent.ends_with(b"plain again"));
+        assert!(!sent.windows(6).any(|window| window == b"secret"));
+
+        // Data may arrive split across reads, so it's joined back up
+        let mut received: Vec<(TelnetEvent, bool)> = Vec::new();
+        while let Some(event) = stream.next() {
+            match (received.last_mut(), event) {
+                (Some((TelnetEvent::Data(last), _)), TelnetEvent::Data(data)) => last.extend(data),
+                (_, event) => received.push((event, stream.is_decrypting())),
+            }
+        }
+        let decrypting = [false, true, true, true, false, false];
+        assert_eq!(
+            received,
+            events.into_iter().zip(decrypting).collect::<Vec<_>>()
+        );
+    }
+
+    // Uppercases data sent, and answers AYT itself
+    struct Shout;
+
+    impl Layer for Shout {
+        fn on_send(&mut self, event: TelnetEvent, events: &mut Events) {
+            match event {
+                TelnetEvent::Data(data) => {
+                    events.forward(TelnetEvent::Data(data.to_ascii_uppercase()))
+                }
+                TelnetEvent::Command(TelnetCommand::AreYouThere) => {
+                    events.reverse(TelnetEvent::Data(b"[yes]".to_vec()))
+                }
+                event => events.forward(event),
+            }
+        }
+    }
+
+    // Logs events in both directions, swallows NOPs, and accepts SGA
+    struct Log(Arc<Mutex<Vec<String>>>);
+
+    impl Layer for Log {
+        fn on_send(&mut self, event: TelnetEvent, events: &mut Events) {
+            self.0.lock().unwrap().push(format!("sent {event}"));
+            events.forward(event);
+        }
+
+        fn on_receive(&mut self, event: TelnetEvent, events: &mut Events) {
+            self.0.lock().unwrap().push(format!("received {event}"));
+            match event {
+                TelnetEvent::Command(TelnetCommand::Nop) => {}
+                TelnetEvent::Negotiation {
+                    action: TelnetAction::Do,
+                    option: TelnetOption::SuppressGoAhead,
+                } => {
+                    events.reverse(TelnetEvent::will(TelnetOption::SuppressGoAhead));
+                    events.forward(event);
+                }
+                event => events.forward(event),
+            }
+        }
+    }
+
+    #[test]
+    fn layers() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let mut stream = TelnetStream::from_stream(MockStream::default());
+        stream.push_layer(Log(log.clone()));
+        stream.push_layer(Shout);
+
+        stream.send_str("hi").unwrap();
+        assert_eq!(stream.next(), Some(TelnetEvent::Data(b"HI".to_vec())));
+
+        stream.send_event(TelnetCommand::Nop.into()).unwrap();
+        stream.send_do(TelnetOption::SuppressGoAhead).unwrap();
+        assert_eq!(
+            stream.next(),
+            Some(TelnetEvent::r#do(TelnetOption::SuppressGoAhead))
+        );
+        assert_eq!(
+            stream.next(),
+            Some(TelnetEvent::will(TelnetOption::SuppressGoAhead))
+        );
+
+        stream
+            .send_event(TelnetCommand::AreYouThere.into())
+            .unwrap();
+        assert_eq!(stream.next(), Some(TelnetEvent::Data(b"[yes]".to_vec())));
+        assert_eq!(stream.next(), None);
+
+        assert_eq!(
+            *log.lock().unwrap(),
+            [
+                r#"sent "HI""#,
+                r#"received "HI""#,
+                "sent IAC NOP",
+                "sent IAC DO SGA",
+                "received IAC NOP",
+                "received IAC DO SGA",
+                "received IAC WILL SGA",
+            ]
+        );
+    }
+
+    #[test]
+    fn into_parts() {
+        let mut stream = TelnetStream::from_stream(MockStream::default());
+        stream.send_str("hi").unwrap();
+        stream.send_will(TelnetOption::Echo).unwrap();
+        stream.get_mut().buffer.extend(b"\x16\x03\x01");
+        assert_eq!(stream.get_ref().buffer.len(), 8);
+
+        // The negotiation and the bytes after it are read along with the data
+        assert_eq!(stream.next(), Some(TelnetEvent::Data(b"hi".to_vec())));
+        let (inner, events, leftover) = stream.into_parts();
+        assert!(inner.buffer.is_empty());
+        assert!(events.is_empty());
+        assert_eq!(leftover, b"\xff\xfb\x01\x16\x03\x01");
+
+        // Events held back by newline translation are returned too
+        let held_back = |reads| {
+            let mut stream = TelnetStream::from_stream(MockStream::default());
+            stream.set_newline_policy(Some(NewlinePolicy::Strict));
+            stream.get_mut().buffer.extend(b"ok\r\xff\xf1\x16");
+            for _ in 0..reads {
+                stream.next().unwrap();
+            }
+            let (_, events, leftover) = stream.into_parts();
+            (events, leftover)
+        };
+        assert_eq!(
+            held_back(1),
+            (
+                vec![TelnetEvent::Data(b"\r".to_vec())],
+                b"\xff\xf1\x16".to_vec()
+            )
+        );
+        assert_eq!(
+            held_back(2),
+            (vec![TelnetCommand::Nop.into()], b"\x16".to_vec())
+        );
+
+        // Addresses and shutdown on TCP
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        let mut client = TelnetStream::from_stream(client);
+        let mut server = TelnetStream::from_stream(server);
+        assert_eq!(client.peer_addr().unwrap(), server.local_addr().unwrap());
+        assert_eq!(client.local_addr().unwrap(), server.peer_addr().unwrap());
+
+        client.set_write_buffer(Some(64));
+        client.send_str("bye").unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+        assert_eq!(server.next(), Some(TelnetEvent::Data(b"bye".to_vec())));
+        assert_eq!(server.next(), None);
+        let mut inner = server.into_inner();
+        inner.write_all(b"still open").unwrap();
+    }
 }
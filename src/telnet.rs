@@ -1,14 +1,23 @@
 use crate::{
+    authentication::AuthenticationTypePair,
     constants,
+    encryption::EncryptionType,
     errors::{TellyError, TellyResult},
-    utils::TellyIterTraits,
+    utils::{NewlinePolicy, TellyIterTraits},
     TelnetCommand,
 };
+use alloc::{
+    format,
+    string::{String, ToString},
+    vec,
+    vec::Vec,
+};
 use bytes::{Buf, BytesMut};
+use core::{fmt, iter, str::FromStr};
 use num_derive::FromPrimitive;
 use num_traits::FromPrimitive;
 
-#[derive(FromPrimitive, PartialEq, Debug, Clone, Copy)]
+#[derive(FromPrimitive, PartialEq, Eq, Hash, Debug, Clone, Copy)]
 /// Options that follow WILL, DO, DONT, WONT, and SB. These are defined across multiple RFCs.
 pub enum TelnetOption {
     /// [RFC856](https://www.rfc-editor.org/rfc/rfc856.html)
@@ -33,6 +42,14 @@ pub enum TelnetOption {
     NegotiateAboutWindowSize = 31,
     /// [RFC1184](https://www.rfc-editor.org/rfc/rfc1184.html)
     LineMode = 34,
+    /// [RFC2941](https://www.rfc-editor.org/rfc/rfc2941.html)
+    Authentication = 37,
+    /// [RFC2946](https://www.rfc-editor.org/rfc/rfc2946.html)
+    Encrypt = 38,
+    /// [RFC1572](https://www.rfc-editor.org/rfc/rfc1572.html)
+    NewEnvironment = 39,
+    /// [draft-altman-telnet-starttls](https://datatracker.ietf.org/doc/html/draft-altman-telnet-starttls-02)
+    StartTls = 46,
     /// Unknown Telnet option.
     Unknown = 0xfe,
 }
@@ -51,8 +68,98 @@ impl From<u8> for TelnetOption {
     }
 }
 
+impl TelnetOption {
+    /// The conventional short name of this option, as used by `arpa/telnet.h`. E.g. "NAWS".
+    pub const fn name(&self) -> &'static str {
+        match self {
+            Self::BinaryTransmission => "BINARY",
+            Self::Echo => "ECHO",
+            Self::Reconnection => "RCP",
+            Self::SuppressGoAhead => "SGA",
+            Self::ApproxMessageSizeNegotiation => "NAMS",
+            Self::Status => "STATUS",
+            Self::TimingMark => "TM",
+            Self::Logout => "LOGOUT",
+            Self::TerminalType => "TTYPE",
+            Self::NegotiateAboutWindowSize => "NAWS",
+            Self::LineMode => "LINEMODE",
+            Self::Authentication => "AUTHENTICATION",
+            Self::Encrypt => "ENCRYPT",
+            Self::NewEnvironment => "NEW-ENVIRON",
+            Self::StartTls => "START_TLS",
+            Self::Unknown => "UNKNOWN",
+        }
+    }
+}
+
+impl fmt::Display for TelnetOption {
+    /// Format as the option's short name. See [TelnetOption::name].
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for TelnetOption {
+    type Err = TellyError;
+
+    /// Parse an option from its short name (case-insensitive) or its decimal value. Options
+    /// this crate doesn't know, including [TelnetOption::Unknown] itself, are rejected, since
+    /// they couldn't be sent as the option meant.
+    fn from_str(s: &str) -> TellyResult<Self> {
+        if let Ok(byte) = s.parse::<u8>() {
+            return match Self::from_u8(byte) {
+                Some(option) if option != Self::Unknown => Ok(option),
+                _ => Err(TellyError::ConversionError(format!(
+                    "Unknown Telnet option {byte}"
+                ))),
+            };
+        }
+
+        let s = s.to_ascii_uppercase();
+        let option = match s.as_str() {
+            "TIMING-MARK" => Self::TimingMark,
+            "TERMINAL-TYPE" => Self::TerminalType,
+            "STARTTLS" => Self::StartTls,
+            _ => (0..=u8::MAX)
+                .filter_map(Self::from_u8)
+                .filter(|option| *option != Self::Unknown)
+                .find(|option| option.name() == s)
+                .ok_or_else(|| {
+                    TellyError::ConversionError(format!("Unknown Telnet option '{s}'"))
+                })?,
+        };
+        Ok(option)
+    }
+}
+
 #[derive(PartialEq, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 /// Represents an event sent over, or to be sent over, Telnet.
+///
+/// # Serialization
+/// With the `serde` feature, events and subnegotiations can be serialized. Options, actions and
+/// commands are serialized by name, so the representation is readable and does not depend on
+/// the numbering of enum variants:
+///
+/// | Type             | Serialized as                                      | Example          |
+/// |------------------|----------------------------------------------------|------------------|
+/// | [TelnetOption]   | Short name                                         | `"NAWS"`         |
+/// | [TelnetAction]   | Name                                               | `"DO"`           |
+/// | [TelnetCommand]  | Short name, or the decimal byte for unlisted ones  | `"AYT"`, `"200"` |
+///
+/// Names are case-insensitive. In self-describing, human-readable formats like JSON, all three
+/// also deserialize from their byte values. Events and subnegotiations use serde's default
+/// (externally tagged) enum representation, with bytes as arrays of numbers. In JSON:
+///
+/// ```json
+/// {"Negotiation": {"action": "WILL", "option": "TTYPE"}}
+/// {"Command": "AYT"}
+/// {"Subnegotiation": {"option": "NAWS", "bytes": [0, 80, 0, 24]}}
+/// {"Data": [104, 105]}
+/// ```
+///
+/// [TelnetSubnegotiation] is represented the same way, like
+/// `{"NegotiateAboutWindowSize": {"width": 80, "height": 24}}` or `"TerminalTypeRequest"`.
 pub enum TelnetEvent {
     /// A Telnet command.
     Command(TelnetCommand),
@@ -103,10 +210,25 @@ impl TelnetEvent {
         }
     }
 
-    /// Transform into bytes.
+    /// Transform into bytes, NVT-encoding data.
     pub fn into_bytes(self) -> Vec<u8> {
+        self.into_bytes_with(NewlinePolicy::Strict)
+    }
+
+    /// Transform into bytes, NVT-encoding data according to a [NewlinePolicy].
+    pub fn into_bytes_with(self, policy: NewlinePolicy) -> Vec<u8> {
+        match self {
+            TelnetEvent::Data(data) => data.into_iter().unix_to_nvt_with(policy).collect(),
+            event => event.into_binary_bytes(),
+        }
+    }
+
+    /// Transform into bytes, for when BINARY-TRANSMISSION
+    /// ([RFC856](https://www.rfc-editor.org/rfc/rfc856.html)) is in effect. Data is sent as-is,
+    /// with only IACs escaped.
+    pub fn into_binary_bytes(self) -> Vec<u8> {
         match self {
-            TelnetEvent::Data(data) => data.into_iter().unix_to_nvt().collect(),
+            TelnetEvent::Data(data) => data.into_iter().escape_iacs().collect(),
             TelnetEvent::Command(command) => {
                 vec![constants::IAC, command.into()]
             }
@@ -162,6 +284,37 @@ impl TryFrom<u8> for TelnetAction {
     }
 }
 
+impl TelnetAction {
+    /// The name of this action as it appears in the RFCs. E.g. "WILL".
+    pub const fn name(&self) -> &'static str {
+        match self {
+            Self::Will => "WILL",
+            Self::Wont => "WONT",
+            Self::Do => "DO",
+            Self::Dont => "DONT",
+        }
+    }
+}
+
+impl fmt::Display for TelnetAction {
+    /// Format as the action's name. See [TelnetAction::name].
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for TelnetAction {
+    type Err = TellyError;
+
+    /// Parse an action from its name (case-insensitive).
+    fn from_str(s: &str) -> TellyResult<Self> {
+        [Self::Will, Self::Wont, Self::Do, Self::Dont]
+            .into_iter()
+            .find(|action| action.name().eq_ignore_ascii_case(s))
+            .ok_or_else(|| TellyError::ConversionError(format!("Unknown Telnet action '{s}'")))
+    }
+}
+
 /// A yet-to-be-parsed Telnet subnegotiation.
 ///
 /// # Example
@@ -177,6 +330,7 @@ impl TryFrom<u8> for TelnetAction {
 /// assert_eq!(parsed, deparsed.try_into().unwrap());
 /// ```
 #[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct UnparsedTelnetSubnegotiation {
     /// The [TelnetOption] this subnegotiation is associated with.
     pub option: TelnetOption,
@@ -205,6 +359,7 @@ impl UnparsedTelnetSubnegotiation {
 
 /// A parsed subnegotiation event.
 #[derive(PartialEq, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum TelnetSubnegotiation {
     /// Parsed NAWS subnegotiation. See [RFC1073](https://datatracker.ietf.org/doc/html/rfc1073)
     /// for details.
@@ -221,6 +376,72 @@ pub enum TelnetSubnegotiation {
     /// Parsed terminal-type response subnegotiation. Contains the name of the terminal as a string. E.g.
     /// "XTERM-256COLOR". See [RFC1091](https://www.rfc-editor.org/rfc/rfc1091.html) for details.
     TerminalTypeResponse(String),
+    /// Parsed NEW-ENVIRON SEND subnegotiation, asking for the listed variables. Values are
+    /// ignored. An empty list asks for all variables. See
+    /// [RFC1572](https://www.rfc-editor.org/rfc/rfc1572.html) for details.
+    NewEnvironmentRequest(Vec<EnvironmentVariable>),
+    /// Parsed NEW-ENVIRON IS subnegotiation, answering a
+    /// [TelnetSubnegotiation::NewEnvironmentRequest].
+    NewEnvironmentResponse(Vec<EnvironmentVariable>),
+    /// Parsed NEW-ENVIRON INFO subnegotiation, sent unprompted when variables change.
+    NewEnvironmentInfo(Vec<EnvironmentVariable>),
+    /// Parsed START_TLS FOLLOWS subnegotiation. Once both ends have sent it, the TLS handshake
+    /// begins. See
+    /// [draft-altman-telnet-starttls](https://datatracker.ietf.org/doc/html/draft-altman-telnet-starttls-02)
+    /// for details.
+    StartTlsFollows,
+    /// Parsed AUTHENTICATION SEND subnegotiation, in which the server offers mechanisms in order
+    /// of preference. See [RFC2941](https://www.rfc-editor.org/rfc/rfc2941.html) and
+    /// [crate::authentication] for details.
+    AuthenticationSend(Vec<AuthenticationTypePair>),
+    /// Parsed AUTHENTICATION IS subnegotiation, carrying the client's data for a mechanism.
+    AuthenticationIs {
+        /// The mechanism and modifiers in use.
+        pair: AuthenticationTypePair,
+        /// Mechanism-specific data.
+        data: Vec<u8>,
+    },
+    /// Parsed AUTHENTICATION REPLY subnegotiation, carrying the server's data for a mechanism.
+    AuthenticationReply {
+        /// The mechanism and modifiers in use.
+        pair: AuthenticationTypePair,
+        /// Mechanism-specific data.
+        data: Vec<u8>,
+    },
+    /// Parsed AUTHENTICATION NAME subnegotiation, naming the user the client wants to log in
+    /// as.
+    AuthenticationName(String),
+    /// Parsed ENCRYPT SUPPORT subnegotiation, in which the side that will decrypt lists the
+    /// encryption types it supports. See [RFC2946](https://www.rfc-editor.org/rfc/rfc2946.html)
+    /// and [crate::encryption] for details.
+    EncryptionSupport(Vec<EncryptionType>),
+    /// Parsed ENCRYPT IS subnegotiation, carrying the encrypting side's data for a type.
+    EncryptionIs {
+        /// The encryption type in use.
+        kind: EncryptionType,
+        /// Type-specific data.
+        data: Vec<u8>,
+    },
+    /// Parsed ENCRYPT REPLY subnegotiation, carrying the decrypting side's data for a type.
+    EncryptionReply {
+        /// The encryption type in use.
+        kind: EncryptionType,
+        /// Type-specific data.
+        data: Vec<u8>,
+    },
+    /// Parsed ENCRYPT START subnegotiation, with a key ID. Everything after it is encrypted.
+    EncryptionStart(Vec<u8>),
+    /// Parsed ENCRYPT END subnegotiation. Everything after it is no longer encrypted.
+    EncryptionEnd,
+    /// Parsed ENCRYPT REQUEST-START subnegotiation, with a key ID, asking the other side to
+    /// START.
+    EncryptionRequestStart(Vec<u8>),
+    /// Parsed ENCRYPT REQUEST-END subnegotiation, asking the other side to END.
+    EncryptionRequestEnd,
+    /// Parsed ENCRYPT ENC_KEYID subnegotiation, proposing a key ID to encrypt with.
+    EncryptionEncKeyId(Vec<u8>),
+    /// Parsed ENCRYPT DEC_KEYID subnegotiation, proposing a key ID to decrypt with.
+    EncryptionDecKeyId(Vec<u8>),
     /// A subnegotiation for which Telly has not implemented parsing. But fear not, for you can
     /// parse it yourself!
     Other {
@@ -262,6 +483,77 @@ impl TryFrom<UnparsedTelnetSubnegotiation> for TelnetSubnegotiation {
 
                 Ok(Self::TerminalTypeResponse(term_name))
             }
+            TelnetOption::NewEnvironment => {
+                let variables = EnvironmentVariable::decode_list(bytes.get(1..).unwrap_or(&[]))?;
+                match bytes.first().copied() {
+                    Some(constants::SEND) => Ok(Self::NewEnvironmentRequest(variables)),
+                    Some(constants::IS) => Ok(Self::NewEnvironmentResponse(variables)),
+                    Some(constants::INFO) => Ok(Self::NewEnvironmentInfo(variables)),
+                    _ => Err(TellyError::DecodeError(
+                        "Expected IS, SEND or INFO in NEW-ENVIRON subnegotiation".into(),
+                    )),
+                }
+            }
+            TelnetOption::StartTls => match bytes[..] {
+                [constants::FOLLOWS] => Ok(Self::StartTlsFollows),
+                _ => Err(TellyError::DecodeError(
+                    "Expected FOLLOWS in START_TLS subnegotiation".into(),
+                )),
+            },
+            TelnetOption::Authentication => {
+                let error =
+                    || TellyError::DecodeError("Malformed AUTHENTICATION subnegotiation".into());
+                let (&command, rest) = bytes.split_first().ok_or_else(error)?;
+                let pair = || AuthenticationTypePair::decode(rest).ok_or_else(error);
+                match command {
+                    constants::SEND if rest.len() % 2 == 0 => Ok(Self::AuthenticationSend(
+                        rest.chunks(2)
+                            .filter_map(AuthenticationTypePair::decode)
+                            .collect(),
+                    )),
+                    constants::IS => Ok(Self::AuthenticationIs {
+                        pair: pair()?,
+                        data: rest[2..].to_vec(),
+                    }),
+                    constants::REPLY => Ok(Self::AuthenticationReply {
+                        pair: pair()?,
+                        data: rest[2..].to_vec(),
+                    }),
+                    constants::NAME => Ok(Self::AuthenticationName(
+                        String::from_utf8_lossy(rest).to_string(),
+                    )),
+                    _ => Err(error()),
+                }
+            }
+            TelnetOption::Encrypt => {
+                let error = || TellyError::DecodeError("Malformed ENCRYPT subnegotiation".into());
+                let (&command, rest) = bytes.split_first().ok_or_else(error)?;
+                let kind_data = || {
+                    rest.split_first()
+                        .map(|(&kind, data)| (EncryptionType::from(kind), data.to_vec()))
+                        .ok_or_else(error)
+                };
+                match command {
+                    constants::SUPPORT => Ok(Self::EncryptionSupport(
+                        rest.iter().copied().map(EncryptionType::from).collect(),
+                    )),
+                    constants::IS => {
+                        let (kind, data) = kind_data()?;
+                        Ok(Self::EncryptionIs { kind, data })
+                    }
+                    constants::REPLY => {
+                        let (kind, data) = kind_data()?;
+                        Ok(Self::EncryptionReply { kind, data })
+                    }
+                    constants::START => Ok(Self::EncryptionStart(rest.to_vec())),
+                    constants::END if rest.is_empty() => Ok(Self::EncryptionEnd),
+                    constants::REQUEST_START => Ok(Self::EncryptionRequestStart(rest.to_vec())),
+                    constants::REQUEST_END if rest.is_empty() => Ok(Self::EncryptionRequestEnd),
+                    constants::ENC_KEYID => Ok(Self::EncryptionEncKeyId(rest.to_vec())),
+                    constants::DEC_KEYID => Ok(Self::EncryptionDecKeyId(rest.to_vec())),
+                    _ => Err(error()),
+                }
+            }
             _ => Ok(Self::Other { option, bytes }),
         }
     }
@@ -286,13 +578,233 @@ impl TelnetSubnegotiation {
                 vec.extend(term_name.as_bytes());
                 vec
             }),
+            Self::NewEnvironmentRequest(variables) => (
+                TelnetOption::NewEnvironment,
+                EnvironmentVariable::encode_list(constants::SEND, &variables),
+            ),
+            Self::NewEnvironmentResponse(variables) => (
+                TelnetOption::NewEnvironment,
+                EnvironmentVariable::encode_list(constants::IS, &variables),
+            ),
+            Self::NewEnvironmentInfo(variables) => (
+                TelnetOption::NewEnvironment,
+                EnvironmentVariable::encode_list(constants::INFO, &variables),
+            ),
+            Self::StartTlsFollows => (TelnetOption::StartTls, vec![constants::FOLLOWS]),
+            Self::AuthenticationSend(pairs) => (
+                TelnetOption::Authentication,
+                iter::once(constants::SEND)
+                    .chain(pairs.iter().flat_map(AuthenticationTypePair::encode))
+                    .collect(),
+            ),
+            Self::AuthenticationIs { pair, data } => (
+                TelnetOption::Authentication,
+                [constants::IS]
+                    .into_iter()
+                    .chain(pair.encode())
+                    .chain(data)
+                    .collect(),
+            ),
+            Self::AuthenticationReply { pair, data } => (
+                TelnetOption::Authentication,
+                [constants::REPLY]
+                    .into_iter()
+                    .chain(pair.encode())
+                    .chain(data)
+                    .collect(),
+            ),
+            Self::AuthenticationName(name) => (TelnetOption::Authentication, {
+                let mut vec = vec![constants::NAME];
+                vec.extend(name.as_bytes());
+                vec
+            }),
+            Self::EncryptionSupport(kinds) => (
+                TelnetOption::Encrypt,
+                iter::once(constants::SUPPORT)
+                    .chain(kinds.into_iter().map(u8::from))
+                    .collect(),
+            ),
+            Self::EncryptionIs { kind, data } => (
+                TelnetOption::Encrypt,
+                [constants::IS, kind.into()]
+                    .into_iter()
+                    .chain(data)
+                    .collect(),
+            ),
+            Self::EncryptionReply { kind, data } => (
+                TelnetOption::Encrypt,
+                [constants::REPLY, kind.into()]
+                    .into_iter()
+                    .chain(data)
+                    .collect(),
+            ),
+            Self::EncryptionStart(keyid) => (
+                TelnetOption::Encrypt,
+                iter::once(constants::START).chain(keyid).collect(),
+            ),
+            Self::EncryptionEnd => (TelnetOption::Encrypt, vec![constants::END]),
+            Self::EncryptionRequestStart(keyid) => (
+                TelnetOption::Encrypt,
+                iter::once(constants::REQUEST_START).chain(keyid).collect(),
+            ),
+            Self::EncryptionRequestEnd => (TelnetOption::Encrypt, vec![constants::REQUEST_END]),
+            Self::EncryptionEncKeyId(keyid) => (
+                TelnetOption::Encrypt,
+                iter::once(constants::ENC_KEYID).chain(keyid).collect(),
+            ),
+            Self::EncryptionDecKeyId(keyid) => (
+                TelnetOption::Encrypt,
+                iter::once(constants::DEC_KEYID).chain(keyid).collect(),
+            ),
         };
 
         (option, bytes)
     }
 }
 
+/// A variable carried by a NEW-ENVIRON subnegotiation. See
+/// [RFC1572](https://www.rfc-editor.org/rfc/rfc1572.html).
+///
+/// # Example
+/// ```
+/// use telly::{EnvironmentVariable, TelnetSubnegotiation, UnparsedTelnetSubnegotiation};
+///
+/// let parsed = TelnetSubnegotiation::NewEnvironmentResponse(vec![
+///     EnvironmentVariable::var("USER", Some("margaret")),
+///     EnvironmentVariable::uservar("EDITOR", None),
+/// ]);
+/// let deparsed = UnparsedTelnetSubnegotiation::from(parsed.clone());
+///
+/// assert_eq!(parsed, deparsed.try_into().unwrap());
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct EnvironmentVariable {
+    /// Whether this is a user-defined variable (USERVAR) rather than a well-known one (VAR).
+    pub user_defined: bool,
+    /// The name of the variable.
+    pub name: String,
+    /// The value of the variable. `None` means the variable is undefined.
+    pub value: Option<String>,
+}
+
+impl EnvironmentVariable {
+    /// Construct a well-known variable, like "USER" or "DISPLAY".
+    pub fn var(name: &str, value: Option<&str>) -> Self {
+        Self {
+            user_defined: false,
+            name: name.into(),
+            value: value.map(Into::into),
+        }
+    }
+
+    /// Construct a user-defined variable.
+    pub fn uservar(name: &str, value: Option<&str>) -> Self {
+        Self {
+            user_defined: true,
+            ..Self::var(name, value)
+        }
+    }
+
+    fn encode_list(command: u8, variables: &[Self]) -> Vec<u8> {
+        fn escape(vec: &mut Vec<u8>, string: &str) {
+            for byte in string.bytes() {
+                if matches!(
+                    byte,
+                    constants::VAR | constants::VALUE | constants::ESC | constants::USERVAR
+                ) {
+                    vec.push(constants::ESC);
+                }
+                vec.push(byte);
+            }
+        }
+
+        let mut vec = vec![command];
+        for variable in variables {
+            vec.push(if variable.user_defined {
+                constants::USERVAR
+            } else {
+                constants::VAR
+            });
+            escape(&mut vec, &variable.name);
+            if let Some(value) = &variable.value {
+                vec.push(constants::VALUE);
+                escape(&mut vec, value);
+            }
+        }
+        vec
+    }
+
+    fn decode_list(bytes: &[u8]) -> TellyResult<Vec<Self>> {
+        let mut variables: Vec<Self> = Vec::new();
+        // The string currently being built: a name, or a value
+        let mut current: Option<Vec<u8>> = None;
+        let mut in_value = false;
+        let mut bytes = bytes.iter().copied();
+
+        let finish = |variables: &mut Vec<Self>, current: Vec<u8>, in_value: bool| {
+            let string = String::from_utf8_lossy(&current).to_string();
+            let variable = variables.last_mut().expect("Bug: value without variable");
+            if in_value {
+                variable.value = Some(string);
+            } else {
+                variable.name = string;
+            }
+        };
+
+        while let Some(byte) = bytes.next() {
+            match byte {
+                constants::VAR | constants::USERVAR => {
+                    if let Some(current) = current.take() {
+                        finish(&mut variables, current, in_value);
+                    }
+                    variables.push(Self {
+                        user_defined: byte == constants::USERVAR,
+                        name: String::new(),
+                        value: None,
+                    });
+                    current = Some(Vec::new());
+                    in_value = false;
+                }
+                constants::VALUE => {
+                    let Some(name) = current.take() else {
+                        return Err(TellyError::DecodeError(
+                            "NEW-ENVIRON value without a variable".into(),
+                        ));
+                    };
+                    finish(&mut variables, name, in_value);
+                    current = Some(Vec::new());
+                    in_value = true;
+                }
+                byte => {
+                    let byte = if byte == constants::ESC {
+                        bytes.next().ok_or_else(|| {
+                            TellyError::DecodeError("NEW-ENVIRON ends with ESC".into())
+                        })?
+                    } else {
+                        byte
+                    };
+                    current
+                        .as_mut()
+                        .ok_or_else(|| {
+                            TellyError::DecodeError("NEW-ENVIRON data without a variable".into())
+                        })?
+                        .push(byte);
+                }
+            }
+        }
+        if let Some(current) = current {
+            finish(&mut variables, current, in_value);
+        }
+
+        Ok(variables)
+    }
+}
+
 /// Stateless Telnet parser.
+///
+/// By default, data is assumed to be NVT-encoded, and NUL bytes are stripped. In binary mode
+/// ([RFC856](https://www.rfc-editor.org/rfc/rfc856.html)), data is passed through untouched.
 pub struct TelnetParser {
     // Translate from NVT?
     translate: bool,
@@ -304,6 +816,16 @@ impl Default for TelnetParser {
     }
 }
 impl TelnetParser {
+    /// Whether data is parsed as binary rather than NVT.
+    pub const fn is_binary(&self) -> bool {
+        !self.translate
+    }
+
+    /// Parse data as binary, e.g. once the remote has agreed to BINARY-TRANSMISSION.
+    pub fn set_binary(&mut self, binary: bool) {
+        self.translate = !binary;
+    }
+
     /// Pull next event out of a BytesMut, if available.
     pub fn next_event(&self, rx_buffer: &mut BytesMut) -> Option<TelnetEvent> {
         let mut event_type = EventType::Null;
@@ -350,7 +872,9 @@ impl TelnetParser {
                         }
                     } else {
                         event_type = EventType::Data;
-                        data_buffer.push(byte);
+                        if !(byte == 0 && self.translate) {
+                            data_buffer.push(byte);
+                        }
                         if advancement == rx_buffer.len() {
                             result = Some(TelnetEvent::Data(data_buffer));
                             break;
@@ -407,6 +931,11 @@ impl TelnetParser {
             rx_buffer.advance(advancement);
         }
 
+        // Data that was nothing but NVT padding
+        if matches!(&result, Some(TelnetEvent::Data(data)) if data.is_empty()) {
+            return self.next_event(rx_buffer);
+        }
+
         result
     }
 }
@@ -461,6 +990,17 @@ mod tests {
                     .into(),
                 )],
             ),
+            (
+                vec![
+                    constants::IAC,
+                    constants::SB,
+                    TelnetOption::StartTls.into(),
+                    constants::FOLLOWS,
+                    constants::IAC,
+                    constants::SE,
+                ],
+                vec![TelnetSubnegotiation::StartTlsFollows.into()],
+            ),
         ];
 
         let parser = TelnetParser::default();
@@ -473,4 +1013,76 @@ mod tests {
             assert_eq!(parser.next_event(&mut bytes), None);
         }
     }
+
+    #[test]
+    fn binary() {
+        let bytes = [
+            0x00,
+            b'\r',
+            0x00,
+            b'\n',
+            constants::IAC,
+            constants::IAC,
+            0x00,
+        ];
+        let mut parser = TelnetParser::default();
+        assert_eq!(
+            parser.next_event(&mut BytesMut::from(&bytes[..])),
+            Some(TelnetEvent::Data(vec![b'\r', b'\n', 0xff]))
+        );
+        assert_eq!(parser.next_event(&mut BytesMut::from(&[0x00][..])), None);
+
+        parser.set_binary(true);
+        assert!(parser.is_binary());
+        assert_eq!(
+            parser.next_event(&mut BytesMut::from(&bytes[..])),
+            Some(TelnetEvent::Data(vec![
+                0x00, b'\r', 0x00, b'\n', 0xff, 0x00
+            ]))
+        );
+
+        let data = TelnetEvent::Data(vec![b'\r', b'\n', 0xff]);
+        assert_eq!(data.clone().into_bytes(), b"\r\0\r\n\xff\xff");
+        assert_eq!(data.into_binary_bytes(), b"\r\n\xff\xff");
+    }
+
+    #[test]
+    fn new_environment() {
+        let bytes = vec![
+            constants::IS,
+            constants::VAR,
+            b'U',
+            b'S',
+            b'E',
+            b'R',
+            constants::VALUE,
+            b'm',
+            constants::ESC,
+            constants::VALUE,
+            constants::USERVAR,
+            b'X',
+            constants::VAR,
+            b'Y',
+            constants::VALUE,
+        ];
+        let parsed: TelnetSubnegotiation =
+            UnparsedTelnetSubnegotiation::new(TelnetOption::NewEnvironment, bytes.clone())
+                .try_into()
+                .unwrap();
+        assert_eq!(
+            parsed,
+            TelnetSubnegotiation::NewEnvironmentResponse(vec![
+                EnvironmentVariable::var("USER", Some("m\x01")),
+                EnvironmentVariable::uservar("X", None),
+                EnvironmentVariable::var("Y", Some("")),
+            ])
+        );
+        assert_eq!(UnparsedTelnetSubnegotiation::from(parsed).bytes, bytes);
+
+        let bad = UnparsedTelnetSubnegotiation::new(
+            TelnetOption::NewEnvironment,
+            vec![constants::IS, b'X'],
+        );
+        assert!(TelnetSubnegotiation::try_from(bad).is_err());
+    }
 }
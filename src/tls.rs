@@ -0,0 +1,596 @@
+//! Telnet over TLS ("telnets", conventionally on port 992) using rustls, behind the `tls`
+//! feature.
+//!
+//! TLS is set up before any Telnet is spoken, so the resulting [TelnetStream] is used as usual.
+//!
+//! # Example
+//! ```no_run
+//! use std::net::TcpStream;
+//! use telly::{tls, TelnetStream};
+//!
+//! let roots = tls::load_certs("ca.pem").unwrap();
+//! let config = tls::client_config(roots, None).unwrap();
+//! let socket = TcpStream::connect(("console.example.com", tls::TELNETS_PORT)).unwrap();
+//! let mut stream = TelnetStream::connect_tls(socket, config, "console.example.com").unwrap();
+//! stream.send_str("hello\r\n").unwrap();
+//! ```
+//!
+//! # START_TLS
+//! Some peers instead upgrade a plain Telnet connection in-band, per
+//! [draft-altman-telnet-starttls](https://datatracker.ietf.org/doc/html/draft-altman-telnet-starttls-02):
+//!
+//! 1. The server sends `IAC DO START_TLS`, and the client agrees with `IAC WILL START_TLS`.
+//! 2. The server sends [TelnetSubnegotiation::StartTlsFollows].
+//! 3. The client replies with the same, and begins the TLS handshake with
+//!    [TelnetStream::start_tls].
+//! 4. On receiving it, the server begins the handshake with [TelnetStream::accept_start_tls].
+//!
+//! Bytes received before the upgrade that weren't parsed yet, like the start of the handshake,
+//! are kept. All options are reset once TLS is established, as the draft requires, so
+//! negotiation starts over. Local settings are carried over to the upgraded stream: newline
+//! translation, write buffering, keepalive, [layers](crate::layer) and ENCRYPT ciphers. The
+//! upgrade fails with [TellyError::TlsUpgradeError] if ENCRYPT is switched on, or if events
+//! received before the handshake were not yet returned.
+use crate::{
+    errors::{TellyError, TellyResult},
+    TelnetStream, TelnetSubnegotiation,
+};
+use rustls::{
+    crypto::{ring, CryptoProvider},
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer, ServerName},
+    server::WebPkiClientVerifier,
+    ClientConfig, ClientConnection, ConnectionCommon, RootCertStore, ServerConfig,
+    ServerConnection, SideData, StreamOwned,
+};
+use std::{
+    io::{self, ErrorKind, Read, Write},
+    net::TcpStream,
+    path::Path,
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+/// The port registered for Telnet over TLS.
+pub const TELNETS_PORT: u16 = 992;
+
+/// How long [TelnetStream::connect_tls] and [TelnetStream::accept_tls] wait for the handshake to
+/// complete.
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// How long to back off when a non-blocking stream has nothing to read yet
+const NONBLOCKING_BACKOFF: Duration = Duration::from_millis(1);
+
+/// A stream of type `S` encrypted with TLS, where `C` is either [ClientConnection] or
+/// [ServerConnection].
+pub type TlsStream<C, S = TcpStream> = StreamOwned<C, S>;
+
+/// Load all certificates from a PEM file, such as a certificate chain or a set of trusted roots.
+pub fn load_certs(path: impl AsRef<Path>) -> TellyResult<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_file_iter(path)?.collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        return Err(rustls::pki_types::pem::Error::NoItemsFound.into());
+    }
+    Ok(certs)
+}
+
+/// Load the first private key from a PEM file. PKCS#1, PKCS#8 and SEC1 keys are supported.
+pub fn load_private_key(path: impl AsRef<Path>) -> TellyResult<PrivateKeyDer<'static>> {
+    Ok(PrivateKeyDer::from_pem_file(path)?)
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+fn root_store(roots: Vec<CertificateDer<'static>>) -> TellyResult<RootCertStore> {
+    let mut store = RootCertStore::empty();
+    for root in roots {
+        store.add(root)?;
+    }
+    Ok(store)
+}
+
+/// Configuration for clients that trust servers with certificates issued by `roots`.
+///
+/// If `identity` is given, its certificate chain and private key are presented to servers that
+/// ask for client authentication.
+pub fn client_config(
+    roots: Vec<CertificateDer<'static>>,
+    identity: Option<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)>,
+) -> TellyResult<Arc<ClientConfig>> {
+    let builder = ClientConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()?
+        .with_root_certificates(root_store(roots)?);
+    let config = match identity {
+        Some((certs, key)) => builder.with_client_auth_cert(certs, key)?,
+        None => builder.with_no_client_auth(),
+    };
+    Ok(Arc::new(config))
+}
+
+/// Configuration for servers presenting the certificate chain `certs`, with the private key
+/// `key`.
+///
+/// If `client_roots` is given, clients must present a certificate issued by one of them.
+pub fn server_config(
+    certs: Vec<CertificateDer<'static>>,
+    key: PrivateKeyDer<'static>,
+    client_roots: Option<Vec<CertificateDer<'static>>>,
+) -> TellyResult<Arc<ServerConfig>> {
+    let builder =
+        ServerConfig::builder_with_provider(provider()).with_safe_default_protocol_versions()?;
+    let builder = match client_roots {
+        Some(roots) => {
+            let verifier = WebPkiClientVerifier::builder_with_provider(
+                Arc::new(root_store(roots)?),
+                provider(),
+            )
+            .build()
+            .map_err(|err| rustls::Error::General(err.to_string()))?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    Ok(Arc::new(builder.with_single_cert(certs, key)?))
+}
+
+// Surface TLS failures from rustls's I/O as such
+fn tls_error(err: io::Error) -> TellyError {
+    match err
+        .get_ref()
+        .and_then(|inner| inner.downcast_ref::<rustls::Error>())
+    {
+        Some(err) => err.clone().into(),
+        None => err.into(),
+    }
+}
+
+// Complete the handshake, waiting out read timeouts, as set for keepalive, until `timeout` has
+// passed
+fn handshake<D: SideData, S: Read + Write>(
+    connection: &mut ConnectionCommon<D>,
+    stream: &mut S,
+    timeout: Duration,
+) -> TellyResult {
+    let deadline = Instant::now() + timeout;
+    while connection.is_handshaking() {
+        match connection.complete_io(stream) {
+            Ok(_) => continue,
+            Err(err) if err.kind() == ErrorKind::WouldBlock => thread::sleep(NONBLOCKING_BACKOFF),
+            Err(err) if matches!(err.kind(), ErrorKind::TimedOut | ErrorKind::Interrupted) => {}
+            Err(err) => return Err(tls_error(err)),
+        }
+        if Instant::now() >= deadline {
+            return Err(io::Error::new(ErrorKind::TimedOut, "TLS handshake timed out").into());
+        }
+    }
+    Ok(())
+}
+
+impl<S: Read + Write> TelnetStream<TlsStream<ClientConnection, S>> {
+    /// Connect over TLS to the server named `server_name` (a DNS name or IP address), on an
+    /// already connected `stream`. The handshake is completed before returning, or fails after
+    /// [HANDSHAKE_TIMEOUT].
+    pub fn connect_tls(
+        stream: S,
+        config: Arc<ClientConfig>,
+        server_name: &str,
+    ) -> TellyResult<Self> {
+        Self::connect_tls_with_timeout(stream, config, server_name, HANDSHAKE_TIMEOUT)
+    }
+
+    /// Like [TelnetStream::connect_tls], but fails with [ErrorKind::TimedOut] if the handshake
+    /// doesn't complete within `timeout`. The timeout is only checked when reads on `stream`
+    /// return, so it should have a read timeout set or be non-blocking.
+    pub fn connect_tls_with_timeout(
+        mut stream: S,
+        config: Arc<ClientConfig>,
+        server_name: &str,
+        timeout: Duration,
+    ) -> TellyResult<Self> {
+        let server_name = ServerName::try_from(server_name.to_owned())
+            .map_err(|err| rustls::Error::General(err.to_string()))?;
+        let mut connection = ClientConnection::new(config, server_name)?;
+        handshake(&mut connection, &mut stream, timeout)?;
+        Ok(Self::from_stream(StreamOwned::new(connection, stream)))
+    }
+}
+
+impl<S: Read + Write> TelnetStream<TlsStream<ServerConnection, S>> {
+    /// Accept a TLS client on an already accepted `stream`. The handshake is completed, and the
+    /// client's certificate verified if required, before returning, or fails after
+    /// [HANDSHAKE_TIMEOUT].
+    pub fn accept_tls(stream: S, config: Arc<ServerConfig>) -> TellyResult<Self> {
+        Self::accept_tls_with_timeout(stream, config, HANDSHAKE_TIMEOUT)
+    }
+
+    /// Like [TelnetStream::accept_tls], but fails with [ErrorKind::TimedOut] if the handshake
+    /// doesn't complete within `timeout`. The timeout is only checked when reads on `stream`
+    /// return, so it should have a read timeout set or be non-blocking, lest a silent client
+    /// hold the connection open.
+    pub fn accept_tls_with_timeout(
+        mut stream: S,
+        config: Arc<ServerConfig>,
+        timeout: Duration,
+    ) -> TellyResult<Self> {
+        let mut connection = ServerConnection::new(config)?;
+        handshake(&mut connection, &mut stream, timeout)?;
+        Ok(Self::from_stream(StreamOwned::new(connection, stream)))
+    }
+
+    /// The certificate chain the client authenticated with, if any.
+    pub fn client_certificates(&self) -> Option<&[CertificateDer<'static>]> {
+        self.get_ref().conn.peer_certificates()
+    }
+}
+
+/// A stream that yields `prefix` before reading from the inner stream. Used to keep bytes that
+/// were read off a connection before handing it to another protocol.
+pub struct Prefixed<S> {
+    prefix: Vec<u8>,
+    position: usize,
+    inner: S,
+}
+
+impl<S> Prefixed<S> {
+    /// Read `prefix`, then `inner`.
+    pub const fn new(prefix: Vec<u8>, inner: S) -> Self {
+        Self {
+            prefix,
+            position: 0,
+            inner,
+        }
+    }
+
+    /// Reference to the inner stream.
+    pub const fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    /// Mutable reference to the inner stream.
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+}
+
+impl<S: Read> Read for Prefixed<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let prefix = &self.prefix[self.position..];
+        if prefix.is_empty() {
+            return self.inner.read(buf);
+        }
+        let length = prefix.len().min(buf.len());
+        buf[..length].copy_from_slice(&prefix[..length]);
+        self.position += length;
+        Ok(length)
+    }
+}
+
+impl<S: Write> Write for Prefixed<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<S: Read + Write> TelnetStream<S> {
+    /// Upgrade to TLS as the client, on receiving [TelnetSubnegotiation::StartTlsFollows] from
+    /// the server. Replies in kind, then completes the handshake with the server named
+    /// `server_name`. See [the module documentation](self#start_tls).
+    pub fn start_tls(
+        mut self,
+        config: Arc<ClientConfig>,
+        server_name: &str,
+    ) -> TellyResult<TelnetStream<TlsStream<ClientConnection, Prefixed<S>>>> {
+        self.send_event(TelnetSubnegotiation::StartTlsFollows.into())?;
+        self.upgrade(|stream| TelnetStream::connect_tls(stream, config, server_name))
+    }
+
+    /// Upgrade to TLS as the server, on receiving [TelnetSubnegotiation::StartTlsFollows] in
+    /// reply to ours. Completes the handshake before returning. See
+    /// [the module documentation](self#start_tls).
+    pub fn accept_start_tls(
+        self,
+        config: Arc<ServerConfig>,
+    ) -> TellyResult<TelnetStream<TlsStream<ServerConnection, Prefixed<S>>>> {
+        self.upgrade(|stream| TelnetStream::accept_tls(stream, config))
+    }
+
+    // Hand the connection, and any bytes not yet parsed, to a TLS stream. Negotiated options
+    // are dropped, while local settings are kept
+    fn upgrade<T: Read + Write>(
+        mut self,
+        connect: impl FnOnce(Prefixed<S>) -> TellyResult<TelnetStream<T>>,
+    ) -> TellyResult<TelnetStream<T>> {
+        // The handshake would be encrypted, which the peer can't expect
+        if self.is_encrypting() || self.is_decrypting() {
+            return Err(TellyError::TlsUpgradeError("ENCRYPT is active".into()));
+        }
+        self.flush()?;
+        let settings = self.take_settings();
+
+        let (stream, events, leftover) = self.into_parts();
+        // Anything before the handshake should have been handled already
+        if !events.is_empty() {
+            return Err(TellyError::TlsUpgradeError(
+                "Telnet events received before the handshake were not handled".into(),
+            ));
+        }
+        let mut upgraded = connect(Prefixed::new(leftover, stream))?;
+        upgraded.apply_settings(settings);
+        Ok(upgraded)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        errors::TellyError,
+        keepalive::{KeepAlive, KeepAliveProbe},
+        layer::{Events, Layer},
+        TelnetEvent, TelnetOption, UnparsedTelnetSubnegotiation,
+    };
+    use rcgen::{BasicConstraints, CertificateParams, CertifiedKey, IsCa, KeyPair};
+    use std::{fs, net::TcpListener, thread, time::Duration};
+
+    // A self-signed CA, and a certificate for `name` issued by it
+    fn generate(name: &str) -> (CertifiedKey, CertifiedKey) {
+        let mut params = CertificateParams::new(Vec::new()).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca_key = KeyPair::generate().unwrap();
+        let ca = CertifiedKey {
+            cert: params.self_signed(&ca_key).unwrap(),
+            key_pair: ca_key,
+        };
+
+        let key = KeyPair::generate().unwrap();
+        let cert = CertificateParams::new(vec![name.into()])
+            .unwrap()
+            .signed_by(&key, &ca.cert, &ca.key_pair)
+            .unwrap();
+        (
+            ca,
+            CertifiedKey {
+                cert,
+                key_pair: key,
+            },
+        )
+    }
+
+    // Shouts whatever data it sends
+    struct Shout;
+
+    impl Layer for Shout {
+        fn on_send(&mut self, event: TelnetEvent, events: &mut Events) {
+            match event {
+                TelnetEvent::Data(data) => {
+                    events.forward(TelnetEvent::Data(data.to_ascii_uppercase()))
+                }
+                event => events.forward(event),
+            }
+        }
+    }
+
+    fn key(certified: &CertifiedKey) -> PrivateKeyDer<'static> {
+        PrivateKeyDer::try_from(certified.key_pair.serialize_der()).unwrap()
+    }
+
+    fn connect() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        (client, server)
+    }
+
+    #[test]
+    fn pem() {
+        let (ca, server) = generate("localhost");
+        let dir = std::env::temp_dir().join(format!("telly-tls-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let chain = format!("{}{}", server.cert.pem(), ca.cert.pem());
+        fs::write(dir.join("chain.pem"), chain).unwrap();
+        fs::write(dir.join("key.pem"), server.key_pair.serialize_pem()).unwrap();
+        fs::write(dir.join("empty.pem"), "").unwrap();
+
+        let certs = load_certs(dir.join("chain.pem")).unwrap();
+        assert_eq!(
+            certs,
+            vec![server.cert.der().clone(), ca.cert.der().clone()]
+        );
+        assert_eq!(load_private_key(dir.join("key.pem")).unwrap(), key(&server));
+        assert!(matches!(
+            load_certs(dir.join("empty.pem")),
+            Err(TellyError::PemError(_))
+        ));
+        assert!(matches!(
+            load_private_key(dir.join("chain.pem")),
+            Err(TellyError::PemError(_))
+        ));
+        assert!(load_certs(dir.join("missing.pem")).is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn telnets() {
+        let (ca, server_cert) = generate("localhost");
+        let config = server_config(
+            vec![server_cert.cert.der().clone()],
+            key(&server_cert),
+            None,
+        )
+        .unwrap();
+        let (client, server) = connect();
+
+        let server = thread::spawn(move || {
+            let mut server = TelnetStream::accept_tls(server, config).unwrap();
+            assert!(server.client_certificates().is_none());
+            server.send_str("login: ").unwrap();
+            server.next()
+        });
+
+        let config = client_config(vec![ca.cert.der().clone()], None).unwrap();
+        let mut client = TelnetStream::connect_tls(client, config, "localhost").unwrap();
+        assert_eq!(client.next(), Some(TelnetEvent::Data(b"login: ".to_vec())));
+        client.send_str("root").unwrap();
+        assert_eq!(
+            server.join().unwrap(),
+            Some(TelnetEvent::Data(b"root".to_vec()))
+        );
+
+        // A server we don't trust
+        let (_, untrusted) = generate("localhost");
+        let config =
+            server_config(vec![untrusted.cert.der().clone()], key(&untrusted), None).unwrap();
+        let (client, server) = connect();
+        let server = thread::spawn(move || TelnetStream::accept_tls(server, config).is_err());
+        let config = client_config(vec![ca.cert.der().clone()], None).unwrap();
+        assert!(matches!(
+            TelnetStream::connect_tls(client, config, "localhost"),
+            Err(TellyError::TlsError(_))
+        ));
+        assert!(server.join().unwrap());
+    }
+
+    #[test]
+    fn silent_client_times_out() {
+        let (_, server_cert) = generate("localhost");
+        let config = server_config(
+            vec![server_cert.cert.der().clone()],
+            key(&server_cert),
+            None,
+        )
+        .unwrap();
+        // The client connects, but never sends a ClientHello
+        let (_client, server) = connect();
+        server
+            .set_read_timeout(Some(Duration::from_millis(10)))
+            .unwrap();
+
+        let start = Instant::now();
+        match TelnetStream::accept_tls_with_timeout(server, config, Duration::from_millis(100)) {
+            Err(TellyError::IoError(err)) => assert_eq!(err.kind(), ErrorKind::TimedOut),
+            result => panic!("Unexpected result: {:?}", result.err()),
+        }
+        assert!(start.elapsed() >= Duration::from_millis(100));
+    }
+
+    #[test]
+    fn client_auth() {
+        let (ca, server_cert) = generate("localhost");
+        let (client_ca, client_cert) = generate("client");
+        let config = server_config(
+            vec![server_cert.cert.der().clone()],
+            key(&server_cert),
+            Some(vec![client_ca.cert.der().clone()]),
+        )
+        .unwrap();
+
+        let (client, server) = connect();
+        let server_config = config.clone();
+        let server = thread::spawn(move || {
+            let server = TelnetStream::accept_tls(server, server_config).unwrap();
+            server.client_certificates().map(<[_]>::to_vec)
+        });
+        let identity = (vec![client_cert.cert.der().clone()], key(&client_cert));
+        let config_with_identity =
+            client_config(vec![ca.cert.der().clone()], Some(identity)).unwrap();
+        TelnetStream::connect_tls(client, config_with_identity, "localhost").unwrap();
+        assert_eq!(
+            server.join().unwrap(),
+            Some(vec![client_cert.cert.der().clone()])
+        );
+
+        // Without a certificate, the server refuses
+        let (client, server) = connect();
+        let server = thread::spawn(move || TelnetStream::accept_tls(server, config));
+        let anonymous = client_config(vec![ca.cert.der().clone()], None).unwrap();
+        // TLS 1.3 clients finish before the server has checked their certificate
+        if let Ok(mut client) = TelnetStream::connect_tls(client, anonymous, "localhost") {
+            assert!(client.next_event().is_err());
+        }
+        assert!(matches!(
+            server.join().unwrap(),
+            Err(TellyError::TlsError(_))
+        ));
+    }
+
+    #[test]
+    fn start_tls() {
+        let (ca, server_cert) = generate("localhost");
+        let config = server_config(
+            vec![server_cert.cert.der().clone()],
+            key(&server_cert),
+            None,
+        )
+        .unwrap();
+        let (client, server) = connect();
+
+        let server = thread::spawn(move || {
+            let mut server = TelnetStream::from_stream(server);
+            server.push_layer(Shout);
+            server.send_do(TelnetOption::StartTls).unwrap();
+            assert_eq!(
+                server.next(),
+                Some(TelnetEvent::will(TelnetOption::StartTls))
+            );
+            server
+                .send_event(TelnetSubnegotiation::StartTlsFollows.into())
+                .unwrap();
+
+            // Let the client's reply and the start of its handshake arrive together, so some of
+            // the handshake is read along with the reply
+            thread::sleep(Duration::from_millis(100));
+            assert_eq!(
+                server.next(),
+                Some(TelnetSubnegotiation::StartTlsFollows.into())
+            );
+            let mut server = server.accept_start_tls(config).unwrap();
+            assert!(server.options().local_options().is_empty());
+            server.send_str("secure").unwrap();
+            server.next()
+        });
+
+        // The handshake waits out read timeouts, as set for keepalive
+        let keepalive = KeepAlive::new(Duration::from_secs(10), KeepAliveProbe::Nop, None);
+        client
+            .set_read_timeout(Some(Duration::from_millis(10)))
+            .unwrap();
+        let mut client = TelnetStream::from_stream(client);
+        client.set_keepalive(Some(keepalive));
+        assert_eq!(
+            client.next(),
+            Some(TelnetEvent::r#do(TelnetOption::StartTls))
+        );
+        client.send_will(TelnetOption::StartTls).unwrap();
+        let Some(TelnetEvent::Subnegotiation(subnegotiation)) = client.next() else {
+            panic!("Expected a subnegotiation");
+        };
+        assert_eq!(
+            TelnetSubnegotiation::try_from(subnegotiation).unwrap(),
+            TelnetSubnegotiation::StartTlsFollows
+        );
+
+        let config = client_config(vec![ca.cert.der().clone()], None).unwrap();
+        let mut client = client.start_tls(config, "localhost").unwrap();
+        // Layers are kept
+        assert_eq!(client.next(), Some(TelnetEvent::Data(b"SECURE".to_vec())));
+        client.send_str("hello").unwrap();
+        assert_eq!(
+            server.join().unwrap(),
+            Some(TelnetEvent::Data(b"hello".to_vec()))
+        );
+
+        assert!(
+            TelnetSubnegotiation::try_from(UnparsedTelnetSubnegotiation {
+                option: TelnetOption::StartTls,
+                bytes: vec![2],
+            })
+            .is_err()
+        );
+    }
+}
@@ -1,6 +1,7 @@
 //! Miscellaneous Telnet utilities.
 use crate::{constants::IAC, errors::TellyError};
-use std::iter::{Fuse, FusedIterator};
+use alloc::{collections::VecDeque, format, vec::Vec};
+use core::iter::{Fuse, FusedIterator};
 
 /// Iterator created by [TellyIterTraits::escape_iacs].
 pub struct EscapeIacs<T: Iterator<Item = u8>> {
@@ -67,17 +68,133 @@ impl<T: Iterator<Item = u8>> Iterator for UnescapeIacs<T> {
     }
 }
 
+/// How line endings are translated between local data and the Network Virtual Terminal.
+///
+/// Locally, lines end with LF. [RFC854](https://www.rfc-editor.org/rfc/rfc854.html) says lines
+/// end with CR LF on the wire, and that a CR on its own is sent as CR NUL. Peers in the wild don't
+/// all agree: some send bare CR or bare LF when the user presses Enter, or pad with NULs.
+///
+/// When encoding, [Strict](NewlinePolicy::Strict) and [Lenient](NewlinePolicy::Lenient) both
+/// send LF as CR LF and CR as CR NUL. [Passthrough](NewlinePolicy::Passthrough) sends data as-is.
+///
+/// When decoding:
+///
+/// | Received       | Strict   | Lenient  | Passthrough |
+/// |----------------|----------|----------|-------------|
+/// | CR LF          | LF       | LF       | CR LF       |
+/// | CR NUL         | CR       | LF       | CR NUL      |
+/// | CR, then other | CR       | LF       | CR          |
+/// | LF             | LF       | LF       | LF          |
+/// | NUL            | stripped | stripped | NUL         |
+///
+/// In strict mode, a CR is held back until the next byte shows what it means, so a peer sending
+/// bare CRs should be decoded leniently instead.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum NewlinePolicy {
+    /// Translate exactly as RFC854 says.
+    #[default]
+    Strict,
+    /// Treat CR, LF, CR LF and CR NUL alike as the end of a line.
+    Lenient,
+    /// Don't translate line endings or strip NULs.
+    Passthrough,
+}
+
+/// Incrementally decodes NVT line endings into local ones, according to a [NewlinePolicy].
+///
+/// Unlike [TellyIterTraits::nvt_to_unix], this can decode data that arrives in chunks, with CR LF
+/// pairs split between them.
+///
+/// # Example
+/// ```
+/// use telly::utils::{NewlineDecoder, NewlinePolicy};
+///
+/// let mut decoder = NewlineDecoder::new(NewlinePolicy::Strict);
+/// let mut output = Vec::new();
+/// decoder.decode(b"one\r", &mut output);
+/// assert_eq!(output, b"one");
+/// decoder.decode(b"\ntwo\r", &mut output);
+/// assert_eq!(output, b"one\ntwo");
+/// assert_eq!(decoder.finish(), Some(b'\r'));
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub struct NewlineDecoder {
+    policy: NewlinePolicy,
+    // Previous byte was a CR
+    after_cr: bool,
+}
+
+impl NewlineDecoder {
+    /// Construct a decoder.
+    pub const fn new(policy: NewlinePolicy) -> Self {
+        Self {
+            policy,
+            after_cr: false,
+        }
+    }
+
+    /// The policy this decoder follows.
+    pub const fn policy(&self) -> NewlinePolicy {
+        self.policy
+    }
+
+    /// Decode a chunk of data, appending the result to `output`.
+    pub fn decode(&mut self, input: &[u8], output: &mut Vec<u8>) {
+        for byte in input {
+            self.decode_byte(*byte, |byte| output.push(byte));
+        }
+    }
+
+    /// Give up on waiting for what follows a CR held back at the end of the input so far, and
+    /// return it. Call this at the end of the input, or when the data is interrupted.
+    pub fn finish(&mut self) -> Option<u8> {
+        if self.policy == NewlinePolicy::Strict && self.after_cr {
+            self.after_cr = false;
+            Some(b'\r')
+        } else {
+            None
+        }
+    }
+
+    fn decode_byte(&mut self, byte: u8, mut emit: impl FnMut(u8)) {
+        let after_cr = core::mem::replace(&mut self.after_cr, byte == b'\r');
+        match (self.policy, after_cr, byte) {
+            (NewlinePolicy::Passthrough, _, byte) => emit(byte),
+
+            (NewlinePolicy::Strict, true, b'\n') => emit(b'\n'),
+            (NewlinePolicy::Strict, true, 0) => emit(b'\r'),
+            (NewlinePolicy::Strict, true, byte) => {
+                // A bare CR
+                emit(b'\r');
+                if byte != b'\r' {
+                    emit(byte);
+                }
+            }
+            (NewlinePolicy::Strict, false, b'\r') => {}
+
+            (NewlinePolicy::Lenient, true, b'\n' | 0) => {}
+            (NewlinePolicy::Lenient, _, b'\r') => emit(b'\n'),
+
+            // NULs are padding
+            (_, _, 0) => {}
+            (_, _, byte) => emit(byte),
+        }
+    }
+}
+
 /// Iterator created by [TellyIterTraits::unix_to_nvt].
 pub struct UnixToNvt<T: Iterator<Item = u8>> {
     inner: T,
+    policy: NewlinePolicy,
     produce_null: bool,
     produce_newline: bool,
 }
 
 impl<T: Iterator<Item = u8>> UnixToNvt<T> {
-    fn from_iterator(it: T) -> Self {
+    fn from_iterator(it: T, policy: NewlinePolicy) -> Self {
         Self {
             inner: it,
+            policy,
             produce_null: false,
             produce_newline: false,
         }
@@ -100,7 +217,9 @@ impl<T: Iterator<Item = u8>> Iterator for UnixToNvt<T> {
             Some(b'\n')
         } else {
             let byte = self.inner.next();
-            if byte == Some(b'\r') {
+            if self.policy == NewlinePolicy::Passthrough {
+                byte
+            } else if byte == Some(b'\r') {
                 // This is '\r\0' in Telnet
                 self.produce_null = true;
                 Some(b'\r')
@@ -117,16 +236,19 @@ impl<T: Iterator<Item = u8>> Iterator for UnixToNvt<T> {
 
 /// Iterator created by [TellyIterTraits::nvt_to_unix].
 pub struct NvtToUnix<T: Iterator<Item = u8>> {
-    // Needs to be fused because we look ahead
+    // Needs to be fused because we decode after the end
     inner: Fuse<T>,
-    buffer: Option<u8>,
+    decoder: NewlineDecoder,
+    // Decoded, but not yet yielded. A byte decodes to at most two.
+    buffer: VecDeque<u8>,
 }
 
 impl<T: Iterator<Item = u8>> NvtToUnix<T> {
-    fn from_iterator(it: T) -> Self {
+    fn from_iterator(it: T, policy: NewlinePolicy) -> Self {
         Self {
             inner: it.fuse(),
-            buffer: None,
+            decoder: NewlineDecoder::new(policy),
+            buffer: VecDeque::with_capacity(2),
         }
     }
 }
@@ -137,23 +259,15 @@ impl<T: Iterator<Item = u8>> Iterator for NvtToUnix<T> {
     type Item = u8;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let byte = self.buffer.take().or_else(|| self.inner.next());
-
-        if byte == Some(b'\r') {
-            // Convert '\r\n' to '\n'
-            if let Some(next_byte) = self.inner.next() {
-                if next_byte == b'\n' {
-                    return Some(b'\n');
-                } else {
-                    self.buffer = Some(next_byte);
-                }
+        while self.buffer.is_empty() {
+            match self.inner.next() {
+                Some(byte) => self
+                    .decoder
+                    .decode_byte(byte, |byte| self.buffer.push_back(byte)),
+                None => return self.decoder.finish(),
             }
-        // 0's are no-ops
-        } else if byte == Some(0) {
-            return self.next();
         }
-
-        byte
+        self.buffer.pop_front()
     }
 }
 
@@ -207,12 +321,35 @@ pub trait TellyIterTraits: Iterator + Sized {
     where
         Self: Iterator<Item = u8>,
     {
-        UnixToNvt::from_iterator(self.escape_iacs())
+        self.unix_to_nvt_with(NewlinePolicy::Strict)
+    }
+
+    /// Translate Unix data to Telnet data, following a [NewlinePolicy].
+    ///
+    /// # Example
+    /// ```
+    /// use telly::utils::{NewlinePolicy, TellyIterTraits};
+    ///
+    /// let bytes = "Hello World!\n";
+    /// let bytes: Vec<u8> = bytes
+    ///     .as_bytes()
+    ///     .iter()
+    ///     .copied()
+    ///     .unix_to_nvt_with(NewlinePolicy::Passthrough)
+    ///     .collect();
+    /// assert_eq!(String::from_utf8_lossy(&bytes), "Hello World!\n");
+    /// ```
+    fn unix_to_nvt_with(self, policy: NewlinePolicy) -> UnixToNvt<EscapeIacs<Self>>
+    where
+        Self: Iterator<Item = u8>,
+    {
+        UnixToNvt::from_iterator(self.escape_iacs(), policy)
     }
 
     /// Translate Telnet data to Unix data. Returns an error if data is improperly encoded.
     ///
-    /// Note that this strips null bytes, which can potentially destroy information.
+    /// Note that this strips null bytes, which can potentially destroy information. Use
+    /// [TellyIterTraits::nvt_to_unix_with] to decode differently.
     ///
     /// # Example
     /// ```
@@ -227,7 +364,33 @@ pub trait TellyIterTraits: Iterator + Sized {
     where
         Self: Iterator<Item = u8>,
     {
-        UnescapeIacs::from_iterator(NvtToUnix::from_iterator(self))
+        self.nvt_to_unix_with(NewlinePolicy::Strict)
+    }
+
+    /// Translate Telnet data to Unix data, following a [NewlinePolicy]. Returns an error if data
+    /// is improperly encoded.
+    ///
+    /// # Example
+    /// ```
+    /// use telly::{
+    ///     errors::TellyError,
+    ///     utils::{NewlinePolicy, TellyIterTraits},
+    /// };
+    ///
+    /// let bytes = "one\rtwo\r\0three\r\n";
+    /// let bytes: Result<Vec<u8>, TellyError> = bytes
+    ///     .as_bytes()
+    ///     .iter()
+    ///     .copied()
+    ///     .nvt_to_unix_with(NewlinePolicy::Lenient)
+    ///     .collect();
+    /// assert_eq!(String::from_utf8_lossy(&bytes.unwrap()), "one\ntwo\nthree\n");
+    /// ```
+    fn nvt_to_unix_with(self, policy: NewlinePolicy) -> UnescapeIacs<NvtToUnix<Self>>
+    where
+        Self: Iterator<Item = u8>,
+    {
+        UnescapeIacs::from_iterator(NvtToUnix::from_iterator(self, policy))
     }
 }
 
@@ -236,6 +399,7 @@ impl<T> TellyIterTraits for T where T: Iterator {}
 #[cfg(test)]
 mod tests {
     use super::*;
+    use alloc::vec;
     use rand::prelude::*;
     #[test]
     fn escape_iacs() {
@@ -266,4 +430,60 @@ mod tests {
             assert_eq!(original, encoded_decoded.unwrap());
         }
     }
+
+    #[test]
+    fn newline_policies() {
+        use NewlinePolicy::*;
+
+        // (peer quirk, received, [strict, lenient, passthrough])
+        type Vector = (&'static str, &'static [u8], [&'static [u8]; 3]);
+        let vectors: [Vector; 8] = [
+            (
+                "RFC854",
+                b"a\r\nb\r\0c",
+                [b"a\nb\rc", b"a\nb\nc", b"a\r\nb\r\0c"],
+            ),
+            ("bare CR", b"a\rb\r", [b"a\rb\r", b"a\nb\n", b"a\rb\r"]),
+            ("bare LF", b"a\nb\n", [b"a\nb\n", b"a\nb\n", b"a\nb\n"]),
+            ("double CR", b"a\r\r\n", [b"a\r\n", b"a\n\n", b"a\r\r\n"]),
+            ("LF CR", b"a\n\rb", [b"a\n\rb", b"a\n\nb", b"a\n\rb"]),
+            ("NUL padding", b"\0a\0\0b", [b"ab", b"ab", b"\0a\0\0b"]),
+            ("CR LF NUL", b"a\r\n\0", [b"a\n", b"a\n", b"a\r\n\0"]),
+            ("IAC", b"\r\xff\xff", [b"\r\xff", b"\n\xff", b"\r\xff"]),
+        ];
+
+        for (quirk, received, expected) in vectors {
+            for (policy, expected) in [Strict, Lenient, Passthrough].into_iter().zip(expected) {
+                let decoded: Result<Vec<u8>, TellyError> =
+                    received.iter().copied().nvt_to_unix_with(policy).collect();
+                assert_eq!(decoded.unwrap(), expected, "{quirk} with {policy:?}");
+
+                // The same, one byte at a time
+                let mut decoder = NewlineDecoder::new(policy);
+                let mut decoded = Vec::new();
+                for byte in received {
+                    decoder.decode(&[*byte], &mut decoded);
+                }
+                decoded.extend(decoder.finish());
+                let decoded: Result<Vec<u8>, TellyError> =
+                    decoded.into_iter().unescape_iacs().collect();
+                assert_eq!(
+                    decoded.unwrap(),
+                    expected,
+                    "{quirk} with {policy:?} in chunks"
+                );
+            }
+        }
+
+        let encode = |policy| -> Vec<u8> {
+            b"a\rb\n\xff"
+                .iter()
+                .copied()
+                .unix_to_nvt_with(policy)
+                .collect()
+        };
+        assert_eq!(encode(Strict), b"a\r\0b\r\n\xff\xff");
+        assert_eq!(encode(Lenient), b"a\r\0b\r\n\xff\xff");
+        assert_eq!(encode(Passthrough), b"a\rb\n\xff\xff");
+    }
 }
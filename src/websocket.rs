@@ -0,0 +1,379 @@
+//! A gateway letting WebSocket clients, like xterm.js in a browser, reach a Telnet server.
+//!
+//! Each WebSocket connection gets its own Telnet connection to the backend, and negotiation is
+//! handled by the gateway: it offers TTYPE, NAWS and BINARY, asks the backend for ECHO, SGA and
+//! BINARY, and refuses everything else.
+//!
+//! # Messages
+//! * Binary and text messages from the client are terminal input, sent to the backend as data.
+//! * A text message like `{"type": "resize", "cols": 80, "rows": 24}` is not input, but the
+//!   size of the client's terminal, sent to the backend with NAWS.
+//! * Data from the backend is sent to the client as binary messages.
+//!
+//! # Example
+//! ```no_run
+//! use telly::{server::TelnetServer, websocket::WebSocketGateway};
+//!
+//! let gateway = WebSocketGateway::new("127.0.0.1:23").unwrap();
+//! let server = TelnetServer::bind("127.0.0.1:8080").unwrap();
+//! server.serve(gateway).unwrap();
+//! ```
+use crate::{
+    errors::{TellyError, TellyResult},
+    server::{ConnectionHandler, ErrorReporter},
+    split::{TelnetReader, TelnetWriter},
+    TelnetAction, TelnetEvent, TelnetOption, TelnetStream, TelnetSubnegotiation,
+};
+use std::{
+    io::ErrorKind,
+    net::{Shutdown, SocketAddr, TcpStream, ToSocketAddrs},
+    sync::{
+        mpsc::{self, Sender, TryRecvError},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+use tungstenite::{HandshakeError, Message, WebSocket};
+
+// How long a read from the client may block before data from the backend is forwarded.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+// Options we offer to perform.
+const LOCAL_OPTIONS: [TelnetOption; 3] = [
+    TelnetOption::BinaryTransmission,
+    TelnetOption::TerminalType,
+    TelnetOption::NegotiateAboutWindowSize,
+];
+// Options we ask the backend to perform.
+const REMOTE_OPTIONS: [TelnetOption; 3] = [
+    TelnetOption::BinaryTransmission,
+    TelnetOption::Echo,
+    TelnetOption::SuppressGoAhead,
+];
+
+type WindowSize = Arc<Mutex<Option<(u16, u16)>>>;
+
+/// Proxies WebSocket connections to a Telnet server. See the [module documentation](self).
+///
+/// As a [ConnectionHandler], the gateway can be served by a
+/// [TelnetServer](crate::server::TelnetServer), as long as the server has no negotiation
+/// script, since its clients speak WebSocket rather than Telnet. For the same reason, the
+/// server's keepalive probes are never sent, and its read timeout is replaced by the gateway's.
+/// Connections that fail are reported to the callback set with
+/// [WebSocketGateway::set_error_handler].
+#[derive(Clone, Debug)]
+pub struct WebSocketGateway {
+    backend: Vec<SocketAddr>,
+    terminal_type: String,
+    on_error: ErrorReporter,
+}
+
+impl WebSocketGateway {
+    /// Construct a gateway to the Telnet server at `backend`.
+    pub fn new(backend: impl ToSocketAddrs) -> TellyResult<Self> {
+        Ok(Self {
+            backend: backend.to_socket_addrs()?.collect(),
+            terminal_type: "XTERM-256COLOR".into(),
+            on_error: ErrorReporter::default(),
+        })
+    }
+
+    /// Call `handler` with the client's address and the error whenever a connection served as
+    /// a [ConnectionHandler] fails, e.g. to log it. Errors are dropped by default.
+    pub fn set_error_handler(
+        &mut self,
+        handler: impl Fn(SocketAddr, &TellyError) + Send + Sync + 'static,
+    ) {
+        self.on_error = ErrorReporter::new(handler);
+    }
+
+    /// Set the terminal type reported to the backend. Defaults to "XTERM-256COLOR", which is
+    /// what xterm.js emulates.
+    pub fn set_terminal_type(&mut self, terminal_type: &str) {
+        self.terminal_type = terminal_type.into();
+    }
+
+    /// Accept a WebSocket connection on `socket`, and proxy it to the backend until either side
+    /// closes.
+    pub fn proxy(&self, socket: TcpStream) -> TellyResult {
+        let mut handshake = tungstenite::accept(socket);
+        let mut websocket = loop {
+            match handshake {
+                Ok(websocket) => break websocket,
+                Err(HandshakeError::Failure(err)) => return Err(err.into()),
+                // A read timed out, e.g. one set by the server for keepalive
+                Err(HandshakeError::Interrupted(handshaking)) => {
+                    handshake = handshaking.handshake();
+                }
+            }
+        };
+        let backend = TcpStream::connect(&self.backend[..])?;
+        let closer = backend.try_clone()?;
+
+        let result = self.relay(&mut websocket, backend);
+        // Unblock the thread receiving from the backend
+        let _ = closer.shutdown(Shutdown::Both);
+        let _ = websocket.close(None);
+        let _ = websocket.flush();
+        result
+    }
+
+    fn relay(&self, websocket: &mut WebSocket<TcpStream>, backend: TcpStream) -> TellyResult {
+        let (mut reader, writer) = TelnetStream::from_stream(backend).split()?;
+        reader.set_auto_reply(true);
+        for option in LOCAL_OPTIONS {
+            writer.send_will(option)?;
+        }
+        for option in REMOTE_OPTIONS {
+            writer.send_do(option)?;
+        }
+
+        let size = WindowSize::default();
+        let (sender, receiver) = mpsc::channel();
+        {
+            let size = size.clone();
+            let terminal_type = self.terminal_type.clone();
+            thread::spawn(move || receive(reader, &size, &terminal_type, &sender));
+        }
+
+        // Reads time out so that data from the backend isn't held up by a quiet client
+        websocket.get_ref().set_read_timeout(Some(POLL_INTERVAL))?;
+        loop {
+            match websocket.read() {
+                Ok(Message::Binary(data)) => writer.send_data(&data)?,
+                Ok(Message::Text(text)) => match parse_resize(&text) {
+                    Some(new_size) => {
+                        *size.lock().expect("Window size poisoned") = Some(new_size);
+                        send_window_size(&writer, Some(new_size))?;
+                    }
+                    None => writer.send_data(text.as_bytes())?,
+                },
+                Ok(Message::Close(_)) | Err(tungstenite::Error::ConnectionClosed) => return Ok(()),
+                // Pings are answered by tungstenite
+                Ok(_) => {}
+                Err(tungstenite::Error::Io(err))
+                    if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+                Err(err) => return Err(err.into()),
+            }
+
+            loop {
+                match receiver.try_recv() {
+                    Ok(data) => websocket.send(Message::Binary(data))?,
+                    Err(TryRecvError::Empty) => break,
+                    // The backend closed
+                    Err(TryRecvError::Disconnected) => return Ok(()),
+                }
+            }
+        }
+    }
+}
+
+impl ConnectionHandler for WebSocketGateway {
+    fn handle_connection(&self, stream: TelnetStream<TcpStream>, peer: SocketAddr) {
+        if let Err(err) = self.proxy(stream.into_inner()) {
+            self.on_error.report(peer, &err);
+        }
+    }
+}
+
+// Forward data from the backend to `sender`, and answer the backend's requests, until either
+// closes.
+fn receive(
+    mut reader: TelnetReader<TcpStream>,
+    size: &WindowSize,
+    terminal_type: &str,
+    sender: &Sender<Vec<u8>>,
+) -> TellyResult {
+    while let Some(event) = reader.next_event()? {
+        match event {
+            TelnetEvent::Data(data) => {
+                // The client closed
+                let Ok(()) = sender.send(data) else { break };
+            }
+            // The size is sent as soon as NAWS is agreed on
+            TelnetEvent::Negotiation {
+                action: TelnetAction::Do,
+                option: TelnetOption::NegotiateAboutWindowSize,
+            } => send_window_size(reader.writer(), *size.lock().expect("Window size poisoned"))?,
+            TelnetEvent::Subnegotiation(subnegotiation) => {
+                if let Ok(TelnetSubnegotiation::TerminalTypeRequest) = subnegotiation.try_into() {
+                    reader.writer().send_event(
+                        TelnetSubnegotiation::TerminalTypeResponse(terminal_type.into()).into(),
+                    )?;
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+fn send_window_size(writer: &TelnetWriter<TcpStream>, size: Option<(u16, u16)>) -> TellyResult {
+    match size {
+        Some((width, height))
+            if writer
+                .options()
+                .is_local_enabled(TelnetOption::NegotiateAboutWindowSize) =>
+        {
+            writer
+                .send_event(TelnetSubnegotiation::NegotiateAboutWindowSize { width, height }.into())
+        }
+        _ => Ok(()),
+    }
+}
+
+// Parse a resize message, like `{"type": "resize", "cols": 80, "rows": 24}`.
+fn parse_resize(text: &str) -> Option<(u16, u16)> {
+    if !text.starts_with('{') {
+        return None;
+    }
+    let message: serde_json::Value = serde_json::from_str(text).ok()?;
+    if message.get("type")? != "resize" {
+        return None;
+    }
+    let dimension = |key| message.get(key)?.as_u64()?.try_into().ok();
+    Some((dimension("cols")?, dimension("rows")?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        keepalive::{KeepAlive, KeepAliveProbe},
+        server::TelnetServer,
+    };
+    use std::{io::Write, sync::mpsc::Receiver, thread::JoinHandle};
+
+    // Serve `handler` with `server`, until the returned handle is shut down
+    fn serve(
+        server: TelnetServer,
+        handler: impl ConnectionHandler,
+    ) -> (SocketAddr, crate::server::ShutdownHandle, JoinHandle<()>) {
+        let address = server.local_addr().unwrap();
+        let handle = server.shutdown_handle();
+        (
+            address,
+            handle,
+            thread::spawn(move || server.serve(handler).unwrap()),
+        )
+    }
+
+    // Wait for the backend to receive `expected`
+    fn expect(received: &Receiver<TelnetEvent>, expected: TelnetEvent) {
+        loop {
+            let event = received.recv_timeout(Duration::from_secs(5)).unwrap();
+            if event == expected {
+                return;
+            }
+        }
+    }
+
+    #[test]
+    fn gateway() {
+        assert_eq!(
+            parse_resize(r#"{"type": "resize", "cols": 100, "rows": 40}"#),
+            Some((100, 40))
+        );
+        assert_eq!(parse_resize(r#"{"type": "resize", "cols": -1}"#), None);
+        assert_eq!(parse_resize("resize"), None);
+
+        // A backend that asks for the window size and terminal type, and echoes data
+        let (events, received) = mpsc::channel();
+        let events = Mutex::new(events);
+        let (backend, backend_handle, backend_thread) = serve(
+            TelnetServer::bind("127.0.0.1:0").unwrap(),
+            move |mut stream: TelnetStream<TcpStream>, _| {
+                let events = events.lock().unwrap().clone();
+                stream
+                    .send_do(TelnetOption::NegotiateAboutWindowSize)
+                    .unwrap();
+                stream.send_do(TelnetOption::TerminalType).unwrap();
+                stream
+                    .send_event(TelnetSubnegotiation::TerminalTypeRequest.into())
+                    .unwrap();
+                while let Some(event) = stream.next() {
+                    if let TelnetEvent::Data(data) = &event {
+                        stream.send_data(&[b"echo: ", &data[..]].concat()).unwrap();
+                    }
+                    if events.send(event).is_err() {
+                        break;
+                    }
+                }
+            },
+        );
+        // Keepalive makes reads time out, even during the handshake
+        let mut server = TelnetServer::bind("127.0.0.1:0").unwrap();
+        server.set_keepalive(Some(KeepAlive::new(
+            Duration::from_millis(40),
+            KeepAliveProbe::Nop,
+            None,
+        )));
+        let (gateway, gateway_handle, gateway_thread) =
+            serve(server, WebSocketGateway::new(backend).unwrap());
+
+        // A client slow to start the handshake
+        let socket = TcpStream::connect(gateway).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        let (mut websocket, _) = tungstenite::client(format!("ws://{gateway}/"), socket).unwrap();
+        websocket
+            .send(Message::text(
+                r#"{"type": "resize", "cols": 100, "rows": 40}"#,
+            ))
+            .unwrap();
+        expect(
+            &received,
+            TelnetSubnegotiation::NegotiateAboutWindowSize {
+                width: 100,
+                height: 40,
+            }
+            .into(),
+        );
+        expect(
+            &received,
+            TelnetSubnegotiation::TerminalTypeResponse("XTERM-256COLOR".into()).into(),
+        );
+
+        websocket.send(Message::binary(b"hi".to_vec())).unwrap();
+        expect(&received, TelnetEvent::Data(b"hi".to_vec()));
+        let mut echoed = Vec::new();
+        while !echoed.ends_with(b"echo: hi") {
+            match websocket.read().unwrap() {
+                Message::Binary(data) => echoed.extend(data),
+                other => panic!("Expected binary message, but got {other:?}"),
+            }
+        }
+
+        websocket.close(None).unwrap();
+        gateway_handle.shutdown();
+        gateway_thread.join().unwrap();
+        backend_handle.shutdown();
+        backend_thread.join().unwrap();
+    }
+
+    #[test]
+    fn errors_reported() {
+        let (errors, failed) = mpsc::channel();
+        let errors = Mutex::new(errors);
+        // Nothing listens on the backend's address, but the handshake fails first anyway
+        let mut gateway = WebSocketGateway::new("127.0.0.1:1").unwrap();
+        gateway.set_error_handler(move |peer, err| {
+            errors
+                .lock()
+                .unwrap()
+                .send((peer, err.to_string()))
+                .unwrap();
+        });
+        let (address, handle, thread) = serve(TelnetServer::bind("127.0.0.1:0").unwrap(), gateway);
+
+        let mut client = TcpStream::connect(address).unwrap();
+        client
+            .write_all(b"not a WebSocket handshake\r\n\r\n")
+            .unwrap();
+        let (peer, _) = failed.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(peer, client.local_addr().unwrap());
+
+        handle.shutdown();
+        thread.join().unwrap();
+    }
+}